@@ -0,0 +1,7 @@
+fn main() {
+    // The kernel only loads ET_EXEC images, so link a plain static executable
+    // instead of the target's default static PIE.
+    if std::env::var("CARGO_CFG_TARGET_OS").as_deref() == Ok("none") {
+        println!("cargo:rustc-link-arg-bins=-no-pie");
+    }
+}
@@ -0,0 +1,83 @@
+// A user program for the kernel, built on `user_lib`. It prints its pid and
+// arguments and lists the root directory, then exits. The build packs it
+// into the initramfs as /bin/hello; the shell runs it with `exec /bin/hello`.
+
+#![no_std]
+#![no_main]
+
+use core::fmt::{self, Write};
+use user_lib::{DirEntries, DT_DIR, O_DIRECTORY, O_RDONLY, STDOUT};
+
+struct Stdout;
+
+impl Write for Stdout {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        user_lib::write(STDOUT, s.as_bytes()).map(|_| ()).map_err(|_| fmt::Error)
+    }
+}
+
+macro_rules! println {
+    ($($arg:tt)*) => {
+        let _ = writeln!(Stdout, $($arg)*);
+    };
+}
+
+// The kernel starts us with the stack pointer at argc, followed by the
+// argv pointers. Hand that to `start` with the stack aligned for a call.
+core::arch::global_asm!(
+    ".globl _start",
+    "_start:",
+    "mov rdi, rsp",
+    "and rsp, -16",
+    "call {start}",
+    "ud2",
+    start = sym start,
+);
+
+extern "C" fn start(stack: *const u64) -> ! {
+    let argc = unsafe { *stack } as usize;
+    println!("hello from pid {}", user_lib::getpid());
+    // The same call through the interrupt gate.
+    let pid = unsafe { user_lib::int80(user_lib::nr::GETPID, [0; 6]) };
+    println!("pid through int 0x80: {}", pid);
+    for i in 0..argc {
+        let arg = unsafe { c_str(*stack.add(1 + i) as *const u8) };
+        println!("argv[{}] = {}", i, arg);
+    }
+    list("/");
+    user_lib::exit(0);
+}
+
+/// The NUL-terminated string at `ptr`.
+///
+/// # Safety
+///
+/// `ptr` must point at a NUL-terminated string that is never freed.
+unsafe fn c_str(ptr: *const u8) -> &'static str {
+    let mut len = 0;
+    while unsafe { *ptr.add(len) } != 0 {
+        len += 1;
+    }
+    core::str::from_utf8(unsafe { core::slice::from_raw_parts(ptr, len) }).unwrap_or("?")
+}
+
+fn list(path: &str) {
+    let Ok(fd) = user_lib::open(path, O_RDONLY | O_DIRECTORY) else {
+        println!("{}: can't open", path);
+        return;
+    };
+    println!("{}:", path);
+    let mut buf = [0u8; 512];
+    while let Ok(len @ 1..) = user_lib::getdents(fd, &mut buf) {
+        for (_, kind, name) in DirEntries::new(&buf[..len]) {
+            println!("  {}{}", name, if kind == DT_DIR { "/" } else { "" });
+        }
+    }
+    let _ = user_lib::close(fd);
+}
+
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    println!("hello: {}", info);
+    user_lib::exit(101);
+}
@@ -0,0 +1,153 @@
+// Just enough ACPI table parsing to find the CPUs and PCI configuration
+// space: the RSDP the bootloader located, the RSDT/XSDT it points to, the
+// MADT ("APIC" table) listing every local APIC and the MCFG table locating
+// PCI Express ECAM windows. All tables are reached through the physical
+// memory map.
+
+use crate::memory::phys_to_virt;
+use alloc::vec::Vec;
+use x86_64::PhysAddr;
+
+const SDT_HEADER_SIZE: usize = 36;
+
+const MADT_LOCAL_APIC: u8 = 0;
+const MADT_LOCAL_APIC_OVERRIDE: u8 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    BadRsdp,
+    BadChecksum,
+    /// A table shorter than its own header.
+    BadLength,
+    TableNotFound,
+}
+
+/// What the MADT tells us about the local APICs.
+#[derive(Debug, Clone)]
+pub struct Madt {
+    pub local_apic_addr: PhysAddr,
+    /// APIC IDs of the usable processors, the bootstrap processor included.
+    pub apic_ids: Vec<u32>,
+}
+
+/// One ECAM window from the MCFG: configuration space for buses
+/// `start_bus..=end_bus` of a segment group, 1 MiB per bus.
+#[derive(Debug, Clone, Copy)]
+pub struct EcamWindow {
+    pub base: PhysAddr,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+fn read<T: Copy>(phys: u64) -> T {
+    unsafe { phys_to_virt(PhysAddr::new(phys)).as_ptr::<T>().read_unaligned() }
+}
+
+fn bytes(phys: u64, len: usize) -> &'static [u8] {
+    unsafe { core::slice::from_raw_parts(phys_to_virt(PhysAddr::new(phys)).as_ptr(), len) }
+}
+
+fn checksum_ok(data: &[u8]) -> bool {
+    data.iter().fold(0u8, |sum, &b| sum.wrapping_add(b)) == 0
+}
+
+/// Finds the table with the given signature. Returns its physical address
+/// and total length, header included.
+pub fn find_table(rsdp: u64, signature: &[u8; 4]) -> Result<(u64, usize), AcpiError> {
+    if bytes(rsdp, 8) != b"RSD PTR " || !checksum_ok(bytes(rsdp, 20)) {
+        return Err(AcpiError::BadRsdp);
+    }
+    let revision: u8 = read(rsdp + 15);
+
+    // ACPI 2.0+ has the XSDT with 64-bit pointers; prefer it when present.
+    let (root, entry_size) = if revision >= 2 && read::<u64>(rsdp + 24) != 0 {
+        (read::<u64>(rsdp + 24), 8)
+    } else {
+        (u64::from(read::<u32>(rsdp + 16)), 4)
+    };
+    let root_len = read::<u32>(root + 4) as usize;
+    if !checksum_ok(bytes(root, root_len)) {
+        return Err(AcpiError::BadChecksum);
+    }
+
+    let entries = root_len.checked_sub(SDT_HEADER_SIZE).ok_or(AcpiError::BadLength)? / entry_size;
+    for i in 0..entries {
+        let entry = root + (SDT_HEADER_SIZE + i * entry_size) as u64;
+        let table = if entry_size == 8 {
+            read::<u64>(entry)
+        } else {
+            u64::from(read::<u32>(entry))
+        };
+        if bytes(table, 4) != signature {
+            continue;
+        }
+        let len = read::<u32>(table + 4) as usize;
+        if len < SDT_HEADER_SIZE {
+            return Err(AcpiError::BadLength);
+        }
+        if !checksum_ok(bytes(table, len)) {
+            return Err(AcpiError::BadChecksum);
+        }
+        return Ok((table, len));
+    }
+    Err(AcpiError::TableNotFound)
+}
+
+/// Reads the local APIC entries from the MADT. x2APIC-only entries are not
+/// supported.
+pub fn parse_madt(rsdp: u64) -> Result<Madt, AcpiError> {
+    let (table, len) = find_table(rsdp, b"APIC")?;
+    let mut local_apic_addr = u64::from(read::<u32>(table + SDT_HEADER_SIZE as u64));
+    let mut apic_ids = Vec::new();
+
+    // Entries follow the local APIC address and the flags.
+    let mut offset = SDT_HEADER_SIZE + 8;
+    while offset + 2 <= len {
+        let entry = table + offset as u64;
+        let kind: u8 = read(entry);
+        let entry_len = read::<u8>(entry + 1) as usize;
+        if entry_len < 2 {
+            break;
+        }
+        match kind {
+            MADT_LOCAL_APIC => {
+                let apic_id: u8 = read(entry + 3);
+                let flags: u32 = read(entry + 4);
+                // Bit 0: enabled. Bit 1 alone marks a CPU that could be
+                // hot-added later, which isn't there now.
+                if flags & 1 != 0 {
+                    apic_ids.push(u32::from(apic_id));
+                }
+            }
+            MADT_LOCAL_APIC_OVERRIDE => local_apic_addr = read(entry + 4),
+            _ => {}
+        }
+        offset += entry_len;
+    }
+
+    Ok(Madt {
+        local_apic_addr: PhysAddr::new(local_apic_addr),
+        apic_ids,
+    })
+}
+
+/// Reads the ECAM windows from the MCFG. Machines without PCI Express (like
+/// QEMU's default i440FX) have no MCFG.
+pub fn parse_mcfg(rsdp: u64) -> Result<Vec<EcamWindow>, AcpiError> {
+    let (table, len) = find_table(rsdp, b"MCFG")?;
+    // 8 reserved bytes, then 16-byte entries.
+    let mut windows = Vec::new();
+    let mut offset = SDT_HEADER_SIZE + 8;
+    while offset + 16 <= len {
+        let entry = table + offset as u64;
+        windows.push(EcamWindow {
+            base: PhysAddr::new(read(entry)),
+            segment: read(entry + 8),
+            start_bus: read(entry + 10),
+            end_bus: read(entry + 11),
+        });
+        offset += 16;
+    }
+    Ok(windows)
+}
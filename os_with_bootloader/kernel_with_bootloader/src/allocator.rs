@@ -0,0 +1,133 @@
+// Kernel heap and the global allocator behind `alloc::{vec::Vec, string::String, ...}`.
+//
+// The heap is a fixed virtual range mapped at boot. How blocks are carved out
+// of it is chosen with a cargo feature:
+//   heap-linked-list  - first-fit free list (default)
+//   heap-fixed-block  - power-of-two block lists with a linked-list fallback
+//   heap-slab         - per-size slabs of whole pages with a linked-list fallback
+
+#[cfg(any(feature = "heap-fixed-block", feature = "heap-slab"))]
+pub mod fixed_size_block;
+pub mod linked_list;
+#[cfg(feature = "heap-slab")]
+pub mod slab;
+
+use crate::memory::{paging, AddressSpace, PagingError};
+use crate::sync::SpinLock;
+use core::alloc::{GlobalAlloc, Layout};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::structures::paging::{Page, PageTableFlags};
+
+pub const HEAP_SIZE: usize = 4 * 1024 * 1024; // 4 MiB
+
+#[cfg(feature = "heap-slab")]
+type Strategy = slab::SlabAllocator;
+#[cfg(all(feature = "heap-fixed-block", not(feature = "heap-slab")))]
+type Strategy = fixed_size_block::FixedSizeBlockAllocator;
+#[cfg(all(
+    feature = "heap-linked-list",
+    not(any(feature = "heap-fixed-block", feature = "heap-slab"))
+))]
+type Strategy = linked_list::LinkedListAllocator;
+
+#[cfg(not(any(
+    feature = "heap-linked-list",
+    feature = "heap-fixed-block",
+    feature = "heap-slab"
+)))]
+compile_error!("enable one of the heap-linked-list, heap-fixed-block or heap-slab features");
+
+/// What every heap strategy has to provide to sit behind [`KernelHeap`].
+pub trait HeapStrategy {
+    const NAME: &'static str;
+
+    /// # Safety
+    /// `start..start + size` must be mapped, writable and unused. Called once.
+    unsafe fn init(&mut self, start: usize, size: usize);
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8;
+
+    /// # Safety
+    /// `ptr` must come from `allocate` with the same `layout`.
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout);
+}
+
+pub struct KernelHeap {
+    strategy: SpinLock<Strategy>,
+    start: AtomicUsize,
+    size: AtomicUsize,
+    used: AtomicUsize,
+}
+
+#[global_allocator]
+static ALLOCATOR: KernelHeap = KernelHeap {
+    strategy: SpinLock::new(Strategy::new()),
+    start: AtomicUsize::new(0),
+    size: AtomicUsize::new(0),
+    used: AtomicUsize::new(0),
+};
+
+unsafe impl GlobalAlloc for KernelHeap {
+    // Interrupts stay off while the heap is locked, so a thread is never
+    // preempted in the middle of an allocation with the lock held.
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = without_interrupts(|| self.strategy.lock().allocate(layout));
+        if !ptr.is_null() {
+            self.used.fetch_add(layout.size(), Ordering::Relaxed);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        without_interrupts(|| unsafe { self.strategy.lock().deallocate(ptr, layout) });
+        self.used.fetch_sub(layout.size(), Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub strategy: &'static str,
+    pub start: usize,
+    pub size: usize,
+    pub used: usize,
+}
+
+pub fn stats() -> HeapStats {
+    HeapStats {
+        strategy: Strategy::NAME,
+        start: ALLOCATOR.start.load(Ordering::Relaxed),
+        size: ALLOCATOR.size.load(Ordering::Relaxed),
+        used: ALLOCATOR.used.load(Ordering::Relaxed),
+    }
+}
+
+/// Maps the heap into kernel space and hands it to the global allocator.
+pub fn init_heap() -> Result<(), PagingError> {
+    let start = paging::reserve_kernel_range(HEAP_SIZE as u64, 4096)?;
+    let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+    AddressSpace::current().map_anonymous(
+        Page::containing_address(start),
+        (HEAP_SIZE / 4096) as u64,
+        flags,
+    )?;
+
+    unsafe {
+        ALLOCATOR
+            .strategy
+            .lock()
+            .init(start.as_u64() as usize, HEAP_SIZE);
+    }
+    ALLOCATOR.start.store(start.as_u64() as usize, Ordering::Relaxed);
+    ALLOCATOR.size.store(HEAP_SIZE, Ordering::Relaxed);
+    Ok(())
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> ! {
+    let stats = stats();
+    panic!(
+        "allocation error: {:?}\n heap ({}) at {:#x}: {} of {} bytes in use",
+        layout, stats.strategy, stats.start, stats.used, stats.size
+    );
+}
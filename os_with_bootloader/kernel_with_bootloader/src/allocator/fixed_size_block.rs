@@ -0,0 +1,90 @@
+use super::HeapStrategy;
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+use core::mem;
+use linked_list_allocator::Heap;
+
+/// Block sizes handed out from the per-size lists. Each size is also the
+/// block's alignment, so they must be powers of two. Anything bigger goes to
+/// the fallback heap.
+pub const BLOCK_SIZES: &[usize] = &[8, 16, 32, 64, 128, 256, 512, 1024, 2048];
+
+/// Free block, stored in the block itself.
+pub struct ListNode {
+    pub next: Option<&'static mut ListNode>,
+}
+
+/// Index into [`BLOCK_SIZES`] of the smallest block that fits `layout`.
+pub fn list_index(layout: &Layout) -> Option<usize> {
+    let required = layout.size().max(layout.align());
+    BLOCK_SIZES.iter().position(|&size| size >= required)
+}
+
+/// Keeps one free list per block size. Freed blocks go back on their list and
+/// are never merged, so allocation and free are both O(1).
+pub struct FixedSizeBlockAllocator {
+    list_heads: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    fallback: Heap,
+}
+
+impl FixedSizeBlockAllocator {
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        FixedSizeBlockAllocator {
+            list_heads: [EMPTY; BLOCK_SIZES.len()],
+            fallback: Heap::empty(),
+        }
+    }
+
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        self.fallback
+            .allocate_first_fit(layout)
+            .map_or(ptr::null_mut(), |ptr| ptr.as_ptr())
+    }
+}
+
+impl HeapStrategy for FixedSizeBlockAllocator {
+    const NAME: &'static str = "fixed-block";
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        unsafe { self.fallback.init(start as *mut u8, size) };
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        match list_index(&layout) {
+            Some(index) => match self.list_heads[index].take() {
+                Some(node) => {
+                    self.list_heads[index] = node.next.take();
+                    node as *mut ListNode as *mut u8
+                }
+                None => {
+                    // List is empty, carve a new block out of the fallback heap.
+                    let size = BLOCK_SIZES[index];
+                    let layout = Layout::from_size_align(size, size).unwrap();
+                    self.fallback_alloc(layout)
+                }
+            },
+            None => self.fallback_alloc(layout),
+        }
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                // Every block size is at least as big and aligned as a ListNode.
+                assert!(mem::size_of::<ListNode>() <= BLOCK_SIZES[index]);
+                assert!(mem::align_of::<ListNode>() <= BLOCK_SIZES[index]);
+                let node = ptr as *mut ListNode;
+                unsafe {
+                    node.write(ListNode {
+                        next: self.list_heads[index].take(),
+                    });
+                    self.list_heads[index] = Some(&mut *node);
+                }
+            }
+            None => unsafe {
+                self.fallback.deallocate(NonNull::new_unchecked(ptr), layout);
+            },
+        }
+    }
+}
@@ -0,0 +1,34 @@
+use super::HeapStrategy;
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+
+/// First-fit allocator over a single free list. Handles any size and
+/// alignment, but every allocation walks the list.
+pub struct LinkedListAllocator {
+    heap: Heap,
+}
+
+impl LinkedListAllocator {
+    pub const fn new() -> Self {
+        LinkedListAllocator { heap: Heap::empty() }
+    }
+}
+
+impl HeapStrategy for LinkedListAllocator {
+    const NAME: &'static str = "linked-list";
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        unsafe { self.heap.init(start as *mut u8, size) };
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        self.heap
+            .allocate_first_fit(layout)
+            .map_or(ptr::null_mut(), |ptr| ptr.as_ptr())
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        unsafe { self.heap.deallocate(NonNull::new_unchecked(ptr), layout) };
+    }
+}
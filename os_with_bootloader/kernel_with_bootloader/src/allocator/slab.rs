@@ -0,0 +1,97 @@
+use super::fixed_size_block::{list_index, ListNode, BLOCK_SIZES};
+use super::HeapStrategy;
+use core::alloc::Layout;
+use core::ptr::{self, NonNull};
+use linked_list_allocator::Heap;
+
+const SLAB_SIZE: usize = 4096;
+
+/// One cache per object size. When a cache runs dry it takes a whole page
+/// from the fallback heap and splits it into objects, so objects of the same
+/// size stay packed together instead of being scattered over the heap.
+pub struct SlabAllocator {
+    free_lists: [Option<&'static mut ListNode>; BLOCK_SIZES.len()],
+    slab_pages: [usize; BLOCK_SIZES.len()],
+    fallback: Heap,
+}
+
+impl SlabAllocator {
+    pub const fn new() -> Self {
+        const EMPTY: Option<&'static mut ListNode> = None;
+        SlabAllocator {
+            free_lists: [EMPTY; BLOCK_SIZES.len()],
+            slab_pages: [0; BLOCK_SIZES.len()],
+            fallback: Heap::empty(),
+        }
+    }
+
+    /// Number of pages given to each size class so far.
+    pub fn slab_pages(&self) -> &[usize] {
+        &self.slab_pages
+    }
+
+    fn fallback_alloc(&mut self, layout: Layout) -> *mut u8 {
+        self.fallback
+            .allocate_first_fit(layout)
+            .map_or(ptr::null_mut(), |ptr| ptr.as_ptr())
+    }
+
+    // Splits a fresh page into objects of size class `index`.
+    fn grow(&mut self, index: usize) -> bool {
+        let page = self.fallback_alloc(Layout::from_size_align(SLAB_SIZE, SLAB_SIZE).unwrap());
+        if page.is_null() {
+            return false;
+        }
+
+        let size = BLOCK_SIZES[index];
+        for offset in (0..SLAB_SIZE).step_by(size).rev() {
+            let node = unsafe { page.add(offset) } as *mut ListNode;
+            unsafe {
+                node.write(ListNode {
+                    next: self.free_lists[index].take(),
+                });
+                self.free_lists[index] = Some(&mut *node);
+            }
+        }
+        self.slab_pages[index] += 1;
+        true
+    }
+}
+
+impl HeapStrategy for SlabAllocator {
+    const NAME: &'static str = "slab";
+
+    unsafe fn init(&mut self, start: usize, size: usize) {
+        unsafe { self.fallback.init(start as *mut u8, size) };
+    }
+
+    fn allocate(&mut self, layout: Layout) -> *mut u8 {
+        let Some(index) = list_index(&layout) else {
+            return self.fallback_alloc(layout);
+        };
+
+        if self.free_lists[index].is_none() && !self.grow(index) {
+            return ptr::null_mut();
+        }
+        let node = self.free_lists[index].take().unwrap();
+        self.free_lists[index] = node.next.take();
+        node as *mut ListNode as *mut u8
+    }
+
+    unsafe fn deallocate(&mut self, ptr: *mut u8, layout: Layout) {
+        match list_index(&layout) {
+            Some(index) => {
+                let node = ptr as *mut ListNode;
+                unsafe {
+                    node.write(ListNode {
+                        next: self.free_lists[index].take(),
+                    });
+                    self.free_lists[index] = Some(&mut *node);
+                }
+            }
+            None => unsafe {
+                self.fallback.deallocate(NonNull::new_unchecked(ptr), layout);
+            },
+        }
+    }
+}
@@ -0,0 +1,127 @@
+// Local APIC (xAPIC, memory-mapped). Every CPU sees its own APIC at the same
+// address. The bootstrap CPU keeps taking its timer and keyboard interrupts
+// from the legacy PIC; the APIC is used for inter-processor interrupts and as
+// the timer on the application processors.
+
+use crate::memory::paging::map_mmio;
+use crate::memory::PagingError;
+use crate::time;
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+use x86_64::PhysAddr;
+
+/// Vectors of the APIC-delivered interrupts, see `interrupts.rs`.
+pub const TIMER_VECTOR: u8 = 0xF0;
+pub const TLB_SHOOTDOWN_VECTOR: u8 = 0xFD;
+pub const SPURIOUS_VECTOR: u8 = 0xFF;
+
+// Register offsets.
+const ID: usize = 0x20;
+const TASK_PRIORITY: usize = 0x80;
+const EOI: usize = 0xB0;
+const SPURIOUS: usize = 0xF0;
+const ICR_LOW: usize = 0x300;
+const ICR_HIGH: usize = 0x310;
+const LVT_TIMER: usize = 0x320;
+const TIMER_INITIAL: usize = 0x380;
+const TIMER_CURRENT: usize = 0x390;
+const TIMER_DIVIDE: usize = 0x3E0;
+
+const SOFTWARE_ENABLE: u32 = 1 << 8;
+const ICR_DELIVERY_PENDING: u32 = 1 << 12;
+const ICR_INIT: u32 = 0b101 << 8;
+const ICR_STARTUP: u32 = 0b110 << 8;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_ALL_EXCLUDING_SELF: u32 = 0b11 << 18;
+const TIMER_PERIODIC: u32 = 1 << 17;
+const TIMER_DIVIDE_BY_16: u32 = 0b0011;
+
+// Zero until `init` has mapped the registers.
+static BASE: AtomicU64 = AtomicU64::new(0);
+// APIC timer counts per scheduler tick, measured once on the bootstrap CPU.
+static TIMER_COUNT: AtomicU32 = AtomicU32::new(0);
+
+fn register(offset: usize) -> *mut u32 {
+    (BASE.load(Ordering::Relaxed) as usize + offset) as *mut u32
+}
+
+fn read(offset: usize) -> u32 {
+    unsafe { register(offset).read_volatile() }
+}
+
+fn write(offset: usize, value: u32) {
+    unsafe { register(offset).write_volatile(value) }
+}
+
+/// Maps the APIC registers and enables the bootstrap CPU's APIC.
+pub fn init(phys: PhysAddr) -> Result<(), PagingError> {
+    let base = map_mmio(phys, 4096)?;
+    BASE.store(base.as_u64(), Ordering::Relaxed);
+    enable();
+    Ok(())
+}
+
+/// Enables the calling CPU's APIC and lets every interrupt through.
+pub fn enable() {
+    write(TASK_PRIORITY, 0);
+    write(SPURIOUS, SOFTWARE_ENABLE | u32::from(SPURIOUS_VECTOR));
+}
+
+pub fn id() -> u32 {
+    read(ID) >> 24
+}
+
+pub fn end_of_interrupt() {
+    write(EOI, 0);
+}
+
+fn send(destination: u32, command: u32) {
+    write(ICR_HIGH, destination << 24);
+    write(ICR_LOW, command);
+    while read(ICR_LOW) & ICR_DELIVERY_PENDING != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Sends the INIT IPI that puts an application processor into wait-for-SIPI.
+pub fn send_init(apic_id: u32) {
+    send(apic_id, ICR_INIT | ICR_LEVEL_ASSERT);
+}
+
+/// Sends a startup IPI; the CPU starts in real mode at `page * 4096`.
+pub fn send_startup(apic_id: u32, page: u8) {
+    send(apic_id, ICR_STARTUP | ICR_LEVEL_ASSERT | u32::from(page));
+}
+
+/// Sends `vector` to every CPU but this one.
+pub fn broadcast(vector: u8) {
+    send(0, ICR_ALL_EXCLUDING_SELF | ICR_LEVEL_ASSERT | u32::from(vector));
+}
+
+/// Measures how fast the APIC timer counts against the PIT. Needs the PIT
+/// interrupt running.
+pub fn calibrate_timer() {
+    const TICKS: u64 = 5;
+    write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    // Masked one-shot count, only read back.
+    write(LVT_TIMER, 1 << 16);
+
+    let start = time::ticks();
+    while time::ticks() == start {
+        core::hint::spin_loop();
+    }
+    write(TIMER_INITIAL, u32::MAX);
+    while time::ticks() < start + 1 + TICKS {
+        core::hint::spin_loop();
+    }
+    let elapsed = u32::MAX - read(TIMER_CURRENT);
+    write(TIMER_INITIAL, 0);
+
+    TIMER_COUNT.store(elapsed / TICKS as u32, Ordering::Relaxed);
+}
+
+/// Starts the calling CPU's APIC timer at the scheduler's tick rate.
+pub fn start_timer() {
+    write(TIMER_DIVIDE, TIMER_DIVIDE_BY_16);
+    write(LVT_TIMER, TIMER_PERIODIC | u32::from(TIMER_VECTOR));
+    write(TIMER_INITIAL, TIMER_COUNT.load(Ordering::Relaxed).max(1));
+}
@@ -0,0 +1,253 @@
+// Block devices: disks and the partitions on them, addressed in 512-byte
+// sectors.
+//
+// Drivers hand their disks to `register`, which puts each one behind a sector
+// cache, reads its partition table and makes the disk and every partition
+// available by name and as /dev/<name>.
+
+pub mod ata;
+pub mod cache;
+pub mod partition;
+pub mod virtio_blk;
+
+use crate::fs::{devfs, FileType, FsError, Inode, Metadata};
+use crate::sync::Mutex;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use cache::BlockCache;
+use partition::{Partition, PartitionDevice};
+
+pub const SECTOR_SIZE: usize = 512;
+
+// Sectors a disk's cache holds.
+const CACHE_SECTORS: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// The request reaches past the end of the device.
+    OutOfRange,
+    /// The buffer isn't a whole number of sectors.
+    BadBuffer,
+    ReadOnly,
+    /// The device reported an error.
+    Device,
+    /// The device didn't answer in time.
+    Timeout,
+}
+
+/// A device storing data in sectors of `SECTOR_SIZE` bytes.
+pub trait BlockDevice: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn sector_count(&self) -> u64;
+
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    /// Reads `buf.len() / SECTOR_SIZE` sectors starting at `sector`.
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError>;
+
+    /// Writes `buf.len() / SECTOR_SIZE` sectors starting at `sector`.
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError>;
+
+    /// Makes completed writes durable.
+    fn flush(&self) -> Result<(), BlockError> {
+        Ok(())
+    }
+}
+
+/// Checks that a request for `len` bytes at `sector` fits `device`; returns
+/// the number of sectors.
+pub fn check_request(device: &dyn BlockDevice, sector: u64, len: usize) -> Result<u64, BlockError> {
+    if len % SECTOR_SIZE != 0 {
+        return Err(BlockError::BadBuffer);
+    }
+    let count = (len / SECTOR_SIZE) as u64;
+    match sector.checked_add(count) {
+        Some(end) if end <= device.sector_count() => Ok(count),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// A registered disk.
+#[derive(Clone)]
+pub struct Disk {
+    pub device: Arc<BlockCache>,
+    pub partitions: Vec<Partition>,
+}
+
+impl Disk {
+    /// The devices that can hold a file system: the partitions, or the whole
+    /// disk if it has no partition table.
+    pub fn volumes(&self) -> Vec<Arc<dyn BlockDevice>> {
+        if self.partitions.is_empty() {
+            return vec![self.device.clone()];
+        }
+        self.partitions
+            .iter()
+            .filter_map(|partition| find(&partition_name(self.device.name(), partition.number)))
+            .collect()
+    }
+}
+
+fn partition_name(disk: &str, number: usize) -> String {
+    format!("{}{}", disk, number)
+}
+
+static DISKS: Mutex<Vec<Disk>> = Mutex::new(Vec::new());
+static DEVICES: Mutex<Vec<Arc<dyn BlockDevice>>> = Mutex::new(Vec::new());
+
+/// Probes for disks on every driver.
+pub fn init() {
+    ata::probe();
+    crate::pci::register_driver(&virtio_blk::DRIVER);
+}
+
+/// Makes `device` and its partitions available. A partition table that
+/// can't be read leaves just the whole disk.
+pub fn register(device: Arc<dyn BlockDevice>) {
+    let cached = Arc::new(BlockCache::new(device, CACHE_SECTORS));
+    let partitions = partition::scan(&*cached).unwrap_or_default();
+    add_device(cached.clone());
+    for partition in &partitions {
+        let name = partition_name(cached.name(), partition.number);
+        add_device(Arc::new(PartitionDevice::new(name, cached.clone(), partition)));
+    }
+    DISKS.lock().push(Disk {
+        device: cached,
+        partitions,
+    });
+}
+
+fn add_device(device: Arc<dyn BlockDevice>) {
+    devfs::register(
+        device.name(),
+        Arc::new(BlockFile {
+            inode: devfs::allocate_inode(),
+            device: device.clone(),
+        }),
+    );
+    DEVICES.lock().push(device);
+}
+
+pub fn disks() -> Vec<Disk> {
+    DISKS.lock().clone()
+}
+
+/// The disk or partition called `name`, e.g. "vda" or "vda1".
+pub fn find(name: &str) -> Option<Arc<dyn BlockDevice>> {
+    DEVICES.lock().iter().find(|device| device.name() == name).cloned()
+}
+
+impl From<BlockError> for FsError {
+    fn from(err: BlockError) -> Self {
+        match err {
+            BlockError::OutOfRange => FsError::InvalidArgument,
+            BlockError::ReadOnly => FsError::ReadOnly,
+            BlockError::BadBuffer | BlockError::Device | BlockError::Timeout => FsError::Io,
+        }
+    }
+}
+
+/// A block device as a file in /dev, readable and writable at any byte
+/// offset.
+struct BlockFile {
+    inode: u64,
+    device: Arc<dyn BlockDevice>,
+}
+
+impl BlockFile {
+    fn size(&self) -> u64 {
+        self.device.sector_count() * SECTOR_SIZE as u64
+    }
+
+    // The whole sectors covering `len` bytes at `offset`.
+    fn sectors(offset: u64, len: usize) -> (u64, Vec<u8>) {
+        let first = offset / SECTOR_SIZE as u64;
+        let end = (offset + len as u64).div_ceil(SECTOR_SIZE as u64);
+        (first, vec![0; ((end - first) as usize) * SECTOR_SIZE])
+    }
+}
+
+impl Inode for BlockFile {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.inode,
+            kind: FileType::BlockDevice,
+            size: self.size(),
+            mode: if self.device.read_only() { 0o440 } else { 0o660 },
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let len = buf.len().min(self.size().saturating_sub(offset) as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        let (first, mut sectors) = BlockFile::sectors(offset, len);
+        self.device.read_sectors(first, &mut sectors)?;
+        let start = (offset % SECTOR_SIZE as u64) as usize;
+        buf[..len].copy_from_slice(&sectors[start..start + len]);
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let len = buf.len().min(self.size().saturating_sub(offset) as usize);
+        if len == 0 {
+            return if buf.is_empty() { Ok(0) } else { Err(FsError::InvalidArgument) };
+        }
+        let (first, mut sectors) = BlockFile::sectors(offset, len);
+        let start = (offset % SECTOR_SIZE as u64) as usize;
+        // Partial sectors at either end keep their other bytes.
+        if start != 0 || len % SECTOR_SIZE != 0 {
+            self.device.read_sectors(first, &mut sectors)?;
+        }
+        sectors[start..start + len].copy_from_slice(&buf[..len]);
+        self.device.write_sectors(first, &sectors)?;
+        Ok(len)
+    }
+}
+
+/// Writes a pattern to the last sector of the scratch disk's second
+/// partition and reads it back past the cache. Only built with the
+/// `selftest` feature; the runner attaches the scratch disk as vda.
+#[cfg(feature = "selftest")]
+pub fn self_check() -> bool {
+    use crate::println;
+
+    let mut ok = true;
+    let mut check = |name: &str, passed: bool| {
+        println!("selftest: {:<28} {}", name, if passed { "ok" } else { "FAILED" });
+        ok &= passed;
+    };
+
+    let disks = disks();
+    let Some(scratch) = disks.iter().find(|disk| disk.device.name() == "vda") else {
+        check("virtio-blk scratch disk", false);
+        return false;
+    };
+    check("scratch disk partitions", scratch.partitions.len() >= 2);
+    if let Some(boot) = disks.iter().find(|disk| disk.device.name() == "hda") {
+        let mut mbr = [0u8; SECTOR_SIZE];
+        let read = boot.device.read_sectors(0, &mut mbr).is_ok();
+        check("ATA boot sector", read && mbr[510..512] == [0x55, 0xAA]);
+    }
+    let (Some(partition), Some(layout)) = (find("vda2"), scratch.partitions.iter().find(|p| p.number == 2))
+    else {
+        return false;
+    };
+
+    let sector = partition.sector_count() - 1;
+    let pattern: Vec<u8> = (0..SECTOR_SIZE).map(|i| (i * 7 + 3) as u8).collect();
+    let written = partition.write_sectors(sector, &pattern).is_ok() && partition.flush().is_ok();
+    scratch.device.invalidate();
+    let mut readback = vec![0u8; SECTOR_SIZE];
+    let absolute = layout.start + sector;
+    let read = scratch.device.read_sectors(absolute, &mut readback).is_ok();
+    check("virtio-blk write/read back", written && read && readback == pattern);
+    ok
+}
@@ -0,0 +1,286 @@
+// IDE/ATA disks in PIO mode on the two legacy channels. Slow, since the CPU
+// moves every word, but every PC and QEMU's default disk interface have it.
+//
+// The drives' interrupts are switched off and the status register is polled.
+// Both drives of a channel share its registers, so a channel lock covers
+// selecting a drive and the whole transfer.
+
+use super::{check_request, register, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::sync::Mutex;
+use alloc::string::String;
+use alloc::sync::Arc;
+use x86_64::instructions::port::Port;
+
+// Task file registers, relative to the channel's I/O base.
+const DATA: u16 = 0;
+const SECTOR_COUNT: u16 = 2;
+const LBA_LOW: u16 = 3;
+const LBA_MID: u16 = 4;
+const LBA_HIGH: u16 = 5;
+const DRIVE: u16 = 6;
+const COMMAND: u16 = 7;
+const STATUS: u16 = 7;
+
+const STATUS_ERR: u8 = 1 << 0;
+const STATUS_DRQ: u8 = 1 << 3;
+const STATUS_DF: u8 = 1 << 5;
+const STATUS_BSY: u8 = 1 << 7;
+
+// Device control register: interrupts off.
+const CONTROL_NIEN: u8 = 1 << 1;
+
+const CMD_READ_SECTORS: u8 = 0x20;
+const CMD_READ_SECTORS_EXT: u8 = 0x24;
+const CMD_WRITE_SECTORS: u8 = 0x30;
+const CMD_WRITE_SECTORS_EXT: u8 = 0x34;
+const CMD_CACHE_FLUSH: u8 = 0xE7;
+const CMD_CACHE_FLUSH_EXT: u8 = 0xEA;
+const CMD_IDENTIFY: u8 = 0xEC;
+
+// Status polls before a drive counts as hung; each is an I/O port read.
+const POLL_LIMIT: usize = 10_000_000;
+
+// The largest transfer one command can ask for.
+const LBA28_MAX_SECTORS: u64 = 256;
+const LBA48_MAX_SECTORS: u64 = 65536;
+
+struct Channel {
+    io_base: u16,
+    control: u16,
+    lock: Mutex<()>,
+}
+
+static CHANNELS: [Channel; 2] = [
+    Channel {
+        io_base: 0x1F0,
+        control: 0x3F6,
+        lock: Mutex::new(()),
+    },
+    Channel {
+        io_base: 0x170,
+        control: 0x376,
+        lock: Mutex::new(()),
+    },
+];
+
+impl Channel {
+    fn read(&self, register: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io_base + register).read() }
+    }
+
+    fn write(&self, register: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io_base + register).write(value) }
+    }
+
+    // The alternate status register reads the status without side effects.
+    fn alt_status(&self) -> u8 {
+        unsafe { Port::<u8>::new(self.control).read() }
+    }
+
+    fn select(&self, slave: bool, lba_bits: u8) {
+        self.write(DRIVE, 0xA0 | 0x40 | (slave as u8) << 4 | lba_bits);
+        // Drives need 400 ns to put their status up after a select.
+        for _ in 0..4 {
+            self.alt_status();
+        }
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, BlockError> {
+        for _ in 0..POLL_LIMIT {
+            let status = self.alt_status();
+            if status & STATUS_BSY == 0 {
+                return Ok(status);
+            }
+            core::hint::spin_loop();
+        }
+        Err(BlockError::Timeout)
+    }
+
+    // Waits until the drive wants the next sector moved.
+    fn wait_data(&self) -> Result<(), BlockError> {
+        let status = self.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 || status & STATUS_DRQ == 0 {
+            return Err(BlockError::Device);
+        }
+        Ok(())
+    }
+
+    fn read_words(&self, buf: &mut [u8]) {
+        let mut port = Port::<u16>::new(self.io_base + DATA);
+        for word in buf.chunks_exact_mut(2) {
+            word.copy_from_slice(&unsafe { port.read() }.to_le_bytes());
+        }
+    }
+
+    fn write_words(&self, buf: &[u8]) {
+        let mut port = Port::<u16>::new(self.io_base + DATA);
+        for word in buf.chunks_exact(2) {
+            unsafe { port.write(u16::from_le_bytes([word[0], word[1]])) };
+        }
+    }
+}
+
+pub struct AtaDrive {
+    name: String,
+    channel: &'static Channel,
+    slave: bool,
+    sectors: u64,
+    lba48: bool,
+    model: String,
+}
+
+impl AtaDrive {
+    /// The model string the drive reported.
+    pub fn model(&self) -> &str {
+        &self.model
+    }
+
+    fn identify(channel: &'static Channel, slave: bool, name: String) -> Option<AtaDrive> {
+        let _guard = channel.lock.lock();
+        unsafe { Port::<u8>::new(channel.control).write(CONTROL_NIEN) };
+        // A channel with nothing attached floats high.
+        if channel.alt_status() == 0xFF {
+            return None;
+        }
+        channel.select(slave, 0);
+        for register in [SECTOR_COUNT, LBA_LOW, LBA_MID, LBA_HIGH] {
+            channel.write(register, 0);
+        }
+        channel.write(COMMAND, CMD_IDENTIFY);
+        if channel.read(STATUS) == 0 {
+            return None;
+        }
+        channel.wait_not_busy().ok()?;
+        // ATAPI and SATA devices answer with a signature instead.
+        if channel.read(LBA_MID) != 0 || channel.read(LBA_HIGH) != 0 {
+            return None;
+        }
+        channel.wait_data().ok()?;
+        let mut identify = [0u8; SECTOR_SIZE];
+        channel.read_words(&mut identify);
+
+        let word = |i: usize| u16::from_le_bytes([identify[2 * i], identify[2 * i + 1]]);
+        let lba48 = word(83) & (1 << 10) != 0;
+        let sectors = if lba48 {
+            (0..4).map(|i| (word(100 + i) as u64) << (16 * i)).sum()
+        } else {
+            word(60) as u64 | (word(61) as u64) << 16
+        };
+        if sectors == 0 {
+            return None;
+        }
+        // The model string is stored with the bytes of each word swapped.
+        let model: String = (27..47)
+            .flat_map(|i| word(i).to_be_bytes())
+            .map(char::from)
+            .collect::<String>()
+            .trim()
+            .into();
+        Some(AtaDrive {
+            name,
+            channel,
+            slave,
+            sectors,
+            lba48,
+            model,
+        })
+    }
+
+    // Issues a read or write of `count` sectors at `lba` and leaves the
+    // channel waiting for the first data transfer.
+    fn command(&self, lba: u64, count: u64, write: bool) {
+        let channel = self.channel;
+        if self.lba48 {
+            channel.select(self.slave, 0);
+            // High bytes first, then low; each register holds two bytes.
+            channel.write(SECTOR_COUNT, (count >> 8) as u8);
+            channel.write(LBA_LOW, (lba >> 24) as u8);
+            channel.write(LBA_MID, (lba >> 32) as u8);
+            channel.write(LBA_HIGH, (lba >> 40) as u8);
+            channel.write(SECTOR_COUNT, count as u8);
+            channel.write(LBA_LOW, lba as u8);
+            channel.write(LBA_MID, (lba >> 8) as u8);
+            channel.write(LBA_HIGH, (lba >> 16) as u8);
+            channel.write(COMMAND, if write { CMD_WRITE_SECTORS_EXT } else { CMD_READ_SECTORS_EXT });
+        } else {
+            channel.select(self.slave, (lba >> 24) as u8 & 0x0F);
+            // A count of 0 means 256.
+            channel.write(SECTOR_COUNT, count as u8);
+            channel.write(LBA_LOW, lba as u8);
+            channel.write(LBA_MID, (lba >> 8) as u8);
+            channel.write(LBA_HIGH, (lba >> 16) as u8);
+            channel.write(COMMAND, if write { CMD_WRITE_SECTORS } else { CMD_READ_SECTORS });
+        }
+    }
+
+    fn max_sectors(&self) -> u64 {
+        if self.lba48 { LBA48_MAX_SECTORS } else { LBA28_MAX_SECTORS }
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buf.len())?;
+        let _guard = self.channel.lock.lock();
+        let step = self.max_sectors() as usize * SECTOR_SIZE;
+        for (i, chunk) in buf.chunks_mut(step).enumerate() {
+            let lba = sector + (i * step / SECTOR_SIZE) as u64;
+            self.command(lba, (chunk.len() / SECTOR_SIZE) as u64, false);
+            for data in chunk.chunks_exact_mut(SECTOR_SIZE) {
+                self.channel.wait_data()?;
+                self.channel.read_words(data);
+            }
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buf.len())?;
+        let _guard = self.channel.lock.lock();
+        let step = self.max_sectors() as usize * SECTOR_SIZE;
+        for (i, chunk) in buf.chunks(step).enumerate() {
+            let lba = sector + (i * step / SECTOR_SIZE) as u64;
+            self.command(lba, (chunk.len() / SECTOR_SIZE) as u64, true);
+            for data in chunk.chunks_exact(SECTOR_SIZE) {
+                self.channel.wait_data()?;
+                self.channel.write_words(data);
+            }
+        }
+        let status = self.channel.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(BlockError::Device);
+        }
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        let _guard = self.channel.lock.lock();
+        self.channel.select(self.slave, 0);
+        self.channel.write(COMMAND, if self.lba48 { CMD_CACHE_FLUSH_EXT } else { CMD_CACHE_FLUSH });
+        let status = self.channel.wait_not_busy()?;
+        if status & (STATUS_ERR | STATUS_DF) != 0 {
+            return Err(BlockError::Device);
+        }
+        Ok(())
+    }
+}
+
+/// Registers the drives found on both channels as hda to hdd.
+pub fn probe() {
+    for (index, channel) in CHANNELS.iter().enumerate() {
+        for slave in [false, true] {
+            let name = String::from(["hda", "hdb", "hdc", "hdd"][index * 2 + slave as usize]);
+            if let Some(drive) = AtaDrive::identify(channel, slave, name) {
+                register(Arc::new(drive));
+            }
+        }
+    }
+}
@@ -0,0 +1,163 @@
+// Sector cache in front of a disk.
+//
+// Writes go straight through to the disk and update the cached copy, so the
+// cache never holds anything the disk doesn't and can be dropped at any time.
+// When full, the least recently used sector goes. Large reads bypass it so
+// that streaming through a file doesn't push out the metadata everything else
+// keeps coming back to.
+
+use super::{check_request, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::sync::Mutex;
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::sync::Arc;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub cached: usize,
+}
+
+pub struct BlockCache {
+    device: Arc<dyn BlockDevice>,
+    capacity: usize,
+    state: Mutex<CacheState>,
+}
+
+struct CacheState {
+    sectors: BTreeMap<u64, Entry>,
+    // Advances on every access; an entry's stamp says when it was last used.
+    clock: u64,
+    hits: u64,
+    misses: u64,
+}
+
+struct Entry {
+    data: Box<[u8; SECTOR_SIZE]>,
+    last_used: u64,
+}
+
+impl CacheState {
+    fn insert(&mut self, capacity: usize, sector: u64, data: &[u8]) {
+        self.clock += 1;
+        if let Some(entry) = self.sectors.get_mut(&sector) {
+            entry.data.copy_from_slice(data);
+            entry.last_used = self.clock;
+            return;
+        }
+        if self.sectors.len() >= capacity {
+            let oldest = self.sectors.iter().min_by_key(|(_, entry)| entry.last_used).map(|(&s, _)| s);
+            if let Some(oldest) = oldest {
+                self.sectors.remove(&oldest);
+            }
+        }
+        let mut copy = Box::new([0u8; SECTOR_SIZE]);
+        copy.copy_from_slice(data);
+        self.sectors.insert(
+            sector,
+            Entry {
+                data: copy,
+                last_used: self.clock,
+            },
+        );
+    }
+}
+
+impl BlockCache {
+    /// Caches up to `capacity` sectors of `device`.
+    pub fn new(device: Arc<dyn BlockDevice>, capacity: usize) -> Self {
+        BlockCache {
+            device,
+            capacity,
+            state: Mutex::new(CacheState {
+                sectors: BTreeMap::new(),
+                clock: 0,
+                hits: 0,
+                misses: 0,
+            }),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let state = self.state.lock();
+        CacheStats {
+            hits: state.hits,
+            misses: state.misses,
+            cached: state.sectors.len(),
+        }
+    }
+
+    /// Forgets every cached sector, so the next reads go to the disk.
+    pub fn invalidate(&self) {
+        self.state.lock().sectors.clear();
+    }
+
+    // Requests of more sectors than this aren't cached.
+    fn bypass_above(&self) -> u64 {
+        (self.capacity / 4) as u64
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn name(&self) -> &str {
+        self.device.name()
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.device.sector_count()
+    }
+
+    fn read_only(&self) -> bool {
+        self.device.read_only()
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        let count = check_request(self, sector, buf.len())?;
+        if count > self.bypass_above() {
+            return self.device.read_sectors(sector, buf);
+        }
+
+        let mut state = self.state.lock();
+        let all_cached = (sector..sector + count).all(|s| state.sectors.contains_key(&s));
+        if all_cached {
+            state.hits += count;
+            state.clock += 1;
+            let clock = state.clock;
+            for (i, chunk) in buf.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+                let entry = state.sectors.get_mut(&(sector + i as u64)).unwrap();
+                entry.last_used = clock;
+                chunk.copy_from_slice(&*entry.data);
+            }
+            return Ok(());
+        }
+
+        // One request for the whole range is cheaper than filling the gaps.
+        state.misses += count;
+        self.device.read_sectors(sector, buf)?;
+        for (i, chunk) in buf.chunks_exact(SECTOR_SIZE).enumerate() {
+            state.insert(self.capacity, sector + i as u64, chunk);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        let count = check_request(self, sector, buf.len())?;
+        let mut state = self.state.lock();
+        let result = self.device.write_sectors(sector, buf);
+        for (i, chunk) in buf.chunks_exact(SECTOR_SIZE).enumerate() {
+            let s = sector + i as u64;
+            // After a failed write the disk's contents are unknown.
+            if result.is_err() {
+                state.sectors.remove(&s);
+            } else if count <= self.bypass_above() || state.sectors.contains_key(&s) {
+                state.insert(self.capacity, s, chunk);
+            }
+        }
+        result
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.device.flush()
+    }
+}
@@ -0,0 +1,297 @@
+// Partition tables: MBR, with logical partitions in an extended partition,
+// and GPT. A disk whose MBR only holds the protective entry is read as GPT,
+// falling back to the backup header at the end of the disk when the primary
+// one is damaged.
+//
+// Partitions are numbered the way Linux does: MBR primaries by slot (1-4),
+// logical partitions from 5, GPT entries by their index from 1.
+
+use super::{check_request, BlockDevice, BlockError, SECTOR_SIZE};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt;
+
+const MBR_SIGNATURE: [u8; 2] = [0x55, 0xAA];
+const MBR_ENTRIES: usize = 446;
+const MBR_TYPE_GPT_PROTECTIVE: u8 = 0xEE;
+const MBR_TYPES_EXTENDED: [u8; 3] = [0x05, 0x0F, 0x85];
+// Bounds the walk along a (possibly looping) chain of extended boot records.
+const MAX_LOGICAL: usize = 64;
+
+const GPT_SIGNATURE: &[u8; 8] = b"EFI PART";
+const GPT_MIN_HEADER_SIZE: usize = 92;
+const GPT_MIN_ENTRY_SIZE: usize = 128;
+// Keep a bogus header from making us read the whole disk.
+const GPT_MAX_ENTRY_SIZE: usize = SECTOR_SIZE;
+const GPT_MAX_ENTRIES: usize = 1024;
+const GPT_MAX_TABLE_LEN: usize = GPT_MAX_ENTRIES * GPT_MIN_ENTRY_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub fn is_zero(&self) -> bool {
+        self.0 == [0; 16]
+    }
+}
+
+// The first three fields are stored little-endian, the rest as bytes.
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:02X}{:02X}{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-{:02X}{:02X}-",
+            b[3], b[2], b[1], b[0], b[5], b[4], b[7], b[6], b[8], b[9]
+        )?;
+        b[10..].iter().try_for_each(|byte| write!(f, "{:02X}", byte))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PartitionKind {
+    /// The MBR system ID byte.
+    Mbr(u8),
+    Gpt { type_guid: Guid, unique_guid: Guid },
+}
+
+#[derive(Debug, Clone)]
+pub struct Partition {
+    pub number: usize,
+    pub start: u64,
+    pub sectors: u64,
+    pub kind: PartitionKind,
+    /// The GPT partition name; empty on MBR disks.
+    pub name: String,
+}
+
+fn read_sector(device: &dyn BlockDevice, sector: u64) -> Result<Vec<u8>, BlockError> {
+    let mut buf = vec![0u8; SECTOR_SIZE];
+    device.read_sectors(sector, &mut buf)?;
+    Ok(buf)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+/// Reads the partition table of `device`. A disk without one has no
+/// partitions.
+pub fn scan(device: &dyn BlockDevice) -> Result<Vec<Partition>, BlockError> {
+    let mbr = read_sector(device, 0)?;
+    if mbr[510..512] != MBR_SIGNATURE {
+        return Ok(Vec::new());
+    }
+    let entries = mbr_entries(&mbr);
+    if entries.iter().any(|&(_, kind, _, _)| kind == MBR_TYPE_GPT_PROTECTIVE) {
+        if let Some(partitions) = scan_gpt(device)? {
+            return Ok(partitions);
+        }
+    }
+
+    let mut partitions = Vec::new();
+    for (slot, kind, start, sectors) in entries {
+        if MBR_TYPES_EXTENDED.contains(&kind) {
+            scan_logical(device, start, &mut partitions)?;
+            continue;
+        }
+        partitions.push(Partition {
+            number: slot + 1,
+            start,
+            sectors,
+            kind: PartitionKind::Mbr(kind),
+            name: String::new(),
+        });
+    }
+    partitions.sort_by_key(|partition| partition.number);
+    Ok(partitions)
+}
+
+// The used entries of a partition table sector as (slot, type, start, sectors).
+fn mbr_entries(sector: &[u8]) -> Vec<(usize, u8, u64, u64)> {
+    (0..4)
+        .filter_map(|slot| {
+            let entry = &sector[MBR_ENTRIES + slot * 16..MBR_ENTRIES + (slot + 1) * 16];
+            let kind = entry[4];
+            let start = u32_at(entry, 8) as u64;
+            let sectors = u32_at(entry, 12) as u64;
+            (kind != 0 && sectors != 0).then_some((slot, kind, start, sectors))
+        })
+        .collect()
+}
+
+// Follows the chain of extended boot records starting at `extended`. Each
+// holds one logical partition, relative to itself, and a link to the next,
+// relative to the extended partition.
+fn scan_logical(device: &dyn BlockDevice, extended: u64, partitions: &mut Vec<Partition>) -> Result<(), BlockError> {
+    let mut ebr = extended;
+    for number in 5..5 + MAX_LOGICAL {
+        let sector = read_sector(device, ebr)?;
+        if sector[510..512] != MBR_SIGNATURE {
+            break;
+        }
+        let entries = mbr_entries(&sector);
+        let mut next = None;
+        for (slot, kind, start, sectors) in entries {
+            match slot {
+                0 => partitions.push(Partition {
+                    number,
+                    start: ebr + start,
+                    sectors,
+                    kind: PartitionKind::Mbr(kind),
+                    name: String::new(),
+                }),
+                1 if MBR_TYPES_EXTENDED.contains(&kind) => next = Some(extended + start),
+                _ => {}
+            }
+        }
+        match next {
+            Some(sector) if sector != ebr => ebr = sector,
+            _ => break,
+        }
+    }
+    Ok(())
+}
+
+// `None` if neither GPT header is valid.
+fn scan_gpt(device: &dyn BlockDevice) -> Result<Option<Vec<Partition>>, BlockError> {
+    let last = device.sector_count().saturating_sub(1);
+    for header_lba in [1, last] {
+        if let Some(partitions) = read_gpt(device, header_lba)? {
+            return Ok(Some(partitions));
+        }
+    }
+    Ok(None)
+}
+
+fn read_gpt(device: &dyn BlockDevice, header_lba: u64) -> Result<Option<Vec<Partition>>, BlockError> {
+    let mut header = read_sector(device, header_lba)?;
+    let header_size = u32_at(&header, 12) as usize;
+    if &header[..8] != GPT_SIGNATURE || !(GPT_MIN_HEADER_SIZE..=SECTOR_SIZE).contains(&header_size) {
+        return Ok(None);
+    }
+    let header_crc = u32_at(&header, 16);
+    header[16..20].fill(0);
+    if crc32(&header[..header_size]) != header_crc {
+        return Ok(None);
+    }
+
+    let entries_lba = u64_at(&header, 72);
+    let entry_count = u32_at(&header, 80) as usize;
+    let entry_size = u32_at(&header, 84) as usize;
+    let entries_crc = u32_at(&header, 88);
+    if !(GPT_MIN_ENTRY_SIZE..=GPT_MAX_ENTRY_SIZE).contains(&entry_size)
+        || entry_size % 8 != 0
+        || entry_count > GPT_MAX_ENTRIES
+    {
+        return Ok(None);
+    }
+    let table_len = entry_count * entry_size;
+    let read_len = table_len.next_multiple_of(SECTOR_SIZE);
+    if table_len > GPT_MAX_TABLE_LEN || check_request(device, entries_lba, read_len).is_err() {
+        return Ok(None);
+    }
+    let mut table = vec![0u8; read_len];
+    device.read_sectors(entries_lba, &mut table)?;
+    if crc32(&table[..table_len]) != entries_crc {
+        return Ok(None);
+    }
+
+    let mut partitions = Vec::new();
+    for (index, entry) in table[..table_len].chunks_exact(entry_size).enumerate() {
+        let type_guid = Guid(entry[0..16].try_into().unwrap());
+        if type_guid.is_zero() {
+            continue;
+        }
+        let first = u64_at(entry, 32);
+        let last = u64_at(entry, 40);
+        // Skip entries whose range is backwards or too big to count.
+        let Some(sectors) = last.checked_sub(first).and_then(|span| span.checked_add(1)) else {
+            continue;
+        };
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|&c| c != 0)
+            .collect();
+        partitions.push(Partition {
+            number: index + 1,
+            start: first,
+            sectors,
+            kind: PartitionKind::Gpt {
+                type_guid,
+                unique_guid: Guid(entry[16..32].try_into().unwrap()),
+            },
+            name: String::from_utf16_lossy(&name),
+        });
+    }
+    Ok(Some(partitions))
+}
+
+// CRC-32 (IEEE 802.3), as used by GPT.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// One partition of a disk as a device of its own, with sectors counted from
+/// the start of the partition.
+pub struct PartitionDevice {
+    name: String,
+    disk: Arc<dyn BlockDevice>,
+    start: u64,
+    sectors: u64,
+}
+
+impl PartitionDevice {
+    pub fn new(name: String, disk: Arc<dyn BlockDevice>, partition: &Partition) -> Self {
+        // A table entry reaching past the end of the disk is cut short.
+        let sectors = partition.sectors.min(disk.sector_count().saturating_sub(partition.start));
+        PartitionDevice {
+            name,
+            disk,
+            start: partition.start,
+            sectors,
+        }
+    }
+}
+
+impl BlockDevice for PartitionDevice {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_only(&self) -> bool {
+        self.disk.read_only()
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buf.len())?;
+        self.disk.read_sectors(self.start + sector, buf)
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buf.len())?;
+        self.disk.write_sectors(self.start + sector, buf)
+    }
+
+    fn flush(&self) -> Result<(), BlockError> {
+        self.disk.flush()
+    }
+}
@@ -0,0 +1,230 @@
+// virtio-blk disks. Each request is a three-descriptor chain: a header
+// saying what to do and where, the data, and a status byte the device fills
+// in. Data goes through a DMA bounce buffer, so requests larger than it are
+// split, and the driver waits for each one to complete before the next. A
+// request that never completes resets the device, so it can't complete
+// later in the middle of another request.
+
+use super::{check_request, register, BlockDevice, BlockError, SECTOR_SIZE};
+use crate::memory::dma::DmaBuffer;
+use crate::pci;
+use crate::sync::Mutex;
+use crate::virtio::{self, Buffer, LegacyDevice, VirtioError, Virtqueue};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU8, Ordering};
+
+// Transitional virtio-blk, which still speaks the legacy interface.
+const DEVICE_ID: u16 = 0x1001;
+
+const FEATURE_READ_ONLY: u32 = 1 << 5;
+const FEATURE_FLUSH: u32 = 1 << 9;
+
+// Device configuration: capacity in sectors.
+const CONFIG_CAPACITY: u16 = 0;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+// The bounce buffer: a page for the header and status byte, then the data.
+const HEADER_SIZE: usize = 16;
+const STATUS_OFFSET: usize = HEADER_SIZE;
+const DATA_OFFSET: usize = 4096;
+const DATA_PAGES: usize = 16;
+
+// Polls of the used ring before a request counts as lost.
+const POLL_LIMIT: usize = 100_000_000;
+
+pub struct VirtioBlk {
+    name: String,
+    sectors: u64,
+    features: u32,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    device: LegacyDevice,
+    features: u32,
+    queue: Virtqueue,
+    dma: DmaBuffer,
+}
+
+impl Inner {
+    // Runs one request whose data, if any, is already in the bounce buffer.
+    fn request(&mut self, kind: u32, sector: u64, len: usize) -> Result<(), BlockError> {
+        let dma = self.dma.as_mut_slice();
+        dma[0..4].copy_from_slice(&kind.to_le_bytes());
+        dma[4..8].fill(0);
+        dma[8..16].copy_from_slice(&sector.to_le_bytes());
+        dma[STATUS_OFFSET] = 0xFF;
+
+        let base = self.dma.phys();
+        let header = Buffer {
+            addr: base,
+            len: HEADER_SIZE as u32,
+            device_writes: false,
+        };
+        let data = Buffer {
+            addr: base + DATA_OFFSET as u64,
+            len: len as u32,
+            device_writes: kind == REQUEST_IN,
+        };
+        let status = Buffer {
+            addr: base + STATUS_OFFSET as u64,
+            len: 1,
+            device_writes: true,
+        };
+        let queued = if len == 0 {
+            self.queue.add(&[header, status])
+        } else {
+            self.queue.add(&[header, data, status])
+        };
+        let head = queued.ok_or(BlockError::Device)?;
+        self.device.notify(&self.queue);
+
+        for _ in 0..POLL_LIMIT {
+            match self.queue.pop_used() {
+                Some((id, _)) if id == head => {
+                    return match unsafe { self.dma.as_mut_ptr().add(STATUS_OFFSET).read_volatile() } {
+                        STATUS_OK => Ok(()),
+                        STATUS_UNSUPPORTED if kind == REQUEST_FLUSH => Ok(()),
+                        _ => Err(BlockError::Device),
+                    };
+                }
+                // Nothing else is ever in flight; popping it frees it.
+                Some(_) => continue,
+                None => core::hint::spin_loop(),
+            }
+        }
+        self.reset();
+        Err(BlockError::Timeout)
+    }
+
+    // Resets the device and sets its queue up from scratch. After the reset
+    // the device no longer looks at the old queue, so the lost request can't
+    // complete into a later one. If the queue can't be set up again the
+    // device is marked failed, and later requests time out as well.
+    fn reset(&mut self) {
+        self.device.negotiate(self.features);
+        match self.device.setup_queue(0) {
+            Ok(queue) => {
+                self.queue = queue;
+                self.device.driver_ok();
+            }
+            Err(_) => self.device.fail(),
+        }
+    }
+}
+
+impl VirtioBlk {
+    fn new(pci: pci::PciAddress, name: String) -> Result<Self, VirtioError> {
+        let device = LegacyDevice::new(pci)?;
+        let features = device.negotiate(FEATURE_READ_ONLY | FEATURE_FLUSH);
+        let resources = device.setup_queue(0).and_then(|queue| {
+            let dma = DmaBuffer::new(1 + DATA_PAGES).ok_or(VirtioError::NoMemory)?;
+            Ok((queue, dma))
+        });
+        let (queue, dma) = match resources {
+            Ok(setup) => setup,
+            Err(err) => {
+                device.fail();
+                return Err(err);
+            }
+        };
+        device.driver_ok();
+        Ok(VirtioBlk {
+            name,
+            sectors: device.config_u64(CONFIG_CAPACITY),
+            features,
+            inner: Mutex::new(Inner {
+                device,
+                features,
+                queue,
+                dma,
+            }),
+        })
+    }
+
+    fn chunk_size() -> usize {
+        DATA_PAGES * 4096
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn sector_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_only(&self) -> bool {
+        self.features & FEATURE_READ_ONLY != 0
+    }
+
+    fn read_sectors(&self, sector: u64, buf: &mut [u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buf.len())?;
+        let mut inner = self.inner.lock();
+        for (i, chunk) in buf.chunks_mut(VirtioBlk::chunk_size()).enumerate() {
+            let start = sector + (i * VirtioBlk::chunk_size() / SECTOR_SIZE) as u64;
+            inner.request(REQUEST_IN, start, chunk.len())?;
+            chunk.copy_from_slice(&inner.dma.as_slice()[DATA_OFFSET..DATA_OFFSET + chunk.len()]);
+        }
+        Ok(())
+    }
+
+    fn write_sectors(&self, sector: u64, buf: &[u8]) -> Result<(), BlockError> {
+        check_request(self, sector, buf.len())?;
+        if self.read_only() {
+            return Err(BlockError::ReadOnly);
+        }
+        let mut inner = self.inner.lock();
+        for (i, chunk) in buf.chunks(VirtioBlk::chunk_size()).enumerate() {
+            let start = sector + (i * VirtioBlk::chunk_size() / SECTOR_SIZE) as u64;
+            inner.dma.as_mut_slice()[DATA_OFFSET..DATA_OFFSET + chunk.len()].copy_from_slice(chunk);
+            inner.request(REQUEST_OUT, start, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    // Without the flush feature the device writes through, so there is
+    // nothing to do.
+    fn flush(&self) -> Result<(), BlockError> {
+        if self.features & FEATURE_FLUSH == 0 {
+            return Ok(());
+        }
+        self.inner.lock().request(REQUEST_FLUSH, 0, 0)
+    }
+}
+
+// Disks are named vda, vdb, ... in the order they are found.
+static NEXT_INDEX: AtomicU8 = AtomicU8::new(0);
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "virtio-blk",
+    matches: &[pci::DeviceMatch::id(virtio::VENDOR_ID, DEVICE_ID)],
+    probe,
+};
+
+/// Registers the disk as the next vdX.
+fn probe(device: &'static pci::PciDevice) -> bool {
+    let index = NEXT_INDEX.load(Ordering::Relaxed);
+    let name = format!("vd{}", (b'a' + index) as char);
+    match VirtioBlk::new(device.address, name) {
+        Ok(disk) => {
+            NEXT_INDEX.store(index + 1, Ordering::Relaxed);
+            register(Arc::new(disk));
+            true
+        }
+        Err(err) => {
+            crate::print!("\nvirtio-blk {}: {:?}", device.address, err);
+            false
+        }
+    }
+}
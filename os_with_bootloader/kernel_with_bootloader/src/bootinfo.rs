@@ -0,0 +1,32 @@
+// What the bootloader handed over, kept for /proc/bootinfo.
+//
+// The boot info itself is consumed piece by piece during startup (the
+// framebuffer goes to the writer, the memory map to the frame allocator), so
+// the entry point copies the interesting parts once the heap is up.
+
+use alloc::vec::Vec;
+use bootloader_api::info::{FrameBufferInfo, MemoryRegion};
+use spin::Once;
+
+pub struct BootSummary {
+    pub memory_regions: Vec<MemoryRegion>,
+    pub framebuffer: Option<FrameBufferInfo>,
+    pub physical_memory_offset: Option<u64>,
+    pub rsdp_addr: Option<u64>,
+    pub kernel_addr: u64,
+    pub kernel_len: u64,
+    pub kernel_image_offset: u64,
+    pub ramdisk_addr: Option<u64>,
+    pub ramdisk_len: u64,
+}
+
+static SUMMARY: Once<BootSummary> = Once::new();
+
+/// Keeps `summary` for /proc/bootinfo. Only the first call counts.
+pub fn record(summary: BootSummary) {
+    SUMMARY.call_once(|| summary);
+}
+
+pub fn get() -> Option<&'static BootSummary> {
+    SUMMARY.get()
+}
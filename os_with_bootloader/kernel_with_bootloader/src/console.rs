@@ -0,0 +1,67 @@
+// Keyboard input waiting to be read by whoever owns the console.
+//
+// The keyboard interrupt handler pushes bytes into a bounded channel, readers
+// sleep on it until something has been typed. The channel's buffer is
+// allocated once, so the interrupt handler never allocates. The raw scancodes
+// go into a second channel, for /dev/keyboard.
+
+use crate::sync::Channel;
+use lazy_static::lazy_static;
+
+const INPUT_BUFFER_SIZE: usize = 256;
+
+lazy_static! {
+    static ref INPUT: Channel<u8> = Channel::new(INPUT_BUFFER_SIZE);
+    static ref SCANCODES: Channel<u8> = Channel::new(INPUT_BUFFER_SIZE);
+}
+
+/// Allocates the input buffers, so the first keystroke doesn't have to.
+pub fn init() {
+    lazy_static::initialize(&INPUT);
+    lazy_static::initialize(&SCANCODES);
+}
+
+/// Called from the keyboard interrupt handler. Input is dropped when nobody
+/// has been reading for a while and the buffer is full.
+pub fn push_input(c: char) {
+    let mut encoded = [0; 4];
+    for &byte in c.encode_utf8(&mut encoded).as_bytes() {
+        if INPUT.try_send(byte).is_err() {
+            return;
+        }
+    }
+}
+
+/// Called from the keyboard interrupt handler with every byte the keyboard
+/// sends. Like typed input, scancodes nobody reads are dropped.
+pub fn push_scancode(scancode: u8) {
+    let _ = SCANCODES.try_send(scancode);
+}
+
+/// Blocks until something has been typed, then moves as much buffered input
+/// as fits into `buf`. Returns the number of bytes copied, which is only 0
+/// for an empty `buf`.
+pub fn read_input(buf: &mut [u8]) -> usize {
+    read_channel(&INPUT, buf)
+}
+
+/// Like `read_input`, for the raw scancodes.
+pub fn read_scancodes(buf: &mut [u8]) -> usize {
+    read_channel(&SCANCODES, buf)
+}
+
+fn read_channel(channel: &Channel<u8>, buf: &mut [u8]) -> usize {
+    let Some((first, rest)) = buf.split_first_mut() else {
+        return 0;
+    };
+    *first = channel.recv();
+    let mut count = 1;
+    for byte in rest {
+        match channel.try_recv() {
+            Some(next) => *byte = next,
+            None => break,
+        }
+        count += 1;
+    }
+    count
+}
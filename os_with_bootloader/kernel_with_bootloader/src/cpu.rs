@@ -0,0 +1,293 @@
+// Per-CPU data, reached through the GS base.
+//
+// Each CPU gets a `Cpu` that is never freed. Its address is in both GS_BASE
+// and KERNEL_GS_BASE. User code can change GS_BASE by loading a segment
+// register; it has no access to KERNEL_GS_BASE. So kernel entries from ring 3
+// restore GS_BASE from KERNEL_GS_BASE before touching per-CPU data: the
+// syscall stubs do it in assembly, interrupt and exception handlers call
+// [`enter_from`].
+
+use crate::memory::protection;
+use alloc::boxed::Box;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::arch::x86_64::{CpuidResult, __cpuid, __cpuid_count};
+use core::ptr;
+use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use spin::Once;
+use x86_64::registers::model_specific::{GsBase, KernelGsBase};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+pub const MAX_CPUS: usize = 16;
+
+/// The fields used from assembly come first, at fixed offsets.
+#[repr(C)]
+pub struct Cpu {
+    self_ptr: *const Cpu,
+    /// Stack `syscall` switches to, the current thread's kernel stack.
+    pub(crate) kernel_rsp: AtomicU64,
+    /// Where `syscall` parks the user stack pointer while switching stacks.
+    pub(crate) user_rsp_scratch: AtomicU64,
+    index: usize,
+    apic_id: AtomicU32,
+    tss: AtomicPtr<TaskStateSegment>,
+    online: AtomicBool,
+    // Set when another CPU asks this one to flush its TLB.
+    pub(crate) tlb_flush_pending: AtomicBool,
+    // Id plus one of the thread running here, 0 until the scheduler starts.
+    current_thread: AtomicU64,
+}
+
+unsafe impl Sync for Cpu {}
+
+static CPUS: [AtomicPtr<Cpu>; MAX_CPUS] = [const { AtomicPtr::new(ptr::null_mut()) }; MAX_CPUS];
+static CPU_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+impl Cpu {
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id.load(Ordering::Relaxed)
+    }
+
+    pub(crate) fn set_apic_id(&self, apic_id: u32) {
+        self.apic_id.store(apic_id, Ordering::Relaxed);
+    }
+
+    pub fn is_online(&self) -> bool {
+        self.online.load(Ordering::Acquire)
+    }
+
+    pub(crate) fn set_online(&self) {
+        self.online.store(true, Ordering::Release);
+    }
+
+    pub(crate) fn set_tss(&self, tss: *mut TaskStateSegment) {
+        self.tss.store(tss, Ordering::Relaxed);
+    }
+
+    pub(crate) fn tss(&self) -> *mut TaskStateSegment {
+        self.tss.load(Ordering::Relaxed)
+    }
+
+    /// Id of the thread running on this CPU, without going through the
+    /// scheduler's lock. `None` until the CPU has joined the scheduler.
+    pub fn current_thread(&self) -> Option<u64> {
+        self.current_thread.load(Ordering::Relaxed).checked_sub(1)
+    }
+
+    pub(crate) fn set_current_thread(&self, id: u64) {
+        self.current_thread.store(id + 1, Ordering::Relaxed);
+    }
+}
+
+/// Allocates the data for the next CPU. Returns `None` once `MAX_CPUS` is reached.
+pub fn register(apic_id: u32) -> Option<&'static Cpu> {
+    let index = CPU_COUNT.load(Ordering::Relaxed);
+    if index == MAX_CPUS {
+        return None;
+    }
+    let cpu = Box::leak(Box::new(Cpu {
+        self_ptr: ptr::null(),
+        kernel_rsp: AtomicU64::new(0),
+        user_rsp_scratch: AtomicU64::new(0),
+        index,
+        apic_id: AtomicU32::new(apic_id),
+        tss: AtomicPtr::new(ptr::null_mut()),
+        online: AtomicBool::new(false),
+        tlb_flush_pending: AtomicBool::new(false),
+        current_thread: AtomicU64::new(0),
+    }));
+    cpu.self_ptr = cpu;
+    CPUS[index].store(cpu, Ordering::Release);
+    CPU_COUNT.store(index + 1, Ordering::Release);
+    Some(cpu)
+}
+
+/// Makes `cpu` the per-CPU data of the calling CPU.
+pub fn install(cpu: &'static Cpu) {
+    let addr = VirtAddr::from_ptr(cpu);
+    GsBase::write(addr);
+    KernelGsBase::write(addr);
+}
+
+/// Sets up the bootstrap CPU. Its APIC ID is filled in once the APIC is
+/// mapped, see `smp::init`.
+pub fn init_bsp() {
+    let cpu = register(0).expect("no per-CPU slot for the bootstrap CPU");
+    install(cpu);
+    cpu.set_online();
+}
+
+/// The calling CPU's data.
+pub fn current() -> &'static Cpu {
+    let cpu: *const Cpu;
+    unsafe {
+        core::arch::asm!("mov {}, gs:[0]", out(reg) cpu, options(nostack, readonly, preserves_flags));
+        &*cpu
+    }
+}
+
+/// Index of the calling CPU, 0 for the bootstrap CPU.
+pub fn id() -> usize {
+    current().index
+}
+
+/// Restores GS_BASE and clears EFLAGS.AC if the interrupt came from user mode.
+pub fn enter_from(stack_frame: &InterruptStackFrame) {
+    if stack_frame.code_segment & 3 == 3 {
+        protection::clear_user_access();
+        GsBase::write(KernelGsBase::read());
+    }
+}
+
+/// Every CPU that has been registered, online or not.
+pub fn all() -> impl Iterator<Item = &'static Cpu> {
+    CPUS[..CPU_COUNT.load(Ordering::Acquire)]
+        .iter()
+        .map(|cpu| unsafe { &*cpu.load(Ordering::Acquire) })
+}
+
+pub fn online_count() -> usize {
+    all().filter(|cpu| cpu.is_online()).count()
+}
+
+/// What CPUID reports about the processor. All CPUs are taken to be the
+/// same model, so the bootstrap CPU's answer stands for every one.
+pub struct Identity {
+    pub vendor: String,
+    pub brand: String,
+    pub family: u32,
+    pub model: u32,
+    pub stepping: u32,
+    /// Feature flags, named like Linux's /proc/cpuinfo does.
+    pub flags: Vec<&'static str>,
+}
+
+impl Identity {
+    pub fn has(&self, flag: &str) -> bool {
+        self.flags.contains(&flag)
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Register {
+    Ebx,
+    Ecx,
+    Edx,
+}
+
+// Leaf, register and bit of each flag shown.
+const FLAGS: [(u32, Register, u32, &str); 39] = [
+    (1, Register::Edx, 0, "fpu"),
+    (1, Register::Edx, 4, "tsc"),
+    (1, Register::Edx, 5, "msr"),
+    (1, Register::Edx, 6, "pae"),
+    (1, Register::Edx, 9, "apic"),
+    (1, Register::Edx, 13, "pge"),
+    (1, Register::Edx, 15, "cmov"),
+    (1, Register::Edx, 16, "pat"),
+    (1, Register::Edx, 19, "clflush"),
+    (1, Register::Edx, 23, "mmx"),
+    (1, Register::Edx, 24, "fxsr"),
+    (1, Register::Edx, 25, "sse"),
+    (1, Register::Edx, 26, "sse2"),
+    (1, Register::Edx, 28, "ht"),
+    (1, Register::Ecx, 0, "sse3"),
+    (1, Register::Ecx, 1, "pclmulqdq"),
+    (1, Register::Ecx, 9, "ssse3"),
+    (1, Register::Ecx, 12, "fma"),
+    (1, Register::Ecx, 13, "cx16"),
+    (1, Register::Ecx, 19, "sse4_1"),
+    (1, Register::Ecx, 20, "sse4_2"),
+    (1, Register::Ecx, 21, "x2apic"),
+    (1, Register::Ecx, 23, "popcnt"),
+    (1, Register::Ecx, 25, "aes"),
+    (1, Register::Ecx, 26, "xsave"),
+    (1, Register::Ecx, 28, "avx"),
+    (1, Register::Ecx, 30, "rdrand"),
+    (1, Register::Ecx, 31, "hypervisor"),
+    (7, Register::Ebx, 0, "fsgsbase"),
+    (7, Register::Ebx, 5, "avx2"),
+    (7, Register::Ebx, 7, "smep"),
+    (7, Register::Ebx, 18, "rdseed"),
+    (7, Register::Ebx, 20, "smap"),
+    (0x8000_0001, Register::Edx, 11, "syscall"),
+    (0x8000_0001, Register::Edx, 20, "nx"),
+    (0x8000_0001, Register::Edx, 26, "pdpe1gb"),
+    (0x8000_0001, Register::Edx, 27, "rdtscp"),
+    (0x8000_0001, Register::Edx, 29, "lm"),
+    (0x8000_0001, Register::Ecx, 0, "lahf_lm"),
+];
+
+static IDENTITY: Once<Identity> = Once::new();
+
+/// The processor's identity, asked for once.
+pub fn identity() -> &'static Identity {
+    IDENTITY.call_once(identify)
+}
+
+fn identify() -> Identity {
+    let max_leaf = __cpuid(0).eax;
+    let max_extended = __cpuid(0x8000_0000).eax;
+    let leaf = |leaf: u32| -> Option<CpuidResult> {
+        let max = if leaf >= 0x8000_0000 { max_extended } else { max_leaf };
+        (leaf <= max).then(|| __cpuid_count(leaf, 0))
+    };
+    let text = |registers: &[u32]| -> String {
+        let bytes: Vec<u8> = registers.iter().flat_map(|r| r.to_le_bytes()).filter(|&b| b != 0).collect();
+        String::from_utf8_lossy(&bytes).trim().into()
+    };
+
+    let vendor = __cpuid(0);
+    let vendor = text(&[vendor.ebx, vendor.edx, vendor.ecx]);
+    let brand = match max_extended >= 0x8000_0004 {
+        true => text(
+            &(0x8000_0002..=0x8000_0004)
+                .map(__cpuid)
+                .flat_map(|r| [r.eax, r.ebx, r.ecx, r.edx])
+                .collect::<Vec<u32>>(),
+        ),
+        false => String::new(),
+    };
+
+    // The extended family and model only count for some base families.
+    let signature = leaf(1).map_or(0, |r| r.eax);
+    let base_family = signature >> 8 & 0xF;
+    let family = match base_family {
+        0xF => base_family + (signature >> 20 & 0xFF),
+        _ => base_family,
+    };
+    let model = match base_family {
+        0x6 | 0xF => (signature >> 4 & 0xF) | (signature >> 16 & 0xF) << 4,
+        _ => signature >> 4 & 0xF,
+    };
+
+    let flags = FLAGS
+        .iter()
+        .filter(|&&(number, register, bit, _)| {
+            leaf(number).is_some_and(|r| {
+                let value = match register {
+                    Register::Ebx => r.ebx,
+                    Register::Ecx => r.ecx,
+                    Register::Edx => r.edx,
+                };
+                value & 1 << bit != 0
+            })
+        })
+        .map(|&(_, _, _, name)| name)
+        .collect();
+
+    Identity {
+        vendor,
+        brand,
+        family,
+        model,
+        stepping: signature & 0xF,
+        flags,
+    }
+}
@@ -0,0 +1,123 @@
+// Just enough ELF64 parsing for the kernel: the file header and the program
+// headers. Section headers and symbols are never needed at run time.
+
+pub const PT_LOAD: u32 = 1;
+
+pub const PF_X: u32 = 1;
+pub const PF_W: u32 = 2;
+pub const PF_R: u32 = 4;
+
+const ELF_MAGIC: [u8; 4] = [0x7f, b'E', b'L', b'F'];
+const ELFCLASS64: u8 = 2;
+const ELFDATA2LSB: u8 = 1;
+const EM_X86_64: u16 = 62;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ElfError {
+    TooShort,
+    BadMagic,
+    Unsupported,
+    BadProgramHeader,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ProgramHeader {
+    pub kind: u32,
+    pub flags: u32,
+    pub offset: u64,
+    pub vaddr: u64,
+    pub file_size: u64,
+    pub mem_size: u64,
+    pub align: u64,
+}
+
+impl ProgramHeader {
+    pub fn is_load(&self) -> bool {
+        self.kind == PT_LOAD
+    }
+
+    pub fn writable(&self) -> bool {
+        self.flags & PF_W != 0
+    }
+
+    pub fn executable(&self) -> bool {
+        self.flags & PF_X != 0
+    }
+}
+
+pub struct ElfFile<'a> {
+    data: &'a [u8],
+    pub entry: u64,
+    pub kind: u16,
+    ph_offset: usize,
+    ph_entry_size: usize,
+    ph_count: usize,
+}
+
+impl<'a> ElfFile<'a> {
+    pub fn parse(data: &'a [u8]) -> Result<Self, ElfError> {
+        if data.len() < 64 {
+            return Err(ElfError::TooShort);
+        }
+        if data[0..4] != ELF_MAGIC {
+            return Err(ElfError::BadMagic);
+        }
+        if data[4] != ELFCLASS64 || data[5] != ELFDATA2LSB || read_u16(data, 18) != EM_X86_64 {
+            return Err(ElfError::Unsupported);
+        }
+
+        let file = ElfFile {
+            data,
+            kind: read_u16(data, 16),
+            entry: read_u64(data, 24),
+            ph_offset: read_u64(data, 32) as usize,
+            ph_entry_size: read_u16(data, 54) as usize,
+            ph_count: read_u16(data, 56) as usize,
+        };
+
+        let table_end = file
+            .ph_count
+            .checked_mul(file.ph_entry_size)
+            .and_then(|size| size.checked_add(file.ph_offset));
+        match table_end {
+            Some(end) if end <= data.len() && file.ph_entry_size >= 56 => Ok(file),
+            _ => Err(ElfError::BadProgramHeader),
+        }
+    }
+
+    pub fn program_headers(&self) -> impl Iterator<Item = ProgramHeader> + '_ {
+        (0..self.ph_count).map(move |i| {
+            let base = self.ph_offset + i * self.ph_entry_size;
+            ProgramHeader {
+                kind: read_u32(self.data, base),
+                flags: read_u32(self.data, base + 4),
+                offset: read_u64(self.data, base + 8),
+                vaddr: read_u64(self.data, base + 16),
+                file_size: read_u64(self.data, base + 32),
+                mem_size: read_u64(self.data, base + 40),
+                align: read_u64(self.data, base + 48),
+            }
+        })
+    }
+
+    /// File contents backing a segment, or an error if it points past the file.
+    pub fn segment_data(&self, header: &ProgramHeader) -> Result<&'a [u8], ElfError> {
+        let start = header.offset as usize;
+        let end = start
+            .checked_add(header.file_size as usize)
+            .ok_or(ElfError::BadProgramHeader)?;
+        self.data.get(start..end).ok_or(ElfError::BadProgramHeader)
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
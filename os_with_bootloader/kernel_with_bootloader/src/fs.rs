@@ -0,0 +1,303 @@
+// Virtual file system.
+//
+// A file system hands out inodes (`Inode`): files, directories and devices,
+// each with a small set of operations. The VFS wraps them in dentries, which
+// know their name and parent and cache looked-up children, so paths only have
+// to be resolved against the backend once. File systems are attached to
+// directories through the mount table; path resolution steps into the mounted
+// root when it reaches a mount point. An open file (`File`) is a dentry plus
+// an offset; processes refer to open files through their descriptor table.
+//
+// The root is a ramfs filled from the initramfs, with devfs on /dev and
+// procfs on /proc. Disk volumes with a file system we know are mounted on
+// /mnt/<device name>.
+
+mod dentry;
+pub mod devfs;
+pub mod ext2;
+pub mod fat;
+mod file;
+pub mod procfs;
+pub mod ramfs;
+
+use crate::block::{self, BlockDevice};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+pub use dentry::{mount, mounts, resolve, Dentry, MountInfo};
+pub use file::{FdTable, File, FileHandle, OpenFlags, SeekFrom};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    DirectoryNotEmpty,
+    ReadOnly,
+    InvalidPath,
+    InvalidArgument,
+    BadFileDescriptor,
+    TooManyOpenFiles,
+    /// The operation makes no sense for this kind of inode or file system.
+    NotSupported,
+    Busy,
+    /// The file system or directory is full.
+    NoSpace,
+    /// The backing device failed.
+    Io,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    File,
+    Directory,
+    CharDevice,
+    BlockDevice,
+    Symlink,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    /// Unique within the file system.
+    pub inode: u64,
+    pub kind: FileType,
+    pub size: u64,
+    pub mode: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub kind: FileType,
+}
+
+/// A file, directory or device of some file system. Operations that don't
+/// apply to the kind of inode keep their default implementation.
+pub trait Inode: Send + Sync {
+    fn metadata(&self) -> Metadata;
+
+    /// Reads from `offset`; returns 0 at the end of the file.
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Err(not_a_file(self.metadata().kind))
+    }
+
+    /// Reads from `offset` for the open file `handle`. Inodes whose contents
+    /// change between reads override this to keep them steady per reader.
+    fn read_handle(&self, _handle: FileHandle, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        self.read_at(offset, buf)
+    }
+
+    /// The open file `handle` was closed.
+    fn release(&self, _handle: FileHandle) {}
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(not_a_file(self.metadata().kind))
+    }
+
+    fn truncate(&self, _len: u64) -> Result<(), FsError> {
+        Err(not_a_file(self.metadata().kind))
+    }
+
+    /// Finds `name` in this directory. `.` and `..` are handled by the VFS.
+    fn lookup(&self, _name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Err(FsError::NotADirectory)
+    }
+
+    /// Creates an empty file or directory called `name` in this directory.
+    fn create(&self, _name: &str, _kind: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(not_a_directory(self.metadata().kind))
+    }
+
+    /// Removes `name`, which must not be a non-empty directory.
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(not_a_directory(self.metadata().kind))
+    }
+
+    /// Target of a symbolic link.
+    fn read_link(&self) -> Result<String, FsError> {
+        Err(FsError::InvalidArgument)
+    }
+}
+
+// Errors of the default `Inode` methods.
+fn not_a_file(kind: FileType) -> FsError {
+    match kind {
+        FileType::Directory => FsError::IsADirectory,
+        _ => FsError::NotSupported,
+    }
+}
+
+fn not_a_directory(kind: FileType) -> FsError {
+    match kind {
+        FileType::Directory => FsError::NotSupported,
+        _ => FsError::NotADirectory,
+    }
+}
+
+pub trait FileSystem: Send + Sync {
+    /// Type name shown in the mount table, like "ramfs".
+    fn name(&self) -> &'static str;
+
+    fn root(&self) -> Arc<dyn Inode>;
+}
+
+/// Sets up the root file system from the initramfs and mounts devfs and
+/// procfs.
+pub fn init() {
+    devfs::init();
+    procfs::init();
+    let root = ramfs::RamFs::from_initramfs(crate::initramfs::get());
+    dentry::mount_root(Arc::new(root));
+    for (path, fs) in [
+        ("/dev", Arc::new(devfs::DevFs) as Arc<dyn FileSystem>),
+        ("/proc", Arc::new(procfs::ProcFs)),
+    ] {
+        let result = match mkdir(path) {
+            Ok(()) | Err(FsError::AlreadyExists) => mount(path, fs),
+            Err(err) => Err(err),
+        };
+        if let Err(err) = result {
+            crate::println!("fs: cannot mount {} ({:?})", path, err);
+        }
+    }
+}
+
+/// The file system on `device`, if any of the drivers recognizes it.
+pub fn probe(device: Arc<dyn BlockDevice>) -> Result<Arc<dyn FileSystem>, FsError> {
+    if let Ok(fs) = fat::FatFs::new(device.clone()) {
+        return Ok(fs);
+    }
+    Ok(ext2::Ext2Fs::new(device)?)
+}
+
+/// Mounts every disk volume holding a known file system on
+/// /mnt/<device name>.
+pub fn mount_block_devices() {
+    for volume in block::disks().iter().flat_map(|disk| disk.volumes()) {
+        let Ok(fs) = probe(volume.clone()) else {
+            continue;
+        };
+        let path = format!("/mnt/{}", volume.name());
+        let result = ["/mnt", &path]
+            .into_iter()
+            .try_for_each(|dir| match mkdir(dir) {
+                Ok(()) | Err(FsError::AlreadyExists) => Ok(()),
+                Err(err) => Err(err),
+            })
+            .and_then(|()| mount(&path, fs.clone()));
+        match result {
+            Ok(()) => crate::print!("\nfs: {} ({}) on {}", volume.name(), fs.name(), path),
+            Err(err) => crate::print!("\nfs: cannot mount {} ({:?})", path, err),
+        }
+    }
+}
+
+/// Opens `path`, creating it first with `OpenFlags::CREATE`.
+pub fn open(path: &str, flags: OpenFlags) -> Result<Arc<File>, FsError> {
+    let dentry = match resolve(path) {
+        Ok(dentry) => dentry,
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (parent, name) = dentry::resolve_parent(path)?;
+            parent.create(&name, FileType::File)?
+        }
+        Err(err) => return Err(err),
+    };
+    File::open(dentry, flags)
+}
+
+pub fn stat(path: &str) -> Result<Metadata, FsError> {
+    Ok(resolve(path)?.inode().metadata())
+}
+
+pub fn read_dir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    resolve(path)?.read_dir()
+}
+
+pub fn mkdir(path: &str) -> Result<(), FsError> {
+    let (parent, name) = dentry::resolve_parent(path)?;
+    parent.create(&name, FileType::Directory).map(|_| ())
+}
+
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    let (parent, name) = dentry::resolve_parent(path)?;
+    parent.unlink(&name)
+}
+
+/// Reads a whole file, for kernel code that wants the contents at once.
+pub fn read_to_end(path: &str) -> Result<Vec<u8>, FsError> {
+    let file = open(path, OpenFlags::READ)?;
+    let mut data = Vec::new();
+    let mut chunk = [0; 512];
+    loop {
+        match file.read(&mut chunk)? {
+            0 => return Ok(data),
+            count => data.extend_from_slice(&chunk[..count]),
+        }
+    }
+}
+
+/// Writes a file with a long name to the runner's FAT32 scratch partition,
+/// reads it back and removes it, then reads the runner's ext2 and ext4
+/// fixtures and the /proc and /dev entries. Only built with the `selftest`
+/// feature.
+#[cfg(feature = "selftest")]
+pub fn self_check() -> bool {
+    const PATH: &str = "/mnt/vda2/Self check with a long name.txt";
+    let contents: Vec<u8> = (0..3000u32).map(|i| (i % 251) as u8).collect();
+    let written = open(PATH, OpenFlags::CREATE | OpenFlags::WRITE | OpenFlags::TRUNCATE)
+        .and_then(|file| file.write(&contents));
+    let read = read_to_end(PATH);
+    let listed = read_dir("/mnt/vda2").is_ok_and(|entries| {
+        entries.iter().any(|entry| entry.name == "Self check with a long name.txt")
+    });
+    let removed = unlink(PATH).is_ok() && stat(PATH).err() == Some(FsError::NotFound);
+    let passed = written == Ok(contents.len()) && read.as_deref() == Ok(&contents[..]) && listed && removed;
+    crate::println!("selftest: {:<28} {}", "FAT32 file round trip", if passed { "ok" } else { "FAILED" });
+
+    let fixtures = ["/mnt/vdb", "/mnt/vdc"].map(check_ext2_fixture);
+    crate::println!("selftest: {:<28} {}", "ext2/ext4 fixture reads", if fixtures == [true; 2] { "ok" } else { "FAILED" });
+
+    let proc_files = ["meminfo", "interrupts", "uptime", "cpuinfo", "tasks", "bootinfo"]
+        .iter()
+        .all(|name| read_to_end(&format!("/proc/{}", name)).is_ok_and(|text| !text.is_empty()));
+    let mut random = [0u8; 32];
+    let devices = ["console", "keyboard", "fb0", "null", "zero", "random"]
+        .iter()
+        .all(|name| stat(&format!("/dev/{}", name)).is_ok_and(|meta| meta.kind == FileType::CharDevice))
+        && open("/dev/random", OpenFlags::READ).and_then(|file| file.read(&mut random)) == Ok(random.len())
+        && random != [0; 32];
+    let pseudo = proc_files && devices;
+    crate::println!("selftest: {:<28} {}", "procfs and devfs entries", if pseudo { "ok" } else { "FAILED" });
+    passed && fixtures == [true; 2] && pseudo
+}
+
+// What the runner puts on its ext2 fixtures: large.bin is 3 MiB + 123 bytes
+// of a pattern, and `note` links to `deep/note.txt`, where `deep` is itself
+// a link to docs/deep.
+#[cfg(feature = "selftest")]
+fn check_ext2_fixture(mount_point: &str) -> bool {
+    let path = |name: &str| format!("{}/{}", mount_point, name);
+    let readme = read_to_end(&path("README"));
+    let large = read_to_end(&path("large.bin"));
+    let large_ok = large.is_ok_and(|data| {
+        data.len() == 3 * 1024 * 1024 + 123 && data.iter().enumerate().all(|(i, &b)| b == (i * 7 + i / 251) as u8)
+    });
+    let note = read_to_end(&path("note"));
+    let listed = read_dir(mount_point).is_ok_and(|entries| {
+        entries.iter().any(|entry| entry.name == "note" && entry.kind == FileType::Symlink)
+    });
+    let read_only = open(&path("README"), OpenFlags::WRITE).and_then(|file| file.write(b"x")).is_err();
+    readme.as_deref() == Ok(&b"Hello from ext2.\n"[..])
+        && large_ok
+        && note.as_deref() == Ok(&b"Found through a symlink.\n"[..])
+        && listed
+        && read_only
+}
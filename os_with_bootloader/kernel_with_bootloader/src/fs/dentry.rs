@@ -0,0 +1,220 @@
+use super::{DirEntry, FileSystem, FileType, FsError, Inode};
+use crate::sync::Mutex;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Once;
+
+// Symbolic links followed while resolving one path.
+const MAX_SYMLINKS: usize = 8;
+
+/// A name in the directory tree, bound to an inode.
+///
+/// Children that were looked up stay cached, so each path component is only
+/// resolved against its file system once. The root dentry of a mounted file
+/// system takes over the name and parent of the directory it is mounted on,
+/// which makes `..` and `path()` work across mounts.
+pub struct Dentry {
+    name: String,
+    inode: Arc<dyn Inode>,
+    parent: Option<Arc<Dentry>>,
+    children: Mutex<BTreeMap<String, Arc<Dentry>>>,
+    // Root of the file system mounted on this directory.
+    mounted: Mutex<Option<Arc<Dentry>>>,
+}
+
+#[derive(Clone)]
+pub struct MountInfo {
+    pub path: String,
+    pub fs: Arc<dyn FileSystem>,
+}
+
+static ROOT: Once<Arc<Dentry>> = Once::new();
+static MOUNTS: Mutex<Vec<MountInfo>> = Mutex::new(Vec::new());
+
+impl Dentry {
+    fn new(name: String, inode: Arc<dyn Inode>, parent: Option<Arc<Dentry>>) -> Arc<Self> {
+        Arc::new(Dentry {
+            name,
+            inode,
+            parent,
+            children: Mutex::new(BTreeMap::new()),
+            mounted: Mutex::new(None),
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn inode(&self) -> &Arc<dyn Inode> {
+        &self.inode
+    }
+
+    pub fn parent(&self) -> Option<Arc<Dentry>> {
+        self.parent.clone()
+    }
+
+    /// Absolute path of this dentry.
+    pub fn path(&self) -> String {
+        let mut components = Vec::new();
+        let mut dentry = Some(self);
+        while let Some(current) = dentry {
+            if current.parent.is_some() {
+                components.push(current.name.as_str());
+            }
+            dentry = current.parent.as_deref();
+        }
+        if components.is_empty() {
+            return String::from("/");
+        }
+        components.iter().rev().fold(String::new(), |path, name| path + "/" + name)
+    }
+
+    // Follows mounts stacked on this dentry.
+    fn mounted_root(self: &Arc<Self>) -> Arc<Dentry> {
+        let mut dentry = self.clone();
+        loop {
+            let next = dentry.mounted.lock().clone();
+            match next {
+                Some(root) => dentry = root,
+                None => return dentry,
+            }
+        }
+    }
+
+    /// Looks up `name` in this directory, stepping into a file system
+    /// mounted on the result.
+    pub fn lookup(self: &Arc<Self>, name: &str) -> Result<Arc<Dentry>, FsError> {
+        match name {
+            "" | "." => return Ok(self.clone()),
+            ".." => return Ok(self.parent.clone().unwrap_or_else(|| self.clone())),
+            _ => {}
+        }
+        let mut children = self.children.lock();
+        let child = match children.get(name) {
+            Some(child) => child.clone(),
+            None => {
+                let inode = self.inode.lookup(name)?;
+                let child = Dentry::new(name.to_string(), inode, Some(self.clone()));
+                children.insert(name.to_string(), child.clone());
+                child
+            }
+        };
+        drop(children);
+        Ok(child.mounted_root())
+    }
+
+    pub fn create(self: &Arc<Self>, name: &str, kind: FileType) -> Result<Arc<Dentry>, FsError> {
+        check_name(name)?;
+        if self.lookup(name).is_ok() {
+            return Err(FsError::AlreadyExists);
+        }
+        let inode = self.inode.create(name, kind)?;
+        let child = Dentry::new(name.to_string(), inode, Some(self.clone()));
+        self.children.lock().insert(name.to_string(), child.clone());
+        Ok(child)
+    }
+
+    pub fn unlink(self: &Arc<Self>, name: &str) -> Result<(), FsError> {
+        check_name(name)?;
+        let mut children = self.children.lock();
+        if children.get(name).is_some_and(|child| child.mounted.lock().is_some()) {
+            return Err(FsError::Busy);
+        }
+        self.inode.unlink(name)?;
+        children.remove(name);
+        Ok(())
+    }
+
+    pub fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        self.inode.read_dir()
+    }
+}
+
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/') {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+fn root() -> Result<Arc<Dentry>, FsError> {
+    ROOT.get().cloned().ok_or(FsError::NotFound)
+}
+
+pub(super) fn mount_root(fs: Arc<dyn FileSystem>) {
+    ROOT.call_once(|| Dentry::new(String::new(), fs.root(), None));
+    MOUNTS.lock().push(MountInfo {
+        path: String::from("/"),
+        fs,
+    });
+}
+
+/// Mounts `fs` on the directory at `path`.
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let target = resolve(path)?;
+    if target.inode.metadata().kind != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    let root = Dentry::new(target.name.clone(), fs.root(), target.parent.clone());
+    let mut mounted = target.mounted.lock();
+    if mounted.is_some() {
+        return Err(FsError::Busy);
+    }
+    *mounted = Some(root);
+    MOUNTS.lock().push(MountInfo {
+        path: target.path(),
+        fs,
+    });
+    Ok(())
+}
+
+/// The mount table, in mount order.
+pub fn mounts() -> Vec<MountInfo> {
+    MOUNTS.lock().clone()
+}
+
+/// Resolves an absolute path, following symbolic links. Relative paths are
+/// taken relative to the root; there is no working directory.
+pub fn resolve(path: &str) -> Result<Arc<Dentry>, FsError> {
+    let mut links = 0;
+    walk(root()?, path, &mut links)
+}
+
+fn walk(start: Arc<Dentry>, path: &str, links: &mut usize) -> Result<Arc<Dentry>, FsError> {
+    let mut dentry = if path.starts_with('/') { root()? } else { start };
+    for component in path.split('/').filter(|c| !c.is_empty()) {
+        if dentry.inode.metadata().kind != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let mut next = dentry.lookup(component)?;
+        if next.inode.metadata().kind == FileType::Symlink {
+            *links += 1;
+            if *links > MAX_SYMLINKS {
+                return Err(FsError::InvalidPath);
+            }
+            let target = next.inode.read_link()?;
+            next = walk(dentry.clone(), &target, links)?;
+        }
+        dentry = next;
+    }
+    Ok(dentry)
+}
+
+/// Resolves everything but the last component of `path`, which is returned
+/// as the name to create or remove in the resulting directory.
+pub(super) fn resolve_parent(path: &str) -> Result<(Arc<Dentry>, String), FsError> {
+    let trimmed = path.trim_end_matches('/');
+    let (dir, name) = match trimmed.rfind('/') {
+        Some(i) => (&trimmed[..i], &trimmed[i + 1..]),
+        None => ("", trimmed),
+    };
+    check_name(name)?;
+    let parent = resolve(dir)?;
+    if parent.inode.metadata().kind != FileType::Directory {
+        return Err(FsError::NotADirectory);
+    }
+    Ok((parent, name.to_string()))
+}
@@ -0,0 +1,254 @@
+// Device files, mounted on /dev.
+//
+// Drivers make a device reachable by registering an inode for it under a
+// name; devfs itself only lists what was registered. The trivial devices
+// live here.
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::console;
+use crate::print;
+use crate::random;
+use crate::serial;
+use crate::sync::Mutex;
+use crate::writer;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+static DEVICES: Mutex<BTreeMap<String, Arc<dyn Inode>>> = Mutex::new(BTreeMap::new());
+
+/// Makes `device` available as /dev/`name`, replacing a device of that name.
+pub fn register(name: &str, device: Arc<dyn Inode>) {
+    DEVICES.lock().insert(name.to_string(), device);
+}
+
+/// Registers the devices every system has, and the serial port and
+/// framebuffer if they are there.
+pub fn init() {
+    register("null", Arc::new(Null));
+    register("zero", Arc::new(Zero));
+    register("console", Arc::new(Console));
+    register("keyboard", Arc::new(Keyboard));
+    register("random", Arc::new(Random));
+    if serial::is_present() {
+        register("serial", Arc::new(Serial));
+    }
+    if writer::with_writer(|_| ()).is_some() {
+        register("fb0", Arc::new(FrameBuffer));
+    }
+}
+
+/// A fresh inode number for a device. The built-in ones use the numbers
+/// below 16.
+pub fn allocate_inode() -> u64 {
+    static NEXT_INODE: AtomicU64 = AtomicU64::new(16);
+    NEXT_INODE.fetch_add(1, Ordering::Relaxed)
+}
+
+/// Metadata of a character device with the given inode number.
+pub fn char_device_metadata(inode: u64) -> Metadata {
+    Metadata {
+        inode,
+        kind: FileType::CharDevice,
+        size: 0,
+        mode: 0o666,
+    }
+}
+
+pub struct DevFs;
+
+struct Root;
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Root)
+    }
+}
+
+impl Inode for Root {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: 1,
+            kind: FileType::Directory,
+            size: DEVICES.lock().len() as u64,
+            mode: 0o755,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        DEVICES.lock().get(name).cloned().ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(DEVICES
+            .lock()
+            .iter()
+            .map(|(name, device)| {
+                let metadata = device.metadata();
+                DirEntry {
+                    name: name.clone(),
+                    inode: metadata.inode,
+                    kind: metadata.kind,
+                }
+            })
+            .collect())
+    }
+
+    fn create(&self, _name: &str, _kind: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+/// Reads nothing, swallows writes.
+struct Null;
+
+impl Inode for Null {
+    fn metadata(&self) -> Metadata {
+        char_device_metadata(2)
+    }
+
+    fn read_at(&self, _offset: u64, _buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+/// Reads zeros, swallows writes.
+struct Zero;
+
+impl Inode for Zero {
+    fn metadata(&self) -> Metadata {
+        char_device_metadata(3)
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        buf.fill(0);
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+/// Keyboard in, screen out. Reads block until something has been typed.
+struct Console;
+
+impl Inode for Console {
+    fn metadata(&self) -> Metadata {
+        char_device_metadata(4)
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(console::read_input(buf))
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        print!("{}", String::from_utf8_lossy(buf));
+        Ok(buf.len())
+    }
+}
+
+/// Scancodes as the keyboard sends them, set 1, key releases included.
+/// Reads block until a key is pressed or released.
+struct Keyboard;
+
+impl Inode for Keyboard {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            mode: 0o444,
+            ..char_device_metadata(5)
+        }
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(console::read_scancodes(buf))
+    }
+}
+
+/// Endless random bytes, see `random`. Writes are accepted and ignored.
+struct Random;
+
+impl Inode for Random {
+    fn metadata(&self) -> Metadata {
+        char_device_metadata(6)
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        random::fill(buf);
+        Ok(buf.len())
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        Ok(buf.len())
+    }
+}
+
+/// COM1. Reads block until a byte arrives.
+struct Serial;
+
+impl Inode for Serial {
+    fn metadata(&self) -> Metadata {
+        char_device_metadata(7)
+    }
+
+    fn read_at(&self, _offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(serial::read_bytes(buf))
+    }
+
+    fn write_at(&self, _offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        serial::write_bytes(buf);
+        Ok(buf.len())
+    }
+}
+
+/// The framebuffer's pixels, row by row, in the layout /proc/bootinfo shows.
+/// The console draws into the same memory.
+struct FrameBuffer;
+
+impl Inode for FrameBuffer {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            size: writer::with_writer(|writer| writer.info().byte_len).unwrap_or(0) as u64,
+            ..char_device_metadata(8)
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let count = writer::with_writer(|writer| {
+            let pixels = writer.pixels_mut();
+            let start = (offset as usize).min(pixels.len());
+            let count = buf.len().min(pixels.len() - start);
+            buf[..count].copy_from_slice(&pixels[start..start + count]);
+            count
+        });
+        Ok(count.unwrap_or(0))
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let count = writer::with_writer(|writer| {
+            let pixels = writer.pixels_mut();
+            let start = (offset as usize).min(pixels.len());
+            let count = buf.len().min(pixels.len() - start);
+            pixels[start..start + count].copy_from_slice(&buf[..count]);
+            count
+        });
+        match count {
+            Some(0) if !buf.is_empty() => Err(FsError::NoSpace),
+            count => Ok(count.unwrap_or(0)),
+        }
+    }
+}
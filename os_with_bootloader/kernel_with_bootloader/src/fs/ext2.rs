@@ -0,0 +1,515 @@
+// ext2, read-only. Volumes made by mkfs.ext3/4 mount too, as long as they
+// don't use features that change how data is found (inline data,
+// encryption, ...); a journal is ignored and extent-mapped files are read
+// through their extent trees.
+//
+// The superblock at byte 1024 gives the geometry; the group descriptor
+// table after it says where each block group keeps its inode table. An
+// inode maps file blocks to disk blocks either through twelve direct
+// pointers and single, double and triple indirect blocks, or through an
+// extent tree rooted in the same 60 bytes.
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use alloc::string::String;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+
+const SUPERBLOCK_POS: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xEF53;
+const ROOT_INODE: u32 = 2;
+
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+const INCOMPAT_RECOVER: u32 = 0x0004;
+const INCOMPAT_EXTENTS: u32 = 0x0040;
+const INCOMPAT_64BIT: u32 = 0x0080;
+const INCOMPAT_MMP: u32 = 0x0100;
+const INCOMPAT_FLEX_BG: u32 = 0x0200;
+const INCOMPAT_CSUM_SEED: u32 = 0x2000;
+const INCOMPAT_LARGEDIR: u32 = 0x4000;
+// Everything else changes the on-disk format in ways we can't read.
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE
+    | INCOMPAT_RECOVER
+    | INCOMPAT_EXTENTS
+    | INCOMPAT_64BIT
+    | INCOMPAT_MMP
+    | INCOMPAT_FLEX_BG
+    | INCOMPAT_CSUM_SEED
+    | INCOMPAT_LARGEDIR;
+
+const MODE_TYPE_MASK: u16 = 0xF000;
+const MODE_DIRECTORY: u16 = 0x4000;
+const MODE_CHAR_DEVICE: u16 = 0x2000;
+const MODE_BLOCK_DEVICE: u16 = 0x6000;
+const MODE_SYMLINK: u16 = 0xA000;
+
+const FLAG_EXTENTS: u32 = 0x0008_0000;
+
+// Bounds on what gets read into memory whole, so a corrupt volume can't
+// exhaust the heap.
+const MAX_DESCRIPTOR_SIZE: u64 = 1024;
+const MAX_DESCRIPTOR_TABLE: u64 = 1024 * 1024;
+const MAX_DIRECTORY_SIZE: u64 = 1024 * 1024;
+
+const DIRECT_BLOCKS: u64 = 12;
+const BLOCK_MAP_SIZE: usize = 60;
+
+const EXTENT_MAGIC: u16 = 0xF30A;
+const EXTENT_MAX_DEPTH: u16 = 5;
+// Extent lengths above this mark unwritten extents, which read as zeros.
+const EXTENT_UNWRITTEN: u16 = 32768;
+
+// File types in directory entries.
+const DIRENT_DIRECTORY: u8 = 2;
+const DIRENT_CHAR_DEVICE: u8 = 3;
+const DIRENT_BLOCK_DEVICE: u8 = 4;
+const DIRENT_SYMLINK: u8 = 7;
+
+fn u16_at(data: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([data[offset], data[offset + 1]])
+}
+
+fn u32_at(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+// Where things are on the volume, from the superblock and the group
+// descriptors.
+struct Geometry {
+    block_size: u64,
+    inode_count: u32,
+    inodes_per_group: u32,
+    inode_size: u64,
+    // First block of each group's inode table.
+    inode_tables: Vec<u64>,
+    dirents_have_type: bool,
+}
+
+impl Geometry {
+    fn read(device: &dyn BlockDevice) -> Result<Geometry, FsError> {
+        let mut sb = vec![0u8; SUPERBLOCK_SIZE];
+        read_device(device, SUPERBLOCK_POS, &mut sb)?;
+        if u16_at(&sb, 56) != MAGIC {
+            return Err(FsError::InvalidArgument);
+        }
+        let incompat = u32_at(&sb, 96);
+        if incompat & !INCOMPAT_SUPPORTED != 0 {
+            return Err(FsError::NotSupported);
+        }
+        let log_block_size = u32_at(&sb, 24);
+        if log_block_size > 6 {
+            return Err(FsError::InvalidArgument);
+        }
+        let block_size = 1024u64 << log_block_size;
+        let blocks_per_group = u32_at(&sb, 32) as u64;
+        let inodes_per_group = u32_at(&sb, 40);
+        let first_data_block = u32_at(&sb, 20) as u64;
+        let mut block_count = u32_at(&sb, 4) as u64;
+        // Revision 0 has fixed 128-byte inodes.
+        let inode_size = match u32_at(&sb, 76) {
+            0 => 128,
+            _ => u16_at(&sb, 88) as u64,
+        };
+        let descriptor_size = if incompat & INCOMPAT_64BIT != 0 {
+            block_count |= (u32_at(&sb, 0x150) as u64) << 32;
+            (u16_at(&sb, 254) as u64).max(32)
+        } else {
+            32
+        };
+        if blocks_per_group == 0
+            || inodes_per_group == 0
+            || inode_size < 128
+            || inode_size > block_size
+            || descriptor_size > MAX_DESCRIPTOR_SIZE
+        {
+            return Err(FsError::InvalidArgument);
+        }
+        let sectors = block_count.checked_mul(block_size / SECTOR_SIZE as u64);
+        if block_count <= first_data_block || sectors.is_none_or(|sectors| sectors > device.sector_count()) {
+            return Err(FsError::InvalidArgument);
+        }
+
+        // The descriptor table starts in the block after the superblock.
+        let groups = (block_count - first_data_block).div_ceil(blocks_per_group);
+        let table_len = groups * descriptor_size;
+        if table_len > MAX_DESCRIPTOR_TABLE {
+            return Err(FsError::NotSupported);
+        }
+        let mut table = vec![0u8; table_len as usize];
+        read_device(device, (first_data_block + 1) * block_size, &mut table)?;
+        let inode_tables = table
+            .chunks_exact(descriptor_size as usize)
+            .map(|descriptor| {
+                let low = u32_at(descriptor, 8) as u64;
+                let high = if descriptor_size >= 64 { u32_at(descriptor, 0x28) as u64 } else { 0 };
+                high << 32 | low
+            })
+            .collect();
+
+        Ok(Geometry {
+            block_size,
+            inode_count: u32_at(&sb, 0),
+            inodes_per_group,
+            inode_size,
+            inode_tables,
+            dirents_have_type: incompat & INCOMPAT_FILETYPE != 0,
+        })
+    }
+}
+
+// The parts of an on-disk inode the driver uses.
+#[derive(Clone)]
+struct RawInode {
+    ino: u32,
+    mode: u16,
+    size: u64,
+    flags: u32,
+    // Block pointers or the root of the extent tree.
+    block_map: [u8; BLOCK_MAP_SIZE],
+    inline_target: bool,
+}
+
+impl RawInode {
+    fn read(device: &dyn BlockDevice, geometry: &Geometry, ino: u32) -> Result<RawInode, FsError> {
+        if ino == 0 || ino > geometry.inode_count {
+            return Err(FsError::Io);
+        }
+        let group = ((ino - 1) / geometry.inodes_per_group) as usize;
+        let index = ((ino - 1) % geometry.inodes_per_group) as u64;
+        let table = *geometry.inode_tables.get(group).ok_or(FsError::Io)?;
+        let pos = table
+            .checked_mul(geometry.block_size)
+            .and_then(|start| start.checked_add(index * geometry.inode_size))
+            .ok_or(FsError::Io)?;
+        let mut raw = [0u8; 128];
+        read_device(device, pos, &mut raw)?;
+
+        let mode = u16_at(&raw, 0);
+        let mut size = u32_at(&raw, 4) as u64;
+        if mode & MODE_TYPE_MASK != MODE_DIRECTORY {
+            size |= (u32_at(&raw, 0x6C) as u64) << 32;
+        }
+        // A symlink whose target fits the block map keeps it there instead
+        // of in a data block.
+        let sectors = u32_at(&raw, 0x1C) as u64;
+        let acl_sectors = if u32_at(&raw, 0x68) != 0 { geometry.block_size / SECTOR_SIZE as u64 } else { 0 };
+        Ok(RawInode {
+            ino,
+            mode,
+            size,
+            flags: u32_at(&raw, 0x20),
+            block_map: raw[0x28..0x28 + BLOCK_MAP_SIZE].try_into().unwrap(),
+            inline_target: mode & MODE_TYPE_MASK == MODE_SYMLINK && sectors == acl_sectors,
+        })
+    }
+}
+
+pub struct Ext2Fs {
+    device: Arc<dyn BlockDevice>,
+    geometry: Geometry,
+    // Read at mount time, so `root` can't fail.
+    root: RawInode,
+    this: Weak<Ext2Fs>,
+}
+
+impl Ext2Fs {
+    /// Mounts the ext2 volume on `device`.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<Ext2Fs>, FsError> {
+        let geometry = Geometry::read(&*device)?;
+        let root = RawInode::read(&*device, &geometry, ROOT_INODE)?;
+        if root.mode & MODE_TYPE_MASK != MODE_DIRECTORY {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(Arc::new_cyclic(|this| Ext2Fs {
+            device,
+            geometry,
+            root,
+            this: this.clone(),
+        }))
+    }
+
+    fn read_bytes(&self, pos: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        read_device(&*self.device, pos, buf)
+    }
+
+    fn read_block(&self, block: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let pos = block.checked_mul(self.geometry.block_size).ok_or(FsError::Io)?;
+        self.read_bytes(pos, buf)
+    }
+
+    fn node(&self, raw: RawInode) -> Arc<Ext2Inode> {
+        Arc::new(Ext2Inode {
+            fs: self.this.upgrade().expect("file system dropped while in use"),
+            raw,
+        })
+    }
+
+    fn inode(&self, ino: u32) -> Result<Arc<Ext2Inode>, FsError> {
+        Ok(self.node(RawInode::read(&*self.device, &self.geometry, ino)?))
+    }
+}
+
+// Reads `buf.len()` bytes at byte `pos` of the device.
+fn read_device(device: &dyn BlockDevice, pos: u64, buf: &mut [u8]) -> Result<(), FsError> {
+    let first = pos / SECTOR_SIZE as u64;
+    let end = pos.checked_add(buf.len() as u64).ok_or(FsError::Io)?.div_ceil(SECTOR_SIZE as u64);
+    let start = (pos % SECTOR_SIZE as u64) as usize;
+    if start == 0 && buf.len() % SECTOR_SIZE == 0 {
+        return Ok(device.read_sectors(first, buf)?);
+    }
+    let mut sectors = vec![0u8; (end - first) as usize * SECTOR_SIZE];
+    device.read_sectors(first, &mut sectors)?;
+    buf.copy_from_slice(&sectors[start..start + buf.len()]);
+    Ok(())
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.node(self.root.clone())
+    }
+}
+
+struct Ext2Inode {
+    fs: Arc<Ext2Fs>,
+    raw: RawInode,
+}
+
+impl Ext2Inode {
+    fn kind(&self) -> FileType {
+        match self.raw.mode & MODE_TYPE_MASK {
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            MODE_CHAR_DEVICE => FileType::CharDevice,
+            MODE_BLOCK_DEVICE => FileType::BlockDevice,
+            _ => FileType::File,
+        }
+    }
+
+    /// The disk block holding file block `logical`, or `None` for a hole.
+    fn map(&self, logical: u64) -> Result<Option<u64>, FsError> {
+        if self.raw.flags & FLAG_EXTENTS != 0 {
+            self.map_extent(logical)
+        } else {
+            self.map_indirect(logical)
+        }
+    }
+
+    fn map_indirect(&self, logical: u64) -> Result<Option<u64>, FsError> {
+        let per_block = self.fs.geometry.block_size / 4;
+        let pointer = |i: u64| u32_at(&self.raw.block_map, i as usize * 4) as u64;
+        // Which root pointer to start from, how many levels of indirect
+        // blocks lie below it, and the index within them.
+        let (root, levels, mut index) = if logical < DIRECT_BLOCKS {
+            return Ok(Some(pointer(logical)).filter(|&b| b != 0));
+        } else if logical - DIRECT_BLOCKS < per_block {
+            (pointer(12), 1, logical - DIRECT_BLOCKS)
+        } else if logical - DIRECT_BLOCKS - per_block < per_block * per_block {
+            (pointer(13), 2, logical - DIRECT_BLOCKS - per_block)
+        } else {
+            let index = logical - DIRECT_BLOCKS - per_block - per_block * per_block;
+            if index >= per_block * per_block * per_block {
+                return Err(FsError::InvalidArgument);
+            }
+            (pointer(14), 3, index)
+        };
+
+        let mut block = root;
+        for level in (0..levels).rev() {
+            if block == 0 {
+                return Ok(None);
+            }
+            let span = per_block.pow(level);
+            let mut entry = [0u8; 4];
+            self.fs.read_bytes(block * self.fs.geometry.block_size + (index / span) * 4, &mut entry)?;
+            block = u32::from_le_bytes(entry) as u64;
+            index %= span;
+        }
+        Ok(Some(block).filter(|&b| b != 0))
+    }
+
+    fn map_extent(&self, logical: u64) -> Result<Option<u64>, FsError> {
+        let mut node = self.raw.block_map.to_vec();
+        for _ in 0..=EXTENT_MAX_DEPTH {
+            if u16_at(&node, 0) != EXTENT_MAGIC {
+                return Err(FsError::Io);
+            }
+            let entries = (u16_at(&node, 2) as usize).min(node.len() / 12 - 1);
+            let depth = u16_at(&node, 6);
+            let entry_at = |i: usize| &node[12 + i * 12..24 + i * 12];
+
+            if depth == 0 {
+                for i in 0..entries {
+                    let extent = entry_at(i);
+                    let first = u32_at(extent, 0) as u64;
+                    let len = u16_at(extent, 4);
+                    let (len, unwritten) = match len > EXTENT_UNWRITTEN {
+                        true => (len - EXTENT_UNWRITTEN, true),
+                        false => (len, false),
+                    };
+                    if logical >= first && logical < first + len as u64 {
+                        if unwritten {
+                            return Ok(None);
+                        }
+                        let start = (u16_at(extent, 6) as u64) << 32 | u32_at(extent, 8) as u64;
+                        return Ok(Some(start + logical - first));
+                    }
+                }
+                return Ok(None);
+            }
+
+            // The last index entry starting at or before `logical` covers it.
+            let Some(index) = (0..entries).rev().map(entry_at).find(|e| u32_at(e, 0) as u64 <= logical) else {
+                return Ok(None);
+            };
+            let child = (u16_at(index, 8) as u64) << 32 | u32_at(index, 4) as u64;
+            node = vec![0u8; self.fs.geometry.block_size as usize];
+            self.fs.read_block(child, &mut node)?;
+        }
+        Err(FsError::Io)
+    }
+
+    fn read_data(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let len = buf.len().min(self.raw.size.saturating_sub(offset) as usize);
+        let block_size = self.fs.geometry.block_size;
+        let mut block = vec![0u8; block_size as usize];
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let within = (pos % block_size) as usize;
+            let count = (block_size as usize - within).min(len - done);
+            match self.map(pos / block_size)? {
+                Some(disk_block) => {
+                    self.fs.read_block(disk_block, &mut block)?;
+                    buf[done..done + count].copy_from_slice(&block[within..within + count]);
+                }
+                None => buf[done..done + count].fill(0),
+            }
+            done += count;
+        }
+        Ok(len)
+    }
+
+    // Directory entries as (inode, file type from the entry, name).
+    fn entries(&self) -> Result<Vec<(u32, Option<u8>, String)>, FsError> {
+        if self.raw.size > MAX_DIRECTORY_SIZE {
+            return Err(FsError::NotSupported);
+        }
+        let mut data = vec![0u8; self.raw.size as usize];
+        self.read_data(0, &mut data)?;
+        let mut entries = Vec::new();
+        for block in data.chunks(self.fs.geometry.block_size as usize) {
+            let mut pos = 0;
+            while pos + 8 <= block.len() {
+                let ino = u32_at(block, pos);
+                let rec_len = u16_at(block, pos + 4) as usize;
+                if rec_len < 8 || pos + rec_len > block.len() {
+                    return Err(FsError::Io);
+                }
+                let (name_len, file_type) = match self.fs.geometry.dirents_have_type {
+                    true => (block[pos + 6] as usize, Some(block[pos + 7])),
+                    false => (u16_at(block, pos + 6) as usize, None),
+                };
+                if ino != 0 && 8 + name_len <= rec_len {
+                    let name = String::from_utf8_lossy(&block[pos + 8..pos + 8 + name_len]).into_owned();
+                    if name != "." && name != ".." {
+                        entries.push((ino, file_type, name));
+                    }
+                }
+                pos += rec_len;
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl Inode for Ext2Inode {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.raw.ino as u64,
+            kind: self.kind(),
+            size: self.raw.size,
+            mode: (self.raw.mode & 0o7777) as u32,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        match self.kind() {
+            FileType::Directory => Err(FsError::IsADirectory),
+            FileType::File => self.read_data(offset, buf),
+            _ => Err(FsError::NotSupported),
+        }
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _len: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        if self.kind() != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let (ino, _, _) = self.entries()?.into_iter().find(|(_, _, n)| n == name).ok_or(FsError::NotFound)?;
+        Ok(self.fs.inode(ino)?)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        if self.kind() != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        self.entries()?
+            .into_iter()
+            .map(|(ino, file_type, name)| {
+                let kind = match file_type {
+                    Some(DIRENT_DIRECTORY) => FileType::Directory,
+                    Some(DIRENT_SYMLINK) => FileType::Symlink,
+                    Some(DIRENT_CHAR_DEVICE) => FileType::CharDevice,
+                    Some(DIRENT_BLOCK_DEVICE) => FileType::BlockDevice,
+                    Some(_) => FileType::File,
+                    None => self.fs.inode(ino)?.kind(),
+                };
+                Ok(DirEntry {
+                    name,
+                    inode: ino as u64,
+                    kind,
+                })
+            })
+            .collect()
+    }
+
+    fn create(&self, _name: &str, _kind: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn read_link(&self) -> Result<String, FsError> {
+        if self.kind() != FileType::Symlink {
+            return Err(FsError::InvalidArgument);
+        }
+        let target = if self.raw.inline_target {
+            let len = (self.raw.size as usize).min(BLOCK_MAP_SIZE);
+            self.raw.block_map[..len].to_vec()
+        } else {
+            // Targets are at most a block long.
+            if self.raw.size > self.fs.geometry.block_size {
+                return Err(FsError::Io);
+            }
+            let mut data = vec![0u8; self.raw.size as usize];
+            self.read_data(0, &mut data)?;
+            data
+        };
+        String::from_utf8(target).map_err(|_| FsError::InvalidPath)
+    }
+}
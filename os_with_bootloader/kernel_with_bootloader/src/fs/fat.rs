@@ -0,0 +1,699 @@
+// FAT12, FAT16 and FAT32 on a block device, readable and writable, with
+// long file names.
+//
+// The volume starts with reserved sectors (boot sector, FAT32's FSInfo),
+// then the file allocation tables, then on FAT12/16 a fixed-size root
+// directory, then the data clusters. The FAT links each cluster of a file to
+// the next one; a directory entry holds the first cluster and the size.
+//
+// FAT has no inodes. A file is identified by where its short directory
+// entry sits on the device, which never moves, and open files are kept in a
+// table under that position so every lookup of a file shares one node. One
+// lock serializes all operations on the volume.
+
+mod dir;
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::block::{BlockDevice, SECTOR_SIZE};
+use crate::sync::Mutex;
+use alloc::collections::BTreeMap;
+use alloc::sync::{Arc, Weak};
+use alloc::vec;
+use alloc::vec::Vec;
+use dir::{Record, Slot, ENTRY_SIZE};
+
+// Key of the root directory in the node table; no entry sits at byte 0.
+const ROOT_KEY: u64 = 0;
+
+// FSInfo: free cluster count, which we stop maintaining once we write.
+const FS_INFO_FREE_COUNT: u64 = 488;
+const FREE_COUNT_UNKNOWN: u32 = 0xFFFF_FFFF;
+
+const MAX_FILE_SIZE: u64 = u32::MAX as u64;
+const MAX_FAT32_CLUSTERS: u64 = 0x0FFF_FFF5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+// Where everything is, from the boot sector. Sector numbers are relative to
+// the start of the volume.
+struct Geometry {
+    fat_type: FatType,
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat_sectors: u64,
+    fat_count: u64,
+    root_dir_start: u64,
+    root_dir_sectors: u64,
+    data_start: u64,
+    cluster_count: u32,
+    root_cluster: u32,
+    fs_info: Option<u64>,
+}
+
+impl Geometry {
+    fn parse(boot: &[u8], device_sectors: u64) -> Result<Geometry, FsError> {
+        let u16_at = |offset: usize| u16::from_le_bytes([boot[offset], boot[offset + 1]]) as u64;
+        let u32_at = |offset: usize| u32::from_le_bytes(boot[offset..offset + 4].try_into().unwrap()) as u64;
+        if boot[510..512] != [0x55, 0xAA] || !matches!(boot[0], 0xEB | 0xE9) {
+            return Err(FsError::InvalidArgument);
+        }
+        let sectors_per_cluster = boot[13] as u64;
+        let reserved = u16_at(14);
+        let fat_count = boot[16] as u64;
+        let root_entries = u16_at(17);
+        let fat_sectors = match u16_at(22) {
+            0 => u32_at(36),
+            n => n,
+        };
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32),
+            n => n,
+        };
+        // The block layer works in 512-byte sectors, so the volume has to.
+        if u16_at(11) != SECTOR_SIZE as u64 {
+            return Err(FsError::NotSupported);
+        }
+        if !sectors_per_cluster.is_power_of_two() || reserved == 0 || fat_count == 0 || fat_sectors == 0 {
+            return Err(FsError::InvalidArgument);
+        }
+        if total_sectors > device_sectors {
+            return Err(FsError::InvalidArgument);
+        }
+
+        let root_dir_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(SECTOR_SIZE as u64);
+        let root_dir_start = reserved + fat_count * fat_sectors;
+        let data_start = root_dir_start + root_dir_sectors;
+        let cluster_count = total_sectors.checked_sub(data_start).ok_or(FsError::InvalidArgument)? / sectors_per_cluster;
+        // The type follows from the number of clusters alone.
+        let fat_type = match cluster_count {
+            0..4085 => FatType::Fat12,
+            4085..65525 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+        // Each FAT needs an entry for every cluster, plus the two reserved
+        // ones, and FAT32 entries only have 28 bits.
+        let fat_bytes = match fat_type {
+            FatType::Fat12 => ((cluster_count + 2) * 3).div_ceil(2),
+            FatType::Fat16 => (cluster_count + 2) * 2,
+            FatType::Fat32 => (cluster_count + 2) * 4,
+        };
+        if cluster_count == 0 || cluster_count > MAX_FAT32_CLUSTERS || fat_bytes > fat_sectors * SECTOR_SIZE as u64 {
+            return Err(FsError::InvalidArgument);
+        }
+        let (root_cluster, fs_info) = match fat_type {
+            FatType::Fat32 => (u32_at(44) as u32, Some(u16_at(48)).filter(|&s| s != 0 && s < reserved)),
+            _ => (0, None),
+        };
+        let geometry = Geometry {
+            fat_type,
+            sectors_per_cluster,
+            fat_start: reserved,
+            fat_sectors,
+            fat_count,
+            root_dir_start,
+            root_dir_sectors,
+            data_start,
+            cluster_count: cluster_count as u32,
+            root_cluster,
+            fs_info,
+        };
+        if fat_type == FatType::Fat32 && !geometry.valid_cluster(root_cluster) {
+            return Err(FsError::InvalidArgument);
+        }
+        Ok(geometry)
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.sectors_per_cluster * SECTOR_SIZE as u64
+    }
+
+    fn valid_cluster(&self, cluster: u32) -> bool {
+        cluster >= 2 && cluster < self.cluster_count + 2
+    }
+
+    // Byte position of a cluster on the device.
+    fn cluster_pos(&self, cluster: u32) -> u64 {
+        (self.data_start + (cluster as u64 - 2) * self.sectors_per_cluster) * SECTOR_SIZE as u64
+    }
+
+    fn end_of_chain(&self) -> u32 {
+        match self.fat_type {
+            FatType::Fat12 => 0xFFF,
+            FatType::Fat16 => 0xFFFF,
+            FatType::Fat32 => 0x0FFF_FFFF,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FatEntry {
+    Free,
+    Next(u32),
+    End,
+}
+
+pub struct FatFs {
+    device: Arc<dyn BlockDevice>,
+    geometry: Geometry,
+    state: Mutex<State>,
+    this: Weak<FatFs>,
+}
+
+struct State {
+    // Where the search for a free cluster starts.
+    next_free: u32,
+    fs_info_stale: bool,
+    nodes: BTreeMap<u64, Weak<FatNode>>,
+}
+
+impl FatFs {
+    /// Mounts the FAT volume on `device`.
+    pub fn new(device: Arc<dyn BlockDevice>) -> Result<Arc<FatFs>, FsError> {
+        let mut boot = vec![0u8; SECTOR_SIZE];
+        device.read_sectors(0, &mut boot)?;
+        let geometry = Geometry::parse(&boot, device.sector_count())?;
+        Ok(Arc::new_cyclic(|this| FatFs {
+            device,
+            geometry,
+            state: Mutex::new(State {
+                next_free: 2,
+                fs_info_stale: false,
+                nodes: BTreeMap::new(),
+            }),
+            this: this.clone(),
+        }))
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.geometry.fat_type
+    }
+
+    fn read_bytes(&self, pos: u64, buf: &mut [u8]) -> Result<(), FsError> {
+        let first = pos / SECTOR_SIZE as u64;
+        let end = (pos + buf.len() as u64).div_ceil(SECTOR_SIZE as u64);
+        let start = (pos % SECTOR_SIZE as u64) as usize;
+        if start == 0 && buf.len() % SECTOR_SIZE == 0 {
+            return Ok(self.device.read_sectors(first, buf)?);
+        }
+        let mut sectors = vec![0u8; (end - first) as usize * SECTOR_SIZE];
+        self.device.read_sectors(first, &mut sectors)?;
+        buf.copy_from_slice(&sectors[start..start + buf.len()]);
+        Ok(())
+    }
+
+    fn write_bytes(&self, pos: u64, buf: &[u8]) -> Result<(), FsError> {
+        let first = pos / SECTOR_SIZE as u64;
+        let end = (pos + buf.len() as u64).div_ceil(SECTOR_SIZE as u64);
+        let start = (pos % SECTOR_SIZE as u64) as usize;
+        if start == 0 && buf.len() % SECTOR_SIZE == 0 {
+            return Ok(self.device.write_sectors(first, buf)?);
+        }
+        let mut sectors = vec![0u8; (end - first) as usize * SECTOR_SIZE];
+        self.device.read_sectors(first, &mut sectors)?;
+        sectors[start..start + buf.len()].copy_from_slice(buf);
+        Ok(self.device.write_sectors(first, &sectors)?)
+    }
+
+    // Byte offset of a cluster's entry within one FAT, and its width.
+    fn fat_slot(&self, cluster: u32) -> (u64, usize) {
+        let cluster = cluster as u64;
+        match self.geometry.fat_type {
+            FatType::Fat12 => (cluster + cluster / 2, 2),
+            FatType::Fat16 => (cluster * 2, 2),
+            FatType::Fat32 => (cluster * 4, 4),
+        }
+    }
+
+    fn read_fat(&self, cluster: u32) -> Result<u32, FsError> {
+        let (offset, width) = self.fat_slot(cluster);
+        let mut bytes = [0u8; 4];
+        self.read_bytes(self.geometry.fat_start * SECTOR_SIZE as u64 + offset, &mut bytes[..width])?;
+        let raw = u32::from_le_bytes(bytes);
+        Ok(match self.geometry.fat_type {
+            FatType::Fat12 if cluster % 2 == 1 => raw >> 4,
+            FatType::Fat12 => raw & 0xFFF,
+            FatType::Fat16 => raw,
+            FatType::Fat32 => raw & 0x0FFF_FFFF,
+        })
+    }
+
+    // Updates the entry in every copy of the FAT.
+    fn write_fat(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let (offset, width) = self.fat_slot(cluster);
+        for fat in 0..self.geometry.fat_count {
+            let pos = (self.geometry.fat_start + fat * self.geometry.fat_sectors) * SECTOR_SIZE as u64 + offset;
+            let mut bytes = [0u8; 4];
+            self.read_bytes(pos, &mut bytes[..width])?;
+            let old = u32::from_le_bytes(bytes);
+            // FAT12 entries share a byte with their neighbour; FAT32 keeps
+            // the top four bits reserved.
+            let new = match self.geometry.fat_type {
+                FatType::Fat12 if cluster % 2 == 1 => (old & 0x000F) | (value & 0xFFF) << 4,
+                FatType::Fat12 => (old & 0xF000) | (value & 0xFFF),
+                FatType::Fat16 => value & 0xFFFF,
+                FatType::Fat32 => (old & 0xF000_0000) | (value & 0x0FFF_FFFF),
+            };
+            self.write_bytes(pos, &new.to_le_bytes()[..width])?;
+        }
+        Ok(())
+    }
+
+    fn entry(&self, cluster: u32) -> Result<FatEntry, FsError> {
+        let value = self.read_fat(cluster)?;
+        let end = self.geometry.end_of_chain();
+        match value {
+            0 => Ok(FatEntry::Free),
+            // Reserved values and the bad-cluster mark close to the end marker
+            // all end a chain.
+            v if v >= end - 8 => Ok(FatEntry::End),
+            v if self.geometry.valid_cluster(v) => Ok(FatEntry::Next(v)),
+            _ => Err(FsError::Io),
+        }
+    }
+
+    /// The clusters of the chain starting at `first`; empty for cluster 0.
+    fn chain(&self, first: u32) -> Result<Vec<u32>, FsError> {
+        let mut clusters = Vec::new();
+        let mut cluster = first;
+        while cluster != 0 {
+            // A chain longer than the volume loops.
+            if !self.geometry.valid_cluster(cluster) || clusters.len() > self.geometry.cluster_count as usize {
+                return Err(FsError::Io);
+            }
+            clusters.push(cluster);
+            cluster = match self.entry(cluster)? {
+                FatEntry::Next(next) => next,
+                FatEntry::End => 0,
+                FatEntry::Free => return Err(FsError::Io),
+            };
+        }
+        Ok(clusters)
+    }
+
+    // Takes a free cluster, zeroes it and appends it to the chain ending in
+    // `last`.
+    fn allocate(&self, state: &mut State, last: Option<u32>) -> Result<u32, FsError> {
+        let count = self.geometry.cluster_count;
+        let start = state.next_free.clamp(2, count + 1);
+        let mut found = None;
+        for i in 0..count {
+            let cluster = 2 + (start - 2 + i) % count;
+            if self.entry(cluster)? == FatEntry::Free {
+                found = Some(cluster);
+                break;
+            }
+        }
+        let cluster = found.ok_or(FsError::NoSpace)?;
+        self.mark_fs_info_stale(state)?;
+        self.write_fat(cluster, self.geometry.end_of_chain())?;
+        if let Some(last) = last {
+            self.write_fat(last, cluster)?;
+        }
+        self.write_bytes(self.geometry.cluster_pos(cluster), &self.zeroed_cluster())?;
+        state.next_free = cluster + 1;
+        Ok(cluster)
+    }
+
+    fn zeroed_cluster(&self) -> Vec<u8> {
+        vec![0u8; self.geometry.cluster_size() as usize]
+    }
+
+    fn free_chain(&self, state: &mut State, first: u32) -> Result<(), FsError> {
+        self.mark_fs_info_stale(state)?;
+        for cluster in self.chain(first)? {
+            self.write_fat(cluster, 0)?;
+        }
+        state.next_free = state.next_free.min(first);
+        Ok(())
+    }
+
+    // FSInfo's free cluster count is only a hint; rather than keep it up to
+    // date, it is marked unknown before the first change.
+    fn mark_fs_info_stale(&self, state: &mut State) -> Result<(), FsError> {
+        if let Some(sector) = self.geometry.fs_info.filter(|_| !state.fs_info_stale) {
+            self.write_bytes(sector * SECTOR_SIZE as u64 + FS_INFO_FREE_COUNT, &FREE_COUNT_UNKNOWN.to_le_bytes())?;
+            state.fs_info_stale = true;
+        }
+        Ok(())
+    }
+
+    // The byte ranges a directory occupies: the fixed root area, or its
+    // clusters.
+    fn dir_extents(&self, key: u64, first_cluster: u32) -> Result<Vec<(u64, u64)>, FsError> {
+        if key == ROOT_KEY && self.geometry.fat_type != FatType::Fat32 {
+            let pos = self.geometry.root_dir_start * SECTOR_SIZE as u64;
+            return Ok(vec![(pos, self.geometry.root_dir_sectors * SECTOR_SIZE as u64)]);
+        }
+        let size = self.geometry.cluster_size();
+        Ok(self.chain(first_cluster)?.into_iter().map(|c| (self.geometry.cluster_pos(c), size)).collect())
+    }
+
+    fn dir_slots(&self, key: u64, first_cluster: u32) -> Result<Vec<Slot>, FsError> {
+        let mut slots = Vec::new();
+        for (pos, len) in self.dir_extents(key, first_cluster)? {
+            let mut data = vec![0u8; len as usize];
+            self.read_bytes(pos, &mut data)?;
+            for (i, raw) in data.chunks_exact(ENTRY_SIZE).enumerate() {
+                slots.push((pos + (i * ENTRY_SIZE) as u64, raw.try_into().unwrap()));
+            }
+        }
+        Ok(slots)
+    }
+
+    // The node for the file whose short entry is at `key`, shared with
+    // anyone who has it open.
+    fn node(&self, state: &mut State, key: u64, kind: FileType, first_cluster: u32, size: u32, read_only: bool) -> Arc<FatNode> {
+        if let Some(node) = state.nodes.get(&key).and_then(Weak::upgrade) {
+            return node;
+        }
+        let node = Arc::new(FatNode {
+            fs: self.this.upgrade().expect("file system dropped while in use"),
+            key,
+            kind,
+            read_only,
+            state: Mutex::new(NodeState {
+                first_cluster,
+                size,
+                removed: false,
+            }),
+        });
+        state.nodes.retain(|_, node| node.strong_count() > 0);
+        state.nodes.insert(key, Arc::downgrade(&node));
+        node
+    }
+
+    fn record_node(&self, state: &mut State, record: &Record) -> Arc<FatNode> {
+        let kind = if record.is_directory() { FileType::Directory } else { FileType::File };
+        let read_only = record.attr & dir::ATTR_READ_ONLY != 0;
+        self.node(state, record.pos, kind, record.first_cluster, record.size, read_only)
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        let mut state = self.state.lock();
+        self.node(&mut state, ROOT_KEY, FileType::Directory, self.geometry.root_cluster, 0, false)
+    }
+}
+
+struct FatNode {
+    fs: Arc<FatFs>,
+    key: u64,
+    kind: FileType,
+    read_only: bool,
+    state: Mutex<NodeState>,
+}
+
+struct NodeState {
+    first_cluster: u32,
+    size: u32,
+    // Unlinked while open; its clusters are gone.
+    removed: bool,
+}
+
+impl FatNode {
+    fn check_live(&self, node: &NodeState) -> Result<(), FsError> {
+        if node.removed { Err(FsError::NotFound) } else { Ok(()) }
+    }
+
+    // Writes the first cluster and size back into the directory entry.
+    fn update_entry(&self, node: &NodeState) -> Result<(), FsError> {
+        if self.key == ROOT_KEY {
+            return Ok(());
+        }
+        let mut entry = [0u8; ENTRY_SIZE];
+        self.fs.read_bytes(self.key, &mut entry)?;
+        dir::set_first_cluster(&mut entry, node.first_cluster);
+        let size = if self.kind == FileType::Directory { 0 } else { node.size };
+        entry[28..32].copy_from_slice(&size.to_le_bytes());
+        if self.kind == FileType::File {
+            entry[11] |= dir::ATTR_ARCHIVE;
+        }
+        self.fs.write_bytes(self.key, &entry)
+    }
+
+    // Makes the chain at least `clusters` long and returns it.
+    fn grow(&self, state: &mut State, node: &mut NodeState, clusters: usize) -> Result<Vec<u32>, FsError> {
+        let mut chain = self.fs.chain(node.first_cluster)?;
+        while chain.len() < clusters {
+            let cluster = self.fs.allocate(state, chain.last().copied())?;
+            if chain.is_empty() {
+                node.first_cluster = cluster;
+            }
+            chain.push(cluster);
+        }
+        Ok(chain)
+    }
+
+    fn write_data(&self, state: &mut State, node: &mut NodeState, offset: u64, buf: &[u8]) -> Result<(), FsError> {
+        let end = offset + buf.len() as u64;
+        if end > MAX_FILE_SIZE {
+            return Err(FsError::NoSpace);
+        }
+        let cluster_size = self.fs.geometry.cluster_size();
+        // Bytes between the old end and `offset` read as zeros. The gap can
+        // be gigabytes, so it is written a cluster at a time.
+        if offset > node.size as u64 {
+            let zeros = self.fs.zeroed_cluster();
+            let mut pos = node.size as u64;
+            while pos < offset {
+                let len = (offset - pos).min(cluster_size) as usize;
+                self.write_data(state, node, pos, &zeros[..len])?;
+                pos += len as u64;
+            }
+        }
+        let chain = self.grow(state, node, end.div_ceil(cluster_size) as usize)?;
+        let mut done = 0;
+        while done < buf.len() {
+            let pos = offset + done as u64;
+            let within = pos % cluster_size;
+            let len = ((cluster_size - within) as usize).min(buf.len() - done);
+            let cluster = chain[(pos / cluster_size) as usize];
+            self.fs.write_bytes(self.fs.geometry.cluster_pos(cluster) + within, &buf[done..done + len])?;
+            done += len;
+        }
+        node.size = node.size.max(end as u32);
+        self.update_entry(node)
+    }
+
+    // Index of a free run of `count` slots, growing the directory if it has none.
+    fn make_room(&self, state: &mut State, node: &mut NodeState, slots: &mut Vec<Slot>, count: usize) -> Result<usize, FsError> {
+        loop {
+            if let Some(index) = dir::free_run(slots, count) {
+                return Ok(index);
+            }
+            if self.key == ROOT_KEY && self.fs.geometry.fat_type != FatType::Fat32 {
+                return Err(FsError::NoSpace);
+            }
+            let chain_len = self.fs.chain(node.first_cluster)?.len() + 1;
+            self.grow(state, node, chain_len)?;
+            self.update_entry(node)?;
+            *slots = self.fs.dir_slots(self.key, node.first_cluster)?;
+        }
+    }
+
+    fn records(&self, node: &NodeState) -> Result<(Vec<Slot>, Vec<Record>), FsError> {
+        let slots = self.fs.dir_slots(self.key, node.first_cluster)?;
+        let records = dir::parse(&slots);
+        Ok((slots, records))
+    }
+
+    fn check_directory(&self) -> Result<(), FsError> {
+        match self.kind {
+            FileType::Directory => Ok(()),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+}
+
+impl Inode for FatNode {
+    fn metadata(&self) -> Metadata {
+        let node = self.state.lock();
+        let mode = match self.kind {
+            FileType::Directory => 0o755,
+            _ if self.read_only => 0o444,
+            _ => 0o644,
+        };
+        Metadata {
+            inode: if self.key == ROOT_KEY { 1 } else { self.key / ENTRY_SIZE as u64 },
+            kind: self.kind,
+            size: node.size as u64,
+            mode,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        if self.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        let _fs = self.fs.state.lock();
+        let node = self.state.lock();
+        self.check_live(&node)?;
+        let len = buf.len().min((node.size as u64).saturating_sub(offset) as usize);
+        if len == 0 {
+            return Ok(0);
+        }
+        let cluster_size = self.fs.geometry.cluster_size();
+        let chain = self.fs.chain(node.first_cluster)?;
+        let mut done = 0;
+        while done < len {
+            let pos = offset + done as u64;
+            let within = pos % cluster_size;
+            let count = ((cluster_size - within) as usize).min(len - done);
+            let cluster = *chain.get((pos / cluster_size) as usize).ok_or(FsError::Io)?;
+            self.fs.read_bytes(self.fs.geometry.cluster_pos(cluster) + within, &mut buf[done..done + count])?;
+            done += count;
+        }
+        Ok(len)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        if self.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let mut fs = self.fs.state.lock();
+        let mut node = self.state.lock();
+        self.check_live(&node)?;
+        self.write_data(&mut fs, &mut node, offset, buf)?;
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, len: u64) -> Result<(), FsError> {
+        if self.kind == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        if self.read_only {
+            return Err(FsError::ReadOnly);
+        }
+        let mut fs = self.fs.state.lock();
+        let mut node = self.state.lock();
+        self.check_live(&node)?;
+        if len > node.size as u64 {
+            return self.write_data(&mut fs, &mut node, len, &[]);
+        }
+        let keep = len.div_ceil(self.fs.geometry.cluster_size()) as usize;
+        let chain = self.fs.chain(node.first_cluster)?;
+        if let Some(&first_dropped) = chain.get(keep) {
+            match keep {
+                0 => node.first_cluster = 0,
+                _ => self.fs.write_fat(chain[keep - 1], self.fs.geometry.end_of_chain())?,
+            }
+            self.fs.free_chain(&mut fs, first_dropped)?;
+        }
+        node.size = len as u32;
+        self.update_entry(&node)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        self.check_directory()?;
+        let mut fs = self.fs.state.lock();
+        let node = self.state.lock();
+        self.check_live(&node)?;
+        let (_, records) = self.records(&node)?;
+        let record = records.iter().find(|r| r.matches(name)).ok_or(FsError::NotFound)?;
+        Ok(self.fs.record_node(&mut fs, record))
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        self.check_directory()?;
+        let _fs = self.fs.state.lock();
+        let node = self.state.lock();
+        self.check_live(&node)?;
+        let (_, records) = self.records(&node)?;
+        Ok(records
+            .into_iter()
+            .map(|record| DirEntry {
+                kind: if record.is_directory() { FileType::Directory } else { FileType::File },
+                inode: record.pos / ENTRY_SIZE as u64,
+                name: record.name,
+            })
+            .collect())
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        self.check_directory()?;
+        let attr = match kind {
+            FileType::File => dir::ATTR_ARCHIVE,
+            FileType::Directory => dir::ATTR_DIRECTORY,
+            _ => return Err(FsError::NotSupported),
+        };
+        if !dir::valid_name(name) {
+            return Err(FsError::InvalidPath);
+        }
+        let mut fs = self.fs.state.lock();
+        let mut node = self.state.lock();
+        self.check_live(&node)?;
+        let (mut slots, records) = self.records(&node)?;
+        if records.iter().any(|r| r.matches(name)) {
+            return Err(FsError::AlreadyExists);
+        }
+        let (short_name, long_name) = dir::short_name_for(name, |short| records.iter().any(|r| &r.short_name == short));
+
+        // A directory starts with a cluster holding `.` and `..`.
+        let first_cluster = match kind {
+            FileType::Directory => {
+                let cluster = self.fs.allocate(&mut fs, None)?;
+                let parent = if self.key == ROOT_KEY { 0 } else { node.first_cluster };
+                let dots = dir::dot_entries(cluster, parent);
+                self.fs.write_bytes(self.fs.geometry.cluster_pos(cluster), dots.as_flattened())?;
+                cluster
+            }
+            _ => 0,
+        };
+        let entries = dir::encode(name, short_name, long_name, attr, first_cluster);
+
+        let index = match self.make_room(&mut fs, &mut node, &mut slots, entries.len()) {
+            Ok(index) => index,
+            Err(err) => {
+                // Nothing refers to the new directory's cluster yet.
+                if first_cluster != 0 {
+                    self.fs.free_chain(&mut fs, first_cluster)?;
+                }
+                return Err(err);
+            }
+        };
+        for (i, entry) in entries.iter().enumerate() {
+            self.fs.write_bytes(slots[index + i].0, entry)?;
+        }
+
+        let pos = slots[index + entries.len() - 1].0;
+        Ok(self.fs.node(&mut fs, pos, kind, first_cluster, 0, false))
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        self.check_directory()?;
+        let mut fs = self.fs.state.lock();
+        let node = self.state.lock();
+        self.check_live(&node)?;
+        let (_, records) = self.records(&node)?;
+        let record = records.iter().find(|r| r.matches(name)).ok_or(FsError::NotFound)?;
+        if record.is_directory() {
+            let contents = dir::parse(&self.fs.dir_slots(record.pos, record.first_cluster)?);
+            if !contents.is_empty() {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+        }
+        for &pos in &record.slots {
+            self.fs.write_bytes(pos, &[dir::SLOT_FREE])?;
+        }
+        if record.first_cluster != 0 {
+            self.fs.free_chain(&mut fs, record.first_cluster)?;
+        }
+        if let Some(open) = fs.nodes.remove(&record.pos).and_then(|node| node.upgrade()) {
+            open.state.lock().removed = true;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,307 @@
+// FAT directory entries.
+//
+// A directory is an array of 32-byte slots. Every file has a short 8.3 entry
+// holding its attributes, first cluster and size; a name that doesn't fit
+// 8.3 is stored in long-name (VFAT) slots right before it, last part first,
+// each carrying 13 UTF-16 characters and a checksum of the short name.
+
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub const ENTRY_SIZE: usize = 32;
+
+pub const ATTR_READ_ONLY: u8 = 0x01;
+pub const ATTR_VOLUME_ID: u8 = 0x08;
+pub const ATTR_DIRECTORY: u8 = 0x10;
+pub const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0F;
+const ATTR_LONG_NAME_MASK: u8 = 0x3F;
+
+pub const SLOT_FREE: u8 = 0xE5;
+pub const SLOT_END: u8 = 0x00;
+// A short name starting with 0xE5 stores 0x05 instead.
+const KANJI_E5: u8 = 0x05;
+
+const LAST_LONG_ENTRY: u8 = 0x40;
+const LONG_CHARS: usize = 13;
+// Enough long name slots for `MAX_NAME_LEN` characters.
+const MAX_LONG_ORDINAL: u8 = MAX_NAME_LEN.div_ceil(LONG_CHARS) as u8;
+// Byte offsets of the characters in a long-name slot.
+const LONG_CHAR_OFFSETS: [usize; LONG_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+
+// Case flags in the reserved byte, as Windows NT sets them for names like
+// "readme.txt" that fit 8.3 but aren't upper case.
+const NT_LOWER_BASE: u8 = 0x08;
+const NT_LOWER_EXT: u8 = 0x10;
+
+pub const MAX_NAME_LEN: usize = 255;
+
+// 1980-01-01, the earliest date FAT can store. There is no clock to stamp
+// files with.
+const DEFAULT_DATE: u16 = (1 << 5) | 1;
+
+/// A directory slot with its position on the device.
+pub type Slot = (u64, [u8; ENTRY_SIZE]);
+
+/// A file as listed in a directory.
+#[derive(Debug, Clone)]
+pub struct Record {
+    pub name: String,
+    pub short_name: [u8; 11],
+    pub attr: u8,
+    pub first_cluster: u32,
+    pub size: u32,
+    /// Position of the short entry on the device.
+    pub pos: u64,
+    /// Positions of all the record's slots, long-name ones first.
+    pub slots: Vec<u64>,
+}
+
+impl Record {
+    pub fn is_directory(&self) -> bool {
+        self.attr & ATTR_DIRECTORY != 0
+    }
+
+    /// Compares like FAT does: ignoring ASCII case, against the long and the
+    /// short name.
+    pub fn matches(&self, name: &str) -> bool {
+        self.name.eq_ignore_ascii_case(name) || display_short(&self.short_name, 0).eq_ignore_ascii_case(name)
+    }
+}
+
+/// First cluster stored in a short entry.
+pub fn first_cluster(entry: &[u8]) -> u32 {
+    let high = u16::from_le_bytes([entry[20], entry[21]]) as u32;
+    let low = u16::from_le_bytes([entry[26], entry[27]]) as u32;
+    high << 16 | low
+}
+
+pub fn set_first_cluster(entry: &mut [u8], cluster: u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name.iter().fold(0u8, |sum, &b| (sum >> 1 | sum << 7).wrapping_add(b))
+}
+
+/// The records of a directory, in order. `.`, `..` and the volume label are
+/// left out.
+pub fn parse(slots: &[Slot]) -> Vec<Record> {
+    let mut records = Vec::new();
+    // The long name being collected: its characters, its slots, the ordinal
+    // expected next and the checksum all its parts must carry.
+    let mut long_chars: Vec<u16> = Vec::new();
+    let mut long_slots: Vec<u64> = Vec::new();
+    let mut expected = 0u8;
+    let mut long_checksum = 0u8;
+
+    for &(pos, ref raw) in slots {
+        match raw[0] {
+            SLOT_END => break,
+            SLOT_FREE => {
+                expected = 0;
+                continue;
+            }
+            _ => {}
+        }
+
+        if raw[11] & ATTR_LONG_NAME_MASK == ATTR_LONG_NAME {
+            let ordinal = raw[0] & 0x1F;
+            // Ordinals count from 1; a slot outside that range orphans the
+            // run it belongs to.
+            if ordinal == 0 || ordinal > MAX_LONG_ORDINAL {
+                expected = 0;
+                continue;
+            }
+            if raw[0] & LAST_LONG_ENTRY != 0 {
+                long_chars = vec![0xFFFF; ordinal as usize * LONG_CHARS];
+                long_slots.clear();
+                long_checksum = raw[13];
+            } else if expected == 0 || ordinal != expected - 1 || raw[13] != long_checksum {
+                expected = 0;
+                continue;
+            }
+            let start = (ordinal as usize - 1) * LONG_CHARS;
+            for (i, &offset) in LONG_CHAR_OFFSETS.iter().enumerate() {
+                long_chars[start + i] = u16::from_le_bytes([raw[offset], raw[offset + 1]]);
+            }
+            long_slots.push(pos);
+            expected = ordinal;
+            continue;
+        }
+
+        let complete_long_name = expected == 1;
+        expected = 0;
+        if raw[11] & ATTR_VOLUME_ID != 0 {
+            continue;
+        }
+        let short_name: [u8; 11] = raw[..11].try_into().unwrap();
+        if short_name[0] == b'.' {
+            continue;
+        }
+        let (name, mut slots) = if complete_long_name && checksum(&short_name) == long_checksum {
+            let len = long_chars.iter().position(|&c| c == 0 || c == 0xFFFF).unwrap_or(long_chars.len());
+            (String::from_utf16_lossy(&long_chars[..len]), long_slots.clone())
+        } else {
+            (display_short(&short_name, raw[12]), Vec::new())
+        };
+        slots.push(pos);
+        records.push(Record {
+            name,
+            short_name,
+            attr: raw[11],
+            first_cluster: first_cluster(raw),
+            size: u32::from_le_bytes(raw[28..32].try_into().unwrap()),
+            pos,
+            slots,
+        });
+    }
+    records
+}
+
+/// "NAME.EXT" from the padded 11-byte form, lowered where `nt_flags` say so.
+fn display_short(short_name: &[u8; 11], nt_flags: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let mut text: String = bytes.iter().map(|&b| if b == KANJI_E5 { 0xE5 } else { b }).map(char::from).collect();
+        text.truncate(text.trim_end_matches(' ').len());
+        if lower { text.to_ascii_lowercase() } else { text }
+    };
+    let base = part(&short_name[..8], nt_flags & NT_LOWER_BASE != 0);
+    let ext = part(&short_name[8..], nt_flags & NT_LOWER_EXT != 0);
+    if ext.is_empty() { base } else { base + "." + &ext }
+}
+
+/// Whether `name` can be stored in a FAT directory at all.
+pub fn valid_name(name: &str) -> bool {
+    name.encode_utf16().count() <= MAX_NAME_LEN
+        && !name.ends_with(['.', ' '])
+        && name.chars().all(|c| c >= ' ' && !"\"*/:<>?\\|".contains(c))
+}
+
+// Characters allowed in a short name besides A-Z and 0-9.
+fn short_char(c: char) -> Option<u8> {
+    match c {
+        'A'..='Z' | '0'..='9' => Some(c as u8),
+        'a'..='z' => Some(c.to_ascii_uppercase() as u8),
+        '!' | '#' | '$' | '%' | '&' | '\'' | '(' | ')' | '-' | '@' | '^' | '_' | '`' | '{' | '}' | '~' => Some(c as u8),
+        _ => None,
+    }
+}
+
+/// The short name for `name` and whether it needs long-name slots as well.
+/// Names that don't fit 8.3 exactly, or aren't upper case, get a generated
+/// "BASIS~N" alias that `taken` says is free.
+pub fn short_name_for(name: &str, taken: impl Fn(&[u8; 11]) -> bool) -> ([u8; 11], bool) {
+    let (base, ext) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    };
+    let mut lossy = base.len() > 8 || ext.len() > 3 || name.chars().any(|c| c.is_lowercase());
+    let mut convert = |part: &str| -> Vec<u8> {
+        part.chars()
+            .filter_map(|c| match (c, short_char(c)) {
+                (_, Some(b)) => Some(b),
+                (' ' | '.', None) => {
+                    lossy = true;
+                    None
+                }
+                (_, None) => {
+                    lossy = true;
+                    Some(b'_')
+                }
+            })
+            .collect()
+    };
+    let base = convert(base);
+    let ext = convert(ext);
+
+    let mut short = [b' '; 11];
+    let ext_len = ext.len().min(3);
+    short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+    if !lossy && !base.is_empty() {
+        short[..base.len()].copy_from_slice(&base);
+        if !taken(&short) {
+            return (short, false);
+        }
+    }
+
+    let basis = if base.is_empty() { &b"_"[..] } else { &base[..base.len().min(6)] };
+    for n in 1..1_000_000u32 {
+        let tail = alloc::format!("~{}", n);
+        let keep = basis.len().min(8 - tail.len());
+        short[..8].fill(b' ');
+        short[..keep].copy_from_slice(&basis[..keep]);
+        short[keep..keep + tail.len()].copy_from_slice(tail.as_bytes());
+        if !taken(&short) {
+            break;
+        }
+    }
+    (short, true)
+}
+
+/// The slots for a new file: long-name slots if needed, then the short entry.
+pub fn encode(name: &str, short_name: [u8; 11], long_name: bool, attr: u8, first_cluster: u32) -> Vec<[u8; ENTRY_SIZE]> {
+    let mut slots = Vec::new();
+    if long_name {
+        let units: Vec<u16> = name.encode_utf16().collect();
+        let count = units.len().div_ceil(LONG_CHARS);
+        let sum = checksum(&short_name);
+        for part in (0..count).rev() {
+            let mut slot = [0u8; ENTRY_SIZE];
+            slot[0] = (part as u8 + 1) | if part + 1 == count { LAST_LONG_ENTRY } else { 0 };
+            slot[11] = ATTR_LONG_NAME;
+            slot[13] = sum;
+            for (i, &offset) in LONG_CHAR_OFFSETS.iter().enumerate() {
+                // The name ends with a NUL, the rest of the slot is padding.
+                let index = part * LONG_CHARS + i;
+                let c = match index.cmp(&units.len()) {
+                    core::cmp::Ordering::Less => units[index],
+                    core::cmp::Ordering::Equal => 0,
+                    core::cmp::Ordering::Greater => 0xFFFF,
+                };
+                slot[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+            }
+            slots.push(slot);
+        }
+    }
+    slots.push(short_entry(short_name, attr, first_cluster));
+    slots
+}
+
+fn short_entry(short_name: [u8; 11], attr: u8, first_cluster: u32) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[..11].copy_from_slice(&short_name);
+    entry[11] = attr;
+    for offset in [16, 18, 24] {
+        entry[offset..offset + 2].copy_from_slice(&DEFAULT_DATE.to_le_bytes());
+    }
+    set_first_cluster(&mut entry, first_cluster);
+    entry
+}
+
+/// The `.` and `..` entries that start a new directory. The root is
+/// referred to as cluster 0.
+pub fn dot_entries(cluster: u32, parent_cluster: u32) -> [[u8; ENTRY_SIZE]; 2] {
+    [
+        short_entry(*b".          ", ATTR_DIRECTORY, cluster),
+        short_entry(*b"..         ", ATTR_DIRECTORY, parent_cluster),
+    ]
+}
+
+/// Index of the first run of `count` unused slots.
+pub fn free_run(slots: &[Slot], count: usize) -> Option<usize> {
+    let mut run = 0;
+    for (i, (_, raw)) in slots.iter().enumerate() {
+        if raw[0] == SLOT_FREE || raw[0] == SLOT_END {
+            run += 1;
+            if run == count {
+                return Some(i + 1 - count);
+            }
+        } else {
+            run = 0;
+        }
+    }
+    None
+}
@@ -0,0 +1,195 @@
+use super::{Dentry, DirEntry, FileType, FsError, Metadata};
+use crate::sync::Mutex;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::ops::BitOr;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// Flags of `open`, with the Linux values.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(0);
+    pub const WRITE: OpenFlags = OpenFlags(1);
+    pub const READ_WRITE: OpenFlags = OpenFlags(2);
+    pub const CREATE: OpenFlags = OpenFlags(0o100);
+    pub const TRUNCATE: OpenFlags = OpenFlags(0o1000);
+    pub const APPEND: OpenFlags = OpenFlags(0o2000);
+    pub const DIRECTORY: OpenFlags = OpenFlags(0o200000);
+
+    const ACCESS_MODE: u32 = 3;
+    const ALL: u32 = 3 | 0o100 | 0o1000 | 0o2000 | 0o200000;
+
+    pub fn from_bits(bits: u32) -> Option<Self> {
+        (bits & !Self::ALL == 0 && bits & Self::ACCESS_MODE != 3).then_some(OpenFlags(bits))
+    }
+
+    pub fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn readable(self) -> bool {
+        self.0 & Self::ACCESS_MODE != 1
+    }
+
+    pub fn writable(self) -> bool {
+        self.0 & Self::ACCESS_MODE != 0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, rhs: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | rhs.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// Identifies one open file, for inodes that keep state per reader.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FileHandle(u64);
+
+impl FileHandle {
+    fn new() -> Self {
+        static NEXT_HANDLE: AtomicU64 = AtomicU64::new(1);
+        FileHandle(NEXT_HANDLE.fetch_add(1, Ordering::Relaxed))
+    }
+}
+
+/// An open file: what a file descriptor refers to. Descriptors duplicated
+/// from each other share it, and with it the offset.
+pub struct File {
+    dentry: Arc<Dentry>,
+    flags: OpenFlags,
+    handle: FileHandle,
+    offset: Mutex<u64>,
+}
+
+impl File {
+    pub(super) fn open(dentry: Arc<Dentry>, flags: OpenFlags) -> Result<Arc<File>, FsError> {
+        let metadata = dentry.inode().metadata();
+        match metadata.kind {
+            FileType::Directory if flags.writable() => return Err(FsError::IsADirectory),
+            FileType::Directory => {}
+            _ if flags.contains(OpenFlags::DIRECTORY) => return Err(FsError::NotADirectory),
+            _ => {}
+        }
+        if flags.contains(OpenFlags::TRUNCATE) && flags.writable() && metadata.kind == FileType::File {
+            dentry.inode().truncate(0)?;
+        }
+        Ok(Arc::new(File {
+            dentry,
+            flags,
+            handle: FileHandle::new(),
+            offset: Mutex::new(0),
+        }))
+    }
+
+    pub fn dentry(&self) -> &Arc<Dentry> {
+        &self.dentry
+    }
+
+    pub fn stat(&self) -> Metadata {
+        self.dentry.inode().metadata()
+    }
+
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, FsError> {
+        if !self.flags.readable() {
+            return Err(FsError::BadFileDescriptor);
+        }
+        let mut offset = self.offset.lock();
+        let count = self.dentry.inode().read_handle(self.handle, *offset, buf)?;
+        *offset += count as u64;
+        Ok(count)
+    }
+
+    pub fn write(&self, buf: &[u8]) -> Result<usize, FsError> {
+        if !self.flags.writable() {
+            return Err(FsError::BadFileDescriptor);
+        }
+        let mut offset = self.offset.lock();
+        if self.flags.contains(OpenFlags::APPEND) {
+            *offset = self.stat().size;
+        }
+        let count = self.dentry.inode().write_at(*offset, buf)?;
+        *offset += count as u64;
+        Ok(count)
+    }
+
+    pub fn seek(&self, pos: SeekFrom) -> Result<u64, FsError> {
+        let mut offset = self.offset.lock();
+        let new = match pos {
+            SeekFrom::Start(start) => Some(start),
+            SeekFrom::Current(delta) => offset.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.stat().size.checked_add_signed(delta),
+        };
+        *offset = new.ok_or(FsError::InvalidArgument)?;
+        Ok(*offset)
+    }
+
+    /// Directory entries from the current offset on, counted in entries;
+    /// advances the offset past the ones `take` accepts.
+    pub fn read_dir(&self, mut take: impl FnMut(&DirEntry) -> bool) -> Result<usize, FsError> {
+        let entries = self.dentry.read_dir()?;
+        let mut offset = self.offset.lock();
+        let mut taken = 0;
+        for entry in entries.iter().skip(*offset as usize) {
+            if !take(entry) {
+                break;
+            }
+            taken += 1;
+        }
+        *offset += taken as u64;
+        Ok(taken)
+    }
+}
+
+impl Drop for File {
+    fn drop(&mut self) {
+        self.dentry.inode().release(self.handle);
+    }
+}
+
+/// Maximum open files per process.
+pub const MAX_FDS: usize = 64;
+
+/// A process's file descriptors.
+#[derive(Clone, Default)]
+pub struct FdTable {
+    files: Vec<Option<Arc<File>>>,
+}
+
+impl FdTable {
+    pub fn new() -> Self {
+        FdTable { files: Vec::new() }
+    }
+
+    /// Stores `file` under the lowest free descriptor.
+    pub fn insert(&mut self, file: Arc<File>) -> Result<usize, FsError> {
+        if let Some(fd) = self.files.iter().position(Option::is_none) {
+            self.files[fd] = Some(file);
+            return Ok(fd);
+        }
+        if self.files.len() == MAX_FDS {
+            return Err(FsError::TooManyOpenFiles);
+        }
+        self.files.push(Some(file));
+        Ok(self.files.len() - 1)
+    }
+
+    pub fn get(&self, fd: usize) -> Result<Arc<File>, FsError> {
+        self.files.get(fd).cloned().flatten().ok_or(FsError::BadFileDescriptor)
+    }
+
+    pub fn remove(&mut self, fd: usize) -> Result<Arc<File>, FsError> {
+        self.files.get_mut(fd).and_then(Option::take).ok_or(FsError::BadFileDescriptor)
+    }
+}
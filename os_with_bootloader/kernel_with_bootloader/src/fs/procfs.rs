@@ -0,0 +1,277 @@
+// Kernel state as files, mounted on /proc.
+//
+// Each file is a function that renders its contents. The text is generated
+// when a read starts at offset 0 and kept, per open file, for the reads that
+// continue it, so a reader going through the file in chunks sees one
+// consistent snapshot whatever other readers do meanwhile.
+
+use super::{DirEntry, FileHandle, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::allocator;
+use crate::bootinfo;
+use crate::cpu;
+use crate::interrupts;
+use crate::memory;
+use crate::sync::Mutex;
+use crate::task;
+use crate::time;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+
+pub type Generator = fn() -> String;
+
+static FILES: Mutex<BTreeMap<&'static str, Arc<ProcFile>>> = Mutex::new(BTreeMap::new());
+
+/// Adds /proc/`name`, whose contents `generate` renders on each read.
+pub fn register(name: &'static str, generate: Generator) {
+    let mut files = FILES.lock();
+    let inode = files.len() as u64 + 2;
+    files.insert(
+        name,
+        Arc::new(ProcFile {
+            inode,
+            generate,
+            snapshots: Mutex::new(BTreeMap::new()),
+        }),
+    );
+}
+
+/// Registers the files procfs itself provides.
+pub fn init() {
+    register("uptime", uptime);
+    register("mounts", mounts);
+    register("meminfo", meminfo);
+    register("interrupts", interrupts);
+    register("cpuinfo", cpuinfo);
+    register("tasks", tasks);
+    register("bootinfo", bootinfo);
+}
+
+fn uptime() -> String {
+    let ms = time::uptime_ms();
+    format!("{}.{:02}\n", ms / 1000, ms % 1000 / 10)
+}
+
+fn mounts() -> String {
+    let mut text = String::new();
+    for mount in super::mounts() {
+        let _ = writeln!(text, "{} {}", mount.path, mount.fs.name());
+    }
+    text
+}
+
+fn meminfo() -> String {
+    let frames = memory::frame_stats();
+    let heap = allocator::stats();
+    let kib = |frames: usize| frames as u64 * memory::frame_allocator::FRAME_SIZE / 1024;
+    let mut text = String::new();
+    for (name, value, unit) in [
+        ("MemTotal", kib(frames.total_frames), " kB"),
+        ("MemFree", kib(frames.free_frames), " kB"),
+        ("MemUsed", kib(frames.used_frames()), " kB"),
+        ("FrameAllocations", frames.allocations as u64, ""),
+        ("FrameFrees", frames.deallocations as u64, ""),
+        ("FrameFailures", frames.failed_allocations as u64, ""),
+        ("HeapTotal", heap.size as u64 / 1024, " kB"),
+        ("HeapUsed", heap.used as u64 / 1024, " kB"),
+    ] {
+        let _ = writeln!(text, "{:<18}{:>10}{}", format!("{}:", name), value, unit);
+    }
+    let _ = writeln!(text, "{:<18}{:>10}", "HeapAllocator:", heap.strategy);
+    text
+}
+
+// A row per vector, a column per CPU, like Linux.
+fn interrupts() -> String {
+    let mut text = String::from("    ");
+    for cpu in cpu::all() {
+        let _ = write!(text, "{:>11}", format!("CPU{}", cpu.index()));
+    }
+    text.push('\n');
+    for line in interrupts::counts() {
+        let _ = write!(text, "{:3}:", line.vector);
+        for count in line.per_cpu {
+            let _ = write!(text, "{:>11}", count);
+        }
+        let _ = writeln!(text, "   {}", line.name);
+    }
+    text
+}
+
+fn cpuinfo() -> String {
+    let identity = cpu::identity();
+    let mut text = String::new();
+    for cpu in cpu::all() {
+        for (name, value) in [
+            ("processor", format!("{}", cpu.index())),
+            ("apicid", format!("{}", cpu.apic_id())),
+            ("online", String::from(if cpu.is_online() { "yes" } else { "no" })),
+            ("vendor_id", identity.vendor.clone()),
+            ("cpu family", format!("{}", identity.family)),
+            ("model", format!("{}", identity.model)),
+            ("model name", identity.brand.clone()),
+            ("stepping", format!("{}", identity.stepping)),
+            ("flags", identity.flags.join(" ")),
+        ] {
+            let _ = writeln!(text, "{:<12}: {}", name, value);
+        }
+        text.push('\n');
+    }
+    text
+}
+
+fn tasks() -> String {
+    let mut text = format!("{:>5} {:>5} {:<9} {:>15}  {}\n", "TID", "PID", "STATE", "STACK (KiB)", "NAME");
+    let mut threads = task::threads();
+    threads.sort_by_key(|thread| thread.id.as_u64());
+    for thread in threads {
+        let pid = thread.pid.map_or(String::from("-"), |pid| format!("{}", pid.as_u64()));
+        let state = thread.state.name();
+        let stack = format!("{}/{}", thread.stack_used / 1024, thread.stack_size / 1024);
+        let _ = writeln!(text, "{:>5} {:>5} {:<9} {:>15}  {}", thread.id.as_u64(), pid, state, stack, thread.name);
+    }
+    text
+}
+
+fn bootinfo() -> String {
+    let Some(boot) = bootinfo::get() else {
+        return String::new();
+    };
+    let optional = |value: Option<u64>| value.map_or(String::from("none"), |value| format!("{:#x}", value));
+    let mut text = String::new();
+    let _ = writeln!(text, "kernel: {:#x}, {} bytes, loaded at offset {:#x}", boot.kernel_addr, boot.kernel_len, boot.kernel_image_offset);
+    let _ = writeln!(text, "ramdisk: {}, {} bytes", optional(boot.ramdisk_addr), boot.ramdisk_len);
+    let _ = writeln!(text, "physical memory offset: {}", optional(boot.physical_memory_offset));
+    let _ = writeln!(text, "rsdp: {}", optional(boot.rsdp_addr));
+    match &boot.framebuffer {
+        Some(fb) => {
+            let _ = writeln!(
+                text,
+                "framebuffer: {}x{}, stride {}, {} bytes per pixel, {:?}, {} bytes",
+                fb.width, fb.height, fb.stride, fb.bytes_per_pixel, fb.pixel_format, fb.byte_len
+            );
+        }
+        None => text.push_str("framebuffer: none\n"),
+    }
+    let _ = writeln!(text, "memory regions: {}", boot.memory_regions.len());
+    for region in &boot.memory_regions {
+        let _ = writeln!(
+            text,
+            "  {:#014x}-{:#014x} {:>10} KiB  {:?}",
+            region.start,
+            region.end,
+            (region.end - region.start) / 1024,
+            region.kind
+        );
+    }
+    text
+}
+
+pub struct ProcFs;
+
+struct Root;
+
+struct ProcFile {
+    inode: u64,
+    generate: Generator,
+    // The text each open file is reading through.
+    snapshots: Mutex<BTreeMap<FileHandle, String>>,
+}
+
+// The part of `data` from `offset` on that fits in `buf`.
+fn read_from(data: &str, offset: u64, buf: &mut [u8]) -> usize {
+    let data = data.as_bytes();
+    let start = (offset as usize).min(data.len());
+    let count = buf.len().min(data.len() - start);
+    buf[..count].copy_from_slice(&data[start..start + count]);
+    count
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "procfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        Arc::new(Root)
+    }
+}
+
+impl Inode for Root {
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: 1,
+            kind: FileType::Directory,
+            size: FILES.lock().len() as u64,
+            mode: 0o555,
+        }
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        FILES
+            .lock()
+            .get(name)
+            .map(|file| file.clone() as Arc<dyn Inode>)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        Ok(FILES
+            .lock()
+            .iter()
+            .map(|(name, file)| DirEntry {
+                name: String::from(*name),
+                inode: file.inode,
+                kind: FileType::File,
+            })
+            .collect())
+    }
+
+    fn create(&self, _name: &str, _kind: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn unlink(&self, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
+
+impl Inode for ProcFile {
+    // The size is unknown until the file is read, like on Linux.
+    fn metadata(&self) -> Metadata {
+        Metadata {
+            inode: self.inode,
+            kind: FileType::File,
+            size: 0,
+            mode: 0o444,
+        }
+    }
+
+    // Without an open file there is nothing to keep the text for.
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        Ok(read_from(&(self.generate)(), offset, buf))
+    }
+
+    fn read_handle(&self, handle: FileHandle, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let mut snapshots = self.snapshots.lock();
+        if offset == 0 || !snapshots.contains_key(&handle) {
+            snapshots.insert(handle, (self.generate)());
+        }
+        Ok(read_from(&snapshots[&handle], offset, buf))
+    }
+
+    fn release(&self, handle: FileHandle) {
+        self.snapshots.lock().remove(&handle);
+    }
+
+    fn write_at(&self, _offset: u64, _buf: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _len: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+}
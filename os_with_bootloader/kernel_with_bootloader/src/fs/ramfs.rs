@@ -0,0 +1,238 @@
+// A file system that lives entirely on the heap.
+//
+// Files filled from the initramfs point into the ramdisk and are only copied
+// to the heap when first written to.
+
+use super::{DirEntry, FileSystem, FileType, FsError, Inode, Metadata};
+use crate::initramfs::{EntryKind, Initramfs};
+use crate::sync::Mutex;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+
+// Largest a file may grow. The heap is shared with the rest of the kernel,
+// and running it dry takes the kernel down.
+const MAX_FILE_SIZE: usize = 16 * 1024 * 1024;
+
+pub struct RamFs {
+    root: Arc<RamInode>,
+}
+
+enum Content {
+    Static(&'static [u8]),
+    Owned(Vec<u8>),
+    Directory(BTreeMap<String, Arc<RamInode>>),
+}
+
+struct RamInode {
+    inode: u64,
+    mode: u32,
+    content: Mutex<Content>,
+}
+
+impl RamInode {
+    fn new(content: Content, mode: u32) -> Arc<Self> {
+        static NEXT_INODE: AtomicU64 = AtomicU64::new(1);
+        Arc::new(RamInode {
+            inode: NEXT_INODE.fetch_add(1, Ordering::Relaxed),
+            mode,
+            content: Mutex::new(content),
+        })
+    }
+
+    fn new_directory() -> Arc<Self> {
+        RamInode::new(Content::Directory(BTreeMap::new()), 0o755)
+    }
+
+    fn kind(content: &Content) -> FileType {
+        match content {
+            Content::Directory(_) => FileType::Directory,
+            _ => FileType::File,
+        }
+    }
+
+    // Directory entry `name`, created as a directory if missing.
+    fn subdirectory(&self, name: &str) -> Option<Arc<RamInode>> {
+        match &mut *self.content.lock() {
+            Content::Directory(entries) => {
+                let child = entries.entry(name.to_string()).or_insert_with(RamInode::new_directory);
+                matches!(&*child.content.lock(), Content::Directory(_)).then(|| child.clone())
+            }
+            _ => None,
+        }
+    }
+}
+
+// Grows or shrinks a file's data, failing instead of exhausting the heap.
+fn resize(data: &mut Vec<u8>, len: usize) -> Result<(), FsError> {
+    if len > MAX_FILE_SIZE {
+        return Err(FsError::NoSpace);
+    }
+    if let Some(additional) = len.checked_sub(data.len()) {
+        data.try_reserve(additional).map_err(|_| FsError::NoSpace)?;
+    }
+    data.resize(len, 0);
+    Ok(())
+}
+
+impl Inode for RamInode {
+    fn metadata(&self) -> Metadata {
+        let content = self.content.lock();
+        let size = match &*content {
+            Content::Static(data) => data.len(),
+            Content::Owned(data) => data.len(),
+            Content::Directory(entries) => entries.len(),
+        };
+        Metadata {
+            inode: self.inode,
+            kind: RamInode::kind(&content),
+            size: size as u64,
+            mode: self.mode,
+        }
+    }
+
+    fn read_at(&self, offset: u64, buf: &mut [u8]) -> Result<usize, FsError> {
+        let content = self.content.lock();
+        let data = match &*content {
+            Content::Static(data) => *data,
+            Content::Owned(data) => data.as_slice(),
+            Content::Directory(_) => return Err(FsError::IsADirectory),
+        };
+        let start = (offset as usize).min(data.len());
+        let count = buf.len().min(data.len() - start);
+        buf[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+
+    fn write_at(&self, offset: u64, buf: &[u8]) -> Result<usize, FsError> {
+        let mut content = self.content.lock();
+        if let Content::Static(data) = *content {
+            *content = Content::Owned(data.to_vec());
+        }
+        let Content::Owned(data) = &mut *content else {
+            return Err(FsError::IsADirectory);
+        };
+        let start = usize::try_from(offset).map_err(|_| FsError::NoSpace)?;
+        let end = start.checked_add(buf.len()).ok_or(FsError::NoSpace)?;
+        if end > data.len() {
+            resize(data, end)?;
+        }
+        data[start..end].copy_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn truncate(&self, len: u64) -> Result<(), FsError> {
+        let mut content = self.content.lock();
+        if let Content::Static(data) = *content {
+            *content = Content::Owned(data.to_vec());
+        }
+        let Content::Owned(data) = &mut *content else {
+            return Err(FsError::IsADirectory);
+        };
+        resize(data, usize::try_from(len).map_err(|_| FsError::NoSpace)?)
+    }
+
+    fn lookup(&self, name: &str) -> Result<Arc<dyn Inode>, FsError> {
+        match &*self.content.lock() {
+            Content::Directory(entries) => entries
+                .get(name)
+                .map(|child| child.clone() as Arc<dyn Inode>)
+                .ok_or(FsError::NotFound),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn read_dir(&self) -> Result<Vec<DirEntry>, FsError> {
+        match &*self.content.lock() {
+            Content::Directory(entries) => Ok(entries
+                .iter()
+                .map(|(name, child)| DirEntry {
+                    name: name.clone(),
+                    inode: child.inode,
+                    kind: RamInode::kind(&child.content.lock()),
+                })
+                .collect()),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn create(&self, name: &str, kind: FileType) -> Result<Arc<dyn Inode>, FsError> {
+        let child = match kind {
+            FileType::File => RamInode::new(Content::Owned(Vec::new()), 0o644),
+            FileType::Directory => RamInode::new_directory(),
+            _ => return Err(FsError::NotSupported),
+        };
+        match &mut *self.content.lock() {
+            Content::Directory(entries) => {
+                if entries.contains_key(name) {
+                    return Err(FsError::AlreadyExists);
+                }
+                entries.insert(name.to_string(), child.clone());
+                Ok(child)
+            }
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn unlink(&self, name: &str) -> Result<(), FsError> {
+        let mut content = self.content.lock();
+        let Content::Directory(entries) = &mut *content else {
+            return Err(FsError::NotADirectory);
+        };
+        let child = entries.get(name).ok_or(FsError::NotFound)?;
+        if let Content::Directory(grandchildren) = &*child.content.lock() {
+            if !grandchildren.is_empty() {
+                return Err(FsError::DirectoryNotEmpty);
+            }
+        }
+        entries.remove(name);
+        Ok(())
+    }
+}
+
+impl RamFs {
+    pub fn new() -> Self {
+        RamFs {
+            root: RamInode::new_directory(),
+        }
+    }
+
+    /// A ramfs holding the contents of the initramfs.
+    pub fn from_initramfs(initramfs: &Initramfs) -> Self {
+        let fs = RamFs::new();
+        for entry in initramfs.entries() {
+            let mut components: Vec<&str> = entry.path.split('/').filter(|c| !c.is_empty()).collect();
+            let Some(name) = components.pop() else {
+                continue;
+            };
+            // A file in the way of a directory drops the entry.
+            let Some(dir) = components.iter().try_fold(fs.root.clone(), |dir, c| dir.subdirectory(c)) else {
+                continue;
+            };
+            match entry.kind {
+                EntryKind::Directory => {
+                    dir.subdirectory(name);
+                }
+                EntryKind::File => {
+                    if let Content::Directory(entries) = &mut *dir.content.lock() {
+                        let file = RamInode::new(Content::Static(entry.data), entry.mode);
+                        entries.insert(name.to_string(), file);
+                    }
+                }
+            }
+        }
+        fs
+    }
+}
+
+impl FileSystem for RamFs {
+    fn name(&self) -> &'static str {
+        "ramfs"
+    }
+
+    fn root(&self) -> Arc<dyn Inode> {
+        self.root.clone()
+    }
+}
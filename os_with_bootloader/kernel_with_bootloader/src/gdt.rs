@@ -0,0 +1,118 @@
+use crate::cpu;
+use crate::memory::stack::GuardedStack;
+use crate::memory::PagingError;
+use alloc::boxed::Box;
+use alloc::format;
+use lazy_static::lazy_static;
+use x86_64::instructions::segmentation::{Segment, CS, DS, ES, SS};
+use x86_64::instructions::tables::load_tss;
+use x86_64::structures::gdt::{Descriptor, GlobalDescriptorTable, SegmentSelector};
+use x86_64::structures::tss::TaskStateSegment;
+use x86_64::VirtAddr;
+
+// Faults that can happen because the current stack is broken get a stack of
+// their own from the TSS, otherwise a stack overflow turns straight into a
+// triple fault.
+pub const DOUBLE_FAULT_IST_INDEX: u16 = 0;
+pub const PAGE_FAULT_IST_INDEX: u16 = 1;
+
+const IST_STACK_SIZE: usize = 4096 * 5;
+
+// The bootstrap CPU's TSS; application processors allocate theirs. Not behind
+// a lock: the CPU reads it directly, and RSP0 is rewritten on every context
+// switch with interrupts disabled.
+static mut TSS: TaskStateSegment = TaskStateSegment::new();
+
+#[derive(Debug, Clone, Copy)]
+pub struct Selectors {
+    pub kernel_code: SegmentSelector,
+    pub kernel_data: SegmentSelector,
+    pub user_data: SegmentSelector,
+    pub user_code: SegmentSelector,
+    tss: SegmentSelector,
+}
+
+// The order matters for `syscall`/`sysret`: kernel data must follow kernel
+// code, and user code must follow user data.
+fn build_gdt(tss: &'static TaskStateSegment) -> (GlobalDescriptorTable, Selectors) {
+    let mut gdt = GlobalDescriptorTable::new();
+    let kernel_code = gdt.add_entry(Descriptor::kernel_code_segment());
+    let kernel_data = gdt.add_entry(Descriptor::kernel_data_segment());
+    let user_data = gdt.add_entry(Descriptor::user_data_segment());
+    let user_code = gdt.add_entry(Descriptor::user_code_segment());
+    let tss = gdt.add_entry(Descriptor::tss_segment(tss));
+    (
+        gdt,
+        Selectors {
+            kernel_code,
+            kernel_data,
+            user_data,
+            user_code,
+            tss,
+        },
+    )
+}
+
+lazy_static! {
+    static ref GDT: (GlobalDescriptorTable, Selectors) = build_gdt(unsafe { &*(&raw const TSS) });
+}
+
+/// Loads the GDT and TSS on the bootstrap CPU.
+pub fn init() {
+    let tss = unsafe { &mut *(&raw mut TSS) };
+    tss.interrupt_stack_table[DOUBLE_FAULT_IST_INDEX as usize] = {
+        static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+        let stack_start = VirtAddr::from_ptr(&raw const STACK);
+        stack_start + IST_STACK_SIZE
+    };
+    tss.interrupt_stack_table[PAGE_FAULT_IST_INDEX as usize] = {
+        static mut STACK: [u8; IST_STACK_SIZE] = [0; IST_STACK_SIZE];
+        let stack_start = VirtAddr::from_ptr(&raw const STACK);
+        stack_start + IST_STACK_SIZE
+    };
+    cpu::current().set_tss(tss);
+
+    GDT.0.load();
+    unsafe { load_segments(GDT.1) };
+}
+
+/// Gives an application processor a GDT and TSS of its own, with its own
+/// fault stacks. The selectors come out the same as on the bootstrap CPU.
+pub fn init_ap() -> Result<(), PagingError> {
+    let name = format!("cpu {} ist", cpu::id());
+    let mut tss = TaskStateSegment::new();
+    for index in [DOUBLE_FAULT_IST_INDEX, PAGE_FAULT_IST_INDEX] {
+        let stack = GuardedStack::new(&name, IST_STACK_SIZE as u64)?;
+        tss.interrupt_stack_table[index as usize] = stack.top();
+        // Lives as long as the CPU.
+        core::mem::forget(stack);
+    }
+    let tss: &'static mut TaskStateSegment = Box::leak(Box::new(tss));
+    cpu::current().set_tss(tss);
+
+    let (gdt, selectors) = build_gdt(tss);
+    let gdt: &'static GlobalDescriptorTable = Box::leak(Box::new(gdt));
+    gdt.load();
+    unsafe { load_segments(selectors) };
+    Ok(())
+}
+
+unsafe fn load_segments(selectors: Selectors) {
+    unsafe {
+        CS::set_reg(selectors.kernel_code);
+        DS::set_reg(selectors.kernel_data);
+        ES::set_reg(selectors.kernel_data);
+        SS::set_reg(selectors.kernel_data);
+        load_tss(selectors.tss);
+    }
+}
+
+pub fn selectors() -> Selectors {
+    GDT.1
+}
+
+/// Sets the stack the CPU switches to when an interrupt or exception arrives
+/// while running in ring 3.
+pub fn set_kernel_stack(top: VirtAddr) {
+    unsafe { (*cpu::current().tss()).privilege_stack_table[0] = top };
+}
@@ -0,0 +1,188 @@
+// The initial RAM filesystem: a tar archive `build.rs` packs from the host
+// `initramfs/` directory and the bootloader loads as its ramdisk.
+//
+// The archive stays where the bootloader mapped it (a kernel-half mapping, so
+// every address space sees it); file contents are slices into it. Only the
+// index of entries is built on the heap. Everything is read-only.
+
+use alloc::string::String;
+use alloc::vec::Vec;
+use spin::Once;
+
+const BLOCK_SIZE: usize = 512;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TarError {
+    Truncated,
+    BadChecksum,
+    BadHeader,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Directory,
+}
+
+/// One file or directory. Paths are absolute, without a trailing slash.
+#[derive(Debug)]
+pub struct Entry {
+    pub path: String,
+    pub kind: EntryKind,
+    pub mode: u32,
+    pub data: &'static [u8],
+}
+
+impl Entry {
+    /// Last path component; empty for the root.
+    pub fn name(&self) -> &str {
+        self.path.rsplit('/').next().unwrap_or("")
+    }
+
+    fn parent(&self) -> &str {
+        match self.path.rfind('/') {
+            Some(0) | None => "/",
+            Some(i) => &self.path[..i],
+        }
+    }
+}
+
+pub struct Initramfs {
+    entries: Vec<Entry>,
+}
+
+static INITRAMFS: Once<Initramfs> = Once::new();
+
+/// Indexes the ramdisk the bootloader loaded, if any. Returns the number of
+/// entries.
+pub fn init(addr: Option<u64>, len: u64) -> Result<usize, TarError> {
+    let data: &'static [u8] = match addr {
+        Some(addr) if len > 0 => unsafe { core::slice::from_raw_parts(addr as *const u8, len as usize) },
+        _ => &[],
+    };
+    let fs = parse(data)?;
+    // Not counting the root directory.
+    let count = fs.entries.len() - 1;
+    INITRAMFS.call_once(|| fs);
+    Ok(count)
+}
+
+/// The filesystem, empty if there was no ramdisk.
+pub fn get() -> &'static Initramfs {
+    INITRAMFS.call_once(|| Initramfs { entries: Vec::new() })
+}
+
+impl Initramfs {
+    pub fn lookup(&self, path: &str) -> Option<&Entry> {
+        let path = normalize(path);
+        self.entries.iter().find(|entry| entry.path == path)
+    }
+
+    /// Contents of the file at `path`.
+    pub fn read(&self, path: &str) -> Option<&'static [u8]> {
+        self.lookup(path)
+            .filter(|entry| entry.kind == EntryKind::File)
+            .map(|entry| entry.data)
+    }
+
+    /// Entries directly inside the directory at `path`.
+    pub fn read_dir<'a>(&'a self, path: &str) -> impl Iterator<Item = &'a Entry> + 'a {
+        let path = normalize(path);
+        self.entries
+            .iter()
+            .filter(move |entry| entry.path != "/" && entry.parent() == path)
+    }
+
+    pub fn entries(&self) -> &[Entry] {
+        &self.entries
+    }
+}
+
+// Absolute, no "." components, no trailing or doubled slashes.
+fn normalize(path: &str) -> String {
+    let mut normalized = String::new();
+    for component in path.split('/').filter(|c| !c.is_empty() && *c != ".") {
+        normalized.push('/');
+        normalized.push_str(component);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+fn parse_octal(field: &[u8]) -> Result<u64, TarError> {
+    let mut value = 0u64;
+    for &b in field.iter().skip_while(|&&b| b == b' ') {
+        match b {
+            b'0'..=b'7' => value = value.checked_mul(8).ok_or(TarError::BadHeader)? + u64::from(b - b'0'),
+            b'\0' | b' ' => break,
+            _ => return Err(TarError::BadHeader),
+        }
+    }
+    Ok(value)
+}
+
+fn c_str(field: &[u8]) -> Result<&str, TarError> {
+    let len = field.iter().position(|&b| b == 0).unwrap_or(field.len());
+    core::str::from_utf8(&field[..len]).map_err(|_| TarError::BadHeader)
+}
+
+// ustar: 512-byte header, then the contents padded to 512 bytes. Two zero
+// blocks end the archive.
+fn parse(data: &'static [u8]) -> Result<Initramfs, TarError> {
+    let mut entries = Vec::new();
+    entries.push(Entry {
+        path: String::from("/"),
+        kind: EntryKind::Directory,
+        mode: 0o755,
+        data: &[],
+    });
+
+    let mut offset = 0;
+    while offset + BLOCK_SIZE <= data.len() {
+        let header = &data[offset..offset + BLOCK_SIZE];
+        if header.iter().all(|&b| b == 0) {
+            break;
+        }
+        let checksum = parse_octal(&header[148..156])?;
+        let sum: u64 = header
+            .iter()
+            .enumerate()
+            .map(|(i, &b)| if (148..156).contains(&i) { u64::from(b' ') } else { u64::from(b) })
+            .sum();
+        if sum != checksum {
+            return Err(TarError::BadChecksum);
+        }
+
+        let size = parse_octal(&header[124..136])? as usize;
+        let mode = parse_octal(&header[100..108])? as u32;
+        let name = c_str(&header[0..100])?;
+        let prefix = if &header[257..262] == b"ustar" { c_str(&header[345..500])? } else { "" };
+        let start = offset + BLOCK_SIZE;
+        let contents = data.get(start..start + size).ok_or(TarError::Truncated)?;
+        offset = start + size.next_multiple_of(BLOCK_SIZE);
+
+        let kind = match header[156] {
+            b'0' | b'\0' => EntryKind::File,
+            b'5' => EntryKind::Directory,
+            // Links, devices and the like are not supported.
+            _ => continue,
+        };
+        let mut path = String::from(prefix);
+        path.push('/');
+        path.push_str(name);
+        let path = normalize(&path);
+        if path == "/" {
+            continue;
+        }
+        entries.push(Entry {
+            path,
+            kind,
+            mode,
+            data: if kind == EntryKind::File { contents } else { &[] },
+        });
+    }
+
+    Ok(Initramfs { entries })
+}
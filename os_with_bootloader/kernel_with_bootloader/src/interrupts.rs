@@ -3,28 +3,99 @@ use lazy_static::lazy_static;
 use pc_keyboard::KeyCode;
 use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
 use pic8259::ChainedPics;
-use spin::Mutex;
 use x86_64::structures::idt::InterruptDescriptorTable;
 use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::idt::PageFaultErrorCode;
 use x86_64::instructions::port::Port;
+use x86_64::registers::control::Cr2;
+use x86_64::{PrivilegeLevel, VirtAddr};
+use crate::apic;
+use crate::console;
+use crate::cpu;
+use crate::gdt;
+use crate::process;
+use crate::smp;
+use crate::sync::SpinLock;
+use crate::syscall;
+use crate::task;
+use crate::time;
+use crate::memory::{protection, stack};
 use crate::writer::FRAME_BUFFER_WRITER;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 pub const PIC_1_OFFSET: u8 = 32;
 pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 
-pub static PICS: Mutex<ChainedPics> =
-    Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+pub static PICS: SpinLock<ChainedPics> =
+    SpinLock::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
+
+
+// How often each vector fired on each CPU, for /proc/interrupts.
+static COUNTS: [[AtomicU64; 256]; cpu::MAX_CPUS] = [const { [const { AtomicU64::new(0) }; 256] }; cpu::MAX_CPUS];
+
+// The vectors that are counted, with the names /proc/interrupts shows.
+const COUNTED: [(u8, &str); 9] = [
+    (3, "breakpoint"),
+    (6, "invalid opcode"),
+    (13, "general protection"),
+    (14, "page fault"),
+    (InterruptIndex::Timer as u8, "PIT timer"),
+    (InterruptIndex::Keyboard as u8, "keyboard"),
+    (apic::TIMER_VECTOR, "APIC timer"),
+    (apic::TLB_SHOOTDOWN_VECTOR, "TLB shootdown"),
+    (apic::SPURIOUS_VECTOR, "spurious"),
+];
+
+// Called by the handlers once GS is the kernel's.
+fn count(vector: u8) {
+    COUNTS[cpu::id()][usize::from(vector)].fetch_add(1, Ordering::Relaxed);
+}
+
+/// How often one vector fired so far.
+pub struct InterruptCount {
+    pub vector: u8,
+    pub name: &'static str,
+    /// Indexed by CPU, for every registered CPU.
+    pub per_cpu: Vec<u64>,
+}
 
+/// The fixed vectors, then every device line something registered for.
+pub fn counts() -> Vec<InterruptCount> {
+    let cpus = cpu::all().count();
+    let devices = (FIRST_DEVICE_IRQ..PIC_LINES as u8).filter_map(|irq| {
+        let name = x86_64::instructions::interrupts::without_interrupts(|| {
+            let line = IRQ_LINES[usize::from(irq)].lock();
+            (!line.handlers.is_empty()).then_some(line.name)
+        })?;
+        Some((PIC_1_OFFSET + irq, name))
+    });
+    COUNTED
+        .iter()
+        .copied()
+        .chain(devices)
+        .map(|(vector, name)| InterruptCount {
+            vector,
+            name,
+            per_cpu: COUNTS[..cpus]
+                .iter()
+                .map(|counts| counts[usize::from(vector)].load(Ordering::Relaxed))
+                .collect(),
+        })
+        .collect()
+}
 
 lazy_static! {
-    static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> = 
-    Mutex::new(
+    static ref KEYBOARD: SpinLock<Keyboard<layouts::Us104Key, ScancodeSet1>> = 
+    SpinLock::new(
         Keyboard::new(layouts::Us104Key, ScancodeSet1, HandleControl::Ignore)
     );
 }
 
 
 extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame) {
+    cpu::enter_from(&stack_frame);
+    count(3);
     println!("EXCEPTION: BREAKPOINT\n Stack Frame:\n {:#?}", stack_frame);
 }
 
@@ -39,6 +110,11 @@ extern "x86-interrupt" fn general_protection_handler(
     stack_frame: InterruptStackFrame,
     _error_code: u64,
 ) {
+    cpu::enter_from(&stack_frame);
+    count(13);
+    if process::from_user(&stack_frame) {
+        process::kill_current(format_args!("general protection fault, error code {:#x}", _error_code));
+    }
     println!(
         "EXCEPTION: GENERAL PROTECTION\n Error Code: {:#?}\n Stack Frame:\n{:#?}",
         _error_code, stack_frame
@@ -46,12 +122,50 @@ extern "x86-interrupt" fn general_protection_handler(
 }
 
 extern "x86-interrupt" fn invalid_opcode_handler(stack_frame: InterruptStackFrame) {
+    cpu::enter_from(&stack_frame);
+    count(6);
+    if process::from_user(&stack_frame) {
+        process::kill_current(format_args!("invalid opcode at {:?}", stack_frame.instruction_pointer));
+    }
     println!(
         "EXCEPTION: INVALID OPCODE\n Stack Frame:\n {:#?}",
         stack_frame
     );
 }
 
+// Runs on its own IST stack (see gdt.rs), so a fault caused by an overflowing
+// stack can still be reported instead of escalating into a triple fault.
+extern "x86-interrupt" fn page_fault_handler(
+    mut stack_frame: InterruptStackFrame,
+    error_code: PageFaultErrorCode,
+) {
+    cpu::enter_from(&stack_frame);
+    count(14);
+    // A fault the protection self-check provoked on purpose.
+    if protection::try_fixup(&mut stack_frame) {
+        return;
+    }
+
+    let address = Cr2::read();
+
+    // User code can't take the kernel down, only itself.
+    if process::from_user(&stack_frame) {
+        process::kill_current(format_args!("page fault at {:?} ({:?})", address, error_code));
+    }
+
+    if let Some(owner) = stack::guard_page_owner(address) {
+        panic!(
+            "stack overflow in {}\n Accessed Address: {:?}\n Stack Frame:\n{:#?}",
+            owner, address, stack_frame
+        );
+    }
+
+    panic!(
+        "EXCEPTION: PAGE FAULT\n Accessed Address: {:?}\n Error Code: {:?}\n Stack Frame:\n{:#?}",
+        address, error_code, stack_frame
+    );
+}
+
 // Handling Timer Interrrupts
 
 #[derive(Debug, Clone, Copy)]
@@ -71,8 +185,13 @@ impl InterruptIndex {
     }
 }
 
-extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn timer_interrupt_handler(stack_frame: InterruptStackFrame) {
+    cpu::enter_from(&stack_frame);
+    count(InterruptIndex::Timer.as_u8());
     // print!(".");
+    time::tick();
+    crate::speaker::on_timer_tick();
+    crate::writer::on_timer_tick();
 
     // End of Interrupt(EOI)
     // PIC expects an explicit “end of interrupt” (EOI) signal from our interrupt handler.
@@ -86,6 +205,31 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
     // and then uses the command and data ports to send an EOI signal to the respective controllers.
     // If the secondary PIC sent the interrupt, both PICs need to be notified because the secondary PIC
     // is connected to an input line of the primary PIC.
+
+    // Preempting may switch to another thread, so this has to come after the EOI,
+    // otherwise no further timer interrupts arrive until we are scheduled again.
+    task::on_timer_tick();
+}
+
+// The application processors' scheduler tick, from their local APIC timer.
+extern "x86-interrupt" fn apic_timer_handler(stack_frame: InterruptStackFrame) {
+    cpu::enter_from(&stack_frame);
+    count(apic::TIMER_VECTOR);
+    apic::end_of_interrupt();
+    task::on_timer_tick();
+}
+
+extern "x86-interrupt" fn tlb_shootdown_handler(stack_frame: InterruptStackFrame) {
+    cpu::enter_from(&stack_frame);
+    count(apic::TLB_SHOOTDOWN_VECTOR);
+    smp::handle_tlb_shootdown();
+    apic::end_of_interrupt();
+}
+
+// Spurious APIC interrupts must not be acknowledged.
+extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: InterruptStackFrame) {
+    cpu::enter_from(&stack_frame);
+    count(apic::SPURIOUS_VECTOR);
 }
 
 //
@@ -96,7 +240,9 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
 // the first key we press. Even if we continue to press keys, no more k's appear on the screen.
 // This is because the keyboard controller won’t send another interrupt until we have read the
 // so-called scancode of the pressed key.
-extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
+extern "x86-interrupt" fn keyboard_interrupt_handler(stack_frame: InterruptStackFrame) {
+    cpu::enter_from(&stack_frame);
+    count(InterruptIndex::Keyboard.as_u8());
     // print!("k");
 
     // Reading the Scancodes
@@ -118,6 +264,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     let mut port = Port::new(0x60);
 
     let scancode: u8 = unsafe { port.read() };// Interrupt happens here
+    console::push_scancode(scancode);
 
     
     if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
@@ -137,6 +284,7 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
                         print!("{}", character);
                         // Some(character);
                     }
+                    console::push_input(character);
                 }
 
                 //print!("c{}", character),
@@ -189,15 +337,117 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
 }
 
 
+// Devices on the other PIC lines (2 is the cascade) register handlers at run
+// time. PCI devices can share a line, so each line keeps a list; every
+// handler checks whether its device raised the interrupt.
+const FIRST_DEVICE_IRQ: u8 = 3;
+const PIC_LINES: usize = 16;
+
+struct IrqLine {
+    name: &'static str,
+    handlers: Vec<fn()>,
+}
+
+static IRQ_LINES: [SpinLock<IrqLine>; PIC_LINES] =
+    [const { SpinLock::new(IrqLine { name: "", handlers: Vec::new() }) }; PIC_LINES];
+
+/// Runs `handler` whenever PIC line `irq` fires, and unmasks the line.
+/// `name` is what /proc/interrupts shows. Returns false for lines the
+/// kernel uses itself.
+pub fn register_irq(irq: u8, name: &'static str, handler: fn()) -> bool {
+    if !(FIRST_DEVICE_IRQ..PIC_LINES as u8).contains(&irq) {
+        return false;
+    }
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut line = IRQ_LINES[usize::from(irq)].lock();
+        line.name = if line.handlers.is_empty() { name } else { "shared" };
+        line.handlers.push(handler);
+
+        let mut pics = PICS.lock();
+        let [mut primary, mut secondary] = unsafe { pics.read_masks() };
+        if irq < 8 {
+            primary &= !(1 << irq);
+        } else {
+            // The secondary PIC reaches the CPU through line 2.
+            primary &= !(1 << 2);
+            secondary &= !(1 << (irq - 8));
+        }
+        unsafe { pics.write_masks(primary, secondary) };
+    });
+    true
+}
+
+fn device_interrupt(irq: u8, stack_frame: &InterruptStackFrame) {
+    cpu::enter_from(stack_frame);
+    let vector = PIC_1_OFFSET + irq;
+    count(vector);
+    let line = IRQ_LINES[usize::from(irq)].lock();
+    for handler in &line.handlers {
+        handler();
+    }
+    drop(line);
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(vector);
+    }
+}
+
+macro_rules! device_interrupt_handlers {
+    ($($irq:literal => $name:ident),* $(,)?) => {
+        $(
+            extern "x86-interrupt" fn $name(stack_frame: InterruptStackFrame) {
+                device_interrupt($irq, &stack_frame);
+            }
+        )*
+
+        const DEVICE_INTERRUPT_HANDLERS: &[(u8, extern "x86-interrupt" fn(InterruptStackFrame))] =
+            &[$(($irq, $name)),*];
+    };
+}
+
+device_interrupt_handlers! {
+    3 => irq3_handler,
+    4 => irq4_handler,
+    5 => irq5_handler,
+    6 => irq6_handler,
+    7 => irq7_handler,
+    8 => irq8_handler,
+    9 => irq9_handler,
+    10 => irq10_handler,
+    11 => irq11_handler,
+    12 => irq12_handler,
+    13 => irq13_handler,
+    14 => irq14_handler,
+    15 => irq15_handler,
+}
+
 lazy_static! {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
         idt.breakpoint.set_handler_fn(breakpoint_handler);
-        idt.double_fault.set_handler_fn(double_fault_handler);
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(double_fault_handler)
+                .set_stack_index(gdt::DOUBLE_FAULT_IST_INDEX);
+            idt.page_fault
+                .set_handler_fn(page_fault_handler)
+                .set_stack_index(gdt::PAGE_FAULT_IST_INDEX);
+        }
         idt.general_protection_fault.set_handler_fn(general_protection_handler);
         idt.invalid_opcode.set_handler_fn(invalid_opcode_handler);
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler); // Timer Interrupt
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler); // Keyboard interrupt
+        for &(irq, handler) in DEVICE_INTERRUPT_HANDLERS {
+            idt[usize::from(PIC_1_OFFSET + irq)].set_handler_fn(handler);
+        }
+        idt[usize::from(apic::TIMER_VECTOR)].set_handler_fn(apic_timer_handler);
+        idt[usize::from(apic::TLB_SHOOTDOWN_VECTOR)].set_handler_fn(tlb_shootdown_handler);
+        idt[usize::from(apic::SPURIOUS_VECTOR)].set_handler_fn(spurious_interrupt_handler);
+        // System call fallback for code that can't use `syscall`; reachable from ring 3.
+        unsafe {
+            idt[syscall::INT80_VECTOR]
+                .set_handler_addr(VirtAddr::from_ptr(syscall::int80_entry as *const ()))
+                .set_privilege_level(PrivilegeLevel::Ring3);
+        }
 
         idt
     };
@@ -207,6 +457,10 @@ pub fn init_idt() {
     IDT.load();
 }
 
+pub fn init_pics() {
+    unsafe { PICS.lock().initialize() };
+}
+
 // #[macro_export]
 // macro_rules! input_char {
 //     () => {
@@ -1,25 +1,57 @@
 #![no_std]
 #![no_main]
-
+#![feature(alloc_error_handler)]
+#![feature(abi_x86_interrupt)]
+
+extern crate alloc;
+
+mod acpi;
+mod allocator;
+mod apic;
+mod block;
+mod bootinfo;
+mod console;
+mod cpu;
+mod elf;
+mod fs;
+mod gdt;
+mod initramfs;
+mod interrupts;
+mod memory;
+mod net;
+mod pci;
+mod process;
+mod random;
+mod serial;
+mod shell;
+mod smp;
+mod sound;
+mod speaker;
+mod sync;
+mod syscall;
+mod task;
+mod time;
+mod virtio;
 mod writer;
-use writer::FrameBufferWriter;
+use block::BlockDevice;
+use writer::{FrameBufferWriter, FRAME_BUFFER_WRITER};
 
 use bootloader_api::config::Mapping;
 use x86_64::instructions::hlt;
-use core::fmt::Write;
+use x86_64::VirtAddr;
 
 // Kernel Memory Management.
+const KERNEL_STACK_SIZE: u64 = 100 * 1024; // 100 KiB
+
 pub static BOOTLOADER_CONFIG: bootloader_api::BootloaderConfig = {
     let mut config = bootloader_api::BootloaderConfig::new_default();
     config.mappings.physical_memory = Some(Mapping::Dynamic);
-    config.kernel_stack_size = 100 * 1024; // 100 KiB
+    config.kernel_stack_size = KERNEL_STACK_SIZE;
     config
 };
 
 bootloader_api::entry_point!(my_entry_point, config = &BOOTLOADER_CONFIG);
 
-static mut FRAME_BUFFER_WRITER: Option<FrameBufferWriter> = None;
-
 struct Cursor {
     x: usize,
     y: usize,
@@ -27,39 +59,24 @@ struct Cursor {
 
 static mut CURSOR: Cursor = Cursor { x: 0, y: 0 };
 
-macro_rules! print {
-    ($fmt:expr) => {
-        unsafe {
-            if let Some(ref mut writer) = FRAME_BUFFER_WRITER {
-                write!(writer, "{}", $fmt).unwrap(); 
-                CURSOR.x += 1; // Move cursor to the right after each character
-            }
-        }
-    };
-}
-
 // Move text right function
 fn move_text_right(steps: usize) {
-    unsafe {
-        if let Some(ref mut writer) = FRAME_BUFFER_WRITER {
-            CURSOR.x += steps;
-            writer.set_cursor_position(CURSOR.x, CURSOR.y);
-        }
-    }
+    writer::with_writer(|writer| unsafe {
+        CURSOR.x += steps;
+        writer.set_cursor_position(CURSOR.x, CURSOR.y);
+    });
 }
 
 // Move text left function
 fn move_text_left(steps: usize) {
-    unsafe {
-        if let Some(ref mut writer) = FRAME_BUFFER_WRITER {
-            if CURSOR.x >= steps {
-                CURSOR.x -= steps;
-            } else {
-                CURSOR.x = 0;
-            }
-            writer.set_cursor_position(CURSOR.x, CURSOR.y);
+    writer::with_writer(|writer| unsafe {
+        if CURSOR.x >= steps {
+            CURSOR.x -= steps;
+        } else {
+            CURSOR.x = 0;
         }
-    }
+        writer.set_cursor_position(CURSOR.x, CURSOR.y);
+    });
 }
 
 fn my_entry_point(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
@@ -67,43 +84,130 @@ fn my_entry_point(boot_info: &'static mut bootloader_api::BootInfo) -> ! {
     let buffer = boot_info.framebuffer.as_mut().unwrap().buffer_mut();
     let frame_buffer_writer = FrameBufferWriter::new(buffer, frame_buffer_info);
 
+    *FRAME_BUFFER_WRITER.lock() = Some(frame_buffer_writer);
+
+    // Hand the usable physical memory over to the frame allocator.
+    let physical_memory_offset = VirtAddr::new(
+        boot_info
+            .physical_memory_offset
+            .into_option()
+            .expect("physical memory should be mapped by the bootloader"),
+    );
+    let rsdp_addr = boot_info.rsdp_addr.into_option();
     unsafe {
-        FRAME_BUFFER_WRITER = Some(frame_buffer_writer);
+        memory::init(&boot_info.memory_regions, physical_memory_offset);
+    }
+    smp::reserve_trampoline();
+    allocator::init_heap().expect("heap initialization failed");
+    bootinfo::record(bootinfo::BootSummary {
+        memory_regions: boot_info.memory_regions.to_vec(),
+        framebuffer: Some(frame_buffer_info),
+        physical_memory_offset: Some(physical_memory_offset.as_u64()),
+        rsdp_addr,
+        kernel_addr: boot_info.kernel_addr,
+        kernel_len: boot_info.kernel_len,
+        kernel_image_offset: boot_info.kernel_image_offset,
+        ramdisk_addr: boot_info.ramdisk_addr.into_option(),
+        ramdisk_len: boot_info.ramdisk_len,
+    });
+    console::init();
+    serial::init();
+    cpu::init_bsp();
+
+    // Fault handlers need their own stacks before anything can overflow.
+    gdt::init();
+    interrupts::init_idt();
+    syscall::init();
+    memory::stack::register_boot_stack("kernel main", KERNEL_STACK_SIZE);
+
+    let kernel_image = memory::protection::KernelImage {
+        phys_addr: boot_info.kernel_addr,
+        len: boot_info.kernel_len,
+        load_offset: boot_info.kernel_image_offset,
+    };
+    let protections = memory::protection::harden(&boot_info.memory_regions, kernel_image)
+        .expect("failed to harden page tables");
+    #[cfg(feature = "selftest")]
+    if !memory::protection::self_check(protections) {
+        panic!("memory protection self-check failed");
     }
+    #[cfg(not(feature = "selftest"))]
+    let _ = protections;
 
     print!("Hi, George!");
     print!("\nThis is Blessing's project.");
     print!("\n\\cRed text\\r \tIndented Text");
 
+    match initramfs::init(boot_info.ramdisk_addr.into_option(), boot_info.ramdisk_len) {
+        Ok(entries) => print!("\ninitramfs: {} entries", entries),
+        Err(err) => print!("\ninitramfs: unreadable ramdisk ({:?})", err),
+    }
+    fs::init();
+    shell::init();
+    process::init();
+    speaker::init();
+
+    // From here on the boot context is the idle thread.
+    time::init();
+    task::init();
+    interrupts::init_pics();
+
+    task::spawn("cursor", cursor_blinker).expect("failed to start cursor thread");
+
+    x86_64::instructions::interrupts::enable();
+    let cpus = smp::init(rsdp_addr);
+    print!("\n{} CPU(s) online", cpus);
+
+    pci::init(rsdp_addr);
+    block::init();
+    for disk in block::disks() {
+        print!(
+            "\n{}: {} MiB, {} partition(s)",
+            disk.device.name(),
+            disk.device.sector_count() / 2048,
+            disk.partitions.len()
+        );
+    }
+    #[cfg(feature = "selftest")]
+    if !block::self_check() {
+        panic!("block device self-check failed");
+    }
+    fs::mount_block_devices();
+    net::init();
+    sound::init();
+    #[cfg(feature = "selftest")]
+    if !fs::self_check() {
+        panic!("file system self-check failed");
+    }
+    shell::spawn_console();
+    task::idle_loop();
+}
+
+fn cursor_blinker() {
     let mut cursor_visible = true;
     loop {
         // Move text dynamically (for testing)
         move_text_right(2); // Move text right by 2 steps
-        for _ in 0..500_000 {
-            x86_64::instructions::hlt();
-        }
+        task::sleep(250);
 
         move_text_left(2); // Move text back to the left
-        for _ in 0..500_000 {
-            x86_64::instructions::hlt();
-        }
+        task::sleep(250);
 
         // Toggle cursor visibility
         cursor_visible = !cursor_visible;
-        unsafe {
-            if let Some(ref mut writer) = FRAME_BUFFER_WRITER {
-                if cursor_visible {
-                    writer.draw_cursor();
-                } else {
-                    writer.clear_cursor();
-                }
+        writer::with_writer(|writer| {
+            if cursor_visible {
+                writer.draw_cursor();
+            } else {
+                writer.clear_cursor();
             }
-        }
+        });
     }
 }
 
 #[panic_handler]
-fn panic(_info: &core::panic::PanicInfo) -> ! {
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    writer::print_diagnostic(format_args!("\n{}\n", info));
     loop {
         hlt();
     }
@@ -0,0 +1,43 @@
+pub mod dma;
+pub mod frame_allocator;
+pub mod paging;
+pub mod protection;
+pub mod stack;
+
+use crate::sync::SpinLock;
+use bootloader_api::info::MemoryRegions;
+use core::sync::atomic::{AtomicU64, Ordering};
+use frame_allocator::{BitmapFrameAllocator, FrameStats};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub use paging::{AddressSpace, PagingError};
+
+pub static FRAME_ALLOCATOR: SpinLock<Option<BitmapFrameAllocator>> = SpinLock::new(None);
+
+// Where the bootloader mapped all of physical memory.
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// Sets up the global frame allocator from the bootloader's memory map and
+/// picks the virtual window used for kernel mappings.
+///
+/// # Safety
+/// Must be called once, with the physical memory offset the bootloader used.
+pub unsafe fn init(memory_regions: &MemoryRegions, physical_memory_offset: VirtAddr) {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
+    let allocator = unsafe { BitmapFrameAllocator::init(memory_regions, physical_memory_offset) };
+    *FRAME_ALLOCATOR.lock() = Some(allocator);
+    paging::init_kernel_window();
+}
+
+/// Virtual address through which the kernel can reach physical address `phys`.
+pub fn phys_to_virt(phys: PhysAddr) -> VirtAddr {
+    VirtAddr::new(PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed) + phys.as_u64())
+}
+
+pub fn frame_stats() -> FrameStats {
+    FRAME_ALLOCATOR
+        .lock()
+        .as_ref()
+        .map(|allocator| allocator.stats())
+        .unwrap_or_default()
+}
@@ -0,0 +1,49 @@
+use super::{phys_to_virt, FRAME_ALLOCATOR};
+use x86_64::structures::paging::{PhysFrame, Size4KiB};
+use x86_64::PhysAddr;
+
+const PAGE_SIZE: usize = 4096;
+
+/// Zeroed, physically contiguous memory that devices can read and write
+/// directly. The kernel reaches it through the physical memory map.
+pub struct DmaBuffer {
+    frame: PhysFrame<Size4KiB>,
+    pages: usize,
+}
+
+impl DmaBuffer {
+    pub fn new(pages: usize) -> Option<Self> {
+        let frame = FRAME_ALLOCATOR.lock().as_mut()?.allocate_contiguous(pages, 1)?;
+        let buffer = DmaBuffer { frame, pages };
+        unsafe { core::ptr::write_bytes(buffer.as_mut_ptr(), 0, buffer.len()) };
+        Some(buffer)
+    }
+
+    pub fn phys(&self) -> PhysAddr {
+        self.frame.start_address()
+    }
+
+    pub fn len(&self) -> usize {
+        self.pages * PAGE_SIZE
+    }
+
+    pub fn as_mut_ptr(&self) -> *mut u8 {
+        phys_to_virt(self.phys()).as_mut_ptr()
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self.as_mut_ptr(), self.len()) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { core::slice::from_raw_parts_mut(self.as_mut_ptr(), self.len()) }
+    }
+}
+
+impl Drop for DmaBuffer {
+    fn drop(&mut self) {
+        if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+            allocator.free_contiguous(self.frame, self.pages);
+        }
+    }
+}
@@ -0,0 +1,270 @@
+use bootloader_api::info::{MemoryRegionKind, MemoryRegions};
+use x86_64::structures::paging::{
+    FrameAllocator, FrameDeallocator, PhysFrame, Size2MiB, Size4KiB,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const FRAME_SIZE: u64 = 4096;
+pub const HUGE_FRAME_SIZE: u64 = 2 * 1024 * 1024;
+/// Number of 4 KiB frames that make up one 2 MiB frame.
+const FRAMES_PER_HUGE_FRAME: usize = (HUGE_FRAME_SIZE / FRAME_SIZE) as usize;
+
+/// Counters kept by the frame allocator, all in units of 4 KiB frames.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct FrameStats {
+    pub total_frames: usize,
+    pub free_frames: usize,
+    pub allocations: usize,
+    pub deallocations: usize,
+    pub failed_allocations: usize,
+}
+
+impl FrameStats {
+    pub fn used_frames(&self) -> usize {
+        self.total_frames - self.free_frames
+    }
+}
+
+/// Physical frame allocator that keeps one bit per 4 KiB frame.
+///
+/// A set bit means the frame is in use (or not RAM at all). The bitmap itself
+/// lives in the first usable region that is large enough to hold it and is
+/// accessed through the bootloader's physical memory mapping.
+pub struct BitmapFrameAllocator {
+    bitmap: &'static mut [u64],
+    frame_count: usize,
+    // Where to start looking for a free frame next time, so single-frame
+    // allocations don't rescan the low part of memory every time.
+    next_hint: usize,
+    stats: FrameStats,
+}
+
+impl BitmapFrameAllocator {
+    /// Builds the allocator from the `Usable` entries of the bootloader's memory map.
+    ///
+    /// # Safety
+    /// `physical_memory_offset` must be the offset at which the bootloader mapped
+    /// all of physical memory, and `memory_regions` must be the map it handed us.
+    pub unsafe fn init(memory_regions: &MemoryRegions, physical_memory_offset: VirtAddr) -> Self {
+        let highest_usable = memory_regions
+            .iter()
+            .filter(|r| r.kind == MemoryRegionKind::Usable)
+            .map(|r| r.end)
+            .max()
+            .expect("bootloader reported no usable memory");
+
+        let frame_count = (highest_usable / FRAME_SIZE) as usize;
+        let words = frame_count.div_ceil(64);
+        let bitmap_bytes = (words * 8) as u64;
+
+        // Find a usable region big enough for the bitmap itself.
+        let bitmap_phys = memory_regions
+            .iter()
+            .filter(|r| r.kind == MemoryRegionKind::Usable)
+            .map(|r| (align_up(r.start, FRAME_SIZE), r.end))
+            .find(|(start, end)| start + bitmap_bytes <= *end)
+            .map(|(start, _)| start)
+            .expect("no usable region large enough for the frame bitmap");
+
+        let bitmap_ptr = (physical_memory_offset + bitmap_phys).as_mut_ptr::<u64>();
+        let bitmap = unsafe { core::slice::from_raw_parts_mut(bitmap_ptr, words) };
+
+        // Everything starts out used, then the usable regions are released.
+        bitmap.fill(u64::MAX);
+
+        let mut allocator = BitmapFrameAllocator {
+            bitmap,
+            frame_count,
+            next_hint: 0,
+            stats: FrameStats::default(),
+        };
+
+        for region in memory_regions.iter() {
+            if region.kind != MemoryRegionKind::Usable {
+                continue;
+            }
+            let first = align_up(region.start, FRAME_SIZE) / FRAME_SIZE;
+            let last = region.end / FRAME_SIZE;
+            for frame in first..last {
+                allocator.clear_bit(frame as usize);
+                allocator.stats.total_frames += 1;
+                allocator.stats.free_frames += 1;
+            }
+        }
+
+        // Frame 0 is never handed out so a null physical address stays invalid.
+        allocator.reserve(0);
+
+        let bitmap_first = (bitmap_phys / FRAME_SIZE) as usize;
+        let bitmap_frames = bitmap_bytes.div_ceil(FRAME_SIZE) as usize;
+        for frame in bitmap_first..bitmap_first + bitmap_frames {
+            allocator.reserve(frame);
+        }
+
+        allocator
+    }
+
+    pub fn stats(&self) -> FrameStats {
+        self.stats
+    }
+
+    /// Allocates a single 4 KiB frame.
+    pub fn allocate(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        let start = self.next_hint;
+        let index = self
+            .find_free(start, self.frame_count)
+            .or_else(|| self.find_free(0, start));
+
+        match index {
+            Some(index) => {
+                self.take(index, 1);
+                self.next_hint = index + 1;
+                Some(PhysFrame::containing_address(PhysAddr::new(
+                    index as u64 * FRAME_SIZE,
+                )))
+            }
+            None => {
+                self.stats.failed_allocations += 1;
+                None
+            }
+        }
+    }
+
+    /// Allocates `count` physically contiguous 4 KiB frames whose first frame is
+    /// aligned to `align_frames` frames. Returns the first frame of the run.
+    pub fn allocate_contiguous(
+        &mut self,
+        count: usize,
+        align_frames: usize,
+    ) -> Option<PhysFrame<Size4KiB>> {
+        let align_frames = align_frames.max(1);
+        if count == 0 {
+            return None;
+        }
+
+        let mut candidate = 0;
+        while candidate + count <= self.frame_count {
+            match (candidate..candidate + count).find(|&i| self.is_used(i)) {
+                // Skip past the used frame and realign.
+                Some(used) => candidate = (used + 1).next_multiple_of(align_frames),
+                None => {
+                    self.take(candidate, count);
+                    return Some(PhysFrame::containing_address(PhysAddr::new(
+                        candidate as u64 * FRAME_SIZE,
+                    )));
+                }
+            }
+        }
+
+        self.stats.failed_allocations += 1;
+        None
+    }
+
+    /// Allocates one 2 MiB frame.
+    pub fn allocate_huge(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate_contiguous(FRAMES_PER_HUGE_FRAME, FRAMES_PER_HUGE_FRAME)
+            .map(|frame| PhysFrame::containing_address(frame.start_address()))
+    }
+
+    /// Returns `count` contiguous frames starting at `frame` to the allocator.
+    pub fn free_contiguous(&mut self, frame: PhysFrame<Size4KiB>, count: usize) {
+        let first = (frame.start_address().as_u64() / FRAME_SIZE) as usize;
+        for index in first..first + count {
+            assert!(
+                index < self.frame_count && self.is_used(index),
+                "double free of physical frame {:#x}",
+                index as u64 * FRAME_SIZE
+            );
+            self.clear_bit(index);
+        }
+        self.stats.free_frames += count;
+        self.stats.deallocations += 1;
+        if first < self.next_hint {
+            self.next_hint = first;
+        }
+    }
+
+    pub fn free(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.free_contiguous(frame, 1);
+    }
+
+    pub fn free_huge(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.free_contiguous(
+            PhysFrame::containing_address(frame.start_address()),
+            FRAMES_PER_HUGE_FRAME,
+        );
+    }
+
+    // Marks a frame used without counting it as an allocation.
+    fn reserve(&mut self, index: usize) {
+        if index < self.frame_count && !self.is_used(index) {
+            self.set_bit(index);
+            self.stats.free_frames -= 1;
+        }
+    }
+
+    fn take(&mut self, first: usize, count: usize) {
+        for index in first..first + count {
+            self.set_bit(index);
+        }
+        self.stats.free_frames -= count;
+        self.stats.allocations += 1;
+    }
+
+    fn find_free(&self, from: usize, to: usize) -> Option<usize> {
+        let mut index = from;
+        while index < to {
+            let word = self.bitmap[index / 64];
+            if word == u64::MAX {
+                // Whole word is used, jump to the next one.
+                index = (index / 64 + 1) * 64;
+                continue;
+            }
+            if word & (1 << (index % 64)) == 0 {
+                return Some(index);
+            }
+            index += 1;
+        }
+        None
+    }
+
+    fn is_used(&self, index: usize) -> bool {
+        self.bitmap[index / 64] & (1 << (index % 64)) != 0
+    }
+
+    fn set_bit(&mut self, index: usize) {
+        self.bitmap[index / 64] |= 1 << (index % 64);
+    }
+
+    fn clear_bit(&mut self, index: usize) {
+        self.bitmap[index / 64] &= !(1 << (index % 64));
+    }
+}
+
+unsafe impl FrameAllocator<Size4KiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
+        self.allocate()
+    }
+}
+
+unsafe impl FrameAllocator<Size2MiB> for BitmapFrameAllocator {
+    fn allocate_frame(&mut self) -> Option<PhysFrame<Size2MiB>> {
+        self.allocate_huge()
+    }
+}
+
+impl FrameDeallocator<Size4KiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size4KiB>) {
+        self.free(frame);
+    }
+}
+
+impl FrameDeallocator<Size2MiB> for BitmapFrameAllocator {
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame<Size2MiB>) {
+        self.free_huge(frame);
+    }
+}
+
+fn align_up(addr: u64, align: u64) -> u64 {
+    (addr + align - 1) & !(align - 1)
+}
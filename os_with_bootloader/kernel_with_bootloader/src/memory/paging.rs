@@ -0,0 +1,495 @@
+use super::frame_allocator::BitmapFrameAllocator;
+use super::{FRAME_ALLOCATOR, phys_to_virt};
+use crate::sync::SpinLock;
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::tlb;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+use x86_64::structures::paging::mapper::{
+    FlagUpdateError, MapToError, MappedFrame, TranslateResult, UnmapError,
+};
+use x86_64::structures::paging::{
+    Mapper, OffsetPageTable, Page, PageSize, PageTable, PageTableFlags, PhysFrame, Size1GiB,
+    Size2MiB, Size4KiB, Translate,
+};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// Errors returned by the address space operations.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingError {
+    OutOfFrames,
+    AlreadyMapped,
+    NotMapped,
+    HugePageConflict,
+    OutOfVirtualSpace,
+}
+
+impl<S: PageSize> From<MapToError<S>> for PagingError {
+    fn from(err: MapToError<S>) -> Self {
+        match err {
+            MapToError::FrameAllocationFailed => PagingError::OutOfFrames,
+            MapToError::PageAlreadyMapped(_) => PagingError::AlreadyMapped,
+            MapToError::ParentEntryHugePage => PagingError::HugePageConflict,
+        }
+    }
+}
+
+impl From<UnmapError> for PagingError {
+    fn from(err: UnmapError) -> Self {
+        match err {
+            UnmapError::ParentEntryHugePage => PagingError::HugePageConflict,
+            UnmapError::PageNotMapped | UnmapError::InvalidFrameAddress(_) => PagingError::NotMapped,
+        }
+    }
+}
+
+impl From<FlagUpdateError> for PagingError {
+    fn from(err: FlagUpdateError) -> Self {
+        match err {
+            FlagUpdateError::ParentEntryHugePage => PagingError::HugePageConflict,
+            FlagUpdateError::PageNotMapped => PagingError::NotMapped,
+        }
+    }
+}
+
+/// Called after page table entries were changed so other CPUs can drop stale
+/// TLB entries. Arguments are the first page and the number of 4 KiB pages.
+pub type TlbShootdownHook = fn(Page<Size4KiB>, u64);
+
+static TLB_SHOOTDOWN_HOOK: SpinLock<Option<TlbShootdownHook>> = SpinLock::new(None);
+
+pub fn set_tlb_shootdown_hook(hook: TlbShootdownHook) {
+    *TLB_SHOOTDOWN_HOOK.lock() = Some(hook);
+}
+
+fn shootdown(start: Page<Size4KiB>, pages: u64) {
+    // Not under the lock: the hook may take a while and can run on several CPUs.
+    let hook = *TLB_SHOOTDOWN_HOOK.lock();
+    if let Some(hook) = hook {
+        hook(start, pages);
+    }
+}
+
+/// One set of page tables, identified by the physical frame of its level 4 table.
+///
+/// Every address space shares the kernel's top-level entries, so kernel code,
+/// the physical memory mapping and MMIO windows are visible from all of them.
+pub struct AddressSpace {
+    level_4_frame: PhysFrame,
+}
+
+impl AddressSpace {
+    /// The address space the CPU is currently running in.
+    pub fn current() -> Self {
+        let (level_4_frame, _) = Cr3::read();
+        AddressSpace { level_4_frame }
+    }
+
+    /// The address space the bootloader handed us, used by kernel threads.
+    pub fn kernel() -> Self {
+        let frame = PhysAddr::new(KERNEL_LEVEL_4.load(Ordering::Relaxed));
+        AddressSpace {
+            level_4_frame: PhysFrame::containing_address(frame),
+        }
+    }
+
+    /// Creates a new address space that shares all existing kernel mappings.
+    pub fn new() -> Result<Self, PagingError> {
+        let frame = FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .and_then(|allocator| allocator.allocate())
+            .ok_or(PagingError::OutOfFrames)?;
+
+        let kernel = AddressSpace::current();
+        let new_table = unsafe { &mut *table_ptr(frame) };
+        new_table.zero();
+        for (index, entry) in kernel.level_4_table().iter().enumerate() {
+            if !entry.is_unused() && !entry.flags().contains(PageTableFlags::USER_ACCESSIBLE) {
+                new_table[index] = entry.clone();
+            }
+        }
+
+        Ok(AddressSpace { level_4_frame: frame })
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    /// Switches the CPU to this address space.
+    ///
+    /// # Safety
+    /// The currently executing code and stack must be mapped in this address space.
+    pub unsafe fn activate(&self) {
+        unsafe { Cr3::write(self.level_4_frame, Cr3Flags::empty()) };
+    }
+
+    pub fn is_active(&self) -> bool {
+        Cr3::read().0 == self.level_4_frame
+    }
+
+    fn level_4_table(&self) -> &PageTable {
+        unsafe { &*table_ptr(self.level_4_frame) }
+    }
+
+    fn mapper(&mut self) -> OffsetPageTable<'_> {
+        let table = unsafe { &mut *table_ptr(self.level_4_frame) };
+        unsafe { OffsetPageTable::new(table, phys_to_virt(PhysAddr::new(0))) }
+    }
+
+    /// Maps `page` to `frame`. Intermediate tables get `USER_ACCESSIBLE` when the
+    /// leaf does, so user mappings actually reach ring 3.
+    pub fn map(
+        &mut self,
+        page: Page<Size4KiB>,
+        frame: PhysFrame<Size4KiB>,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        let parent_flags = parent_flags(flags);
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator.as_mut().ok_or(PagingError::OutOfFrames)?;
+        let flush = unsafe {
+            self.mapper()
+                .map_to_with_table_flags(page, frame, flags, parent_flags, allocator)?
+        };
+        self.flush_local(flush);
+        Ok(())
+    }
+
+    /// Maps a 2 MiB page.
+    pub fn map_huge(
+        &mut self,
+        page: Page<Size2MiB>,
+        frame: PhysFrame<Size2MiB>,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        let parent_flags = parent_flags(flags);
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator.as_mut().ok_or(PagingError::OutOfFrames)?;
+        let flush = unsafe {
+            self.mapper().map_to_with_table_flags(
+                page,
+                frame,
+                flags | PageTableFlags::HUGE_PAGE,
+                parent_flags,
+                allocator,
+            )?
+        };
+        self.flush_local(flush);
+        Ok(())
+    }
+
+    /// Backs `count` pages starting at `start` with freshly allocated, zeroed
+    /// frames. On failure none of the pages stay mapped.
+    pub fn map_anonymous(
+        &mut self,
+        start: Page<Size4KiB>,
+        count: u64,
+        flags: PageTableFlags,
+    ) -> Result<(), PagingError> {
+        for page in Page::range(start, start + count) {
+            if let Err(err) = self.map_zeroed(page, flags) {
+                for mapped in Page::range(start, page) {
+                    if let Ok(frame) = self.unmap(mapped) {
+                        FRAME_ALLOCATOR.lock().as_mut().unwrap().free(frame);
+                    }
+                }
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    fn map_zeroed(&mut self, page: Page<Size4KiB>, flags: PageTableFlags) -> Result<(), PagingError> {
+        let frame = FRAME_ALLOCATOR
+            .lock()
+            .as_mut()
+            .and_then(|allocator| allocator.allocate())
+            .ok_or(PagingError::OutOfFrames)?;
+        // Never hand out whatever the previous owner left in the frame.
+        unsafe {
+            core::ptr::write_bytes(
+                phys_to_virt(frame.start_address()).as_mut_ptr::<u8>(),
+                0,
+                Size4KiB::SIZE as usize,
+            )
+        };
+        if let Err(err) = self.map(page, frame, flags) {
+            FRAME_ALLOCATOR.lock().as_mut().unwrap().free(frame);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    /// Removes the mapping of `page` and returns the frame it pointed to.
+    /// The frame is not freed; that is up to the caller.
+    pub fn unmap(&mut self, page: Page<Size4KiB>) -> Result<PhysFrame<Size4KiB>, PagingError> {
+        let (frame, flush) = self.mapper().unmap(page)?;
+        self.flush_local(flush);
+        shootdown(page, 1);
+        Ok(frame)
+    }
+
+    pub fn unmap_huge(&mut self, page: Page<Size2MiB>) -> Result<PhysFrame<Size2MiB>, PagingError> {
+        let (frame, flush) = self.mapper().unmap(page)?;
+        self.flush_local(flush);
+        shootdown(Page::containing_address(page.start_address()), 512);
+        Ok(frame)
+    }
+
+    /// Changes the flags of an existing 4 KiB mapping.
+    pub fn protect(&mut self, page: Page<Size4KiB>, flags: PageTableFlags) -> Result<(), PagingError> {
+        let flush = unsafe { self.mapper().update_flags(page, flags)? };
+        self.flush_local(flush);
+        shootdown(page, 1);
+        Ok(())
+    }
+
+    pub fn protect_huge(&mut self, page: Page<Size2MiB>, flags: PageTableFlags) -> Result<(), PagingError> {
+        let flush = unsafe {
+            self.mapper()
+                .update_flags(page, flags | PageTableFlags::HUGE_PAGE)?
+        };
+        self.flush_local(flush);
+        shootdown(Page::containing_address(page.start_address()), 512);
+        Ok(())
+    }
+
+    /// Rewrites the flags of every mapping that overlaps `start..end`, whatever
+    /// page size it uses. Unmapped holes are skipped. A huge page that only
+    /// partly overlaps the range is changed as a whole.
+    pub fn update_range_flags(
+        &mut self,
+        start: VirtAddr,
+        end: VirtAddr,
+        update: impl Fn(PageTableFlags) -> PageTableFlags,
+    ) -> Result<(), PagingError> {
+        let mut addr = start.align_down(Size4KiB::SIZE).as_u64();
+        while addr < end.as_u64() {
+            let virt = VirtAddr::new(addr);
+            let (size, flags) = match self.mapper().translate(virt) {
+                TranslateResult::Mapped { frame, flags, .. } => (frame.size(), flags),
+                _ => (Size4KiB::SIZE, PageTableFlags::empty()),
+            };
+
+            if flags.contains(PageTableFlags::PRESENT) {
+                let new_flags = update(flags);
+                let mut mapper = self.mapper();
+                unsafe {
+                    match size {
+                        Size4KiB::SIZE => mapper
+                            .update_flags(Page::<Size4KiB>::containing_address(virt), new_flags)?
+                            .ignore(),
+                        Size2MiB::SIZE => mapper
+                            .update_flags(Page::<Size2MiB>::containing_address(virt), new_flags)?
+                            .ignore(),
+                        _ => mapper
+                            .update_flags(Page::<Size1GiB>::containing_address(virt), new_flags)?
+                            .ignore(),
+                    }
+                }
+            }
+
+            match (addr & !(size - 1)).checked_add(size) {
+                Some(next) => addr = next,
+                None => break,
+            }
+        }
+
+        if self.is_active() {
+            tlb::flush_all();
+        }
+        let first = Page::containing_address(start);
+        shootdown(first, (end - first.start_address()).div_ceil(Size4KiB::SIZE));
+        Ok(())
+    }
+
+    /// Copies `data` to `addr` in this address space, which doesn't have to be
+    /// the active one. The target range must already be mapped.
+    pub fn write_bytes(&mut self, addr: VirtAddr, data: &[u8]) -> Result<(), PagingError> {
+        let mut done = 0;
+        while done < data.len() {
+            let target = addr + done as u64;
+            let phys = self.translate(target).ok_or(PagingError::NotMapped)?;
+            let page_left = (Size4KiB::SIZE - u64::from(target.page_offset())) as usize;
+            let chunk = page_left.min(data.len() - done);
+            unsafe {
+                core::ptr::copy_nonoverlapping(
+                    data[done..].as_ptr(),
+                    phys_to_virt(phys).as_mut_ptr::<u8>(),
+                    chunk,
+                );
+            }
+            done += chunk;
+        }
+        Ok(())
+    }
+
+    /// Frees every page table and frame this address space doesn't share with
+    /// the kernel, then its level 4 table. Only for address spaces made with
+    /// [`new`](Self::new) whose private mappings are all anonymous memory.
+    ///
+    /// # Safety
+    /// The address space must not be active on any CPU.
+    pub unsafe fn destroy(self) {
+        assert!(!self.is_active(), "destroying the active address space");
+        let kernel = AddressSpace::kernel();
+        let mut allocator = FRAME_ALLOCATOR.lock();
+        let allocator = allocator.as_mut().unwrap();
+
+        let own = self.level_4_table();
+        for (index, entry) in own.iter().enumerate() {
+            let shared = entry.addr() == kernel.level_4_table()[index].addr();
+            if entry.is_unused() || shared {
+                continue;
+            }
+            unsafe { free_table(allocator, entry.frame().unwrap(), 3) };
+        }
+        allocator.free(self.level_4_frame);
+    }
+
+    /// Translates a virtual address to the physical address it is mapped to.
+    pub fn translate(&mut self, addr: VirtAddr) -> Option<PhysAddr> {
+        self.mapper().translate_addr(addr)
+    }
+
+    /// Like [`translate`](Self::translate), but also returns the leaf flags.
+    pub fn translate_with_flags(&mut self, addr: VirtAddr) -> Option<(PhysAddr, PageTableFlags)> {
+        match self.mapper().translate(addr) {
+            TranslateResult::Mapped { frame, offset, flags } => {
+                let start = match frame {
+                    MappedFrame::Size4KiB(frame) => frame.start_address(),
+                    MappedFrame::Size2MiB(frame) => frame.start_address(),
+                    MappedFrame::Size1GiB(frame) => frame.start_address(),
+                };
+                Some((start + offset, flags))
+            }
+            _ => None,
+        }
+    }
+
+    // Flushing the local TLB only matters when the tables are live on this CPU.
+    fn flush_local<S: PageSize>(&self, flush: x86_64::structures::paging::mapper::MapperFlush<S>) {
+        if self.is_active() {
+            flush.flush();
+        } else {
+            flush.ignore();
+        }
+    }
+}
+
+// Frees a page table of the given level, everything mapped through it, and
+// the table itself.
+unsafe fn free_table(allocator: &mut BitmapFrameAllocator, table_frame: PhysFrame, level: u8) {
+    let table = unsafe { &*table_ptr(table_frame) };
+    for entry in table.iter().filter(|entry| !entry.is_unused()) {
+        let huge = entry.flags().contains(PageTableFlags::HUGE_PAGE);
+        match (level, huge) {
+            (1, _) => allocator.free(PhysFrame::containing_address(entry.addr())),
+            (2, true) => allocator.free_huge(PhysFrame::containing_address(entry.addr())),
+            (_, false) => unsafe {
+                free_table(allocator, PhysFrame::containing_address(entry.addr()), level - 1)
+            },
+            // 1 GiB pages are never handed out for process memory.
+            (_, true) => {}
+        }
+    }
+    allocator.free(table_frame);
+}
+
+fn parent_flags(flags: PageTableFlags) -> PageTableFlags {
+    let mut parent = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+    if flags.contains(PageTableFlags::USER_ACCESSIBLE) {
+        parent |= PageTableFlags::USER_ACCESSIBLE;
+    }
+    parent
+}
+
+fn table_ptr(frame: PhysFrame) -> *mut PageTable {
+    phys_to_virt(frame.start_address()).as_mut_ptr()
+}
+
+/// Hands out virtual address ranges for kernel mappings (MMIO, heap, stacks)
+/// from one level 4 entry that the bootloader left unused.
+struct KernelWindow {
+    next: u64,
+    end: u64,
+}
+
+static KERNEL_WINDOW: SpinLock<Option<KernelWindow>> = SpinLock::new(None);
+
+/// Size of the virtual window covered by a single level 4 entry (512 GiB).
+const LEVEL_4_ENTRY_SIZE: u64 = 1 << 39;
+
+// Level 4 table the bootloader left in CR3.
+static KERNEL_LEVEL_4: AtomicU64 = AtomicU64::new(0);
+
+pub(super) fn init_kernel_window() {
+    let kernel = AddressSpace::current();
+    KERNEL_LEVEL_4.store(kernel.level_4_frame.start_address().as_u64(), Ordering::Relaxed);
+    // Only look at the upper half so user address spaces never collide with it.
+    let index = (256..512)
+        .rev()
+        .find(|&i| kernel.level_4_table()[i].is_unused())
+        .expect("no free level 4 entry for kernel mappings");
+
+    // Give the entry its level 3 table right away. Address spaces copy the
+    // kernel's level 4 entries when they are created, so later mappings in the
+    // window show up everywhere only if the entry already exists.
+    let frame = FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .and_then(|allocator| allocator.allocate())
+        .expect("no frame for the kernel window page table");
+    unsafe { (*table_ptr(frame)).zero() };
+    let level_4_table = unsafe { &mut *table_ptr(kernel.level_4_frame) };
+    level_4_table[index].set_frame(frame, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+
+    // Sign-extend the canonical address of the chosen entry.
+    let start = VirtAddr::new_truncate(index as u64 * LEVEL_4_ENTRY_SIZE).as_u64();
+    *KERNEL_WINDOW.lock() = Some(KernelWindow {
+        next: start,
+        end: start + (LEVEL_4_ENTRY_SIZE - 1),
+    });
+}
+
+/// Reserves `size` bytes of kernel virtual address space aligned to `align`.
+/// Nothing is mapped yet.
+pub fn reserve_kernel_range(size: u64, align: u64) -> Result<VirtAddr, PagingError> {
+    let mut window = KERNEL_WINDOW.lock();
+    let window = window.as_mut().ok_or(PagingError::OutOfVirtualSpace)?;
+    let start = window
+        .next
+        .checked_next_multiple_of(align.max(Size4KiB::SIZE))
+        .ok_or(PagingError::OutOfVirtualSpace)?;
+    let size = size.checked_next_multiple_of(Size4KiB::SIZE).ok_or(PagingError::OutOfVirtualSpace)?;
+    // Aligning may have carried `start` past the end of the window.
+    let room = window.end.checked_sub(start).ok_or(PagingError::OutOfVirtualSpace)?;
+    if size > room {
+        return Err(PagingError::OutOfVirtualSpace);
+    }
+    window.next = start + size;
+    Ok(VirtAddr::new(start))
+}
+
+/// Maps a device's registers at `phys` into kernel space, uncached.
+pub fn map_mmio(phys: PhysAddr, size: u64) -> Result<VirtAddr, PagingError> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(phys);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(phys + (size.max(1) - 1));
+    let pages = (last_frame.start_address() - first_frame.start_address()) / Size4KiB::SIZE + 1;
+
+    let base = reserve_kernel_range(pages * Size4KiB::SIZE, Size4KiB::SIZE)?;
+    let first_page = Page::<Size4KiB>::containing_address(base);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::NO_CACHE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_EXECUTE;
+
+    let mut space = AddressSpace::current();
+    for (i, frame) in PhysFrame::range_inclusive(first_frame, last_frame).enumerate() {
+        space.map(first_page + i as u64, frame, flags)?;
+    }
+
+    Ok(base + (phys.as_u64() - first_frame.start_address().as_u64()))
+}
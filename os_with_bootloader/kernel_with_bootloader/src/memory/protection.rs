@@ -0,0 +1,250 @@
+use super::{phys_to_virt, AddressSpace, PagingError};
+use crate::elf::ElfFile;
+use bootloader_api::info::MemoryRegions;
+use core::arch::x86_64::__cpuid_count;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::registers::control::{Cr0, Cr0Flags, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::{Efer, EferFlags};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::PageTableFlags;
+use x86_64::{PhysAddr, VirtAddr};
+
+// Read by the `int 0x80` entry stub, which can't call into Rust before
+// clearing AC.
+pub(crate) static SMAP_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Which protections ended up enabled, for the boot log and the self-check.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Protections {
+    pub smep: bool,
+    pub smap: bool,
+    pub umip: bool,
+}
+
+/// Where the bootloader put the kernel ELF file (`kernel_addr`/`kernel_len` in
+/// `BootInfo`) and the offset it was loaded at (`kernel_image_offset`).
+#[derive(Debug, Clone, Copy)]
+pub struct KernelImage {
+    pub phys_addr: u64,
+    pub len: u64,
+    pub load_offset: u64,
+}
+
+/// Tightens the page tables and CPU protection bits left by the bootloader:
+/// kernel text R-X, rodata R--, data RW- + NX, the physical memory map NX,
+/// and SMEP/SMAP/UMIP when the CPU has them.
+pub fn harden(
+    memory_regions: &MemoryRegions,
+    kernel: KernelImage,
+) -> Result<Protections, PagingError> {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        // Without WP the kernel could still write to read-only pages.
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+
+    let mut space = AddressSpace::current();
+    remap_kernel_sections(&mut space, kernel)?;
+
+    let physical_memory_end = memory_regions
+        .iter()
+        .map(|region| region.end)
+        .max()
+        .unwrap_or(0);
+    space.update_range_flags(
+        phys_to_virt(PhysAddr::new(0)),
+        phys_to_virt(PhysAddr::new(physical_memory_end)),
+        |flags| flags | PageTableFlags::NO_EXECUTE,
+    )?;
+
+    Ok(enable_cpu_protections())
+}
+
+/// Turns on the same CPU protection bits on an application processor. The
+/// page tables are shared and were hardened by the bootstrap CPU already.
+pub fn init_ap() -> Protections {
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::NO_EXECUTE_ENABLE));
+        Cr0::update(|flags| flags.insert(Cr0Flags::WRITE_PROTECT));
+    }
+    enable_cpu_protections()
+}
+
+fn remap_kernel_sections(space: &mut AddressSpace, kernel: KernelImage) -> Result<(), PagingError> {
+    let image = unsafe {
+        core::slice::from_raw_parts(
+            phys_to_virt(PhysAddr::new(kernel.phys_addr)).as_ptr::<u8>(),
+            kernel.len as usize,
+        )
+    };
+    let elf = ElfFile::parse(image).expect("kernel image is not a valid ELF file");
+
+    for segment in elf.program_headers().filter(|header| header.is_load()) {
+        let start = VirtAddr::new(kernel.load_offset + segment.vaddr);
+        let end = start + segment.mem_size;
+        space.update_range_flags(start, end, |flags| {
+            let mut flags = flags - PageTableFlags::WRITABLE - PageTableFlags::NO_EXECUTE;
+            if segment.writable() {
+                flags |= PageTableFlags::WRITABLE;
+            }
+            if !segment.executable() {
+                flags |= PageTableFlags::NO_EXECUTE;
+            }
+            flags
+        })?;
+    }
+    Ok(())
+}
+
+fn enable_cpu_protections() -> Protections {
+    // CPUID leaf 7: EBX bit 7 = SMEP, bit 20 = SMAP, ECX bit 2 = UMIP.
+    let max_leaf = __cpuid_count(0, 0).eax;
+    if max_leaf < 7 {
+        return Protections::default();
+    }
+    let features = __cpuid_count(7, 0);
+    let enabled = Protections {
+        smep: features.ebx & (1 << 7) != 0,
+        smap: features.ebx & (1 << 20) != 0,
+        umip: features.ecx & (1 << 2) != 0,
+    };
+
+    let mut flags = Cr4Flags::empty();
+    if enabled.smep {
+        flags |= Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION;
+    }
+    if enabled.smap {
+        flags |= Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION;
+    }
+    if enabled.umip {
+        flags |= Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION;
+    }
+    unsafe { Cr4::update(|cr4| cr4.insert(flags)) };
+    SMAP_ENABLED.store(enabled.smap, Ordering::Relaxed);
+
+    enabled
+}
+
+/// Runs `f` with SMAP temporarily lifted so it can touch user memory.
+/// Callers are expected to have validated the user pointers first.
+pub fn with_user_access<R>(f: impl FnOnce() -> R) -> R {
+    let smap = SMAP_ENABLED.load(Ordering::Relaxed);
+    if smap {
+        unsafe { core::arch::asm!("stac", options(nomem, nostack)) };
+    }
+    let result = f();
+    if smap {
+        unsafe { core::arch::asm!("clac", options(nomem, nostack)) };
+    }
+    result
+}
+
+/// Clears EFLAGS.AC, which user code can set with `popf`, so SMAP holds
+/// again after an entry from ring 3.
+pub fn clear_user_access() {
+    if SMAP_ENABLED.load(Ordering::Relaxed) {
+        unsafe { core::arch::asm!("clac", options(nomem, nostack)) };
+    }
+}
+
+// Address the page fault handler jumps to when a probe below faults. Zero when
+// no probe is running.
+static FIXUP: AtomicU64 = AtomicU64::new(0);
+
+/// Called first thing by the page fault handler. If a probe expected this
+/// fault, the faulting instruction is skipped and `true` is returned.
+pub fn try_fixup(stack_frame: &mut InterruptStackFrame) -> bool {
+    let fixup = FIXUP.swap(0, Ordering::Relaxed);
+    if fixup == 0 {
+        return false;
+    }
+    unsafe {
+        stack_frame
+            .as_mut()
+            .update(|frame| frame.instruction_pointer = VirtAddr::new(fixup));
+    }
+    true
+}
+
+/// Probes one access. Returns `true` if it faulted. `rsp` is saved in `rdx`
+/// so the `call` probe can recover from a fault on the callee's first
+/// instruction, where the return address is already pushed.
+#[cfg(feature = "selftest")]
+macro_rules! probe {
+    ($($insn:literal),+; $addr:expr) => {{
+        let faulted: u64;
+        unsafe {
+            core::arch::asm!(
+                "mov rdx, rsp",
+                "lea rcx, [rip + 2f]",
+                "mov [{fixup}], rcx",
+                "xor r8d, r8d",
+                $($insn,)+
+                "mov qword ptr [{fixup}], 0",
+                "jmp 3f",
+                "2:",
+                "mov rsp, rdx",
+                "mov r8d, 1",
+                "3:",
+                fixup = in(reg) FIXUP.as_ptr(),
+                addr = in(reg) $addr,
+                out("r8") faulted,
+                out("rcx") _,
+                out("rdx") _,
+            );
+        }
+        faulted != 0
+    }};
+}
+
+// Writable, non-executable page holding nothing but `ret` instructions.
+#[cfg(feature = "selftest")]
+#[repr(align(4096))]
+struct ProbePage([u8; 4096]);
+#[cfg(feature = "selftest")]
+static mut DATA_PROBE: ProbePage = ProbePage([0xC3; 4096]);
+
+/// Deliberately performs accesses that the protections above must forbid and
+/// reports each one. Only built with the `selftest` feature; interrupts must
+/// be off so nothing else takes a page fault in between.
+#[cfg(feature = "selftest")]
+pub fn self_check(enabled: Protections) -> bool {
+    use crate::println;
+    use x86_64::structures::paging::{Page, PhysFrame, Size4KiB};
+
+    let mut passed = true;
+    let mut check = |name: &str, faulted: bool| {
+        println!("selftest: {:<28} {}", name, if faulted { "ok" } else { "FAILED" });
+        passed &= faulted;
+    };
+
+    let text = self_check as *const u8;
+    // Writes back the byte that is already there, in case the write goes through.
+    check(
+        "write to kernel text",
+        probe!("mov cl, byte ptr [{addr}]", "mov byte ptr [{addr}], cl"; text),
+    );
+
+    let data = &raw mut DATA_PROBE as *const u8;
+    check("execute kernel data", probe!("call {addr}"; data));
+
+    // SMEP and SMAP need a user page to aim at. Borrow the probe page's frame
+    // and map it a second time, user-accessible, somewhere in the lower half.
+    let mut space = AddressSpace::current();
+    let user = Page::<Size4KiB>::containing_address(VirtAddr::new(0x0000_7000_0000_0000));
+    let frame = PhysFrame::containing_address(space.translate(VirtAddr::from_ptr(data)).unwrap());
+    let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if space.map(user, frame, flags).is_ok() {
+        let user_addr = user.start_address().as_u64();
+        if enabled.smep {
+            check("execute user page (SMEP)", probe!("call {addr}"; user_addr));
+        }
+        if enabled.smap {
+            check("read user page (SMAP)", probe!("mov cl, byte ptr [{addr}]"; user_addr));
+        }
+        let _ = space.unmap(user);
+    }
+
+    // UMIP only restricts ring 3, so it is checked once user processes exist.
+    passed
+}
@@ -0,0 +1,184 @@
+use super::paging::{reserve_kernel_range, AddressSpace};
+use super::{PagingError, FRAME_ALLOCATOR};
+use crate::sync::SpinLock;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+const PAGE_SIZE: u64 = 4096;
+/// Unused stack memory is filled with this byte so the deepest point the stack
+/// ever reached can be found later.
+const STACK_PAINT: u8 = 0xCD;
+
+/// A stack known to the kernel, with the unmapped guard page right below it.
+#[derive(Debug, Clone)]
+pub struct StackInfo {
+    pub name: String,
+    pub guard: VirtAddr,
+    pub bottom: VirtAddr,
+    pub top: VirtAddr,
+}
+
+impl StackInfo {
+    pub fn size(&self) -> u64 {
+        self.top - self.bottom
+    }
+
+    /// Deepest the stack has been used so far, in bytes.
+    pub fn high_water_mark(&self) -> u64 {
+        let bytes = unsafe {
+            core::slice::from_raw_parts(self.bottom.as_ptr::<u8>(), self.size() as usize)
+        };
+        let untouched = bytes.iter().take_while(|&&b| b == STACK_PAINT).count() as u64;
+        self.size() - untouched
+    }
+
+    fn guard_contains(&self, addr: VirtAddr) -> bool {
+        addr >= self.guard && addr < self.bottom
+    }
+}
+
+static STACKS: SpinLock<Vec<StackInfo>> = SpinLock::new(Vec::new());
+
+/// Kernel stack with an unmapped guard page below it. Running off the end
+/// hits the guard page and is reported as a stack overflow.
+pub struct GuardedStack {
+    info: StackInfo,
+}
+
+impl GuardedStack {
+    pub fn new(name: &str, size: u64) -> Result<Self, PagingError> {
+        let size = size.next_multiple_of(PAGE_SIZE);
+        let guard = reserve_kernel_range(size + PAGE_SIZE, PAGE_SIZE)?;
+        let bottom = guard + PAGE_SIZE;
+
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+        AddressSpace::current().map_anonymous(
+            Page::containing_address(bottom),
+            size / PAGE_SIZE,
+            flags,
+        )?;
+
+        let info = StackInfo {
+            name: String::from(name),
+            guard,
+            bottom,
+            top: bottom + size,
+        };
+        unsafe { core::ptr::write_bytes(bottom.as_mut_ptr::<u8>(), STACK_PAINT, size as usize) };
+        STACKS.lock().push(info.clone());
+
+        Ok(GuardedStack { info })
+    }
+
+    /// Initial stack pointer, 16-byte aligned.
+    pub fn top(&self) -> VirtAddr {
+        self.info.top
+    }
+
+    pub fn info(&self) -> &StackInfo {
+        &self.info
+    }
+}
+
+impl Drop for GuardedStack {
+    fn drop(&mut self) {
+        STACKS.lock().retain(|stack| stack.bottom != self.info.bottom);
+
+        let mut space = AddressSpace::current();
+        let first = Page::<Size4KiB>::containing_address(self.info.bottom);
+        let last = Page::<Size4KiB>::containing_address(self.info.top - 1u64);
+        for page in Page::range_inclusive(first, last) {
+            if let Ok(frame) = space.unmap(page) {
+                if let Some(allocator) = FRAME_ALLOCATOR.lock().as_mut() {
+                    allocator.free(frame);
+                }
+            }
+        }
+    }
+}
+
+/// Registers the stack the bootloader set up for us. The bootloader leaves
+/// the page below it unmapped, so the bottom is found by walking the page
+/// tables down from the current stack pointer.
+pub fn register_boot_stack(name: &str, size: u64) {
+    let rsp: u64;
+    unsafe { core::arch::asm!("mov {}, rsp", out(reg) rsp, options(nomem, nostack)) };
+    let rsp = VirtAddr::new(rsp);
+    let mut space = AddressSpace::current();
+
+    let mut bottom = Page::<Size4KiB>::containing_address(rsp);
+    while space.translate((bottom - 1).start_address()).is_some() {
+        bottom -= 1;
+    }
+
+    let info = StackInfo {
+        name: String::from(name),
+        guard: (bottom - 1).start_address(),
+        bottom: bottom.start_address(),
+        top: bottom.start_address() + size.next_multiple_of(PAGE_SIZE),
+    };
+
+    // Paint what is below us (minus some slack for this function's own frame)
+    // so the high-water mark means something for the boot stack as well.
+    let paint_end = rsp - 256u64;
+    if paint_end > info.bottom {
+        unsafe {
+            core::ptr::write_bytes(
+                info.bottom.as_mut_ptr::<u8>(),
+                STACK_PAINT,
+                (paint_end - info.bottom) as usize,
+            )
+        };
+    }
+
+    STACKS.lock().push(info);
+}
+
+/// Name of the stack whose guard page contains `addr`, if any. Used by the
+/// page fault handler, so it must not block on the registry lock nor
+/// allocate: the fault may have hit with the heap lock held.
+pub fn guard_page_owner(addr: VirtAddr) -> Option<OwnerName> {
+    let stacks = STACKS.try_lock()?;
+    stacks
+        .iter()
+        .find(|stack| stack.guard_contains(addr))
+        .map(|stack| OwnerName::new(&stack.name))
+}
+
+const OWNER_NAME_LEN: usize = 48;
+
+/// A stack's name copied out of the registry, cut short if it's long.
+pub struct OwnerName {
+    bytes: [u8; OWNER_NAME_LEN],
+    len: usize,
+}
+
+impl OwnerName {
+    fn new(name: &str) -> Self {
+        let mut len = name.len().min(OWNER_NAME_LEN);
+        while !name.is_char_boundary(len) {
+            len -= 1;
+        }
+        let mut bytes = [0; OWNER_NAME_LEN];
+        bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+        OwnerName { bytes, len }
+    }
+
+    pub fn as_str(&self) -> &str {
+        core::str::from_utf8(&self.bytes[..self.len]).unwrap_or("?")
+    }
+}
+
+impl fmt::Display for OwnerName {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+/// Snapshot of all registered stacks.
+pub fn stacks() -> Vec<StackInfo> {
+    STACKS.lock().clone()
+}
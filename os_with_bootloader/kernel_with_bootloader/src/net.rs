@@ -0,0 +1,478 @@
+// Networking: Ethernet card drivers and an IPv4 stack on top of them.
+//
+// Drivers hand their cards to `register`. The first card becomes the
+// interface of the stack, which is smoltcp: ARP, IPv4, ICMP (it answers
+// echo requests itself), UDP and TCP. A kernel thread polls it every tick,
+// or sooner when a driver or socket calls `poll_now`, and wakes threads
+// waiting on sockets when anything happened. Other kernel code uses the
+// blocking sockets in `socket`.
+//
+// The interface gets its address, gateway and DNS servers over DHCP, or
+// falls back to what QEMU's user-mode networking hands out; see `dhcp`.
+// `dns` resolves host names.
+
+mod dhcp;
+pub mod dns;
+pub mod e1000;
+pub mod echo;
+pub mod http;
+pub mod socket;
+pub mod telnet;
+pub mod virtio_net;
+
+use crate::shell;
+use crate::sync::{Mutex, WaitQueue};
+use crate::task::{self, ThreadId};
+use crate::time;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use smoltcp::iface::{Config, Interface, PollResult, SocketHandle, SocketSet};
+use smoltcp::phy::{self, DeviceCapabilities, Medium};
+use smoltcp::time::Instant;
+use smoltcp::wire::{
+    EthernetAddress, HardwareAddress, Icmpv4Packet, Icmpv4Repr, IpCidr, Ipv4Address, Ipv4Cidr,
+};
+use spin::Once;
+
+/// Largest Ethernet frame, without the frame check sequence.
+pub const MAX_FRAME: usize = 1514;
+
+// How long the stack thread sleeps between polls when nothing wakes it.
+const POLL_INTERVAL_MS: u64 = 10;
+// Polls without news after which socket waiters are woken anyway.
+const QUIET_POLLS_BETWEEN_WAKEUPS: u32 = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// No card has been registered.
+    NoInterface,
+    /// The card can't take another frame right now.
+    Busy,
+    AddressInUse,
+    /// The connection was reset or closed by the other side.
+    ConnectionClosed,
+    /// The destination can't be reached or the data can't be sent.
+    Unreachable,
+    Timeout,
+    InvalidArgument,
+}
+
+/// An Ethernet card.
+pub trait Nic: Send + Sync {
+    fn name(&self) -> &str;
+
+    fn mac_address(&self) -> [u8; 6];
+
+    fn link_up(&self) -> bool;
+
+    /// Queues one frame of at most `MAX_FRAME` bytes for sending.
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// Moves the next received frame into `buf`, which holds `MAX_FRAME`
+    /// bytes, and returns its length. `None` if nothing has arrived.
+    fn receive(&self, buf: &mut [u8]) -> Option<usize>;
+}
+
+/// Frame and byte counts of a card, as the stack saw them.
+#[derive(Debug, Default)]
+pub struct Counters {
+    pub rx_packets: AtomicU64,
+    pub rx_bytes: AtomicU64,
+    pub tx_packets: AtomicU64,
+    pub tx_bytes: AtomicU64,
+    pub tx_dropped: AtomicU64,
+}
+
+/// The interface's IPv4 configuration.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IpConfig {
+    pub address: Ipv4Cidr,
+    pub gateway: Option<Ipv4Address>,
+    pub dns_servers: Vec<Ipv4Address>,
+    /// The server that leased the address; `None` for the fallback.
+    pub dhcp_server: Option<Ipv4Address>,
+}
+
+impl fmt::Display for IpConfig {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.address)?;
+        if let Some(gateway) = self.gateway {
+            write!(f, " via {}", gateway)?;
+        }
+        for (i, server) in self.dns_servers.iter().enumerate() {
+            write!(f, "{}{}", if i == 0 { ", DNS " } else { " " }, server)?;
+        }
+        match self.dhcp_server {
+            Some(server) => write!(f, " (DHCP from {})", server),
+            None => write!(f, " (no DHCP server, QEMU defaults)"),
+        }
+    }
+}
+
+/// A registered card.
+pub struct NetDevice {
+    pub nic: Arc<dyn Nic>,
+    pub counters: Counters,
+}
+
+// The card under smoltcp's device interface. Every frame goes through a
+// heap buffer; the drivers copy to and from their DMA memory anyway.
+struct Port {
+    device: Arc<NetDevice>,
+}
+
+struct RxToken {
+    frame: Vec<u8>,
+}
+
+struct TxToken<'a> {
+    device: &'a NetDevice,
+}
+
+impl phy::Device for Port {
+    type RxToken<'a> = RxToken;
+    type TxToken<'a> = TxToken<'a>;
+
+    fn receive(&mut self, _timestamp: Instant) -> Option<(RxToken, TxToken<'_>)> {
+        let mut frame = vec![0; MAX_FRAME];
+        let len = self.device.nic.receive(&mut frame)?;
+        frame.truncate(len);
+        let counters = &self.device.counters;
+        counters.rx_packets.fetch_add(1, Ordering::Relaxed);
+        counters.rx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+        Some((RxToken { frame }, TxToken { device: &self.device }))
+    }
+
+    fn transmit(&mut self, _timestamp: Instant) -> Option<TxToken<'_>> {
+        Some(TxToken { device: &self.device })
+    }
+
+    fn capabilities(&self) -> DeviceCapabilities {
+        let mut capabilities = DeviceCapabilities::default();
+        capabilities.medium = Medium::Ethernet;
+        capabilities.max_transmission_unit = MAX_FRAME;
+        capabilities
+    }
+}
+
+impl phy::RxToken for RxToken {
+    fn consume<R, F>(self, f: F) -> R
+    where
+        F: FnOnce(&[u8]) -> R,
+    {
+        f(&self.frame)
+    }
+}
+
+impl phy::TxToken for TxToken<'_> {
+    fn consume<R, F>(self, len: usize, f: F) -> R
+    where
+        F: FnOnce(&mut [u8]) -> R,
+    {
+        let mut frame = vec![0; len];
+        let result = f(&mut frame);
+        let counters = &self.device.counters;
+        match self.device.nic.transmit(&frame) {
+            Ok(()) => {
+                counters.tx_packets.fetch_add(1, Ordering::Relaxed);
+                counters.tx_bytes.fetch_add(len as u64, Ordering::Relaxed);
+            }
+            // Lost like on the wire; TCP sends it again.
+            Err(_) => {
+                counters.tx_dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        result
+    }
+}
+
+/// The interface, its card and every socket.
+pub(crate) struct Stack {
+    pub(crate) iface: Interface,
+    port: Port,
+    pub(crate) sockets: SocketSet<'static>,
+    // Sockets whose owner is done with them, removed once closed.
+    closing: Vec<SocketHandle>,
+    config: Option<IpConfig>,
+    dhcp: dhcp::Client,
+}
+
+impl Stack {
+    fn poll(&mut self) -> PollResult {
+        let now = now();
+        let mut result = self.iface.poll(now, &mut self.port, &mut self.sockets);
+        if dhcp::update(self) {
+            result = PollResult::SocketStateChanged;
+        }
+        let sockets = &mut self.sockets;
+        self.closing.retain(|&handle| {
+            let done = socket::is_closed(sockets, handle);
+            if done {
+                sockets.remove(handle);
+            }
+            !done
+        });
+        result
+    }
+
+    // Gives the interface `config`'s address and default route, or none.
+    fn configure(&mut self, config: Option<IpConfig>) {
+        self.iface.update_ip_addrs(|addrs| {
+            addrs.clear();
+            if let Some(config) = &config {
+                let _ = addrs.push(IpCidr::Ipv4(config.address));
+            }
+        });
+        let routes = self.iface.routes_mut();
+        routes.remove_default_ipv4_route();
+        if let Some(gateway) = config.as_ref().and_then(|config| config.gateway) {
+            let _ = routes.add_default_ipv4_route(gateway);
+        }
+        self.config = config;
+    }
+
+    /// Drops `handle` once whatever it is doing has finished.
+    pub(crate) fn release(&mut self, handle: SocketHandle) {
+        self.closing.push(handle);
+    }
+}
+
+static DEVICES: Mutex<Vec<Arc<NetDevice>>> = Mutex::new(Vec::new());
+static STACK: Mutex<Option<Stack>> = Mutex::new(None);
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+static POLLER: Once<ThreadId> = Once::new();
+
+// Bumped whenever a poll may have changed a socket, so waiters can tell
+// whether anything happened since they last looked.
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+static ACTIVITY: WaitQueue = WaitQueue::new();
+
+fn now() -> Instant {
+    Instant::from_millis(time::uptime_ms() as i64)
+}
+
+/// The next free card name: eth0, eth1, ...
+pub fn allocate_name() -> String {
+    format!("eth{}", NEXT_INDEX.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Makes `nic` available. The first card becomes the stack's interface.
+pub fn register(nic: Arc<dyn Nic>) {
+    let device = Arc::new(NetDevice {
+        nic,
+        counters: Counters::default(),
+    });
+    DEVICES.lock().push(device.clone());
+
+    let mut stack = STACK.lock();
+    if stack.is_some() {
+        return;
+    }
+    let mut port = Port { device };
+    let mac = EthernetAddress(port.device.nic.mac_address());
+    let mut config = Config::new(HardwareAddress::Ethernet(mac));
+    config.random_seed = crate::random::next_u64();
+    let iface = Interface::new(config, &mut port, now());
+    let mut sockets = SocketSet::new(Vec::new());
+    let dhcp = dhcp::Client::new(&mut sockets);
+    *stack = Some(Stack {
+        iface,
+        port,
+        sockets,
+        closing: Vec::new(),
+        config: None,
+        dhcp,
+    });
+}
+
+pub fn devices() -> Vec<Arc<NetDevice>> {
+    DEVICES.lock().clone()
+}
+
+/// The interface's configuration, once it has one.
+pub fn config() -> Option<IpConfig> {
+    with_stack(|stack| stack.config.clone()).ok().flatten()
+}
+
+/// Runs `f` on the stack. Fails if no card has been registered.
+pub(crate) fn with_stack<R>(f: impl FnOnce(&mut Stack) -> R) -> Result<R, NetError> {
+    STACK.lock().as_mut().map(f).ok_or(NetError::NoInterface)
+}
+
+/// Blocks until `f` returns `Some`, running it on the stack after every poll
+/// that may have changed a socket. Gives up with `Timeout` once the uptime
+/// reaches `deadline` milliseconds.
+pub(crate) fn wait_for<R>(
+    deadline: Option<u64>,
+    mut f: impl FnMut(&mut Stack) -> Option<R>,
+) -> Result<R, NetError> {
+    loop {
+        let seen = GENERATION.load(Ordering::Acquire);
+        if let Some(result) = with_stack(&mut f)? {
+            return Ok(result);
+        }
+        if deadline.is_some_and(|deadline| time::uptime_ms() >= deadline) {
+            return Err(NetError::Timeout);
+        }
+        ACTIVITY.wait_until(|| (GENERATION.load(Ordering::Acquire) != seen).then_some(()));
+    }
+}
+
+/// Gets the stack thread to poll right away, e.g. because a card received
+/// something or a socket has data to send. May be called from interrupt
+/// handlers.
+pub fn poll_now() {
+    if let Some(&id) = POLLER.get() {
+        task::wake(id);
+    }
+}
+
+fn poll_loop() {
+    let mut quiet_polls = 0;
+    loop {
+        let changed = with_stack(|stack| stack.poll() == PollResult::SocketStateChanged);
+        // Waiters with a deadline need to look at the clock now and then,
+        // even when nothing happens.
+        quiet_polls += 1;
+        if changed == Ok(true) || quiet_polls >= QUIET_POLLS_BETWEEN_WAKEUPS {
+            quiet_polls = 0;
+            GENERATION.fetch_add(1, Ordering::Release);
+            ACTIVITY.wake_all();
+        }
+        task::sleep(POLL_INTERVAL_MS);
+    }
+}
+
+/// Probes for cards and, if there is one, starts the stack thread, the
+/// echo services, remote shell sessions, the HTTP server and the network
+/// shell commands.
+pub fn init() {
+    crate::pci::register_driver(&e1000::DRIVER);
+    crate::pci::register_driver(&virtio_net::DRIVER);
+    if STACK.lock().is_none() {
+        return;
+    }
+    match task::spawn("net", poll_loop) {
+        Ok(id) => {
+            POLLER.call_once(|| id);
+        }
+        Err(err) => {
+            crate::print!("\nnet: cannot start the stack thread ({:?})", err);
+            return;
+        }
+    }
+    echo::start();
+    telnet::start();
+    http::start();
+    shell::register("ping", "send ICMP echo requests to a host", ping);
+    shell::register("ifconfig", "show network cards and addresses, or renew the DHCP lease", ifconfig);
+    shell::register("nslookup", "look up the IPv4 addresses of a host name", dns::nslookup);
+}
+
+fn ifconfig(args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+    match args {
+        [] => {}
+        ["renew"] => {
+            with_stack(dhcp::renew).map_err(|err| format!("{:?}", err))?;
+            poll_now();
+            return Ok(());
+        }
+        _ => return Err(String::from("usage: ifconfig [renew]")),
+    }
+    let devices = devices();
+    if devices.is_empty() {
+        let _ = writeln!(out, "no network cards");
+    }
+    let config = config();
+    for (i, device) in devices.iter().enumerate() {
+        let nic = &device.nic;
+        let link = if nic.link_up() { "up" } else { "down" };
+        let _ = writeln!(out, "{}: link {}, MAC {}", nic.name(), link, EthernetAddress(nic.mac_address()));
+        // Only the first card is the stack's interface.
+        match (i, &config) {
+            (0, Some(config)) => {
+                let _ = write!(out, "    inet {}", config.address);
+                if let Some(gateway) = config.gateway {
+                    let _ = write!(out, ", gateway {}", gateway);
+                }
+                let _ = match config.dhcp_server {
+                    Some(server) => writeln!(out, " (DHCP from {})", server),
+                    None => writeln!(out, " (no DHCP server, QEMU defaults)"),
+                };
+                for server in &config.dns_servers {
+                    let _ = writeln!(out, "    dns {}", server);
+                }
+            }
+            (0, None) => {
+                let _ = writeln!(out, "    no address yet");
+            }
+            _ => {}
+        }
+        let counters = &device.counters;
+        let _ = writeln!(
+            out,
+            "    rx {} packets, {} bytes",
+            counters.rx_packets.load(Ordering::Relaxed),
+            counters.rx_bytes.load(Ordering::Relaxed)
+        );
+        let _ = writeln!(
+            out,
+            "    tx {} packets, {} bytes, {} dropped",
+            counters.tx_packets.load(Ordering::Relaxed),
+            counters.tx_bytes.load(Ordering::Relaxed),
+            counters.tx_dropped.load(Ordering::Relaxed)
+        );
+    }
+    Ok(())
+}
+
+// Echo requests sent by `ping`, a second apart.
+const PING_COUNT: u16 = 4;
+const PING_TIMEOUT_MS: u64 = 1000;
+const PING_PAYLOAD: &[u8] = b"kernel ping payload";
+
+fn ping(args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+    let [target] = args else {
+        return Err(String::from("usage: ping <host>"));
+    };
+    let addresses = dns::resolve(target).map_err(|err| format!("{}: {:?}", target, err))?;
+    let target = addresses[0];
+    let ident = crate::random::next_u64() as u16;
+    let socket = socket::IcmpSocket::bind(ident).map_err(|err| format!("{:?}", err))?;
+
+    let mut received = 0;
+    for seq_no in 0..PING_COUNT {
+        let request = Icmpv4Repr::EchoRequest { ident, seq_no, data: PING_PAYLOAD };
+        let mut bytes = vec![0; request.buffer_len()];
+        request.emit(&mut Icmpv4Packet::new_unchecked(&mut bytes), &Default::default());
+        let sent = time::uptime_ms();
+        socket.send_to(&bytes, target).map_err(|err| format!("{:?}", err))?;
+
+        let deadline = sent + PING_TIMEOUT_MS;
+        let reply = socket.recv_until(deadline, |data, from| {
+            let packet = Icmpv4Packet::new_checked(data).ok()?;
+            match Icmpv4Repr::parse(&packet, &Default::default()).ok()? {
+                Icmpv4Repr::EchoReply { ident: id, seq_no: seq, .. } if id == ident && seq == seq_no => Some(from),
+                _ => None,
+            }
+        });
+        match reply {
+            Some(from) => {
+                received += 1;
+                let _ = writeln!(out, "reply from {}: seq={} time={} ms", from, seq_no, time::uptime_ms() - sent);
+            }
+            None => {
+                let _ = writeln!(out, "no reply: seq={}", seq_no);
+            }
+        }
+        task::sleep(deadline.saturating_sub(time::uptime_ms()));
+    }
+    let _ = writeln!(out, "{} sent, {} received", PING_COUNT, received);
+    Ok(())
+}
+
@@ -0,0 +1,81 @@
+// DHCPv4. smoltcp's client socket gets a lease and renews it; after every
+// poll the stack applies whatever the socket reports. If the interface has
+// had no address for a few seconds, it gets the addresses QEMU's user-mode
+// networking hands out, while the client keeps asking in the background.
+
+use super::{IpConfig, Stack};
+use crate::time;
+use alloc::vec;
+use smoltcp::iface::{SocketHandle, SocketSet};
+use smoltcp::socket::dhcpv4;
+use smoltcp::wire::{Ipv4Address, Ipv4Cidr};
+
+// How long to wait for a server before falling back.
+const FALLBACK_AFTER_MS: u64 = 5000;
+
+// QEMU user-mode networking: the guest is 10.0.2.15, the host's gateway
+// 10.0.2.2 and its DNS proxy 10.0.2.3.
+const FALLBACK_ADDRESS: Ipv4Address = Ipv4Address::new(10, 0, 2, 15);
+const FALLBACK_PREFIX: u8 = 24;
+const FALLBACK_GATEWAY: Ipv4Address = Ipv4Address::new(10, 0, 2, 2);
+const FALLBACK_DNS: Ipv4Address = Ipv4Address::new(10, 0, 2, 3);
+
+pub(super) struct Client {
+    handle: SocketHandle,
+    // Uptime at which the interface lost its address, or came up without
+    // one. `None` while it has one.
+    unconfigured_since: Option<u64>,
+}
+
+impl Client {
+    pub(super) fn new(sockets: &mut SocketSet<'static>) -> Client {
+        Client {
+            handle: sockets.add(dhcpv4::Socket::new()),
+            unconfigured_since: Some(time::uptime_ms()),
+        }
+    }
+}
+
+fn fallback() -> IpConfig {
+    IpConfig {
+        address: Ipv4Cidr::new(FALLBACK_ADDRESS, FALLBACK_PREFIX),
+        gateway: Some(FALLBACK_GATEWAY),
+        dns_servers: vec![FALLBACK_DNS],
+        dhcp_server: None,
+    }
+}
+
+/// Applies what the client found out since the last poll, or the fallback
+/// once it is due. Returns whether the configuration changed.
+pub(super) fn update(stack: &mut Stack) -> bool {
+    let event = stack.sockets.get_mut::<dhcpv4::Socket>(stack.dhcp.handle).poll();
+    let config = match event {
+        Some(dhcpv4::Event::Configured(lease)) => Some(IpConfig {
+            address: lease.address,
+            gateway: lease.router,
+            dns_servers: lease.dns_servers.iter().copied().collect(),
+            dhcp_server: Some(lease.server.address),
+        }),
+        Some(dhcpv4::Event::Deconfigured) => None,
+        None => match stack.dhcp.unconfigured_since {
+            Some(since) if time::uptime_ms() >= since + FALLBACK_AFTER_MS => Some(fallback()),
+            _ => return false,
+        },
+    };
+    let name = stack.port.device.nic.name();
+    match &config {
+        Some(config) => crate::print!("\nnet: {} is {}", name, config),
+        None => crate::print!("\nnet: {} lost its DHCP lease", name),
+    }
+    stack.dhcp.unconfigured_since = match config {
+        Some(_) => None,
+        None => Some(time::uptime_ms()),
+    };
+    stack.configure(config);
+    true
+}
+
+/// Drops the lease, if any, and starts over asking for one.
+pub(super) fn renew(stack: &mut Stack) {
+    stack.sockets.get_mut::<dhcpv4::Socket>(stack.dhcp.handle).reset();
+}
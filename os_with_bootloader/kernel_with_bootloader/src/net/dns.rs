@@ -0,0 +1,299 @@
+// A stub DNS resolver. A-record queries go over UDP to the servers the
+// interface was configured with, asking for recursion so they do the real
+// work. Answers are cached for as long as their TTL allows, up to an hour.
+
+use super::socket::UdpSocket;
+use super::{config, NetError};
+use crate::sync::Mutex;
+use crate::time;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use smoltcp::wire::{IpAddress, IpEndpoint, Ipv4Address};
+
+const DNS_PORT: u16 = 53;
+
+const HEADER_SIZE: usize = 12;
+const FLAG_RESPONSE: u8 = 0x80;
+// Recursion desired, in the high byte of the flags.
+const FLAG_RECURSION: u8 = 0x01;
+const RCODE_NAME_ERROR: u8 = 3;
+const TYPE_A: u16 = 1;
+const CLASS_IN: u16 = 1;
+
+const MAX_NAME: usize = 253;
+const MAX_LABEL: usize = 63;
+// What a server may send over UDP without EDNS.
+const MAX_MESSAGE: usize = 512;
+
+// Each server gets this long to answer, and every server is tried this
+// many times.
+const QUERY_TIMEOUT_MS: u64 = 2000;
+const ATTEMPTS: usize = 2;
+
+const CACHE_ENTRIES: usize = 64;
+const MAX_TTL_SECS: u32 = 3600;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DnsError {
+    Net(NetError),
+    /// The interface has no DNS server (yet).
+    NoServer,
+    InvalidName,
+    /// The name doesn't exist or has no IPv4 address.
+    NotFound,
+    /// A server answered with this error code.
+    ServerFailure(u8),
+    /// No server answered in time.
+    NoAnswer,
+}
+
+impl From<NetError> for DnsError {
+    fn from(err: NetError) -> Self {
+        DnsError::Net(err)
+    }
+}
+
+/// The result of resolving a name.
+#[derive(Debug, Clone)]
+pub struct Lookup {
+    pub addresses: Vec<Ipv4Address>,
+    /// Seconds the answer stays in the cache.
+    pub ttl: u32,
+    /// The server that answered; `None` if it came from the cache or the
+    /// name was an address already.
+    pub server: Option<Ipv4Address>,
+}
+
+struct CacheEntry {
+    addresses: Vec<Ipv4Address>,
+    // Uptime in milliseconds.
+    expires: u64,
+}
+
+static CACHE: Mutex<BTreeMap<String, CacheEntry>> = Mutex::new(BTreeMap::new());
+
+/// The IPv4 addresses of `name`, which may also be an address itself.
+pub fn resolve(name: &str) -> Result<Vec<Ipv4Address>, DnsError> {
+    lookup(name).map(|lookup| lookup.addresses)
+}
+
+pub fn lookup(name: &str) -> Result<Lookup, DnsError> {
+    if let Ok(address) = name.parse::<Ipv4Address>() {
+        return Ok(Lookup {
+            addresses: vec![address],
+            ttl: 0,
+            server: None,
+        });
+    }
+    let name = normalize(name).ok_or(DnsError::InvalidName)?;
+    if let Some(lookup) = cached(&name) {
+        return Ok(lookup);
+    }
+    let servers = config().map(|config| config.dns_servers).unwrap_or_default();
+    if servers.is_empty() {
+        return Err(DnsError::NoServer);
+    }
+
+    let id = crate::random::next_u64() as u16;
+    let query = build_query(id, &name);
+    let socket = UdpSocket::bind(0)?;
+    let mut buf = [0; MAX_MESSAGE];
+    let mut error = DnsError::NoAnswer;
+    for _ in 0..ATTEMPTS {
+        for &server in &servers {
+            let endpoint = IpEndpoint::new(IpAddress::Ipv4(server), DNS_PORT);
+            socket.send_to(&query, endpoint)?;
+            let deadline = time::uptime_ms() + QUERY_TIMEOUT_MS;
+            loop {
+                let (count, from) = match socket.recv_from_until(Some(deadline), &mut buf) {
+                    Ok(received) => received,
+                    Err(NetError::Timeout) => break,
+                    Err(err) => return Err(err.into()),
+                };
+                if from != endpoint {
+                    continue;
+                }
+                match parse_response(&buf[..count], id) {
+                    // Not the answer to this query; keep waiting.
+                    None => continue,
+                    Some(Ok((addresses, ttl))) => {
+                        let ttl = ttl.min(MAX_TTL_SECS);
+                        insert(name, addresses.clone(), ttl);
+                        return Ok(Lookup {
+                            addresses,
+                            ttl,
+                            server: Some(server),
+                        });
+                    }
+                    Some(Err(DnsError::NotFound)) => return Err(DnsError::NotFound),
+                    // Maybe another server does better.
+                    Some(Err(err)) => {
+                        error = err;
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    Err(error)
+}
+
+/// Forgets every cached answer.
+pub fn flush_cache() {
+    CACHE.lock().clear();
+}
+
+// Lower case without the trailing dot, if it is a valid host name.
+fn normalize(name: &str) -> Option<String> {
+    let name = name.strip_suffix('.').unwrap_or(name).to_ascii_lowercase();
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME
+        && name.split('.').all(|label| {
+            (1..=MAX_LABEL).contains(&label.len())
+                && label.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+        });
+    valid.then_some(name)
+}
+
+fn cached(name: &str) -> Option<Lookup> {
+    let mut cache = CACHE.lock();
+    let now = time::uptime_ms();
+    let entry = cache.get(name)?;
+    if entry.expires <= now {
+        cache.remove(name);
+        return None;
+    }
+    Some(Lookup {
+        addresses: entry.addresses.clone(),
+        ttl: ((entry.expires - now) / 1000) as u32,
+        server: None,
+    })
+}
+
+// When the cache is full, expired entries go first, then the one closest
+// to expiring.
+fn insert(name: String, addresses: Vec<Ipv4Address>, ttl: u32) {
+    if ttl == 0 {
+        return;
+    }
+    let mut cache = CACHE.lock();
+    let now = time::uptime_ms();
+    if cache.len() >= CACHE_ENTRIES {
+        cache.retain(|_, entry| entry.expires > now);
+    }
+    if cache.len() >= CACHE_ENTRIES {
+        let soonest = cache.iter().min_by_key(|(_, entry)| entry.expires).map(|(name, _)| name.clone());
+        if let Some(soonest) = soonest {
+            cache.remove(&soonest);
+        }
+    }
+    let expires = now + ttl as u64 * 1000;
+    cache.insert(name, CacheEntry { addresses, expires });
+}
+
+fn build_query(id: u16, name: &str) -> Vec<u8> {
+    let mut query = Vec::with_capacity(HEADER_SIZE + name.len() + 6);
+    query.extend_from_slice(&id.to_be_bytes());
+    query.extend_from_slice(&[FLAG_RECURSION, 0]);
+    // One question, no records.
+    query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+    for label in name.split('.') {
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    query.push(0);
+    query.extend_from_slice(&TYPE_A.to_be_bytes());
+    query.extend_from_slice(&CLASS_IN.to_be_bytes());
+    query
+}
+
+fn be16(bytes: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(bytes.get(at..at + 2)?.try_into().ok()?))
+}
+
+// The offset just past the name at `at`, which ends either with the root
+// label or with a pointer to the rest of it elsewhere.
+fn skip_name(packet: &[u8], mut at: usize) -> Option<usize> {
+    loop {
+        let len = *packet.get(at)? as usize;
+        match len {
+            0 => return Some(at + 1),
+            _ if len & 0xC0 == 0xC0 => return Some(at + 2),
+            _ => at += 1 + len,
+        }
+    }
+}
+
+// The A records in an answer to query `id` and the shortest of their TTLs.
+// `None` if the packet isn't such an answer or doesn't parse.
+fn parse_response(packet: &[u8], id: u16) -> Option<Result<(Vec<Ipv4Address>, u32), DnsError>> {
+    if packet.len() < HEADER_SIZE || be16(packet, 0)? != id || packet[2] & FLAG_RESPONSE == 0 {
+        return None;
+    }
+    match packet[3] & 0x0F {
+        0 => {}
+        RCODE_NAME_ERROR => return Some(Err(DnsError::NotFound)),
+        code => return Some(Err(DnsError::ServerFailure(code))),
+    }
+    let questions = be16(packet, 4)?;
+    let answers = be16(packet, 6)?;
+    let mut at = HEADER_SIZE;
+    for _ in 0..questions {
+        // The name, then its type and class.
+        at = skip_name(packet, at)? + 4;
+    }
+    let mut addresses = Vec::new();
+    let mut ttl = u32::MAX;
+    for _ in 0..answers {
+        at = skip_name(packet, at)?;
+        let record = packet.get(at..at + 10)?;
+        let kind = be16(record, 0)?;
+        let class = be16(record, 2)?;
+        let record_ttl = u32::from_be_bytes(record[4..8].try_into().ok()?);
+        let len = be16(record, 8)? as usize;
+        let data = packet.get(at + 10..at + 10 + len)?;
+        // CNAMEs come with the records of the name they point to.
+        if kind == TYPE_A && class == CLASS_IN && len == 4 {
+            addresses.push(Ipv4Address::new(data[0], data[1], data[2], data[3]));
+            ttl = ttl.min(record_ttl);
+        }
+        at += 10 + len;
+    }
+    if addresses.is_empty() {
+        return Some(Err(DnsError::NotFound));
+    }
+    Some(Ok((addresses, ttl)))
+}
+
+pub(super) fn nslookup(args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+    match args {
+        ["-f"] => {
+            flush_cache();
+            Ok(())
+        }
+        [name] => {
+            let lookup = lookup(name).map_err(|err| format!("{}: {:?}", name, err))?;
+            match lookup.server {
+                Some(server) => {
+                    let _ = writeln!(out, "server {}", server);
+                }
+                None if lookup.ttl > 0 => {
+                    let _ = writeln!(out, "cached");
+                }
+                None => {}
+            }
+            for address in &lookup.addresses {
+                let _ = writeln!(out, "{} has address {}", name, address);
+            }
+            if lookup.ttl > 0 {
+                let _ = writeln!(out, "ttl {} s", lookup.ttl);
+            }
+            Ok(())
+        }
+        _ => Err(String::from("usage: nslookup <name> | nslookup -f (flush the cache)")),
+    }
+}
@@ -0,0 +1,446 @@
+// Intel 8254x (e1000) cards, the network card QEMU gives a machine unless
+// told otherwise. The registers are memory-mapped through BAR 0. Receive
+// and transmit each use a ring of legacy descriptors in DMA memory, every
+// descriptor with its own 2 KiB buffer, which holds a whole frame.
+//
+// The card interrupts on its PCI line when frames arrive or the link
+// changes; the handler acknowledges the cause and gets the stack to poll,
+// which is what actually takes the frames off the ring.
+
+use super::{allocate_name, poll_now, register, NetError, Nic, MAX_FRAME};
+use crate::interrupts;
+use crate::memory::dma::DmaBuffer;
+use crate::memory::paging::map_mmio;
+use crate::pci::{self, Bar};
+use crate::sync::{Mutex, SpinLock};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{fence, Ordering};
+use smoltcp::wire::EthernetAddress;
+use x86_64::PhysAddr;
+
+const VENDOR_INTEL: u16 = 0x8086;
+
+// Register offsets.
+const CTRL: usize = 0x0000;
+const STATUS: usize = 0x0008;
+const EERD: usize = 0x0014;
+const ICR: usize = 0x00C0;
+const IMS: usize = 0x00D0;
+const IMC: usize = 0x00D8;
+const RCTL: usize = 0x0100;
+const TCTL: usize = 0x0400;
+const TIPG: usize = 0x0410;
+const RDBAL: usize = 0x2800;
+const RDBAH: usize = 0x2804;
+const RDLEN: usize = 0x2808;
+const RDH: usize = 0x2810;
+const RDT: usize = 0x2818;
+const RDTR: usize = 0x2820;
+const TDBAL: usize = 0x3800;
+const TDBAH: usize = 0x3804;
+const TDLEN: usize = 0x3808;
+const TDH: usize = 0x3810;
+const TDT: usize = 0x3818;
+const MTA: usize = 0x5200;
+const RAL: usize = 0x5400;
+const RAH: usize = 0x5404;
+
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+const CTRL_LRST: u32 = 1 << 3;
+const CTRL_ILOS: u32 = 1 << 7;
+const CTRL_PHY_RST: u32 = 1 << 31;
+
+const STATUS_LU: u32 = 1 << 1;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+
+// Interrupt causes: transmit written back, link status change, receive
+// ring running low, receive overrun, receive timer.
+const INT_LSC: u32 = 1 << 2;
+const INT_RXDMT0: u32 = 1 << 4;
+const INT_RXO: u32 = 1 << 6;
+const INT_RXT0: u32 = 1 << 7;
+const INT_RECEIVE: u32 = INT_RXDMT0 | INT_RXO | INT_RXT0;
+
+// Receive: enabled, accept broadcast, strip the CRC; 2048-byte buffers.
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+const RCTL_SECRC: u32 = 1 << 26;
+
+// Transmit: enabled, pad short packets, the usual collision settings.
+const TCTL_EN: u32 = 1 << 1;
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT: u32 = 0x0F << 4;
+const TCTL_COLD: u32 = 0x40 << 12;
+// Inter-packet gap for copper, as the manual recommends.
+const TIPG_COPPER: u32 = 10 | 8 << 10 | 6 << 20;
+
+const RAH_AV: u32 = 1 << 31;
+
+const DESC_DD: u8 = 1 << 0;
+const DESC_EOP: u8 = 1 << 1;
+const CMD_EOP: u8 = 1 << 0;
+const CMD_IFCS: u8 = 1 << 1;
+const CMD_RS: u8 = 1 << 3;
+
+// Ring lengths must be multiples of 8 descriptors.
+const RECEIVE_DESCRIPTORS: usize = 32;
+const TRANSMIT_DESCRIPTORS: usize = 32;
+const BUFFER_SIZE: usize = 2048;
+const DESCRIPTOR_SIZE: usize = 16;
+const PAGE_SIZE: usize = 4096;
+
+// Polls of a self-clearing bit before giving up on the card.
+const POLL_LIMIT: usize = 1_000_000;
+
+// Cards of the 8254x family QEMU can emulate.
+const MATCHES: &[pci::DeviceMatch] = &[
+    pci::DeviceMatch::id(VENDOR_INTEL, 0x100E),
+    pci::DeviceMatch::id(VENDOR_INTEL, 0x100F),
+    pci::DeviceMatch::id(VENDOR_INTEL, 0x10D3),
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum E1000Error {
+    NoMemoryBar,
+    Map,
+    ResetTimeout,
+    NoMemory,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct ReceiveDescriptor {
+    addr: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct TransmitDescriptor {
+    addr: u64,
+    length: u16,
+    cso: u8,
+    cmd: u8,
+    status: u8,
+    css: u8,
+    special: u16,
+}
+
+// The card's register window.
+#[derive(Clone, Copy)]
+struct Registers {
+    base: u64,
+}
+
+impl Registers {
+    fn read(&self, offset: usize) -> u32 {
+        unsafe { ((self.base as usize + offset) as *const u32).read_volatile() }
+    }
+
+    fn write(&self, offset: usize, value: u32) {
+        unsafe { ((self.base as usize + offset) as *mut u32).write_volatile(value) }
+    }
+}
+
+// Every card's registers, for the interrupt handler.
+static CARDS: SpinLock<Vec<Registers>> = SpinLock::new(Vec::new());
+
+pub struct E1000 {
+    name: String,
+    mac: [u8; 6],
+    registers: Registers,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    receive: Ring,
+    transmit: Ring,
+}
+
+// Descriptors and their buffers. `next` is the descriptor to look at next:
+// the oldest one the card may have filled, or the next one to send from.
+struct Ring {
+    descriptors: DmaBuffer,
+    buffers: DmaBuffer,
+    count: usize,
+    next: usize,
+}
+
+impl Ring {
+    fn new(count: usize) -> Result<Ring, E1000Error> {
+        Ok(Ring {
+            descriptors: DmaBuffer::new((count * DESCRIPTOR_SIZE).div_ceil(PAGE_SIZE)).ok_or(E1000Error::NoMemory)?,
+            buffers: DmaBuffer::new((count * BUFFER_SIZE).div_ceil(PAGE_SIZE)).ok_or(E1000Error::NoMemory)?,
+            count,
+            next: 0,
+        })
+    }
+
+    fn buffer_phys(&self, index: usize) -> u64 {
+        self.buffers.phys().as_u64() + (index * BUFFER_SIZE) as u64
+    }
+
+    fn buffer(&mut self, index: usize) -> &mut [u8] {
+        &mut self.buffers.as_mut_slice()[index * BUFFER_SIZE..(index + 1) * BUFFER_SIZE]
+    }
+
+    fn receive_descriptor(&self, index: usize) -> *mut ReceiveDescriptor {
+        unsafe { (self.descriptors.as_mut_ptr() as *mut ReceiveDescriptor).add(index) }
+    }
+
+    fn transmit_descriptor(&self, index: usize) -> *mut TransmitDescriptor {
+        unsafe { (self.descriptors.as_mut_ptr() as *mut TransmitDescriptor).add(index) }
+    }
+}
+
+impl E1000 {
+    fn new(device: &pci::PciDevice, name: String) -> Result<Self, E1000Error> {
+        let bar = device
+            .bars
+            .iter()
+            .find(|bar| bar.index == 0 && matches!(bar.bar, Bar::Memory(addr) if addr != 0))
+            .ok_or(E1000Error::NoMemoryBar)?;
+        let Bar::Memory(phys) = bar.bar else {
+            return Err(E1000Error::NoMemoryBar);
+        };
+        let base = map_mmio(PhysAddr::new(phys), bar.size).map_err(|_| E1000Error::Map)?;
+        let registers = Registers { base: base.as_u64() };
+        device.address.enable_bus_master();
+
+        // Reset, with interrupts off until the rings are set up.
+        registers.write(IMC, u32::MAX);
+        registers.write(CTRL, registers.read(CTRL) | CTRL_RST);
+        wait_clear(registers, CTRL, CTRL_RST)?;
+        registers.write(IMC, u32::MAX);
+        registers.read(ICR);
+
+        let ctrl = registers.read(CTRL) & !(CTRL_LRST | CTRL_ILOS | CTRL_PHY_RST);
+        registers.write(CTRL, ctrl | CTRL_SLU | CTRL_ASDE);
+        for i in 0..128 {
+            registers.write(MTA + i * 4, 0);
+        }
+        let mac = read_mac(registers);
+
+        let receive = Ring::new(RECEIVE_DESCRIPTORS)?;
+        for i in 0..receive.count {
+            let descriptor = ReceiveDescriptor {
+                addr: receive.buffer_phys(i),
+                length: 0,
+                checksum: 0,
+                status: 0,
+                errors: 0,
+                special: 0,
+            };
+            unsafe { receive.receive_descriptor(i).write_volatile(descriptor) };
+        }
+        let transmit = Ring::new(TRANSMIT_DESCRIPTORS)?;
+        for i in 0..transmit.count {
+            // Marked done, so every descriptor starts out free.
+            let descriptor = TransmitDescriptor {
+                addr: transmit.buffer_phys(i),
+                length: 0,
+                cso: 0,
+                cmd: 0,
+                status: DESC_DD,
+                css: 0,
+                special: 0,
+            };
+            unsafe { transmit.transmit_descriptor(i).write_volatile(descriptor) };
+        }
+
+        let rx = receive.descriptors.phys().as_u64();
+        registers.write(RDBAL, rx as u32);
+        registers.write(RDBAH, (rx >> 32) as u32);
+        registers.write(RDLEN, (receive.count * DESCRIPTOR_SIZE) as u32);
+        registers.write(RDH, 0);
+        // Every descriptor but one belongs to the card.
+        registers.write(RDT, (receive.count - 1) as u32);
+        registers.write(RDTR, 0);
+        registers.write(RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+
+        let tx = transmit.descriptors.phys().as_u64();
+        registers.write(TDBAL, tx as u32);
+        registers.write(TDBAH, (tx >> 32) as u32);
+        registers.write(TDLEN, (transmit.count * DESCRIPTOR_SIZE) as u32);
+        registers.write(TDH, 0);
+        registers.write(TDT, 0);
+        registers.write(TIPG, TIPG_COPPER);
+        registers.write(TCTL, TCTL_EN | TCTL_PSP | TCTL_CT | TCTL_COLD);
+
+        Ok(E1000 {
+            name,
+            mac,
+            registers,
+            inner: Mutex::new(Inner { receive, transmit }),
+        })
+    }
+
+    // Takes interrupts on the card's PCI line. Without one the stack's
+    // regular polling still picks up frames.
+    fn enable_interrupts(&self, device: &pci::PciDevice) -> bool {
+        x86_64::instructions::interrupts::without_interrupts(|| CARDS.lock().push(self.registers));
+        if !interrupts::register_irq(device.interrupt_line, "e1000", interrupt) {
+            return false;
+        }
+        device.address.enable_legacy_interrupt();
+        self.registers.write(IMS, INT_RECEIVE | INT_LSC);
+        true
+    }
+}
+
+fn wait_clear(registers: Registers, offset: usize, bit: u32) -> Result<(), E1000Error> {
+    for _ in 0..POLL_LIMIT {
+        if registers.read(offset) & bit == 0 {
+            return Ok(());
+        }
+        core::hint::spin_loop();
+    }
+    Err(E1000Error::ResetTimeout)
+}
+
+// The firmware (or QEMU) leaves the address in receive address register 0;
+// otherwise it is the first three words of the EEPROM.
+fn read_mac(registers: Registers) -> [u8; 6] {
+    let high = registers.read(RAH);
+    if high & RAH_AV != 0 {
+        let low = registers.read(RAL).to_le_bytes();
+        let high = high.to_le_bytes();
+        return [low[0], low[1], low[2], low[3], high[0], high[1]];
+    }
+    let mut mac = [0; 6];
+    for word in 0..3 {
+        registers.write(EERD, EERD_START | (word as u32) << 8);
+        let mut value = 0;
+        for _ in 0..POLL_LIMIT {
+            value = registers.read(EERD);
+            if value & EERD_DONE != 0 {
+                break;
+            }
+        }
+        mac[word * 2..word * 2 + 2].copy_from_slice(&((value >> 16) as u16).to_le_bytes());
+    }
+    let low = u32::from_le_bytes([mac[0], mac[1], mac[2], mac[3]]);
+    registers.write(RAL, low);
+    registers.write(RAH, u16::from_le_bytes([mac[4], mac[5]]) as u32 | RAH_AV);
+    mac
+}
+
+// Reading the cause register acknowledges the interrupt, which has to
+// happen before the EOI for a level-triggered line.
+fn interrupt() {
+    let mut wake = false;
+    for registers in CARDS.lock().iter() {
+        let cause = registers.read(ICR);
+        wake |= cause & (INT_RECEIVE | INT_LSC) != 0;
+    }
+    if wake {
+        poll_now();
+    }
+}
+
+impl Nic for E1000 {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.registers.read(STATUS) & STATUS_LU != 0
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME {
+            return Err(NetError::InvalidArgument);
+        }
+        let ring = &mut self.inner.lock().transmit;
+        let index = ring.next;
+        let descriptor = ring.transmit_descriptor(index);
+        if unsafe { (*descriptor).status } & DESC_DD == 0 {
+            return Err(NetError::Busy);
+        }
+        ring.buffer(index)[..frame.len()].copy_from_slice(frame);
+        unsafe {
+            descriptor.write_volatile(TransmitDescriptor {
+                addr: ring.buffer_phys(index),
+                length: frame.len() as u16,
+                cso: 0,
+                cmd: CMD_EOP | CMD_IFCS | CMD_RS,
+                status: 0,
+                css: 0,
+                special: 0,
+            });
+        }
+        ring.next = (index + 1) % ring.count;
+        fence(Ordering::SeqCst);
+        self.registers.write(TDT, ring.next as u32);
+        Ok(())
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> Option<usize> {
+        let ring = &mut self.inner.lock().receive;
+        loop {
+            let index = ring.next;
+            let descriptor = ring.receive_descriptor(index);
+            let status = unsafe { (*descriptor).status };
+            if status & DESC_DD == 0 {
+                return None;
+            }
+            fence(Ordering::Acquire);
+            let (length, errors) = unsafe { ((*descriptor).length as usize, (*descriptor).errors) };
+            // Frames never span buffers at this size; anything odd is dropped.
+            let len = length.min(MAX_FRAME).min(buf.len());
+            let good = status & DESC_EOP != 0 && errors == 0;
+            if good {
+                buf[..len].copy_from_slice(&ring.buffer(index)[..len]);
+            }
+            unsafe {
+                (*descriptor).status = 0;
+            }
+            fence(Ordering::SeqCst);
+            // Give the descriptor back to the card.
+            self.registers.write(RDT, index as u32);
+            ring.next = (index + 1) % ring.count;
+            if good {
+                return Some(len);
+            }
+        }
+    }
+}
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "e1000",
+    matches: MATCHES,
+    probe,
+};
+
+fn probe(device: &'static pci::PciDevice) -> bool {
+    match E1000::new(device, allocate_name()) {
+        Ok(nic) => {
+            let irq = if nic.enable_interrupts(device) {
+                alloc::format!("IRQ {}", device.interrupt_line)
+            } else {
+                String::from("polled")
+            };
+            crate::print!("\n{}: e1000 at {}, {}, {}", nic.name, device.address, EthernetAddress(nic.mac), irq);
+            register(Arc::new(nic));
+            true
+        }
+        Err(err) => {
+            crate::print!("\ne1000 {}: {:?}", device.address, err);
+            false
+        }
+    }
+}
@@ -0,0 +1,57 @@
+// The echo service (RFC 862) on TCP and UDP port 7: whatever arrives is
+// sent straight back. It gives the stack something to talk to from the
+// host; the runner forwards a host port to it.
+
+use super::socket::{TcpListener, TcpStream, UdpSocket};
+use crate::task;
+
+const PORT: u16 = 7;
+
+/// Starts a thread for each protocol.
+pub fn start() {
+    for (name, service) in [("echo/tcp", tcp as fn()), ("echo/udp", udp)] {
+        if let Err(err) = task::spawn(name, service) {
+            crate::print!("\n{}: cannot start ({:?})", name, err);
+        }
+    }
+}
+
+fn tcp() {
+    let listener = match TcpListener::bind(PORT) {
+        Ok(listener) => listener,
+        Err(err) => {
+            crate::print!("\necho/tcp: {:?}", err);
+            return;
+        }
+    };
+    while let Ok(stream) = listener.accept() {
+        let spawned = task::spawn("echo/tcp session", move || serve(stream));
+        if let Err(err) = spawned {
+            crate::print!("\necho/tcp: cannot start a session ({:?})", err);
+        }
+    }
+}
+
+// Echoes until the other side closes its end.
+fn serve(stream: TcpStream) {
+    let mut buf = [0; 1024];
+    while let Ok(count) = stream.read(&mut buf) {
+        if count == 0 || stream.write_all(&buf[..count]).is_err() {
+            break;
+        }
+    }
+}
+
+fn udp() {
+    let socket = match UdpSocket::bind(PORT) {
+        Ok(socket) => socket,
+        Err(err) => {
+            crate::print!("\necho/udp: {:?}", err);
+            return;
+        }
+    };
+    let mut buf = [0; 2048];
+    while let Ok((count, from)) = socket.recv_from(&mut buf) {
+        let _ = socket.send_to(&buf[..count], from);
+    }
+}
@@ -0,0 +1,417 @@
+// A small HTTP/1.1 server on TCP port 80, for looking at the kernel from a
+// browser or curl on the host.
+//
+// GET and HEAD only. Paths under /api/ are JSON snapshots of kernel state;
+// everything else is a file under /www, which comes from the ramdisk, with
+// a listing for directories that have no index.html. Connections are kept
+// alive between requests unless the client says otherwise, and each one is
+// served by a thread of its own.
+
+use super::socket::{TcpListener, TcpStream};
+use super::NetError;
+use crate::allocator;
+use crate::bootinfo;
+use crate::fs::{self, FileType};
+use crate::interrupts;
+use crate::memory;
+use crate::task;
+use crate::time;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const PORT: u16 = 80;
+
+const DOCUMENT_ROOT: &str = "/www";
+const INDEX: &str = "index.html";
+
+// Connections beyond this many are turned away.
+const MAX_SESSIONS: usize = 8;
+// Longest request head (request line and headers) accepted.
+const MAX_HEAD: usize = 8 * 1024;
+// How long a connection may sit idle between requests.
+const IDLE_TIMEOUT_MS: u64 = 30_000;
+
+static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Starts the thread accepting connections.
+pub fn start() {
+    if let Err(err) = task::spawn("http", listen) {
+        crate::print!("\nhttp: cannot start ({:?})", err);
+    }
+}
+
+fn listen() {
+    let listener = match TcpListener::bind(PORT) {
+        Ok(listener) => listener,
+        Err(err) => {
+            crate::print!("\nhttp: {:?}", err);
+            return;
+        }
+    };
+    while let Ok(stream) = listener.accept() {
+        if SESSIONS.fetch_add(1, Ordering::AcqRel) >= MAX_SESSIONS {
+            SESSIONS.fetch_sub(1, Ordering::AcqRel);
+            let _ = respond(&stream, &error(503, "Service Unavailable"), false, false);
+            continue;
+        }
+        let spawned = task::spawn("http session", move || {
+            serve(stream);
+            SESSIONS.fetch_sub(1, Ordering::AcqRel);
+        });
+        if let Err(err) = spawned {
+            SESSIONS.fetch_sub(1, Ordering::AcqRel);
+            crate::print!("\nhttp: cannot start a session ({:?})", err);
+        }
+    }
+}
+
+struct Request {
+    method: String,
+    path: String,
+    // HTTP/1.0 closes after each response unless asked not to, 1.1 the
+    // other way around.
+    keep_alive: bool,
+}
+
+struct Response {
+    status: u16,
+    reason: &'static str,
+    content_type: &'static str,
+    body: Vec<u8>,
+    // Kernel state changes all the time; files from the ramdisk don't.
+    cacheable: bool,
+}
+
+fn serve(stream: TcpStream) {
+    // What was read past the end of the previous request.
+    let mut pending = Vec::new();
+    loop {
+        let request = match read_request(&stream, &mut pending) {
+            Ok(Some(request)) => request,
+            Ok(None) => return,
+            Err(response) => {
+                let _ = respond(&stream, &response, false, false);
+                return;
+            }
+        };
+        let head_only = request.method == "HEAD";
+        let response = match request.method.as_str() {
+            "GET" | "HEAD" => route(&request.path),
+            _ => error(405, "Method Not Allowed"),
+        };
+        if respond(&stream, &response, head_only, request.keep_alive).is_err() || !request.keep_alive {
+            return;
+        }
+    }
+}
+
+// The next request's head. `None` once the client has closed the connection
+// or gone quiet; `Err` with the response for a request that can't be served.
+fn read_request(stream: &TcpStream, pending: &mut Vec<u8>) -> Result<Option<Request>, Response> {
+    let end = loop {
+        if let Some(at) = pending.windows(4).position(|window| window == b"\r\n\r\n") {
+            break at;
+        }
+        if pending.len() > MAX_HEAD {
+            return Err(error(431, "Request Header Fields Too Large"));
+        }
+        let mut buf = [0; 1024];
+        let deadline = time::uptime_ms() + IDLE_TIMEOUT_MS;
+        match stream.read_until(Some(deadline), &mut buf) {
+            Ok(0) | Err(NetError::Timeout) => return Ok(None),
+            Ok(count) => pending.extend_from_slice(&buf[..count]),
+            Err(_) => return Ok(None),
+        }
+    };
+    let head: Vec<u8> = pending.drain(..end + 4).collect();
+    let head = core::str::from_utf8(&head).map_err(|_| error(400, "Bad Request"))?;
+    let mut lines = head.split("\r\n");
+    let mut words = lines.next().unwrap_or("").split(' ');
+    let (Some(method), Some(target), Some(version), None) = (words.next(), words.next(), words.next(), words.next())
+    else {
+        return Err(error(400, "Bad Request"));
+    };
+    let mut keep_alive = match version {
+        "HTTP/1.1" => true,
+        "HTTP/1.0" => false,
+        _ => return Err(error(505, "HTTP Version Not Supported")),
+    };
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        if name.trim().eq_ignore_ascii_case("connection") {
+            let value = value.trim();
+            if value.eq_ignore_ascii_case("close") {
+                keep_alive = false;
+            } else if value.eq_ignore_ascii_case("keep-alive") {
+                keep_alive = true;
+            }
+        }
+    }
+    // The query string isn't used.
+    let path = target.split('?').next().unwrap_or("");
+    let path = percent_decode(path).ok_or_else(|| error(400, "Bad Request"))?;
+    Ok(Some(Request {
+        method: String::from(method),
+        path,
+        keep_alive,
+    }))
+}
+
+fn respond(stream: &TcpStream, response: &Response, head_only: bool, keep_alive: bool) -> Result<(), NetError> {
+    let mut head = format!(
+        "HTTP/1.1 {} {}\r\nServer: kernel\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: {}\r\n",
+        response.status,
+        response.reason,
+        response.content_type,
+        response.body.len(),
+        if keep_alive { "keep-alive" } else { "close" }
+    );
+    if !response.cacheable {
+        head.push_str("Cache-Control: no-store\r\n");
+    }
+    if response.status == 405 {
+        head.push_str("Allow: GET, HEAD\r\n");
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    if !head_only {
+        stream.write_all(&response.body)?;
+    }
+    Ok(())
+}
+
+fn error(status: u16, reason: &'static str) -> Response {
+    Response {
+        status,
+        reason,
+        content_type: "text/plain; charset=utf-8",
+        body: format!("{} {}\n", status, reason).into_bytes(),
+        cacheable: false,
+    }
+}
+
+fn json(body: String) -> Response {
+    Response {
+        status: 200,
+        reason: "OK",
+        content_type: "application/json",
+        body: body.into_bytes(),
+        cacheable: false,
+    }
+}
+
+fn route(path: &str) -> Response {
+    match path {
+        "/api" | "/api/" => json(String::from(r#"["uptime","memory","interrupts","tasks"]"#)),
+        "/api/uptime" => json(uptime()),
+        "/api/memory" => json(memory_regions()),
+        "/api/interrupts" => json(interrupt_counts()),
+        "/api/tasks" => json(tasks()),
+        _ if path.starts_with("/api/") => error(404, "Not Found"),
+        _ => file(path),
+    }
+}
+
+// Decodes %XX escapes; `None` if one is malformed or the result isn't UTF-8.
+fn percent_decode(path: &str) -> Option<String> {
+    let bytes = path.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = core::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn file(path: &str) -> Response {
+    // Requests can't climb out of the document root.
+    if !path.starts_with('/') || path.split('/').any(|segment| segment == "..") {
+        return error(400, "Bad Request");
+    }
+    let full = format!("{}{}", DOCUMENT_ROOT, path.trim_end_matches('/'));
+    let metadata = match fs::stat(&full) {
+        Ok(metadata) => metadata,
+        Err(_) => return error(404, "Not Found"),
+    };
+    match metadata.kind {
+        FileType::Directory => {
+            let index = format!("{}/{}", full, INDEX);
+            if fs::stat(&index).is_ok_and(|index| index.kind == FileType::File) {
+                return contents(&index);
+            }
+            listing(path, &full)
+        }
+        FileType::File => contents(&full),
+        // Devices could block or never end.
+        _ => error(403, "Forbidden"),
+    }
+}
+
+fn contents(path: &str) -> Response {
+    match fs::read_to_end(path) {
+        Ok(body) => Response {
+            status: 200,
+            reason: "OK",
+            content_type: content_type(path),
+            body,
+            cacheable: true,
+        },
+        Err(_) => error(500, "Internal Server Error"),
+    }
+}
+
+fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit_once('.').map_or("", |(_, extension)| extension);
+    match extension {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css",
+        "js" => "text/javascript",
+        "json" => "application/json",
+        "txt" | "md" => "text/plain; charset=utf-8",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "ico" => "image/x-icon",
+        _ => "application/octet-stream",
+    }
+}
+
+fn listing(path: &str, full: &str) -> Response {
+    let entries = match fs::read_dir(full) {
+        Ok(entries) => entries,
+        Err(_) => return error(500, "Internal Server Error"),
+    };
+    let base = path.trim_end_matches('/');
+    let mut body = format!("<!DOCTYPE html>\n<title>{0}/</title>\n<h1>{0}/</h1>\n<ul>\n", html_escape(base));
+    for entry in entries {
+        let suffix = if entry.kind == FileType::Directory { "/" } else { "" };
+        let name = html_escape(&entry.name);
+        let _ = writeln!(body, "<li><a href=\"{}/{}{}\">{}{}</a></li>", html_escape(base), name, suffix, name, suffix);
+    }
+    body.push_str("</ul>\n");
+    Response {
+        status: 200,
+        reason: "OK",
+        content_type: "text/html; charset=utf-8",
+        body: body.into_bytes(),
+        cacheable: false,
+    }
+}
+
+fn html_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// `text` as a JSON string literal.
+fn json_string(text: &str) -> String {
+    let mut quoted = String::with_capacity(text.len() + 2);
+    quoted.push('"');
+    for c in text.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => {
+                let _ = write!(quoted, "\\u{:04x}", c as u32);
+            }
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn uptime() -> String {
+    format!("{{\"uptime_ms\":{}}}", time::uptime_ms())
+}
+
+// The bootloader's memory map, and how much of it the frame allocator and
+// the heap use.
+fn memory_regions() -> String {
+    let mut text = String::from("{\"regions\":[");
+    if let Some(boot) = bootinfo::get() {
+        for (i, region) in boot.memory_regions.iter().enumerate() {
+            let _ = write!(
+                text,
+                "{}{{\"start\":{},\"end\":{},\"kind\":{}}}",
+                if i == 0 { "" } else { "," },
+                region.start,
+                region.end,
+                json_string(&format!("{:?}", region.kind))
+            );
+        }
+    }
+    let frames = memory::frame_stats();
+    let heap = allocator::stats();
+    let _ = write!(
+        text,
+        "],\"frame_size\":{},\"frames\":{{\"total\":{},\"free\":{}}},\"heap\":{{\"size\":{},\"used\":{},\"allocator\":{}}}}}",
+        memory::frame_allocator::FRAME_SIZE,
+        frames.total_frames,
+        frames.free_frames,
+        heap.size,
+        heap.used,
+        json_string(heap.strategy)
+    );
+    text
+}
+
+fn interrupt_counts() -> String {
+    let mut text = String::from("[");
+    for (i, line) in interrupts::counts().iter().enumerate() {
+        let per_cpu: Vec<String> = line.per_cpu.iter().map(|count| format!("{}", count)).collect();
+        let _ = write!(
+            text,
+            "{}{{\"vector\":{},\"name\":{},\"per_cpu\":[{}]}}",
+            if i == 0 { "" } else { "," },
+            line.vector,
+            json_string(line.name),
+            per_cpu.join(",")
+        );
+    }
+    text.push(']');
+    text
+}
+
+fn tasks() -> String {
+    let mut threads = task::threads();
+    threads.sort_by_key(|thread| thread.id.as_u64());
+    let mut text = String::from("[");
+    for (i, thread) in threads.iter().enumerate() {
+        let pid = thread.pid.map_or(String::from("null"), |pid| format!("{}", pid.as_u64()));
+        let _ = write!(
+            text,
+            "{}{{\"id\":{},\"pid\":{},\"name\":{},\"state\":\"{}\",\"stack_used\":{},\"stack_size\":{}}}",
+            if i == 0 { "" } else { "," },
+            thread.id.as_u64(),
+            pid,
+            json_string(&thread.name),
+            thread.state.name(),
+            thread.stack_used,
+            thread.stack_size
+        );
+    }
+    text.push(']');
+    text
+}
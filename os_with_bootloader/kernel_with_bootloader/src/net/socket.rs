@@ -0,0 +1,344 @@
+// Blocking sockets for kernel threads, over the stack's smoltcp sockets.
+//
+// Each wrapper owns a socket handle. Operations that can't complete yet
+// wait for the stack thread's next poll and try again. Dropping a wrapper
+// closes its socket; TCP ones stay in the set until the close handshake is
+// over, so the peer sees a FIN rather than a reset.
+
+use super::{poll_now, wait_for, with_stack, NetError, Stack};
+use crate::sync::Mutex;
+use crate::time;
+use alloc::vec;
+use smoltcp::iface::{SocketHandle, SocketSet};
+use smoltcp::socket::{icmp, tcp, udp, Socket};
+use smoltcp::time::Duration;
+use smoltcp::wire::{IpAddress, IpEndpoint};
+
+const TCP_BUFFER_SIZE: usize = 16 * 1024;
+// A peer that stops acknowledging is dropped after `TCP_TIMEOUT`; keep-alives
+// give an idle one something to acknowledge.
+const TCP_TIMEOUT: Duration = Duration::from_secs(60);
+const TCP_KEEP_ALIVE: Duration = Duration::from_secs(20);
+// How long a write waits for room in the send buffer, for a peer that is
+// there but doesn't read.
+const TCP_WRITE_TIMEOUT_MS: u64 = 30_000;
+const UDP_PACKETS: usize = 16;
+const UDP_BUFFER_SIZE: usize = 16 * 1024;
+const ICMP_PACKETS: usize = 4;
+const ICMP_BUFFER_SIZE: usize = 4 * 1024;
+
+// Local ports picked for sockets bound to port 0.
+const EPHEMERAL_PORTS: core::ops::Range<u16> = 49152..65535;
+
+/// Whether the stack can forget `handle`: true for anything but a TCP
+/// socket that is still closing.
+pub(super) fn is_closed(sockets: &SocketSet, handle: SocketHandle) -> bool {
+    sockets.iter().find(|&(h, _)| h == handle).is_none_or(|(_, socket)| match socket {
+        Socket::Tcp(socket) => !socket.is_open(),
+        _ => true,
+    })
+}
+
+fn ephemeral_port() -> u16 {
+    let span = EPHEMERAL_PORTS.end - EPHEMERAL_PORTS.start;
+    EPHEMERAL_PORTS.start + (crate::random::next_u64() % span as u64) as u16
+}
+
+fn tcp_socket() -> tcp::Socket<'static> {
+    let mut socket = tcp::Socket::new(
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+        tcp::SocketBuffer::new(vec![0; TCP_BUFFER_SIZE]),
+    );
+    socket.set_timeout(Some(TCP_TIMEOUT));
+    socket.set_keep_alive(Some(TCP_KEEP_ALIVE));
+    socket
+}
+
+fn listen(stack: &mut Stack, port: u16) -> Result<SocketHandle, NetError> {
+    let mut socket = tcp_socket();
+    socket.listen(port).map_err(|_| NetError::InvalidArgument)?;
+    Ok(stack.sockets.add(socket))
+}
+
+/// A TCP port accepting connections.
+pub struct TcpListener {
+    port: u16,
+    // The socket waiting for the next connection. Once one comes in it
+    // becomes that connection and a fresh socket takes its place.
+    handle: Mutex<SocketHandle>,
+}
+
+impl TcpListener {
+    pub fn bind(port: u16) -> Result<TcpListener, NetError> {
+        if port == 0 {
+            return Err(NetError::InvalidArgument);
+        }
+        let handle = with_stack(|stack| {
+            let taken = stack.sockets.iter().any(|(_, socket)| match socket {
+                Socket::Tcp(socket) => {
+                    socket.state() == tcp::State::Listen
+                        && socket.listen_endpoint().port == port
+                }
+                _ => false,
+            });
+            if taken {
+                return Err(NetError::AddressInUse);
+            }
+            listen(stack, port)
+        })??;
+        Ok(TcpListener {
+            port,
+            handle: Mutex::new(handle),
+        })
+    }
+
+    /// Blocks until a connection is established and returns it.
+    pub fn accept(&self) -> Result<TcpStream, NetError> {
+        let mut handle = self.handle.lock();
+        let port = self.port;
+        let current = *handle;
+        wait_for(None, |stack| {
+            let socket = stack.sockets.get_mut::<tcp::Socket>(current);
+            match socket.state() {
+                tcp::State::Listen | tcp::State::SynReceived => None,
+                // Reset before it got going; keep listening.
+                tcp::State::Closed => {
+                    let _ = socket.listen(port);
+                    None
+                }
+                _ => Some(()),
+            }
+        })?;
+        let fresh = with_stack(|stack| listen(stack, port))??;
+        let connection = core::mem::replace(&mut *handle, fresh);
+        Ok(TcpStream { handle: connection })
+    }
+}
+
+impl Drop for TcpListener {
+    fn drop(&mut self) {
+        let handle = *self.handle.lock();
+        let _ = with_stack(|stack| {
+            stack.sockets.remove(handle);
+        });
+    }
+}
+
+/// An established TCP connection.
+pub struct TcpStream {
+    handle: SocketHandle,
+}
+
+impl TcpStream {
+    /// The other end's address and port.
+    pub fn peer(&self) -> Option<IpEndpoint> {
+        with_stack(|stack| stack.sockets.get::<tcp::Socket>(self.handle).remote_endpoint())
+            .ok()
+            .flatten()
+    }
+
+    /// Blocks until data arrives and moves what fits into `buf`. Returns 0
+    /// once the other side has closed its end and everything was read.
+    pub fn read(&self, buf: &mut [u8]) -> Result<usize, NetError> {
+        self.read_until(None, buf)
+    }
+
+    /// Like `read`, giving up with `Timeout` once the uptime reaches
+    /// `deadline` milliseconds.
+    pub fn read_until(&self, deadline: Option<u64>, buf: &mut [u8]) -> Result<usize, NetError> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let count = wait_for(deadline, |stack| {
+            let socket = stack.sockets.get_mut::<tcp::Socket>(self.handle);
+            if socket.can_recv() {
+                Some(socket.recv_slice(buf).map_err(|_| NetError::ConnectionClosed))
+            } else if !socket.may_recv() {
+                Some(Ok(0))
+            } else {
+                None
+            }
+        })??;
+        // The window just opened up again.
+        poll_now();
+        Ok(count)
+    }
+
+    /// Blocks until there is room to send and queues as much of `data` as
+    /// fits. Returns how much that was, or `Timeout` if no room came up in
+    /// time.
+    pub fn write(&self, data: &[u8]) -> Result<usize, NetError> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+        let deadline = time::uptime_ms() + TCP_WRITE_TIMEOUT_MS;
+        let count = wait_for(Some(deadline), |stack| {
+            let socket = stack.sockets.get_mut::<tcp::Socket>(self.handle);
+            if !socket.may_send() {
+                Some(Err(NetError::ConnectionClosed))
+            } else if socket.can_send() {
+                Some(socket.send_slice(data).map_err(|_| NetError::ConnectionClosed))
+            } else {
+                None
+            }
+        })??;
+        poll_now();
+        Ok(count)
+    }
+
+    pub fn write_all(&self, mut data: &[u8]) -> Result<(), NetError> {
+        while !data.is_empty() {
+            let count = self.write(data)?;
+            data = &data[count..];
+        }
+        Ok(())
+    }
+
+    /// Sends a FIN once everything queued has gone out. Reading still works
+    /// until the other side closes too.
+    pub fn close(&self) {
+        let _ = with_stack(|stack| stack.sockets.get_mut::<tcp::Socket>(self.handle).close());
+        poll_now();
+    }
+}
+
+impl Drop for TcpStream {
+    fn drop(&mut self) {
+        let _ = with_stack(|stack| {
+            stack.sockets.get_mut::<tcp::Socket>(self.handle).close();
+            stack.release(self.handle);
+        });
+        poll_now();
+    }
+}
+
+/// A bound UDP port.
+pub struct UdpSocket {
+    handle: SocketHandle,
+}
+
+impl UdpSocket {
+    /// Binds `port`, or a free ephemeral port if it is 0.
+    pub fn bind(port: u16) -> Result<UdpSocket, NetError> {
+        let port = if port == 0 { ephemeral_port() } else { port };
+        let handle = with_stack(|stack| {
+            let taken = stack.sockets.iter().any(|(_, socket)| match socket {
+                Socket::Udp(socket) => socket.endpoint().port == port,
+                _ => false,
+            });
+            if taken {
+                return Err(NetError::AddressInUse);
+            }
+            let mut socket = udp::Socket::new(
+                udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; UDP_PACKETS], vec![0; UDP_BUFFER_SIZE]),
+                udp::PacketBuffer::new(vec![udp::PacketMetadata::EMPTY; UDP_PACKETS], vec![0; UDP_BUFFER_SIZE]),
+            );
+            socket.bind(port).map_err(|_| NetError::InvalidArgument)?;
+            Ok(stack.sockets.add(socket))
+        })??;
+        Ok(UdpSocket { handle })
+    }
+
+    /// Queues `data` as one datagram to `to`, waiting for room if needed.
+    pub fn send_to(&self, data: &[u8], to: IpEndpoint) -> Result<(), NetError> {
+        wait_for(None, |stack| {
+            let socket = stack.sockets.get_mut::<udp::Socket>(self.handle);
+            match socket.send_slice(data, to) {
+                Ok(()) => Some(Ok(())),
+                Err(udp::SendError::BufferFull) => None,
+                Err(udp::SendError::Unaddressable) => Some(Err(NetError::Unreachable)),
+            }
+        })??;
+        poll_now();
+        Ok(())
+    }
+
+    /// Blocks until a datagram arrives and moves it into `buf`, cut to fit.
+    /// Returns its length and sender.
+    pub fn recv_from(&self, buf: &mut [u8]) -> Result<(usize, IpEndpoint), NetError> {
+        self.recv_from_until(None, buf)
+    }
+
+    /// Like `recv_from`, giving up with `Timeout` once the uptime reaches
+    /// `deadline` milliseconds.
+    pub fn recv_from_until(
+        &self,
+        deadline: Option<u64>,
+        buf: &mut [u8],
+    ) -> Result<(usize, IpEndpoint), NetError> {
+        wait_for(deadline, |stack| {
+            let socket = stack.sockets.get_mut::<udp::Socket>(self.handle);
+            let (datagram, meta) = socket.recv().ok()?;
+            // A datagram too big for `buf` is cut.
+            let count = datagram.len().min(buf.len());
+            buf[..count].copy_from_slice(&datagram[..count]);
+            Some((count, meta.endpoint))
+        })
+    }
+}
+
+impl Drop for UdpSocket {
+    fn drop(&mut self) {
+        let _ = with_stack(|stack| stack.release(self.handle));
+    }
+}
+
+/// ICMP echo traffic with one identifier, for `ping`.
+pub struct IcmpSocket {
+    handle: SocketHandle,
+}
+
+impl IcmpSocket {
+    pub fn bind(ident: u16) -> Result<IcmpSocket, NetError> {
+        let handle = with_stack(|stack| {
+            let mut socket = icmp::Socket::new(
+                icmp::PacketBuffer::new(vec![icmp::PacketMetadata::EMPTY; ICMP_PACKETS], vec![0; ICMP_BUFFER_SIZE]),
+                icmp::PacketBuffer::new(vec![icmp::PacketMetadata::EMPTY; ICMP_PACKETS], vec![0; ICMP_BUFFER_SIZE]),
+            );
+            socket.bind(icmp::Endpoint::Ident(ident)).map_err(|_| NetError::InvalidArgument)?;
+            Ok(stack.sockets.add(socket))
+        })??;
+        Ok(IcmpSocket { handle })
+    }
+
+    /// Queues an ICMP message, header included, to `to`.
+    pub fn send_to(&self, message: &[u8], to: impl Into<IpAddress>) -> Result<(), NetError> {
+        let to = to.into();
+        wait_for(None, |stack| {
+            let socket = stack.sockets.get_mut::<icmp::Socket>(self.handle);
+            match socket.send_slice(message, to) {
+                Ok(()) => Some(Ok(())),
+                Err(icmp::SendError::BufferFull) => None,
+                Err(icmp::SendError::Unaddressable) => Some(Err(NetError::Unreachable)),
+            }
+        })??;
+        poll_now();
+        Ok(())
+    }
+
+    /// Hands each message that arrives to `accept` until it returns `Some`
+    /// or the uptime reaches `deadline` milliseconds.
+    pub fn recv_until<R>(
+        &self,
+        deadline: u64,
+        mut accept: impl FnMut(&[u8], IpAddress) -> Option<R>,
+    ) -> Option<R> {
+        wait_for(Some(deadline), |stack| {
+            let socket = stack.sockets.get_mut::<icmp::Socket>(self.handle);
+            while let Ok((message, from)) = socket.recv() {
+                if let Some(result) = accept(message, from) {
+                    return Some(result);
+                }
+            }
+            None
+        })
+        .ok()
+    }
+}
+
+impl Drop for IcmpSocket {
+    fn drop(&mut self) {
+        let _ = with_stack(|stack| stack.release(self.handle));
+    }
+}
+
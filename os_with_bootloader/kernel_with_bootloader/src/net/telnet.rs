@@ -0,0 +1,175 @@
+// Remote shell sessions over TCP port 23, telnet-style. Each connection
+// gets its own session of the kernel shell, in a thread of its own.
+//
+// Negotiation is kept minimal: the server never offers anything, agrees to
+// suppress go-ahead and refuses every other option. Clients so stay in
+// line mode and echo locally, and a plain TCP client such as netcat sees
+// nothing but text. Input has the protocol's commands taken out; output
+// ends lines with "\r\n".
+
+use super::socket::{TcpListener, TcpStream};
+use crate::shell::{self, Terminal};
+use crate::task;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+const PORT: u16 = 23;
+
+// Sessions beyond this many are turned away.
+const MAX_SESSIONS: usize = 4;
+
+// Protocol bytes (RFC 854).
+const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+
+static SESSIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// Starts the thread accepting connections.
+pub fn start() {
+    if let Err(err) = task::spawn("telnet", listen) {
+        crate::print!("\ntelnet: cannot start ({:?})", err);
+    }
+}
+
+fn listen() {
+    let listener = match TcpListener::bind(PORT) {
+        Ok(listener) => listener,
+        Err(err) => {
+            crate::print!("\ntelnet: {:?}", err);
+            return;
+        }
+    };
+    while let Ok(stream) = listener.accept() {
+        if SESSIONS.fetch_add(1, Ordering::AcqRel) >= MAX_SESSIONS {
+            SESSIONS.fetch_sub(1, Ordering::AcqRel);
+            let _ = stream.write_all(b"too many sessions\r\n");
+            continue;
+        }
+        let spawned = task::spawn("telnet session", move || {
+            serve(stream);
+            SESSIONS.fetch_sub(1, Ordering::AcqRel);
+        });
+        if let Err(err) = spawned {
+            SESSIONS.fetch_sub(1, Ordering::AcqRel);
+            crate::print!("\ntelnet: cannot start a session ({:?})", err);
+        }
+    }
+}
+
+fn serve(stream: TcpStream) {
+    let mut terminal = TelnetTerminal {
+        stream,
+        state: State::Data,
+    };
+    let _ = terminal.write_str("kernel shell; `exit` ends the session\n");
+    shell::run(&mut terminal);
+}
+
+// Where the input parser is within the protocol's commands.
+#[derive(Clone, Copy)]
+enum State {
+    Data,
+    // After a carriage return, which may be followed by a NUL to drop.
+    Return,
+    Command,
+    // After WILL, WONT, DO or DONT, waiting for the option.
+    Option(u8),
+    Subnegotiation,
+    SubnegotiationCommand,
+}
+
+struct TelnetTerminal {
+    stream: TcpStream,
+    state: State,
+}
+
+impl TelnetTerminal {
+    // Moves the data bytes of `input` to `out` and the replies to the
+    // commands in it to `replies`.
+    fn filter(&mut self, input: &[u8], out: &mut Vec<u8>, replies: &mut Vec<u8>) {
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (State::Return, 0) => State::Data,
+                (State::Data | State::Return, IAC) => State::Command,
+                (State::Data | State::Return, b'\r') => {
+                    out.push(byte);
+                    State::Return
+                }
+                (State::Data | State::Return, _) => {
+                    out.push(byte);
+                    State::Data
+                }
+                // An escaped 0xFF, which the shell drops anyway.
+                (State::Command, IAC) => State::Data,
+                (State::Command, WILL | WONT | DO | DONT) => State::Option(byte),
+                (State::Command, SB) => State::Subnegotiation,
+                // Go ahead, interrupt, are you there and the like.
+                (State::Command, _) => State::Data,
+                (State::Option(command), option) => {
+                    let reply = match (command, option) {
+                        (DO, SUPPRESS_GO_AHEAD) => Some(WILL),
+                        (WILL, SUPPRESS_GO_AHEAD) => Some(DO),
+                        (DO, _) => Some(WONT),
+                        (WILL, _) => Some(DONT),
+                        // Turning an option off needs no answer; they are
+                        // all off already.
+                        _ => None,
+                    };
+                    if let Some(reply) = reply {
+                        replies.extend_from_slice(&[IAC, reply, option]);
+                    }
+                    State::Data
+                }
+                (State::Subnegotiation, IAC) => State::SubnegotiationCommand,
+                (State::Subnegotiation, _) => State::Subnegotiation,
+                (State::SubnegotiationCommand, SE) => State::Data,
+                (State::SubnegotiationCommand, _) => State::Subnegotiation,
+            };
+        }
+    }
+}
+
+impl Write for TelnetTerminal {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        for (i, line) in s.split('\n').enumerate() {
+            if i > 0 {
+                self.stream.write_all(b"\r\n").map_err(|_| fmt::Error)?;
+            }
+            self.stream.write_all(line.as_bytes()).map_err(|_| fmt::Error)?;
+        }
+        Ok(())
+    }
+}
+
+impl Terminal for TelnetTerminal {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        let mut raw = [0; 64];
+        let mut data = Vec::new();
+        let mut replies = Vec::new();
+        // Input that is all commands doesn't count; wait for more.
+        while data.is_empty() {
+            let count = match self.stream.read(&mut raw[..buf.len().min(64)]) {
+                Ok(0) | Err(_) => return 0,
+                Ok(count) => count,
+            };
+            self.filter(&raw[..count], &mut data, &mut replies);
+            if !replies.is_empty() && self.stream.write_all(&replies).is_err() {
+                return 0;
+            }
+            replies.clear();
+        }
+        buf[..data.len()].copy_from_slice(&data);
+        data.len()
+    }
+
+    fn can_exit(&self) -> bool {
+        true
+    }
+}
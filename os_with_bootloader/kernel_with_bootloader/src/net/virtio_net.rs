@@ -0,0 +1,211 @@
+// virtio-net cards. Receive buffers are all posted to queue 0 up front and
+// each one goes back to the device as soon as its frame has been copied
+// out. Frames to send are copied into a pool of transmit buffers on queue
+// 1, which are reclaimed once the device is done with them. Every buffer is
+// a header (zeroed on transmit: no checksum offload, no segmentation)
+// followed by the frame, each in its own descriptor.
+
+use super::{allocate_name, register, NetError, Nic, MAX_FRAME};
+use crate::memory::dma::DmaBuffer;
+use crate::pci;
+use crate::sync::Mutex;
+use crate::virtio::{self, Buffer, LegacyDevice, VirtioError, Virtqueue};
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use smoltcp::wire::EthernetAddress;
+
+// Transitional virtio-net, which still speaks the legacy interface.
+const DEVICE_ID: u16 = 0x1000;
+
+const FEATURE_MAC: u32 = 1 << 5;
+const FEATURE_STATUS: u32 = 1 << 16;
+
+// Device configuration: the MAC address, then the link status.
+const CONFIG_MAC: u16 = 0;
+const CONFIG_STATUS: u16 = 6;
+const STATUS_LINK_UP: u16 = 1;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+// The legacy header, without the merged-buffers count.
+const HEADER_SIZE: usize = 10;
+
+// Buffers are slots of this size in one DMA block per queue: the header,
+// then the frame at `FRAME_OFFSET`.
+const SLOT_SIZE: usize = 2048;
+const FRAME_OFFSET: usize = 16;
+const RECEIVE_SLOTS: usize = 64;
+const TRANSMIT_SLOTS: usize = 32;
+const PAGE_SIZE: usize = 4096;
+
+pub struct VirtioNet {
+    name: String,
+    mac: [u8; 6],
+    features: u32,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    device: LegacyDevice,
+    receive: Ring,
+    transmit: Ring,
+    // Transmit slots the device isn't using.
+    free: Vec<usize>,
+}
+
+// A queue and the slots of its buffers.
+struct Ring {
+    queue: Virtqueue,
+    memory: DmaBuffer,
+    // The slot behind each chain the device has, by chain ID.
+    slot_of: Vec<usize>,
+}
+
+impl Ring {
+    fn new(device: &LegacyDevice, index: u16, slots: usize) -> Result<Ring, VirtioError> {
+        let queue = device.setup_queue(index)?;
+        let memory = DmaBuffer::new((slots * SLOT_SIZE).div_ceil(PAGE_SIZE)).ok_or(VirtioError::NoMemory)?;
+        Ok(Ring {
+            slot_of: vec![0; queue.size() as usize],
+            queue,
+            memory,
+        })
+    }
+
+    // Hands `slot` to the device with a frame of `len` bytes, which it
+    // fills if `device_writes`.
+    fn add(&mut self, slot: usize, len: usize, device_writes: bool) -> Option<()> {
+        let base = self.memory.phys() + (slot * SLOT_SIZE) as u64;
+        let header = Buffer {
+            addr: base,
+            len: HEADER_SIZE as u32,
+            device_writes,
+        };
+        let frame = Buffer {
+            addr: base + FRAME_OFFSET as u64,
+            len: len as u32,
+            device_writes,
+        };
+        let id = self.queue.add(&[header, frame])?;
+        self.slot_of[id as usize] = slot;
+        Some(())
+    }
+
+    fn slot(&mut self, slot: usize) -> &mut [u8] {
+        &mut self.memory.as_mut_slice()[slot * SLOT_SIZE..(slot + 1) * SLOT_SIZE]
+    }
+}
+
+impl VirtioNet {
+    fn new(pci: pci::PciAddress, name: String) -> Result<Self, VirtioError> {
+        let device = LegacyDevice::new(pci)?;
+        let features = device.negotiate(FEATURE_MAC | FEATURE_STATUS);
+        let rings = Ring::new(&device, RECEIVE_QUEUE, RECEIVE_SLOTS)
+            .and_then(|receive| Ok((receive, Ring::new(&device, TRANSMIT_QUEUE, TRANSMIT_SLOTS)?)));
+        let (mut receive, transmit) = match rings {
+            Ok(rings) => rings,
+            Err(err) => {
+                device.fail();
+                return Err(err);
+            }
+        };
+        for slot in 0..RECEIVE_SLOTS {
+            if receive.add(slot, MAX_FRAME, true).is_none() {
+                break;
+            }
+        }
+        device.notify(&receive.queue);
+        device.driver_ok();
+
+        let mac = if features & FEATURE_MAC != 0 {
+            core::array::from_fn(|i| device.config_u8(CONFIG_MAC + i as u16))
+        } else {
+            // QEMU's prefix, locally administered.
+            let random = crate::random::next_u64().to_le_bytes();
+            [0x52, 0x54, 0x00, random[0], random[1], random[2]]
+        };
+        Ok(VirtioNet {
+            name,
+            mac,
+            features,
+            inner: Mutex::new(Inner {
+                device,
+                receive,
+                transmit,
+                free: (0..TRANSMIT_SLOTS).collect(),
+            }),
+        })
+    }
+}
+
+impl Nic for VirtioNet {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn mac_address(&self) -> [u8; 6] {
+        self.mac
+    }
+
+    // Without the status feature the link is always up.
+    fn link_up(&self) -> bool {
+        if self.features & FEATURE_STATUS == 0 {
+            return true;
+        }
+        self.inner.lock().device.config_u16(CONFIG_STATUS) & STATUS_LINK_UP != 0
+    }
+
+    fn transmit(&self, frame: &[u8]) -> Result<(), NetError> {
+        if frame.len() > MAX_FRAME {
+            return Err(NetError::InvalidArgument);
+        }
+        let inner = &mut *self.inner.lock();
+        while let Some((id, _)) = inner.transmit.queue.pop_used() {
+            inner.free.push(inner.transmit.slot_of[id as usize]);
+        }
+        let slot = inner.free.pop().ok_or(NetError::Busy)?;
+        let buffer = inner.transmit.slot(slot);
+        buffer[..HEADER_SIZE].fill(0);
+        buffer[FRAME_OFFSET..FRAME_OFFSET + frame.len()].copy_from_slice(frame);
+        if inner.transmit.add(slot, frame.len(), false).is_none() {
+            inner.free.push(slot);
+            return Err(NetError::Busy);
+        }
+        inner.device.notify(&inner.transmit.queue);
+        Ok(())
+    }
+
+    fn receive(&self, buf: &mut [u8]) -> Option<usize> {
+        let inner = &mut *self.inner.lock();
+        let (id, written) = inner.receive.queue.pop_used()?;
+        let slot = inner.receive.slot_of[id as usize];
+        let len = (written as usize).saturating_sub(HEADER_SIZE).min(MAX_FRAME).min(buf.len());
+        buf[..len].copy_from_slice(&inner.receive.slot(slot)[FRAME_OFFSET..FRAME_OFFSET + len]);
+        inner.receive.add(slot, MAX_FRAME, true);
+        inner.device.notify(&inner.receive.queue);
+        Some(len)
+    }
+}
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "virtio-net",
+    matches: &[pci::DeviceMatch::id(virtio::VENDOR_ID, DEVICE_ID)],
+    probe,
+};
+
+fn probe(device: &'static pci::PciDevice) -> bool {
+    match VirtioNet::new(device.address, allocate_name()) {
+        Ok(nic) => {
+            crate::print!("\n{}: virtio-net at {}, {}", nic.name, device.address, EthernetAddress(nic.mac));
+            register(Arc::new(nic));
+            true
+        }
+        Err(err) => {
+            crate::print!("\nvirtio-net {}: {:?}", device.address, err);
+            false
+        }
+    }
+}
@@ -0,0 +1,672 @@
+// PCI: configuration space access, bus enumeration and driver binding.
+//
+// Configuration space is reached through the legacy 0xCF8/0xCFC port pair,
+// which covers the first 256 bytes of every function, or through PCI
+// Express ECAM when the ACPI MCFG table describes a window: memory-mapped,
+// 4 KiB per function. ECAM is mapped a bus at a time as enumeration reaches
+// it; anything outside what was mapped goes through the ports.
+//
+// `init` walks the buses from the host bridge down through PCI-to-PCI
+// bridges and records each function with its sized BARs and capabilities.
+// Drivers describe the devices they handle with `DeviceMatch`es and are
+// offered every unclaimed matching function when they register.
+
+mod capability;
+mod ids;
+
+pub use capability::{Capability, ExtendedCapability};
+
+use crate::acpi;
+use crate::memory::paging::map_mmio;
+use crate::shell;
+use crate::sync::{Mutex, SpinLock};
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Once;
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+const CONFIG_ADDRESS: u16 = 0xCF8;
+const CONFIG_DATA: u16 = 0xCFC;
+
+// Configuration space offsets.
+const VENDOR_ID: u16 = 0x00;
+const DEVICE_ID: u16 = 0x02;
+const COMMAND: u16 = 0x04;
+const STATUS: u16 = 0x06;
+const REVISION: u16 = 0x08;
+const PROG_IF: u16 = 0x09;
+const SUBCLASS: u16 = 0x0A;
+const CLASS: u16 = 0x0B;
+const HEADER_TYPE: u16 = 0x0E;
+const BAR0: u16 = 0x10;
+const SECONDARY_BUS: u16 = 0x19;
+const SUBSYSTEM_VENDOR_ID: u16 = 0x2C;
+const SUBSYSTEM_ID: u16 = 0x2E;
+const CAPABILITIES: u16 = 0x34;
+const INTERRUPT_LINE: u16 = 0x3C;
+const INTERRUPT_PIN: u16 = 0x3D;
+
+const COMMAND_IO: u16 = 1 << 0;
+const COMMAND_MEMORY: u16 = 1 << 1;
+const COMMAND_BUS_MASTER: u16 = 1 << 2;
+const COMMAND_INTX_DISABLE: u16 = 1 << 10;
+
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+const MULTI_FUNCTION: u8 = 0x80;
+const HEADER_GENERAL: u8 = 0x00;
+const HEADER_BRIDGE: u8 = 0x01;
+
+const CLASS_BRIDGE: u8 = 0x06;
+const SUBCLASS_PCI_BRIDGE: u8 = 0x04;
+
+const LEGACY_CONFIG_SIZE: u16 = 256;
+const ECAM_CONFIG_SIZE: u16 = 4096;
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+// The address/data pair is one shared register window.
+static CONFIG_LOCK: SpinLock<()> = SpinLock::new(());
+
+// Segment group 0's ECAM window, if the firmware has one.
+struct Ecam {
+    base: PhysAddr,
+    start_bus: u8,
+    end_bus: u8,
+    // Virtual address of each bus's configuration space, 0 until mapped.
+    buses: [AtomicU64; 256],
+}
+
+static ECAM: Once<Ecam> = Once::new();
+// Taken while mapping a bus, so it is only mapped once.
+static ECAM_MAP_LOCK: Mutex<()> = Mutex::new(());
+
+impl Ecam {
+    fn bus(&self, bus: u8) -> Option<u64> {
+        Some(self.buses[bus as usize].load(Ordering::Acquire)).filter(|&base| base != 0)
+    }
+
+    // Makes `bus` reachable through ECAM, if the window covers it.
+    fn map_bus(&self, bus: u8) {
+        if bus < self.start_bus || bus > self.end_bus {
+            return;
+        }
+        let _guard = ECAM_MAP_LOCK.lock();
+        if self.bus(bus).is_some() {
+            return;
+        }
+        let phys = self.base + (bus - self.start_bus) as u64 * ECAM_BUS_SIZE;
+        match map_mmio(phys, ECAM_BUS_SIZE) {
+            Ok(base) => self.buses[bus as usize].store(base.as_u64(), Ordering::Release),
+            Err(err) => crate::print!("\npci: cannot map ECAM for bus {} ({:?})", bus, err),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciAddress {
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{:02x}:{:02x}.{}", self.bus, self.device, self.function)
+    }
+}
+
+/// Where a base address register points.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Io(u16),
+    Memory(u64),
+}
+
+impl PciAddress {
+    fn config_address(&self, offset: u16) -> u32 {
+        1 << 31
+            | (self.bus as u32) << 16
+            | (self.device as u32) << 11
+            | (self.function as u32) << 8
+            | (offset & 0xFC) as u32
+    }
+
+    // The register's address in ECAM, if the bus is mapped there.
+    fn ecam_register(&self, offset: u16) -> Option<*mut u32> {
+        let base = ECAM.get()?.bus(self.bus)?;
+        let function = (self.device as u64) << 15 | (self.function as u64) << 12;
+        Some((base + function + (offset & 0xFFC) as u64) as *mut u32)
+    }
+
+    /// How much configuration space can be reached: 4 KiB through ECAM,
+    /// 256 bytes through the ports.
+    pub fn config_size(&self) -> u16 {
+        if self.ecam_register(0).is_some() { ECAM_CONFIG_SIZE } else { LEGACY_CONFIG_SIZE }
+    }
+
+    /// Reads the aligned dword containing `offset`. Offsets past what can be
+    /// reached read as all ones, like a missing device.
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        if let Some(register) = self.ecam_register(offset) {
+            return unsafe { register.read_volatile() };
+        }
+        if offset >= LEGACY_CONFIG_SIZE {
+            return u32::MAX;
+        }
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let _guard = CONFIG_LOCK.lock();
+            unsafe {
+                Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+                Port::<u32>::new(CONFIG_DATA).read()
+            }
+        })
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        if let Some(register) = self.ecam_register(offset) {
+            return unsafe { register.write_volatile(value) };
+        }
+        if offset >= LEGACY_CONFIG_SIZE {
+            return;
+        }
+        x86_64::instructions::interrupts::without_interrupts(|| {
+            let _guard = CONFIG_LOCK.lock();
+            unsafe {
+                Port::<u32>::new(CONFIG_ADDRESS).write(self.config_address(offset));
+                Port::<u32>::new(CONFIG_DATA).write(value);
+            }
+        })
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        (self.read_u32(offset) >> ((offset & 2) * 8)) as u16
+    }
+
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        let shift = (offset & 2) * 8;
+        let old = self.read_u32(offset) & !(0xFFFF << shift);
+        self.write_u32(offset, old | (value as u32) << shift);
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        (self.read_u32(offset) >> ((offset & 3) * 8)) as u8
+    }
+
+    pub fn vendor_id(&self) -> u16 {
+        self.read_u16(VENDOR_ID)
+    }
+
+    pub fn device_id(&self) -> u16 {
+        self.read_u16(DEVICE_ID)
+    }
+
+    /// Base address register `index`, or `None` if it is unused. A 64-bit
+    /// memory BAR also consumes the register after it.
+    pub fn bar(&self, index: u8) -> Option<Bar> {
+        let offset = BAR0 + index as u16 * 4;
+        let low = self.read_u32(offset);
+        if low & 1 == 1 {
+            let port = (low & !0x3) as u16;
+            return (port != 0).then_some(Bar::Io(port));
+        }
+        let mut addr = (low & !0xF) as u64;
+        if (low >> 1) & 0b11 == 0b10 {
+            addr |= (self.read_u32(offset + 4) as u64) << 32;
+        }
+        (addr != 0).then_some(Bar::Memory(addr))
+    }
+
+    /// Lets the device decode its BARs and do DMA. Its legacy interrupt line
+    /// stays off until `enable_legacy_interrupt`; drivers poll or use
+    /// message-signalled interrupts.
+    pub fn enable_bus_master(&self) {
+        let command = self.read_u16(COMMAND);
+        self.write_u16(
+            COMMAND,
+            command | COMMAND_IO | COMMAND_MEMORY | COMMAND_BUS_MASTER | COMMAND_INTX_DISABLE,
+        );
+    }
+
+    /// Lets the device raise its legacy interrupt line again, for drivers
+    /// that take interrupts through the PIC.
+    pub fn enable_legacy_interrupt(&self) {
+        let command = self.read_u16(COMMAND);
+        self.write_u16(COMMAND, command & !COMMAND_INTX_DISABLE);
+    }
+
+    fn exists(&self) -> bool {
+        self.vendor_id() != 0xFFFF
+    }
+}
+
+/// A base address register with the size of the range behind it.
+#[derive(Debug, Clone, Copy)]
+pub struct BarInfo {
+    pub index: u8,
+    /// Where it points; an address of 0 means firmware left it unassigned.
+    pub bar: Bar,
+    pub size: u64,
+    pub wide: bool,
+    pub prefetchable: bool,
+}
+
+/// A function found while enumerating, with what its configuration space
+/// said at the time.
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    /// Only general (type 0) headers have these.
+    pub subsystem: Option<(u16, u16)>,
+    pub interrupt_line: u8,
+    /// 1 to 4 for INTA# to INTD#, 0 for none.
+    pub interrupt_pin: u8,
+    pub bars: Vec<BarInfo>,
+    pub capabilities: Vec<Capability>,
+    pub extended_capabilities: Vec<ExtendedCapability>,
+    driver: Once<&'static str>,
+    msix_table: Once<u64>,
+}
+
+impl PciDevice {
+    fn probe(address: PciAddress) -> PciDevice {
+        let header_type = address.read_u8(HEADER_TYPE) & !MULTI_FUNCTION;
+        PciDevice {
+            address,
+            vendor_id: address.vendor_id(),
+            device_id: address.device_id(),
+            class: address.read_u8(CLASS),
+            subclass: address.read_u8(SUBCLASS),
+            prog_if: address.read_u8(PROG_IF),
+            revision: address.read_u8(REVISION),
+            header_type,
+            subsystem: (header_type == HEADER_GENERAL)
+                .then(|| (address.read_u16(SUBSYSTEM_VENDOR_ID), address.read_u16(SUBSYSTEM_ID))),
+            interrupt_line: address.read_u8(INTERRUPT_LINE),
+            interrupt_pin: address.read_u8(INTERRUPT_PIN),
+            bars: size_bars(address, header_type),
+            capabilities: match address.read_u16(STATUS) & STATUS_CAPABILITIES {
+                0 => Vec::new(),
+                _ => capability::parse(address, address.read_u8(CAPABILITIES)),
+            },
+            extended_capabilities: match address.config_size() {
+                ECAM_CONFIG_SIZE => capability::parse_extended(address),
+                _ => Vec::new(),
+            },
+            driver: Once::new(),
+            msix_table: Once::new(),
+        }
+    }
+
+    /// Name of the driver that claimed the device.
+    pub fn driver(&self) -> Option<&'static str> {
+        self.driver.get().copied()
+    }
+
+    /// Vendor and device as the ID database knows them.
+    pub fn name(&self) -> String {
+        let vendor = ids::vendor_name(self.vendor_id);
+        let device = ids::device_name(self.vendor_id, self.device_id);
+        match (vendor, device) {
+            (Some(vendor), Some(device)) => format!("{} {}", vendor, device),
+            (Some(vendor), None) => format!("{} device {:04x}", vendor, self.device_id),
+            _ => format!("Device {:04x}:{:04x}", self.vendor_id, self.device_id),
+        }
+    }
+
+    pub fn class_name(&self) -> &'static str {
+        ids::class_name(self.class, self.subclass)
+    }
+
+    /// Points the device's MSI at `vector` on the CPU with local APIC ID
+    /// `apic_id` and turns it on, with a single message. Returns false if
+    /// the device can't do MSI.
+    pub fn enable_msi(&self, vector: u8, apic_id: u32) -> bool {
+        let Some(&Capability::Msi { offset, wide, .. }) =
+            self.capabilities.iter().find(|c| matches!(c, Capability::Msi { .. }))
+        else {
+            return false;
+        };
+        let address = self.address;
+        let (low, high) = capability::msi_message_address(apic_id);
+        address.write_u32(offset + 4, low);
+        let data = if wide {
+            address.write_u32(offset + 8, high);
+            offset + 12
+        } else {
+            offset + 8
+        };
+        address.write_u16(data, vector as u16);
+        // One message, enabled.
+        let control = address.read_u16(offset + 2) & !capability::MSI_MULTIPLE_ENABLE;
+        address.write_u16(offset + 2, control | capability::MSI_ENABLE);
+        address.enable_bus_master();
+        true
+    }
+
+    /// Points MSI-X table entry `entry` at `vector` on the CPU with local
+    /// APIC ID `apic_id`, unmasks it and turns MSI-X on. Returns false if
+    /// the device can't do MSI-X, has no such entry or its table can't be
+    /// mapped.
+    pub fn enable_msix(&self, entry: u16, vector: u8, apic_id: u32) -> bool {
+        let Some(&Capability::MsiX { offset, table_size, table_bar, table_offset, .. }) =
+            self.capabilities.iter().find(|c| matches!(c, Capability::MsiX { .. }))
+        else {
+            return false;
+        };
+        if entry >= table_size {
+            return false;
+        }
+        let table = match self.msix_table.get() {
+            Some(&table) => table,
+            None => {
+                let Some(Bar::Memory(base)) = self.address.bar(table_bar) else {
+                    return false;
+                };
+                let size = table_size as u64 * capability::MSIX_ENTRY_SIZE;
+                let Ok(table) = map_mmio(PhysAddr::new(base + table_offset as u64), size) else {
+                    return false;
+                };
+                *self.msix_table.call_once(|| table.as_u64())
+            }
+        };
+        let (low, high) = capability::msi_message_address(apic_id);
+        let slot = (table + entry as u64 * capability::MSIX_ENTRY_SIZE) as *mut u32;
+        unsafe {
+            slot.write_volatile(low);
+            slot.add(1).write_volatile(high);
+            slot.add(2).write_volatile(vector as u32);
+            // Vector control: bit 0 masks the entry.
+            slot.add(3).write_volatile(0);
+        }
+        let control = self.address.read_u16(offset + 2) & !capability::MSIX_FUNCTION_MASK;
+        self.address.write_u16(offset + 2, control | capability::MSIX_ENABLE);
+        self.address.enable_bus_master();
+        true
+    }
+}
+
+// Sizes the BARs by writing all ones and reading back which address bits
+// stick. Decoding is off meanwhile, so the device doesn't answer at the
+// bogus address.
+fn size_bars(address: PciAddress, header_type: u8) -> Vec<BarInfo> {
+    let count = match header_type {
+        HEADER_GENERAL => 6,
+        HEADER_BRIDGE => 2,
+        _ => 0,
+    };
+    let command = address.read_u16(COMMAND);
+    address.write_u16(COMMAND, command & !(COMMAND_IO | COMMAND_MEMORY));
+
+    let mut bars = Vec::new();
+    let mut index = 0;
+    while index < count {
+        let offset = BAR0 + index as u16 * 4;
+        let original = address.read_u32(offset);
+        address.write_u32(offset, u32::MAX);
+        let mask = address.read_u32(offset);
+        address.write_u32(offset, original);
+
+        if original & 1 == 1 {
+            // I/O BARs may implement only the low 16 address bits.
+            let mask = (mask & !0x3) | if mask >> 16 == 0 { 0xFFFF_0000 } else { 0 };
+            let size = (!mask).wrapping_add(1) as u64;
+            if mask & !0xFFFF_0000 != 0 {
+                bars.push(BarInfo {
+                    index,
+                    bar: Bar::Io((original & !0x3) as u16),
+                    size,
+                    wide: false,
+                    prefetchable: false,
+                });
+            }
+            index += 1;
+            continue;
+        }
+
+        let wide = (original >> 1) & 0b11 == 0b10;
+        let mut base = (original & !0xF) as u64;
+        let mut mask = (mask & !0xF) as u64 | 0xFFFF_FFFF_0000_0000;
+        if wide && index + 1 < count {
+            let original_high = address.read_u32(offset + 4);
+            address.write_u32(offset + 4, u32::MAX);
+            let mask_high = address.read_u32(offset + 4);
+            address.write_u32(offset + 4, original_high);
+            base |= (original_high as u64) << 32;
+            mask = (mask & 0xFFFF_FFFF) | (mask_high as u64) << 32;
+        }
+        if mask & 0xFFFF_FFF0 != 0 || mask >> 32 != 0xFFFF_FFFF {
+            bars.push(BarInfo {
+                index,
+                bar: Bar::Memory(base),
+                size: (!mask).wrapping_add(1),
+                wide,
+                prefetchable: original & 0x8 != 0,
+            });
+        }
+        index += if wide { 2 } else { 1 };
+    }
+
+    address.write_u16(COMMAND, command);
+    bars
+}
+
+static DEVICES: Once<Vec<PciDevice>> = Once::new();
+
+// Walks the bus hierarchy depth first, like firmware numbers it.
+fn enumerate() -> Vec<PciDevice> {
+    let mut found = Vec::new();
+    let mut scanned = [false; 256];
+    let host = PciAddress { bus: 0, device: 0, function: 0 };
+    if let Some(ecam) = ECAM.get() {
+        ecam.map_bus(0);
+    }
+    if host.read_u8(HEADER_TYPE) & MULTI_FUNCTION == 0 {
+        scan_bus(0, &mut found, &mut scanned);
+    } else {
+        // Several host controllers; function N owns bus N.
+        for function in 0..8u8 {
+            if (PciAddress { function, ..host }).exists() {
+                scan_bus(function, &mut found, &mut scanned);
+            }
+        }
+    }
+    found
+}
+
+fn scan_bus(bus: u8, found: &mut Vec<PciDevice>, scanned: &mut [bool; 256]) {
+    if core::mem::replace(&mut scanned[bus as usize], true) {
+        return;
+    }
+    if let Some(ecam) = ECAM.get() {
+        ecam.map_bus(bus);
+    }
+    for device in 0..32u8 {
+        let first = PciAddress { bus, device, function: 0 };
+        if !first.exists() {
+            continue;
+        }
+        let functions = if first.read_u8(HEADER_TYPE) & MULTI_FUNCTION != 0 { 8 } else { 1 };
+        for function in 0..functions {
+            let address = PciAddress { function, ..first };
+            if !address.exists() {
+                continue;
+            }
+            let device = PciDevice::probe(address);
+            let bridge = device.class == CLASS_BRIDGE && device.subclass == SUBCLASS_PCI_BRIDGE;
+            found.push(device);
+            if bridge {
+                scan_bus(address.read_u8(SECONDARY_BUS), found, scanned);
+            }
+        }
+    }
+}
+
+/// Every function present on the system, in enumeration order.
+pub fn devices() -> &'static [PciDevice] {
+    DEVICES.call_once(enumerate)
+}
+
+/// Sets up ECAM if the ACPI tables have a window for segment group 0, then
+/// enumerates the buses and adds the `lspci` command.
+pub fn init(rsdp: Option<u64>) {
+    let windows = rsdp.ok_or(acpi::AcpiError::BadRsdp).and_then(acpi::parse_mcfg);
+    if let Some(window) = windows.iter().flatten().find(|window| window.segment == 0) {
+        ECAM.call_once(|| Ecam {
+            base: window.base,
+            start_bus: window.start_bus,
+            end_bus: window.end_bus,
+            buses: [const { AtomicU64::new(0) }; 256],
+        });
+    }
+    let count = devices().len();
+    match ECAM.get() {
+        Some(ecam) => crate::print!("\npci: {} functions, ECAM at {:#x}", count, ecam.base.as_u64()),
+        None => crate::print!("\npci: {} functions, configuration through ports", count),
+    }
+    shell::register("lspci", "list PCI devices (-v for BARs and capabilities)", lspci);
+}
+
+/// Which devices a driver handles. Fields left `None` match anything.
+#[derive(Debug, Clone, Copy)]
+pub struct DeviceMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+}
+
+impl DeviceMatch {
+    /// One particular device.
+    pub const fn id(vendor_id: u16, device_id: u16) -> DeviceMatch {
+        DeviceMatch {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+        }
+    }
+
+    /// Every device of a class, like all IDE controllers.
+    pub const fn class(class: u8, subclass: u8) -> DeviceMatch {
+        DeviceMatch {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+        }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        self.vendor_id.is_none_or(|id| id == device.vendor_id)
+            && self.device_id.is_none_or(|id| id == device.device_id)
+            && self.class.is_none_or(|class| class == device.class)
+            && self.subclass.is_none_or(|subclass| subclass == device.subclass)
+    }
+}
+
+pub struct Driver {
+    pub name: &'static str,
+    pub matches: &'static [DeviceMatch],
+    /// Sets the device up; returns whether the driver took it.
+    pub probe: fn(&'static PciDevice) -> bool,
+}
+
+// Held while a driver is offered devices, so two drivers can't both claim
+// one.
+static BIND_LOCK: Mutex<()> = Mutex::new(());
+
+/// Offers `driver` every unclaimed device it matches, in enumeration order.
+pub fn register_driver(driver: &'static Driver) {
+    let _guard = BIND_LOCK.lock();
+    for device in devices() {
+        if device.driver().is_none() && driver.matches.iter().any(|m| m.matches(device)) && (driver.probe)(device) {
+            device.driver.call_once(|| driver.name);
+        }
+    }
+}
+
+fn lspci(args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+    let verbose = match args {
+        [] => false,
+        ["-v"] => true,
+        _ => return Err(String::from("usage: lspci [-v]")),
+    };
+    for device in devices() {
+        let _ = write!(
+            out,
+            "{} {}: {} [{:04x}:{:04x}]",
+            device.address,
+            device.class_name(),
+            device.name(),
+            device.vendor_id,
+            device.device_id
+        );
+        if device.revision != 0 {
+            let _ = write!(out, " (rev {:02x})", device.revision);
+        }
+        let _ = writeln!(out);
+        if verbose {
+            let _ = describe(device, out);
+        }
+    }
+    Ok(())
+}
+
+fn describe(device: &PciDevice, out: &mut dyn Write) -> fmt::Result {
+    writeln!(
+        out,
+        "\tClass {:02x}{:02x}, programming interface {:02x}, header type {:02x}",
+        device.class, device.subclass, device.prog_if, device.header_type
+    )?;
+    if let Some((vendor, id)) = device.subsystem.filter(|&(vendor, _)| vendor != 0) {
+        writeln!(out, "\tSubsystem: {:04x}:{:04x}", vendor, id)?;
+    }
+    if device.interrupt_pin != 0 {
+        let pin = (b'A' + device.interrupt_pin - 1) as char;
+        writeln!(out, "\tInterrupt: pin {} routed to IRQ {}", pin, device.interrupt_line)?;
+    }
+    for bar in &device.bars {
+        let size = human_size(bar.size);
+        match bar.bar {
+            Bar::Io(port) => writeln!(out, "\tBAR{}: I/O ports at {:#x} [size={}]", bar.index, port, size)?,
+            Bar::Memory(addr) => writeln!(
+                out,
+                "\tBAR{}: memory at {:#x} ({}-bit, {}) [size={}]",
+                bar.index,
+                addr,
+                if bar.wide { 64 } else { 32 },
+                if bar.prefetchable { "prefetchable" } else { "non-prefetchable" },
+                size
+            )?,
+        }
+    }
+    for capability in &device.capabilities {
+        writeln!(out, "\tCapability: {}", capability)?;
+    }
+    for capability in &device.extended_capabilities {
+        writeln!(out, "\tExtended capability: {}", capability)?;
+    }
+    if let Some(driver) = device.driver() {
+        writeln!(out, "\tKernel driver in use: {}", driver)?;
+    }
+    Ok(())
+}
+
+fn human_size(bytes: u64) -> String {
+    match bytes {
+        size if size >= 1 << 30 && size % (1 << 30) == 0 => format!("{}G", size >> 30),
+        size if size >= 1 << 20 && size % (1 << 20) == 0 => format!("{}M", size >> 20),
+        size if size >= 1 << 10 && size % (1 << 10) == 0 => format!("{}K", size >> 10),
+        size => format!("{}", size),
+    }
+}
@@ -0,0 +1,170 @@
+// The capability lists in configuration space: the classic one starting at
+// the capabilities pointer, a chain of (ID, next) bytes, and the PCI Express
+// extended one from offset 0x100, a chain of dwords that needs ECAM to reach.
+// MSI and MSI-X are decoded; everything else is kept by ID for lspci.
+
+use super::PciAddress;
+use alloc::vec::Vec;
+use core::fmt;
+
+const ID_POWER_MANAGEMENT: u8 = 0x01;
+const ID_MSI: u8 = 0x05;
+const ID_VENDOR: u8 = 0x09;
+const ID_PCI_EXPRESS: u8 = 0x10;
+const ID_MSIX: u8 = 0x11;
+const ID_SATA: u8 = 0x12;
+
+const EXTENDED_START: u16 = 0x100;
+
+pub(super) const MSI_ENABLE: u16 = 1 << 0;
+pub(super) const MSI_MULTIPLE_ENABLE: u16 = 0b111 << 4;
+const MSI_64BIT: u16 = 1 << 7;
+const MSI_PER_VECTOR_MASK: u16 = 1 << 8;
+
+pub(super) const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+pub(super) const MSIX_ENABLE: u16 = 1 << 15;
+pub(super) const MSIX_ENTRY_SIZE: u64 = 16;
+
+// Messages are writes to the local APIC's window; the destination APIC ID
+// goes in bits 12 to 19.
+const MSI_ADDRESS_BASE: u32 = 0xFEE0_0000;
+
+// Longest chain followed, in case a broken device links back into it.
+const MAX_CAPABILITIES: usize = 48;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Capability {
+    Msi {
+        offset: u16,
+        /// Takes a 64-bit message address.
+        wide: bool,
+        per_vector_mask: bool,
+        /// How many vectors the device asks for.
+        vectors: u8,
+    },
+    MsiX {
+        offset: u16,
+        table_size: u16,
+        table_bar: u8,
+        table_offset: u32,
+        pba_bar: u8,
+        pba_offset: u32,
+    },
+    Other {
+        id: u8,
+        offset: u16,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct ExtendedCapability {
+    pub id: u16,
+    pub version: u8,
+    pub offset: u16,
+}
+
+pub(super) fn parse(address: PciAddress, pointer: u8) -> Vec<Capability> {
+    let mut found = Vec::new();
+    let mut offset = (pointer & 0xFC) as u16;
+    while offset >= 0x40 && found.len() < MAX_CAPABILITIES {
+        let header = address.read_u16(offset);
+        let (id, next) = (header as u8, (header >> 8) as u8);
+        found.push(match id {
+            ID_MSI => {
+                let control = address.read_u16(offset + 2);
+                Capability::Msi {
+                    offset,
+                    wide: control & MSI_64BIT != 0,
+                    per_vector_mask: control & MSI_PER_VECTOR_MASK != 0,
+                    vectors: 1 << ((control >> 1) & 0b111),
+                }
+            }
+            ID_MSIX => {
+                let control = address.read_u16(offset + 2);
+                let table = address.read_u32(offset + 4);
+                let pba = address.read_u32(offset + 8);
+                Capability::MsiX {
+                    offset,
+                    table_size: (control & 0x7FF) + 1,
+                    table_bar: (table & 0b111) as u8,
+                    table_offset: table & !0b111,
+                    pba_bar: (pba & 0b111) as u8,
+                    pba_offset: pba & !0b111,
+                }
+            }
+            id => Capability::Other { id, offset },
+        });
+        offset = (next & 0xFC) as u16;
+    }
+    found
+}
+
+pub(super) fn parse_extended(address: PciAddress) -> Vec<ExtendedCapability> {
+    let mut found = Vec::new();
+    let mut offset = EXTENDED_START;
+    while offset >= EXTENDED_START && found.len() < MAX_CAPABILITIES {
+        let header = address.read_u32(offset);
+        // An all-zero header means an empty list; all ones, nothing there.
+        if header == 0 || header == u32::MAX {
+            break;
+        }
+        found.push(ExtendedCapability {
+            id: header as u16,
+            version: ((header >> 16) & 0xF) as u8,
+            offset,
+        });
+        offset = ((header >> 20) & 0xFFC) as u16;
+    }
+    found
+}
+
+/// The message address, low and high dwords, that interrupts the CPU with
+/// local APIC ID `apic_id`.
+pub(super) fn msi_message_address(apic_id: u32) -> (u32, u32) {
+    (MSI_ADDRESS_BASE | (apic_id & 0xFF) << 12, 0)
+}
+
+impl fmt::Display for Capability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Capability::Msi { offset, wide, per_vector_mask, vectors } => write!(
+                f,
+                "[{:02x}] MSI: {} vector(s), {}-bit{}",
+                offset,
+                vectors,
+                if wide { 64 } else { 32 },
+                if per_vector_mask { ", maskable" } else { "" }
+            ),
+            Capability::MsiX { offset, table_size, table_bar, table_offset, pba_bar, pba_offset } => write!(
+                f,
+                "[{:02x}] MSI-X: {} entries, table at BAR{}+{:#x}, PBA at BAR{}+{:#x}",
+                offset, table_size, table_bar, table_offset, pba_bar, pba_offset
+            ),
+            Capability::Other { id, offset } => {
+                let name = match id {
+                    ID_POWER_MANAGEMENT => "Power Management",
+                    ID_VENDOR => "Vendor Specific",
+                    ID_PCI_EXPRESS => "PCI Express",
+                    ID_SATA => "SATA",
+                    _ => "Unknown",
+                };
+                write!(f, "[{:02x}] {} ({:#04x})", offset, name, id)
+            }
+        }
+    }
+}
+
+impl fmt::Display for ExtendedCapability {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self.id {
+            0x0001 => "Advanced Error Reporting",
+            0x0002 => "Virtual Channel",
+            0x0003 => "Device Serial Number",
+            0x000B => "Vendor Specific",
+            0x000E => "Alternative Routing-ID",
+            0x0010 => "SR-IOV",
+            _ => "Unknown",
+        };
+        write!(f, "[{:03x}] {} ({:#06x}) v{}", self.offset, name, self.id, self.version)
+    }
+}
@@ -0,0 +1,103 @@
+// A small slice of the PCI ID database: the vendors and devices QEMU's
+// machines and common virtual hardware present, and the class codes.
+// Anything missing is shown by number.
+
+const VENDORS: &[(u16, &str)] = &[
+    (0x1022, "AMD"),
+    (0x10DE, "NVIDIA"),
+    (0x10EC, "Realtek"),
+    (0x1234, "QEMU"),
+    (0x15AD, "VMware"),
+    (0x1AF4, "Red Hat, Inc."),
+    (0x1B36, "Red Hat, Inc."),
+    (0x8086, "Intel Corporation"),
+];
+
+const DEVICES: &[(u16, u16, &str)] = &[
+    (0x1234, 0x1111, "Standard VGA"),
+    (0x10EC, 0x8139, "RTL-8100/8101L/8139 Fast Ethernet"),
+    (0x1AF4, 0x1000, "Virtio network device"),
+    (0x1AF4, 0x1001, "Virtio block device"),
+    (0x1AF4, 0x1002, "Virtio memory balloon"),
+    (0x1AF4, 0x1003, "Virtio console"),
+    (0x1AF4, 0x1004, "Virtio SCSI"),
+    (0x1AF4, 0x1005, "Virtio RNG"),
+    (0x1AF4, 0x1041, "Virtio 1.0 network device"),
+    (0x1AF4, 0x1042, "Virtio 1.0 block device"),
+    (0x1AF4, 0x1050, "Virtio 1.0 GPU"),
+    (0x1B36, 0x0001, "QEMU PCI-PCI bridge"),
+    (0x1B36, 0x0008, "QEMU PCIe Host bridge"),
+    (0x1B36, 0x000C, "QEMU PCIe Root port"),
+    (0x1B36, 0x000D, "QEMU XHCI Host Controller"),
+    (0x8086, 0x100E, "82540EM Gigabit Ethernet Controller"),
+    (0x8086, 0x10D3, "82574L Gigabit Network Connection"),
+    (0x8086, 0x1237, "440FX - 82441FX PMC [Natoma]"),
+    (0x8086, 0x2415, "82801AA AC'97 Audio Controller"),
+    (0x8086, 0x2668, "82801FB/FBM/FR/FW/FRW (ICH6 Family) High Definition Audio Controller"),
+    (0x8086, 0x2918, "82801IB (ICH9) LPC Interface Controller"),
+    (0x8086, 0x2922, "82801IR/IO/IH (ICH9R/DO/DH) 6 port SATA Controller [AHCI mode]"),
+    (0x8086, 0x2930, "82801I (ICH9 Family) SMBus Controller"),
+    (0x8086, 0x293E, "82801I (ICH9 Family) HD Audio Controller"),
+    (0x8086, 0x29C0, "82G33/G31/P35/P31 Express DRAM Controller"),
+    (0x8086, 0x7000, "82371SB PIIX3 ISA [Natoma/Triton II]"),
+    (0x8086, 0x7010, "82371SB PIIX3 IDE [Natoma/Triton II]"),
+    (0x8086, 0x7020, "82371SB PIIX3 USB [Natoma/Triton II]"),
+    (0x8086, 0x7110, "82371AB/EB/MB PIIX4 ISA"),
+    (0x8086, 0x7111, "82371AB/EB/MB PIIX4 IDE"),
+    (0x8086, 0x7113, "82371AB/EB/MB PIIX4 ACPI"),
+];
+
+// Class names, with the subclass where it says more than the class.
+const CLASSES: &[(u8, &str)] = &[
+    (0x00, "Unclassified device"),
+    (0x01, "Mass storage controller"),
+    (0x02, "Network controller"),
+    (0x03, "Display controller"),
+    (0x04, "Multimedia controller"),
+    (0x05, "Memory controller"),
+    (0x06, "Bridge"),
+    (0x07, "Communication controller"),
+    (0x08, "Generic system peripheral"),
+    (0x09, "Input device controller"),
+    (0x0C, "Serial bus controller"),
+    (0x0D, "Wireless controller"),
+    (0x10, "Encryption controller"),
+    (0x11, "Signal processing controller"),
+];
+
+const SUBCLASSES: &[(u8, u8, &str)] = &[
+    (0x01, 0x00, "SCSI storage controller"),
+    (0x01, 0x01, "IDE interface"),
+    (0x01, 0x06, "SATA controller"),
+    (0x01, 0x08, "Non-Volatile memory controller"),
+    (0x02, 0x00, "Ethernet controller"),
+    (0x03, 0x00, "VGA compatible controller"),
+    (0x04, 0x01, "Multimedia audio controller"),
+    (0x04, 0x03, "Audio device"),
+    (0x06, 0x00, "Host bridge"),
+    (0x06, 0x01, "ISA bridge"),
+    (0x06, 0x04, "PCI bridge"),
+    (0x06, 0x80, "Bridge"),
+    (0x0C, 0x03, "USB controller"),
+    (0x0C, 0x05, "SMBus"),
+];
+
+pub fn vendor_name(vendor_id: u16) -> Option<&'static str> {
+    VENDORS.iter().find(|&&(id, _)| id == vendor_id).map(|&(_, name)| name)
+}
+
+pub fn device_name(vendor_id: u16, device_id: u16) -> Option<&'static str> {
+    DEVICES
+        .iter()
+        .find(|&&(vendor, device, _)| vendor == vendor_id && device == device_id)
+        .map(|&(_, _, name)| name)
+}
+
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    SUBCLASSES
+        .iter()
+        .find(|&&(c, s, _)| c == class && s == subclass)
+        .map(|&(_, _, name)| name)
+        .or_else(|| CLASSES.iter().find(|&&(c, _)| c == class).map(|&(_, name)| name))
+        .unwrap_or("Unknown class")
+}
@@ -0,0 +1,264 @@
+// User-mode processes.
+//
+// A process is an address space plus the kernel thread that runs its code in
+// ring 3. The thread enters user mode through `iretq` and comes back into
+// the kernel on interrupts and exceptions, using the kernel stack that the
+// scheduler puts into the TSS. A process that faults is killed; the kernel
+// keeps running.
+//
+// `exec` in the shell starts a program from the file system and waits for it.
+
+pub mod loader;
+
+use crate::elf::ElfError;
+use crate::fs::{self, FdTable, FsError, OpenFlags};
+use crate::gdt;
+use crate::memory::{AddressSpace, PagingError};
+use crate::println;
+use crate::shell;
+use crate::sync::{Mutex, WaitQueue};
+use crate::task;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, AtomicI64, AtomicU64, Ordering};
+use x86_64::structures::idt::InterruptStackFrame;
+use x86_64::structures::paging::PhysFrame;
+use x86_64::VirtAddr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Pid(u64);
+
+impl Pid {
+    fn new() -> Self {
+        static NEXT_PID: AtomicU64 = AtomicU64::new(1);
+        Pid(NEXT_PID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ProcessError {
+    Elf(ElfError),
+    Paging(PagingError),
+    Fs(FsError),
+    /// A segment or the stack would land outside the user half.
+    BadAddress,
+    NotExecutable,
+}
+
+impl From<ElfError> for ProcessError {
+    fn from(err: ElfError) -> Self {
+        ProcessError::Elf(err)
+    }
+}
+
+impl From<FsError> for ProcessError {
+    fn from(err: FsError) -> Self {
+        ProcessError::Fs(err)
+    }
+}
+
+impl From<PagingError> for ProcessError {
+    fn from(err: PagingError) -> Self {
+        ProcessError::Paging(err)
+    }
+}
+
+pub struct Process {
+    pid: Pid,
+    name: String,
+    // Kept outside the lock so the scheduler can load CR3 without taking it.
+    level_4_frame: PhysFrame,
+    space: Mutex<Option<AddressSpace>>,
+    // Next free address for anonymous `mmap` regions.
+    mmap_next: AtomicU64,
+    exit_code: AtomicI64,
+    exited: AtomicBool,
+    exit_waiters: WaitQueue,
+    files: Mutex<FdTable>,
+}
+
+impl Process {
+    pub fn pid(&self) -> Pid {
+        self.pid
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn level_4_frame(&self) -> PhysFrame {
+        self.level_4_frame
+    }
+
+    pub fn exit_code(&self) -> i64 {
+        self.exit_code.load(Ordering::Relaxed)
+    }
+
+    /// Blocks until the process has exited and returns its exit code.
+    pub fn wait(&self) -> i64 {
+        self.exit_waiters
+            .wait_until(|| self.exited.load(Ordering::Acquire).then(|| self.exit_code()))
+    }
+
+    /// Picks an unused user address range of `len` bytes for `mmap`.
+    /// A request that doesn't fit leaves the free range as it was.
+    pub fn reserve_mmap_region(&self, len: u64) -> Result<VirtAddr, ProcessError> {
+        let len = len
+            .checked_next_multiple_of(4096)
+            .filter(|&len| len <= loader::MMAP_END)
+            .ok_or(ProcessError::BadAddress)?;
+        self.mmap_next
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |start| {
+                start.checked_add(len).filter(|&end| end <= loader::MMAP_END)
+            })
+            .map(VirtAddr::new)
+            .map_err(|_| ProcessError::BadAddress)
+    }
+
+    /// Runs `f` on the process's file descriptor table.
+    pub fn with_files<R>(&self, f: impl FnOnce(&mut FdTable) -> R) -> R {
+        f(&mut self.files.lock())
+    }
+
+    /// Runs `f` on the process's address space.
+    pub fn with_space<R>(&self, f: impl FnOnce(&mut AddressSpace) -> R) -> R {
+        f(self.space.lock().as_mut().unwrap())
+    }
+}
+
+impl Drop for Process {
+    // The last reference goes away when the idle thread reaps the process's
+    // exited thread, which runs in the kernel address space.
+    fn drop(&mut self) {
+        if let Some(space) = self.space.get_mut().take() {
+            unsafe { space.destroy() };
+        }
+    }
+}
+
+pub fn init() {
+    shell::register("exec", "run a program from the file system as a user process", exec);
+}
+
+/// Loads an ELF executable into a new address space and starts it.
+pub fn spawn(name: &str, image: &[u8], argv: &[&str], envp: &[&str]) -> Result<Arc<Process>, ProcessError> {
+    // Standard input, output and error all refer to the console.
+    let console = fs::open("/dev/console", OpenFlags::READ_WRITE)?;
+    let mut files = FdTable::new();
+    for _ in 0..3 {
+        files.insert(console.clone())?;
+    }
+
+    let mut space = AddressSpace::new()?;
+    let prepared = loader::load_elf(&mut space, image)
+        .and_then(|entry| Ok((entry, loader::setup_user_stack(&mut space, argv, envp)?)));
+    let (entry, stack_pointer) = match prepared {
+        Ok(prepared) => prepared,
+        Err(err) => {
+            unsafe { space.destroy() };
+            return Err(err);
+        }
+    };
+
+    let process = Arc::new(Process {
+        pid: Pid::new(),
+        name: String::from(name),
+        level_4_frame: space.level_4_frame(),
+        space: Mutex::new(Some(space)),
+        mmap_next: AtomicU64::new(loader::MMAP_BASE),
+        exit_code: AtomicI64::new(0),
+        exited: AtomicBool::new(false),
+        exit_waiters: WaitQueue::new(),
+        files: Mutex::new(files),
+    });
+
+    task::spawn_in_process(name, process.clone(), move || enter_user_mode(entry, stack_pointer))?;
+    Ok(process)
+}
+
+/// Process the current thread belongs to, if it is a user thread.
+pub fn current() -> Option<Arc<Process>> {
+    task::current_process()
+}
+
+/// Whether an exception interrupted ring 3 code.
+pub fn from_user(stack_frame: &InterruptStackFrame) -> bool {
+    stack_frame.code_segment & 3 == 3
+}
+
+/// Ends the current process with the given exit code.
+pub fn exit_current(code: i64) -> ! {
+    if let Some(process) = current() {
+        process.exit_code.store(code, Ordering::Relaxed);
+        process.exited.store(true, Ordering::Release);
+        process.exit_waiters.wake_all();
+    }
+    task::exit();
+}
+
+/// Ends the current process after an exception it caused.
+pub fn kill_current(reason: fmt::Arguments) -> ! {
+    if let Some(process) = current() {
+        println!("process {} ({}) killed: {}", process.pid.0, process.name, reason);
+    }
+    exit_current(-1);
+}
+
+/// Drops to ring 3 at `entry` with the given user stack. Never returns; the
+/// thread only comes back into the kernel through interrupts and exceptions.
+pub fn enter_user_mode(entry: VirtAddr, stack_pointer: VirtAddr) -> ! {
+    let selectors = gdt::selectors();
+    unsafe {
+        core::arch::asm!(
+            "mov ds, {data:x}",
+            "mov es, {data:x}",
+            "push {data}",
+            "push {stack}",
+            "push 0x202", // RFLAGS with interrupts enabled
+            "push {code}",
+            "push {entry}",
+            // Don't leak kernel values to user space.
+            "xor eax, eax",
+            "xor ebx, ebx",
+            "xor ecx, ecx",
+            "xor edx, edx",
+            "xor esi, esi",
+            "xor edi, edi",
+            "xor ebp, ebp",
+            "xor r8d, r8d",
+            "xor r9d, r9d",
+            "xor r10d, r10d",
+            "xor r11d, r11d",
+            "xor r12d, r12d",
+            "xor r13d, r13d",
+            "xor r14d, r14d",
+            "xor r15d, r15d",
+            "iretq",
+            data = in(reg) u64::from(selectors.user_data.0),
+            code = in(reg) u64::from(selectors.user_code.0),
+            stack = in(reg) stack_pointer.as_u64(),
+            entry = in(reg) entry.as_u64(),
+            options(noreturn),
+        );
+    }
+}
+
+// The program's standard streams are the console's, so its output shows on
+// the screen even when the shell runs over the network.
+fn exec(args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+    let Some(path) = args.first() else {
+        return Err(String::from("usage: exec <program> [args...]"));
+    };
+    let image = fs::read_to_end(path).map_err(|err| format!("{}: {:?}", path, err))?;
+    let name = path.rsplit('/').next().unwrap_or(path);
+    let process = spawn(name, &image, args, &[]).map_err(|err| format!("{}: {:?}", path, err))?;
+    let code = process.wait();
+    let _ = writeln!(out, "process {} ({}) exited with code {}", process.pid.0, process.name(), code);
+    Ok(())
+}
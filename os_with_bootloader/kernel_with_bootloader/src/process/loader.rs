@@ -0,0 +1,135 @@
+use super::ProcessError;
+use crate::elf::{ElfError, ElfFile, ProgramHeader};
+use crate::memory::AddressSpace;
+use alloc::vec::Vec;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+const ET_EXEC: u16 = 2;
+
+/// Exclusive end of the user half of the address space.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
+/// Top of the initial user stack. The page below the stack stays unmapped.
+pub const USER_STACK_TOP: u64 = 0x0000_7fff_ffff_0000;
+pub const USER_STACK_SIZE: u64 = 64 * 1024;
+
+/// Anonymous `mmap` regions are handed out upwards from here.
+pub const MMAP_BASE: u64 = 0x0000_1000_0000_0000;
+pub const MMAP_END: u64 = 0x0000_7000_0000_0000;
+
+/// Maps the `PT_LOAD` segments of `image` into `space` and returns the entry point.
+pub fn load_elf(space: &mut AddressSpace, image: &[u8]) -> Result<VirtAddr, ProcessError> {
+    let elf = ElfFile::parse(image)?;
+    if elf.kind != ET_EXEC {
+        return Err(ProcessError::NotExecutable);
+    }
+
+    for segment in elf.program_headers().filter(ProgramHeader::is_load) {
+        // The file part would spill over whatever follows the segment.
+        if segment.file_size > segment.mem_size {
+            return Err(ElfError::BadProgramHeader.into());
+        }
+        if segment.mem_size == 0 {
+            continue;
+        }
+        let end = segment
+            .vaddr
+            .checked_add(segment.mem_size)
+            .filter(|&end| end <= USER_SPACE_END)
+            .ok_or(ProcessError::BadAddress)?;
+        let data = elf.segment_data(&segment)?;
+
+        let mut flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+        if segment.writable() {
+            flags |= PageTableFlags::WRITABLE;
+        }
+        if !segment.executable() {
+            flags |= PageTableFlags::NO_EXECUTE;
+        }
+
+        let first = Page::<Size4KiB>::containing_address(VirtAddr::new(segment.vaddr));
+        let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+        for page in Page::range_inclusive(first, last) {
+            match space.translate_with_flags(page.start_address()) {
+                // Segments that aren't page aligned can share a page; give the
+                // page the union of both permissions.
+                Some((_, existing)) => {
+                    let mut merged = existing | flags;
+                    if !(existing.contains(PageTableFlags::NO_EXECUTE)
+                        && flags.contains(PageTableFlags::NO_EXECUTE))
+                    {
+                        merged.remove(PageTableFlags::NO_EXECUTE);
+                    }
+                    space.protect(page, merged)?;
+                }
+                None => space.map_anonymous(page, 1, flags)?,
+            }
+        }
+
+        // The rest up to `mem_size` (.bss) is already zero.
+        space.write_bytes(VirtAddr::new(segment.vaddr), data)?;
+    }
+
+    let entry = VirtAddr::try_new(elf.entry).map_err(|_| ProcessError::BadAddress)?;
+    Ok(entry)
+}
+
+/// Maps the user stack and lays out `argc`, `argv`, `envp` and an empty auxv
+/// the way the System V ABI expects them at process start. Returns the
+/// initial stack pointer, which points at `argc`.
+pub fn setup_user_stack(
+    space: &mut AddressSpace,
+    argv: &[&str],
+    envp: &[&str],
+) -> Result<VirtAddr, ProcessError> {
+    let bottom = VirtAddr::new(USER_STACK_TOP - USER_STACK_SIZE);
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::USER_ACCESSIBLE
+        | PageTableFlags::NO_EXECUTE;
+    space.map_anonymous(
+        Page::containing_address(bottom),
+        USER_STACK_SIZE / 4096,
+        flags,
+    )?;
+
+    // Strings go at the very top.
+    let mut sp = USER_STACK_TOP;
+    let mut push_str = |space: &mut AddressSpace, s: &str| -> Result<u64, ProcessError> {
+        sp -= s.len() as u64 + 1;
+        if sp < bottom.as_u64() {
+            return Err(ProcessError::BadAddress);
+        }
+        space.write_bytes(VirtAddr::new(sp), s.as_bytes())?;
+        space.write_bytes(VirtAddr::new(sp + s.len() as u64), &[0])?;
+        Ok(sp)
+    };
+    let mut env_ptrs = Vec::with_capacity(envp.len());
+    for s in envp {
+        env_ptrs.push(push_str(space, s)?);
+    }
+    let mut arg_ptrs = Vec::with_capacity(argv.len());
+    for s in argv {
+        arg_ptrs.push(push_str(space, s)?);
+    }
+
+    // argc, argv[], NULL, envp[], NULL, AT_NULL auxv entry.
+    let mut words: Vec<u64> = Vec::with_capacity(argv.len() + envp.len() + 5);
+    words.push(argv.len() as u64);
+    words.extend(&arg_ptrs);
+    words.push(0);
+    words.extend(&env_ptrs);
+    words.push(0);
+    words.extend([0, 0]);
+
+    let table_size = words.len() as u64 * 8;
+    let sp = (sp - table_size) & !0xf;
+    if sp < bottom.as_u64() {
+        return Err(ProcessError::BadAddress);
+    }
+    let bytes: Vec<u8> = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+    space.write_bytes(VirtAddr::new(sp), &bytes)?;
+
+    Ok(VirtAddr::new(sp))
+}
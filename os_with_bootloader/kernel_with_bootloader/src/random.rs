@@ -0,0 +1,48 @@
+// Random numbers for /dev/random.
+//
+// RDRAND when the CPU has it. Otherwise a SplitMix64 sequence whose steps are
+// mixed with the time stamp counter: fine for picking ports or salting
+// hashes, useless for keys.
+
+use crate::cpu;
+use core::arch::x86_64::{_rdrand64_step, _rdtsc};
+use core::sync::atomic::{AtomicU64, Ordering};
+
+const GOLDEN_GAMMA: u64 = 0x9E37_79B9_7F4A_7C15;
+
+// RDRAND may fail when its entropy source is drained; Intel suggests ten
+// retries before giving up.
+const RDRAND_RETRIES: usize = 10;
+
+static STATE: AtomicU64 = AtomicU64::new(GOLDEN_GAMMA);
+
+fn hardware() -> Option<u64> {
+    if !cpu::identity().has("rdrand") {
+        return None;
+    }
+    let mut value = 0;
+    for _ in 0..RDRAND_RETRIES {
+        if unsafe { _rdrand64_step(&mut value) } == 1 {
+            return Some(value);
+        }
+    }
+    None
+}
+
+fn mixed() -> u64 {
+    let tsc = unsafe { _rdtsc() };
+    let mut z = STATE.fetch_add(GOLDEN_GAMMA ^ (tsc << 1), Ordering::Relaxed) ^ tsc;
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
+}
+
+pub fn next_u64() -> u64 {
+    hardware().unwrap_or_else(mixed)
+}
+
+pub fn fill(buf: &mut [u8]) {
+    for chunk in buf.chunks_mut(8) {
+        chunk.copy_from_slice(&next_u64().to_le_bytes()[..chunk.len()]);
+    }
+}
@@ -0,0 +1,119 @@
+// The first serial port (COM1), a 16550 UART at 0x3F8, run at 115200 baud,
+// 8N1, FIFOs on.
+//
+// Its interrupt isn't used: writers wait for room in the transmitter, and
+// readers poll the line status, sleeping a tick between polls. QEMU connects
+// the port to its serial console (`-serial stdio` puts it on the terminal).
+
+use crate::sync::SpinLock;
+use crate::task;
+use core::sync::atomic::{AtomicBool, Ordering};
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+const COM1: u16 = 0x3F8;
+
+// Registers, relative to the base port. With DLAB set in the line control
+// register the first two are the baud rate divisor instead.
+const DATA: u16 = 0;
+const INTERRUPT_ENABLE: u16 = 1;
+const FIFO_CONTROL: u16 = 2;
+const LINE_CONTROL: u16 = 3;
+const MODEM_CONTROL: u16 = 4;
+const LINE_STATUS: u16 = 5;
+
+const LINE_8N1: u8 = 0x03;
+const LINE_DLAB: u8 = 0x80;
+// Enable and clear both FIFOs, interrupt at 14 bytes.
+const FIFO_ENABLE: u8 = 0xC7;
+const MODEM_DTR_RTS_OUT2: u8 = 0x0B;
+const MODEM_LOOPBACK: u8 = 0x10;
+
+const STATUS_DATA_READY: u8 = 1 << 0;
+const STATUS_TRANSMIT_EMPTY: u8 = 1 << 5;
+
+// 115200 / divisor baud.
+const BAUD_DIVISOR: u16 = 1;
+
+// Polls of the transmitter before a byte is dropped, so a stuck port can't
+// hang the kernel.
+const POLL_LIMIT: usize = 100_000;
+
+static PORT: SpinLock<()> = SpinLock::new(());
+static PRESENT: AtomicBool = AtomicBool::new(false);
+
+fn read(register: u16) -> u8 {
+    unsafe { Port::<u8>::new(COM1 + register).read() }
+}
+
+fn write(register: u16, value: u8) {
+    unsafe { Port::<u8>::new(COM1 + register).write(value) }
+}
+
+/// Programs the UART and checks in loopback mode that it echoes a byte.
+/// Returns whether the port is there.
+pub fn init() -> bool {
+    let present = without_interrupts(|| {
+        let _guard = PORT.lock();
+        write(INTERRUPT_ENABLE, 0);
+        write(LINE_CONTROL, LINE_DLAB);
+        write(DATA, BAUD_DIVISOR as u8);
+        write(INTERRUPT_ENABLE, (BAUD_DIVISOR >> 8) as u8);
+        write(LINE_CONTROL, LINE_8N1);
+        write(FIFO_CONTROL, FIFO_ENABLE);
+        write(MODEM_CONTROL, MODEM_LOOPBACK | MODEM_DTR_RTS_OUT2);
+        write(DATA, 0xAE);
+        let echoed = read(DATA) == 0xAE;
+        write(MODEM_CONTROL, MODEM_DTR_RTS_OUT2);
+        echoed
+    });
+    PRESENT.store(present, Ordering::Relaxed);
+    present
+}
+
+pub fn is_present() -> bool {
+    PRESENT.load(Ordering::Relaxed)
+}
+
+/// Sends `bytes`, waiting for the transmitter between them.
+pub fn write_bytes(bytes: &[u8]) {
+    if !is_present() {
+        return;
+    }
+    for &byte in bytes {
+        without_interrupts(|| {
+            let _guard = PORT.lock();
+            for _ in 0..POLL_LIMIT {
+                if read(LINE_STATUS) & STATUS_TRANSMIT_EMPTY != 0 {
+                    write(DATA, byte);
+                    return;
+                }
+                core::hint::spin_loop();
+            }
+        });
+    }
+}
+
+/// Blocks until at least one byte has arrived, then moves what is waiting
+/// into `buf`. Returns the number of bytes read, which is only 0 for an
+/// empty `buf` or a missing port.
+pub fn read_bytes(buf: &mut [u8]) -> usize {
+    if buf.is_empty() || !is_present() {
+        return 0;
+    }
+    loop {
+        let count = without_interrupts(|| {
+            let _guard = PORT.lock();
+            let mut count = 0;
+            while count < buf.len() && read(LINE_STATUS) & STATUS_DATA_READY != 0 {
+                buf[count] = read(DATA);
+                count += 1;
+            }
+            count
+        });
+        if count > 0 {
+            return count;
+        }
+        task::sleep(1);
+    }
+}
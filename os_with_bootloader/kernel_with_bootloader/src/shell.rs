@@ -0,0 +1,175 @@
+// A kernel command shell. Subsystems register commands by name; a session
+// reads lines from a terminal, splits them on whitespace and runs the
+// command the first word names.
+//
+// The console session runs in its own thread. The keyboard interrupt handler
+// already echoes what is typed, so the shell never echoes input itself.
+// Other terminals, such as network sessions, run their own sessions and
+// may let `exit` end them.
+
+use crate::console;
+use crate::fs::{self, FileType};
+use crate::sync::Mutex;
+use crate::task;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+
+/// Runs a command with the words after its name, writing its output to the
+/// terminal. An `Err` is printed as the command's error message.
+pub type CommandFn = fn(&[&str], &mut dyn Write) -> Result<(), String>;
+
+struct Command {
+    summary: &'static str,
+    run: CommandFn,
+}
+
+static COMMANDS: Mutex<BTreeMap<&'static str, Command>> = Mutex::new(BTreeMap::new());
+
+// Longest line kept; the rest of a longer one is dropped.
+const MAX_LINE: usize = 256;
+
+const PROMPT: &str = "> ";
+
+/// Where a session's input comes from and its output goes.
+pub trait Terminal: Write {
+    /// Blocks until input arrives and moves it into `buf`. Returns 0 once
+    /// the terminal has gone away.
+    fn read(&mut self, buf: &mut [u8]) -> usize;
+
+    /// Whether an `exit` line ends the session. The console's never ends.
+    fn can_exit(&self) -> bool {
+        false
+    }
+}
+
+/// Adds `name`, replacing any command already called that.
+pub fn register(name: &'static str, summary: &'static str, run: CommandFn) {
+    COMMANDS.lock().insert(name, Command { summary, run });
+}
+
+/// Registers the commands the shell itself provides.
+pub fn init() {
+    register("help", "list commands", help);
+    register("ls", "list a directory", ls);
+    register("cat", "print files", cat);
+}
+
+/// Runs one command line, writing its output or error to `out`.
+pub fn execute(line: &str, out: &mut dyn Write) {
+    let words: Vec<&str> = line.split_whitespace().collect();
+    let Some((&name, args)) = words.split_first() else {
+        return;
+    };
+    // Don't hold the table while the command runs; it may register more.
+    let run = COMMANDS.lock().get(name).map(|command| command.run);
+    let result = match run {
+        Some(run) => run(args, out),
+        None => Err(String::from("command not found")),
+    };
+    if let Err(message) = result {
+        let _ = writeln!(out, "{}: {}", name, message);
+    }
+}
+
+/// Reads and runs lines from `terminal` until it goes away or, if it allows
+/// that, `exit` is entered.
+pub fn run(terminal: &mut dyn Terminal) {
+    let mut line = String::new();
+    let mut buf = [0; 64];
+    // A "\r\n" ends one line, not two.
+    let mut after_cr = false;
+    let _ = terminal.write_str(PROMPT);
+    loop {
+        let count = terminal.read(&mut buf);
+        if count == 0 {
+            return;
+        }
+        for &byte in &buf[..count] {
+            match byte {
+                b'\n' if after_cr => {}
+                b'\r' | b'\n' => {
+                    if line.trim() == "exit" && terminal.can_exit() {
+                        return;
+                    }
+                    execute(&line, terminal);
+                    line.clear();
+                    let _ = terminal.write_str(PROMPT);
+                }
+                0x08 | 0x7F => {
+                    line.pop();
+                }
+                byte if byte.is_ascii() && !byte.is_ascii_control() && line.len() < MAX_LINE => {
+                    line.push(byte as char);
+                }
+                _ => {}
+            }
+            after_cr = byte == b'\r';
+        }
+    }
+}
+
+// The framebuffer and keyboard.
+struct ConsoleTerminal;
+
+impl Write for ConsoleTerminal {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        crate::print!("{}", s);
+        Ok(())
+    }
+}
+
+impl Terminal for ConsoleTerminal {
+    fn read(&mut self, buf: &mut [u8]) -> usize {
+        console::read_input(buf)
+    }
+}
+
+/// Starts a session on the console.
+pub fn spawn_console() {
+    let spawned = task::spawn("shell", || {
+        crate::print!("\n");
+        run(&mut ConsoleTerminal);
+    });
+    if let Err(err) = spawned {
+        crate::print!("\nshell: cannot start ({:?})", err);
+    }
+}
+
+fn help(_args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+    for (name, command) in COMMANDS.lock().iter() {
+        let _ = writeln!(out, "{:<10}{}", name, command.summary);
+    }
+    Ok(())
+}
+
+fn ls(args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+    let path = match args {
+        [] => "/",
+        [path] => path,
+        _ => return Err(String::from("usage: ls [directory]")),
+    };
+    let entries = fs::read_dir(path).map_err(|err| format!("{}: {:?}", path, err))?;
+    for entry in entries {
+        let suffix = match entry.kind {
+            FileType::Directory => "/",
+            FileType::Symlink => "@",
+            _ => "",
+        };
+        let _ = writeln!(out, "{}{}", entry.name, suffix);
+    }
+    Ok(())
+}
+
+fn cat(args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+    if args.is_empty() {
+        return Err(String::from("usage: cat file..."));
+    }
+    for path in args {
+        let data = fs::read_to_end(path).map_err(|err| format!("{}: {:?}", path, err))?;
+        let _ = out.write_str(&String::from_utf8_lossy(&data));
+    }
+    Ok(())
+}
@@ -0,0 +1,350 @@
+// Bringing up the application processors (APs).
+//
+// The MADT lists the local APIC of every CPU. Each AP is woken with the
+// INIT-SIPI-SIPI sequence and starts in real mode at the trampoline below,
+// copied to a page under 1 MiB. The trampoline switches straight to long mode
+// on page tables that are the kernel's plus an identity mapping of the
+// trampoline page, and calls `ap_entry` on a fresh kernel stack. From there
+// the AP sets up its per-CPU state and joins the scheduler.
+
+use crate::memory::paging::set_tlb_shootdown_hook;
+use crate::memory::stack::GuardedStack;
+use crate::memory::{phys_to_virt, protection, AddressSpace, FRAME_ALLOCATOR};
+use crate::{acpi, apic, cpu, gdt, interrupts, println, syscall, task, time};
+use alloc::format;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use x86_64::instructions::tlb;
+use x86_64::structures::paging::{Page, PageTable, PageTableFlags, PhysFrame, Size4KiB};
+use x86_64::{PhysAddr, VirtAddr};
+
+const AP_STACK_SIZE: u64 = 64 * 1024;
+
+// The trampoline needs its code page below 1 MiB (the startup IPI takes a page
+// number) and its level 4 table below 4 GiB (CR3 is loaded from 32-bit code).
+// Five pages: code, then level 4 to level 1 tables.
+const TRAMPOLINE_PAGES: usize = 5;
+const TRAMPOLINE_LIMIT: u64 = 0x10_0000;
+
+// Offsets of the data slots at the start of the trampoline, see the assembly.
+const SLOT_GDT: usize = 8;
+const SLOT_GDT_POINTER: usize = 32;
+const SLOT_LONG_MODE_TARGET: usize = 40;
+const SLOT_CR3: usize = 48;
+const SLOT_STACK_TOP: usize = 56;
+const SLOT_ARGUMENT: usize = 64;
+const SLOT_ENTRY: usize = 72;
+const SLOT_ACK: usize = 80;
+
+core::arch::global_asm!(
+    ".pushsection .text.ap_trampoline, \"ax\"",
+    ".global ap_trampoline_start",
+    ".global ap_trampoline_long_mode",
+    ".global ap_trampoline_end",
+    ".code16",
+    "ap_trampoline_start:",
+    "jmp 2f",
+    // Data slots, filled in by `prepare_trampoline` and `start_ap`.
+    ".balign 8",
+    // GDT: null, 64-bit code, data.
+    ".quad 0",
+    ".quad 0x00AF9A000000FFFF",
+    ".quad 0x00CF92000000FFFF",
+    // GDT pointer: limit, 32-bit linear base.
+    ".word 23",
+    ".long 0",
+    // Far jump target into long mode: 32-bit offset, code selector.
+    ".balign 8",
+    ".long 0",
+    ".word 0x08",
+    // CR3, stack top, argument, entry point; then the acknowledgement the
+    // AP sets once it no longer needs them.
+    ".balign 8",
+    ".quad 0",
+    ".quad 0",
+    ".quad 0",
+    ".quad 0",
+    ".quad 0",
+    "2:",
+    "cli",
+    "cld",
+    "mov ax, cs",
+    "mov ds, ax",
+    "lgdt [32]",
+    // PAE, then long mode and NX (the kernel's page tables use it) in EFER.
+    "mov eax, cr4",
+    "or eax, 1 << 5",
+    "mov cr4, eax",
+    "mov eax, [48]",
+    "mov cr3, eax",
+    "mov ecx, 0xC0000080",
+    "rdmsr",
+    "or eax, (1 << 8) | (1 << 11)",
+    "wrmsr",
+    // Paging and protection on at once puts us in long mode.
+    "mov eax, cr0",
+    "or eax, 0x80000001",
+    "mov cr0, eax",
+    "jmp fword ptr [40]",
+    ".code64",
+    "ap_trampoline_long_mode:",
+    "mov ax, 0x10",
+    "mov ds, ax",
+    "mov es, ax",
+    "mov ss, ax",
+    "mov rsp, [rip + ap_trampoline_start + 56]",
+    "mov rdi, [rip + ap_trampoline_start + 64]",
+    "mov rax, [rip + ap_trampoline_start + 72]",
+    "mov qword ptr [rip + ap_trampoline_start + 80], 1",
+    "call rax",
+    "ud2",
+    "ap_trampoline_end:",
+    ".popsection",
+);
+
+unsafe extern "C" {
+    static ap_trampoline_start: u8;
+    static ap_trampoline_long_mode: u8;
+    static ap_trampoline_end: u8;
+}
+
+// Physical address of the reserved trampoline pages, 0 if there are none.
+static TRAMPOLINE: AtomicU64 = AtomicU64::new(0);
+
+/// Sets aside low memory for the trampoline. Must run right after the frame
+/// allocator is set up, before anything else takes the low frames.
+pub fn reserve_trampoline() {
+    let mut allocator = FRAME_ALLOCATOR.lock();
+    let Some(allocator) = allocator.as_mut() else {
+        return;
+    };
+    match allocator.allocate_contiguous(TRAMPOLINE_PAGES, 1) {
+        Some(frame) if frame.start_address().as_u64() + (TRAMPOLINE_PAGES as u64) * 4096 <= TRAMPOLINE_LIMIT => {
+            TRAMPOLINE.store(frame.start_address().as_u64(), Ordering::Relaxed);
+        }
+        Some(frame) => allocator.free_contiguous(frame, TRAMPOLINE_PAGES),
+        None => {}
+    }
+}
+
+/// Starts every application processor listed in the MADT and returns how many
+/// CPUs are online afterwards. Needs the PIT interrupt running for delays.
+pub fn init(rsdp: Option<u64>) -> usize {
+    let madt = match rsdp.ok_or(acpi::AcpiError::BadRsdp).and_then(acpi::parse_madt) {
+        Ok(madt) => madt,
+        Err(err) => {
+            println!("smp: no usable MADT ({:?}), staying on one CPU", err);
+            return 1;
+        }
+    };
+    if let Err(err) = apic::init(madt.local_apic_addr) {
+        println!("smp: cannot map the local APIC ({:?})", err);
+        return 1;
+    }
+    let bsp_apic_id = apic::id();
+    cpu::current().set_apic_id(bsp_apic_id);
+    set_tlb_shootdown_hook(shootdown);
+    apic::calibrate_timer();
+
+    let base = TRAMPOLINE.load(Ordering::Relaxed);
+    if base == 0 {
+        println!("smp: no low memory for the AP trampoline");
+        return 1;
+    }
+    if !unsafe { prepare_trampoline(PhysAddr::new(base)) } {
+        println!("smp: cannot identity-map the AP trampoline");
+        return 1;
+    }
+
+    for &apic_id in madt.apic_ids.iter().filter(|&&id| id != bsp_apic_id) {
+        match start_ap(PhysAddr::new(base), apic_id) {
+            ApStart::Online => {}
+            ApStart::Failed => println!("smp: CPU with APIC ID {} did not start", apic_id),
+            ApStart::Lost => {
+                println!("smp: CPU with APIC ID {} did not start, not starting any more", apic_id);
+                break;
+            }
+        }
+    }
+    cpu::online_count()
+}
+
+// Copies the trampoline code and builds its page tables.
+unsafe fn prepare_trampoline(base: PhysAddr) -> bool {
+    let start = &raw const ap_trampoline_start as usize;
+    let code_len = &raw const ap_trampoline_end as usize - start;
+    let long_mode_offset = &raw const ap_trampoline_long_mode as usize - start;
+    let code = phys_to_virt(base).as_mut_ptr::<u8>();
+    unsafe {
+        core::ptr::copy_nonoverlapping(start as *const u8, code, code_len);
+        write_slot(code, SLOT_GDT_POINTER + 2, (base.as_u64() as u32) + SLOT_GDT as u32);
+        write_slot(code, SLOT_LONG_MODE_TARGET, base.as_u64() as u32 + long_mode_offset as u32);
+    }
+
+    // The level 4 table is a copy of the kernel's. Along the path to the
+    // identity-mapped trampoline page, each table is replaced by a copy as
+    // well, so the kernel's own tables are never touched.
+    let frame = |i: u64| PhysFrame::<Size4KiB>::containing_address(base + i * 4096);
+    let table = |frame: PhysFrame| unsafe { &mut *phys_to_virt(frame.start_address()).as_mut_ptr::<PageTable>() };
+    let kernel_level_4 = table(AddressSpace::kernel().level_4_frame());
+    let level_4 = table(frame(1));
+    *level_4 = kernel_level_4.clone();
+
+    let addr = VirtAddr::new(base.as_u64());
+    let indices = [addr.p4_index(), addr.p3_index(), addr.p2_index()];
+    let mut parent = level_4;
+    for (level, index) in indices.into_iter().enumerate() {
+        let copy = frame(level as u64 + 2);
+        let entry = &mut parent[index];
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) {
+            return false;
+        }
+        let original = entry.frame().ok().map(|original| table(original).clone());
+        let new_table = table(copy);
+        match original {
+            Some(original) => *new_table = original,
+            None => new_table.zero(),
+        }
+        entry.set_frame(copy, PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+        parent = new_table;
+    }
+    // Writable for the AP's acknowledgement.
+    parent[addr.p1_index()].set_frame(frame(0), PageTableFlags::PRESENT | PageTableFlags::WRITABLE);
+
+    unsafe { write_slot(code, SLOT_CR3, frame(1).start_address().as_u64() as u32) };
+    true
+}
+
+unsafe fn write_slot<T>(code: *mut u8, offset: usize, value: T) {
+    unsafe { code.add(offset).cast::<T>().write_unaligned(value) };
+}
+
+fn wait_ms(ms: u64) {
+    // One extra tick, the current one may be almost over.
+    let until = time::ticks() + time::ms_to_ticks(ms) + 1;
+    while time::ticks() < until {
+        core::hint::spin_loop();
+    }
+}
+
+enum ApStart {
+    Online,
+    Failed,
+    // The AP never picked up its slots, so it may still do so later and
+    // they can't be handed to another one.
+    Lost,
+}
+
+fn start_ap(base: PhysAddr, apic_id: u32) -> ApStart {
+    let Some(cpu) = cpu::register(apic_id) else {
+        return ApStart::Failed;
+    };
+    let stack = match GuardedStack::new(&format!("cpu {} idle", cpu.index()), AP_STACK_SIZE) {
+        Ok(stack) => stack,
+        Err(_) => return ApStart::Failed,
+    };
+
+    let code = phys_to_virt(base).as_mut_ptr::<u8>();
+    let ack = unsafe { &*code.add(SLOT_ACK).cast::<AtomicU64>() };
+    ack.store(0, Ordering::Relaxed);
+    unsafe {
+        write_slot(code, SLOT_STACK_TOP, stack.top().as_u64());
+        write_slot(code, SLOT_ARGUMENT, cpu as *const cpu::Cpu as u64);
+        write_slot(code, SLOT_ENTRY, ap_entry as usize as u64);
+    }
+    // The AP runs on this stack for good, even if it shows up late.
+    core::mem::forget(stack);
+
+    let page = (base.as_u64() >> 12) as u8;
+    apic::send_init(apic_id);
+    wait_ms(10);
+    for _ in 0..2 {
+        apic::send_startup(apic_id, page);
+        wait_ms(10);
+        if cpu.is_online() {
+            break;
+        }
+    }
+    for _ in 0..10 {
+        if cpu.is_online() {
+            return ApStart::Online;
+        }
+        wait_ms(10);
+    }
+    match (cpu.is_online(), ack.load(Ordering::Acquire) != 0) {
+        (true, _) => ApStart::Online,
+        (false, true) => ApStart::Failed,
+        (false, false) => ApStart::Lost,
+    }
+}
+
+// First Rust code on an application processor, called by the trampoline.
+extern "C" fn ap_entry(cpu: &'static cpu::Cpu) -> ! {
+    unsafe { AddressSpace::kernel().activate() };
+    cpu::install(cpu);
+    gdt::init_ap().expect("failed to set up the AP's GDT");
+    interrupts::init_idt();
+    protection::init_ap();
+    syscall::init();
+    apic::enable();
+    task::init_ap();
+
+    cpu.set_online();
+    apic::start_timer();
+    x86_64::instructions::interrupts::enable();
+    task::idle_loop();
+}
+
+// TLB shootdown. The requesting CPU flags every other online CPU, sends one
+// IPI to all of them and waits until each has flushed. A CPU that is itself
+// waiting to send a request serves the pending one meanwhile, so two CPUs
+// shooting down at the same time can't deadlock.
+static SHOOTDOWN_LOCK: spin::Mutex<()> = spin::Mutex::new(());
+static SHOOTDOWN_START: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PAGES: AtomicU64 = AtomicU64::new(0);
+static SHOOTDOWN_PENDING: AtomicUsize = AtomicUsize::new(0);
+
+// Above this many pages, flushing the whole TLB is cheaper.
+const MAX_INVLPG_PAGES: u64 = 32;
+
+fn shootdown(start: Page<Size4KiB>, pages: u64) {
+    if cpu::online_count() < 2 {
+        return;
+    }
+    let _guard = loop {
+        if let Some(guard) = SHOOTDOWN_LOCK.try_lock() {
+            break guard;
+        }
+        handle_tlb_shootdown();
+        core::hint::spin_loop();
+    };
+
+    SHOOTDOWN_START.store(start.start_address().as_u64(), Ordering::Relaxed);
+    SHOOTDOWN_PAGES.store(pages, Ordering::Relaxed);
+    let me = cpu::id();
+    for cpu in cpu::all().filter(|cpu| cpu.is_online() && cpu.index() != me) {
+        SHOOTDOWN_PENDING.fetch_add(1, Ordering::AcqRel);
+        cpu.tlb_flush_pending.store(true, Ordering::Release);
+    }
+    apic::broadcast(apic::TLB_SHOOTDOWN_VECTOR);
+    while SHOOTDOWN_PENDING.load(Ordering::Acquire) != 0 {
+        core::hint::spin_loop();
+    }
+}
+
+/// Flushes what the current shootdown request asks for, if it is addressed
+/// to this CPU. Called from the IPI handler.
+pub fn handle_tlb_shootdown() {
+    if !cpu::current().tlb_flush_pending.swap(false, Ordering::AcqRel) {
+        return;
+    }
+    let start = SHOOTDOWN_START.load(Ordering::Relaxed);
+    let pages = SHOOTDOWN_PAGES.load(Ordering::Relaxed);
+    if pages > MAX_INVLPG_PAGES {
+        tlb::flush_all();
+    } else {
+        for i in 0..pages {
+            tlb::flush(VirtAddr::new(start + i * 4096));
+        }
+    }
+    SHOOTDOWN_PENDING.fetch_sub(1, Ordering::AcqRel);
+}
@@ -0,0 +1,146 @@
+// Sound cards: PCM playback and a volume control.
+//
+// Drivers hand their cards to `register`. Players give a card a rate and
+// a function producing samples, and the card pulls from it as its buffers
+// drain; `aplay` plays WAV files that way, converting them to what the card
+// takes. `mixer` shows and sets the volume.
+
+pub mod ac97;
+pub mod wav;
+
+use crate::fs;
+use crate::shell;
+use crate::sync::Mutex;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SoundError {
+    /// No card has been registered.
+    NoCard,
+    /// The card stopped taking samples.
+    Stalled,
+}
+
+/// Fills the buffer with interleaved 16-bit stereo samples, left first,
+/// and returns how many it wrote. Fewer than fit means that was the last.
+pub type Source<'a> = dyn FnMut(&mut [i16]) -> usize + 'a;
+
+/// A card that plays 16-bit stereo PCM.
+pub trait SoundCard: Send + Sync {
+    fn name(&self) -> &str;
+
+    /// The rate closest to `rate` Hz that the card can play at.
+    fn supported_rate(&self, rate: u32) -> u32;
+
+    /// Plays what `source` produces at `rate` Hz, which must be a
+    /// supported rate, and returns once it has all been played.
+    fn play(&self, rate: u32, source: &mut Source) -> Result<(), SoundError>;
+
+    /// Volume in percent of the loudest; 0 is muted.
+    fn volume(&self) -> u8;
+
+    fn set_volume(&self, percent: u8);
+}
+
+static CARDS: Mutex<Vec<Arc<dyn SoundCard>>> = Mutex::new(Vec::new());
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// The next free card name: snd0, snd1, ...
+pub fn allocate_name() -> String {
+    format!("snd{}", NEXT_INDEX.fetch_add(1, Ordering::Relaxed))
+}
+
+pub fn register(card: Arc<dyn SoundCard>) {
+    CARDS.lock().push(card);
+}
+
+pub fn cards() -> Vec<Arc<dyn SoundCard>> {
+    CARDS.lock().clone()
+}
+
+/// The card players use: the first one.
+pub fn default_card() -> Option<Arc<dyn SoundCard>> {
+    CARDS.lock().first().cloned()
+}
+
+/// Probes for cards and registers the sound shell commands.
+pub fn init() {
+    crate::pci::register_driver(&ac97::DRIVER);
+    shell::register("aplay", "play a WAV file on the sound card", aplay);
+    shell::register("mixer", "show or set the sound card's volume", mixer);
+}
+
+/// Plays `wav` on `card`. The card plays at the file's rate if it can;
+/// otherwise every output frame takes the input frame nearest in time.
+pub fn play_wav(card: &dyn SoundCard, wav: &wav::Wav) -> Result<(), SoundError> {
+    let rate = card.supported_rate(wav.rate);
+    let frames = wav.frames() as u64;
+    let mut produced = 0u64;
+    card.play(rate, &mut |buf: &mut [i16]| {
+        let mut written = 0;
+        for out in buf.chunks_exact_mut(2) {
+            let frame = produced * wav.rate as u64 / rate as u64;
+            if frame >= frames {
+                break;
+            }
+            let (left, right) = wav.frame(frame as usize);
+            out[0] = left;
+            out[1] = right;
+            produced += 1;
+            written += 2;
+        }
+        written
+    })
+}
+
+fn aplay(args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+    let [path] = args else {
+        return Err(String::from("usage: aplay <file.wav>"));
+    };
+    let card = default_card().ok_or_else(|| format!("{:?}", SoundError::NoCard))?;
+    let data = fs::read_to_end(path).map_err(|err| format!("{}: {:?}", path, err))?;
+    let wav = wav::parse(&data).map_err(|err| format!("{}: {:?}", path, err))?;
+    let rate = card.supported_rate(wav.rate);
+    let _ = writeln!(
+        out,
+        "{}: {} Hz, {} bit, {} channel(s), {}.{:03} s on {} at {} Hz",
+        path,
+        wav.rate,
+        wav.bits,
+        wav.channels,
+        wav.frames() / wav.rate as usize,
+        wav.frames() % wav.rate as usize * 1000 / wav.rate as usize,
+        card.name(),
+        rate
+    );
+    play_wav(&*card, &wav).map_err(|err| format!("{:?}", err))
+}
+
+fn mixer(args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+    let cards = cards();
+    if cards.is_empty() {
+        return Err(format!("{:?}", SoundError::NoCard));
+    }
+    match args {
+        [] => {}
+        [volume] => {
+            let percent = volume
+                .trim_end_matches('%')
+                .parse::<u8>()
+                .ok()
+                .filter(|&percent| percent <= 100)
+                .ok_or_else(|| String::from("the volume is a percentage from 0 to 100"))?;
+            cards[0].set_volume(percent);
+        }
+        _ => return Err(String::from("usage: mixer [volume%]")),
+    }
+    for card in &cards {
+        let _ = writeln!(out, "{}: volume {}%", card.name(), card.volume());
+    }
+    Ok(())
+}
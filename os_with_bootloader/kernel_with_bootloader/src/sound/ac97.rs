@@ -0,0 +1,331 @@
+// Intel AC'97 audio controllers (ICH), such as QEMU's `-device AC97`.
+//
+// The controller has two I/O windows: the codec's mixer registers (BAR 0)
+// and the bus master (BAR 1). Its PCM out channel plays from a buffer
+// descriptor list, a ring of 32 descriptors each pointing at a buffer of
+// samples, up to the descriptor the driver marked as the last valid one.
+// Playback fills buffers and moves that mark along behind the descriptor
+// the card is playing, polling for progress; if the card catches up with
+// the mark it halts until more arrives.
+
+use super::{allocate_name, register, SoundCard, SoundError, Source};
+use crate::memory::dma::DmaBuffer;
+use crate::pci::{self, Bar};
+use crate::sync::Mutex;
+use crate::task;
+use crate::time;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU8, Ordering};
+use x86_64::instructions::port::Port;
+
+const VENDOR_INTEL: u16 = 0x8086;
+
+// 82801AA (the one QEMU emulates), AB, BA, ICH3, ICH4, ICH5 and ICH6.
+const MATCHES: &[pci::DeviceMatch] = &[
+    pci::DeviceMatch::id(VENDOR_INTEL, 0x2415),
+    pci::DeviceMatch::id(VENDOR_INTEL, 0x2425),
+    pci::DeviceMatch::id(VENDOR_INTEL, 0x2445),
+    pci::DeviceMatch::id(VENDOR_INTEL, 0x2485),
+    pci::DeviceMatch::id(VENDOR_INTEL, 0x24C5),
+    pci::DeviceMatch::id(VENDOR_INTEL, 0x24D5),
+    pci::DeviceMatch::id(VENDOR_INTEL, 0x266E),
+];
+
+// Mixer registers, 16 bits each.
+const RESET: u16 = 0x00;
+const MASTER_VOLUME: u16 = 0x02;
+const PCM_OUT_VOLUME: u16 = 0x18;
+const EXTENDED_ID: u16 = 0x28;
+const EXTENDED_CONTROL: u16 = 0x2A;
+const FRONT_DAC_RATE: u16 = 0x2C;
+
+// Variable rate audio, in the extended ID and control registers.
+const EXTENDED_VRA: u16 = 1 << 0;
+// Volumes are attenuations in 1.5 dB steps, left in the high byte.
+const MUTE: u16 = 1 << 15;
+const VOLUME_STEPS: u16 = 31;
+const PCM_0DB: u16 = 0x0808;
+const DEFAULT_VOLUME: u8 = 80;
+
+// Bus master registers: the PCM out channel's, then global ones.
+const PCM_OUT: u16 = 0x10;
+const BDBAR: u16 = 0x00;
+const CIV: u16 = 0x04;
+const LVI: u16 = 0x05;
+const SR: u16 = 0x06;
+const CR: u16 = 0x0B;
+const GLOBAL_CONTROL: u16 = 0x2C;
+const GLOBAL_STATUS: u16 = 0x30;
+
+const SR_HALTED: u16 = 1 << 0;
+// Last valid buffer done, buffer done, FIFO error; written 1 to clear.
+const SR_EVENTS: u16 = 0b111 << 2;
+const CR_RUN: u8 = 1 << 0;
+const CR_RESET: u8 = 1 << 1;
+// Set to take the link out of cold reset.
+const GC_COLD_RESET: u32 = 1 << 1;
+const GS_CODEC_READY: u32 = 1 << 8;
+
+const DESCRIPTORS: usize = 32;
+const BUFFER_SIZE: usize = 4096;
+const SAMPLES_PER_BUFFER: usize = BUFFER_SIZE / 2;
+const PAGE_SIZE: usize = 4096;
+
+// The fixed rate without variable rate audio, and the range with it.
+const FIXED_RATE: u32 = 48_000;
+const MIN_RATE: u32 = 8_000;
+
+const CODEC_TIMEOUT_MS: u64 = 200;
+const RESET_POLLS: usize = 100_000;
+// How often playback checks on the card, and how long the card may go
+// without finishing a buffer before playback gives up.
+const POLL_INTERVAL_MS: u64 = 10;
+const STALL_TIMEOUT_MS: u64 = 1000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ac97Error {
+    /// BAR 0 or 1 isn't an I/O window.
+    NoIoBars,
+    /// The codec never reported ready.
+    CodecTimeout,
+    NoMemory,
+    /// The buffers ended up where the card's 32-bit addresses can't reach.
+    DmaOutOfReach,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u32,
+    // 16-bit samples, both channels counted.
+    samples: u16,
+    flags: u16,
+}
+
+pub struct Ac97 {
+    name: String,
+    mixer: u16,
+    bus_master: u16,
+    variable_rate: bool,
+    volume: AtomicU8,
+    // Taken for the whole of a playback.
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    list: DmaBuffer,
+    buffers: DmaBuffer,
+}
+
+fn read_u8(port: u16) -> u8 {
+    unsafe { Port::<u8>::new(port).read() }
+}
+
+fn write_u8(port: u16, value: u8) {
+    unsafe { Port::<u8>::new(port).write(value) }
+}
+
+fn read_u16(port: u16) -> u16 {
+    unsafe { Port::<u16>::new(port).read() }
+}
+
+fn write_u16(port: u16, value: u16) {
+    unsafe { Port::<u16>::new(port).write(value) }
+}
+
+fn read_u32(port: u16) -> u32 {
+    unsafe { Port::<u32>::new(port).read() }
+}
+
+fn write_u32(port: u16, value: u32) {
+    unsafe { Port::<u32>::new(port).write(value) }
+}
+
+impl Ac97 {
+    fn new(device: &pci::PciDevice, name: String) -> Result<Self, Ac97Error> {
+        let (Some(Bar::Io(mixer)), Some(Bar::Io(bus_master))) = (device.address.bar(0), device.address.bar(1))
+        else {
+            return Err(Ac97Error::NoIoBars);
+        };
+        device.address.enable_bus_master();
+
+        write_u32(bus_master + GLOBAL_CONTROL, GC_COLD_RESET);
+        // Probing runs before the boot thread may sleep, so this spins.
+        let deadline = time::ticks() + time::ms_to_ticks(CODEC_TIMEOUT_MS) + 1;
+        while read_u32(bus_master + GLOBAL_STATUS) & GS_CODEC_READY == 0 {
+            if time::ticks() >= deadline {
+                return Err(Ac97Error::CodecTimeout);
+            }
+            core::hint::spin_loop();
+        }
+        write_u16(mixer + RESET, 0);
+        let variable_rate = read_u16(mixer + EXTENDED_ID) & EXTENDED_VRA != 0;
+        if variable_rate {
+            let control = read_u16(mixer + EXTENDED_CONTROL);
+            write_u16(mixer + EXTENDED_CONTROL, control | EXTENDED_VRA);
+        }
+        write_u16(mixer + PCM_OUT_VOLUME, PCM_0DB);
+
+        let list = DmaBuffer::new(1).ok_or(Ac97Error::NoMemory)?;
+        let buffers = DmaBuffer::new((DESCRIPTORS * BUFFER_SIZE).div_ceil(PAGE_SIZE)).ok_or(Ac97Error::NoMemory)?;
+        let end = buffers.phys().as_u64() + buffers.len() as u64;
+        if list.phys().as_u64() > u32::MAX as u64 || end > u32::MAX as u64 {
+            return Err(Ac97Error::DmaOutOfReach);
+        }
+        let card = Ac97 {
+            name,
+            mixer,
+            bus_master,
+            variable_rate,
+            volume: AtomicU8::new(0),
+            inner: Mutex::new(Inner { list, buffers }),
+        };
+        card.set_volume(DEFAULT_VOLUME);
+        Ok(card)
+    }
+
+    fn channel(&self, register: u16) -> u16 {
+        self.bus_master + PCM_OUT + register
+    }
+
+    // Stops the channel and puts its registers back to their reset state.
+    fn reset_channel(&self) {
+        write_u8(self.channel(CR), 0);
+        write_u8(self.channel(CR), CR_RESET);
+        for _ in 0..RESET_POLLS {
+            if read_u8(self.channel(CR)) & CR_RESET == 0 {
+                break;
+            }
+            core::hint::spin_loop();
+        }
+        write_u16(self.channel(SR), SR_EVENTS);
+    }
+}
+
+impl Inner {
+    // Fills buffer `index` from `source` and points its descriptor at it.
+    // Returns how many samples went in.
+    fn fill(&mut self, index: usize, source: &mut Source) -> usize {
+        let buffer = &mut self.buffers.as_mut_slice()[index * BUFFER_SIZE..(index + 1) * BUFFER_SIZE];
+        // Page-aligned, so fine for 16-bit samples.
+        let samples = unsafe { core::slice::from_raw_parts_mut(buffer.as_mut_ptr() as *mut i16, SAMPLES_PER_BUFFER) };
+        // Whole frames only.
+        let count = source(samples).min(SAMPLES_PER_BUFFER) & !1;
+        let descriptor = Descriptor {
+            addr: (self.buffers.phys().as_u64() + (index * BUFFER_SIZE) as u64) as u32,
+            samples: count as u16,
+            flags: 0,
+        };
+        unsafe { (self.list.as_mut_ptr() as *mut Descriptor).add(index).write_volatile(descriptor) };
+        count
+    }
+}
+
+impl SoundCard for Ac97 {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn supported_rate(&self, rate: u32) -> u32 {
+        if self.variable_rate {
+            rate.clamp(MIN_RATE, FIXED_RATE)
+        } else {
+            FIXED_RATE
+        }
+    }
+
+    fn play(&self, rate: u32, source: &mut Source) -> Result<(), SoundError> {
+        let inner = &mut *self.inner.lock();
+        if self.variable_rate {
+            write_u16(self.mixer + FRONT_DAC_RATE, rate as u16);
+        }
+        self.reset_channel();
+        write_u32(self.channel(BDBAR), inner.list.phys().as_u64() as u32);
+
+        // Buffers handed to the card and buffers it has finished, so far.
+        let mut queued = 0;
+        let mut played = 0;
+        let mut exhausted = false;
+        let mut queue = |inner: &mut Inner, queued: &mut usize, exhausted: &mut bool| {
+            let index = *queued % DESCRIPTORS;
+            let count = inner.fill(index, source);
+            if count > 0 {
+                write_u8(self.channel(LVI), index as u8);
+                *queued += 1;
+            }
+            *exhausted = count < SAMPLES_PER_BUFFER;
+        };
+        while !exhausted && queued < DESCRIPTORS {
+            queue(inner, &mut queued, &mut exhausted);
+        }
+        if queued == 0 {
+            return Ok(());
+        }
+        write_u8(self.channel(CR), CR_RUN);
+
+        let mut current = 0;
+        let mut progress = time::uptime_ms();
+        let result = loop {
+            let index = read_u8(self.channel(CIV)) as usize % DESCRIPTORS;
+            let finished = (index + DESCRIPTORS - current) % DESCRIPTORS;
+            if finished > 0 {
+                played += finished;
+                current = index;
+                progress = time::uptime_ms();
+            }
+            // Refill everything but the buffer being played.
+            while !exhausted && queued - played < DESCRIPTORS {
+                queue(inner, &mut queued, &mut exhausted);
+            }
+            let status = read_u16(self.channel(SR));
+            write_u16(self.channel(SR), status & SR_EVENTS);
+            if exhausted && status & SR_HALTED != 0 {
+                break Ok(());
+            }
+            if time::uptime_ms() - progress > STALL_TIMEOUT_MS {
+                break Err(SoundError::Stalled);
+            }
+            task::sleep(POLL_INTERVAL_MS);
+        };
+        self.reset_channel();
+        result
+    }
+
+    fn volume(&self) -> u8 {
+        self.volume.load(Ordering::Relaxed)
+    }
+
+    fn set_volume(&self, percent: u8) {
+        let percent = percent.min(100);
+        let value = if percent == 0 {
+            MUTE
+        } else {
+            let attenuation = (100 - percent as u16) * VOLUME_STEPS / 100;
+            attenuation << 8 | attenuation
+        };
+        write_u16(self.mixer + MASTER_VOLUME, value);
+        self.volume.store(percent, Ordering::Relaxed);
+    }
+}
+
+pub static DRIVER: pci::Driver = pci::Driver {
+    name: "ac97",
+    matches: MATCHES,
+    probe,
+};
+
+fn probe(device: &'static pci::PciDevice) -> bool {
+    match Ac97::new(device, allocate_name()) {
+        Ok(card) => {
+            let rate = if card.variable_rate { "variable rate" } else { "48 kHz" };
+            crate::print!("\n{}: AC'97 at {}, {}", card.name, device.address, rate);
+            register(Arc::new(card));
+            true
+        }
+        Err(err) => {
+            crate::print!("\nac97 {}: {:?}", device.address, err);
+            false
+        }
+    }
+}
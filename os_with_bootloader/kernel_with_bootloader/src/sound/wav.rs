@@ -0,0 +1,89 @@
+// WAV files: a RIFF container with a "fmt " chunk describing the samples
+// and a "data" chunk holding them. Only plain PCM is understood, 8-bit
+// (unsigned) or 16-bit (signed, little-endian), mono or stereo.
+
+const FORMAT_PCM: u16 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WavError {
+    /// Not a RIFF/WAVE file.
+    NotWav,
+    /// A chunk reaches past the end of the file, or one is missing.
+    Truncated,
+    /// Compressed, or a sample size or channel count that isn't supported.
+    Unsupported,
+}
+
+/// A parsed file, borrowing its samples.
+pub struct Wav<'a> {
+    pub channels: u16,
+    pub rate: u32,
+    pub bits: u16,
+    data: &'a [u8],
+}
+
+fn le16(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn le32(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
+
+pub fn parse(bytes: &[u8]) -> Result<Wav<'_>, WavError> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(WavError::NotWav);
+    }
+    let mut format = None;
+    let mut data = None;
+    let mut at = 12;
+    while at + 8 <= bytes.len() {
+        let id = &bytes[at..at + 4];
+        let len = le32(bytes, at + 4) as usize;
+        let body = bytes.get(at + 8..at + 8 + len).ok_or(WavError::Truncated)?;
+        match id {
+            b"fmt " if len >= 16 => format = Some(body),
+            b"data" => data = Some(body),
+            _ => {}
+        }
+        // Chunks are padded to an even length.
+        at += 8 + len + (len & 1);
+    }
+    let (format, data) = (format.ok_or(WavError::Truncated)?, data.ok_or(WavError::Truncated)?);
+    let wav = Wav {
+        channels: le16(format, 2),
+        rate: le32(format, 4),
+        bits: le16(format, 14),
+        data,
+    };
+    let supported = le16(format, 0) == FORMAT_PCM
+        && matches!(wav.channels, 1 | 2)
+        && matches!(wav.bits, 8 | 16)
+        && wav.rate > 0;
+    if !supported {
+        return Err(WavError::Unsupported);
+    }
+    Ok(wav)
+}
+
+impl Wav<'_> {
+    fn frame_size(&self) -> usize {
+        self.channels as usize * self.bits as usize / 8
+    }
+
+    pub fn frames(&self) -> usize {
+        self.data.len() / self.frame_size()
+    }
+
+    /// Frame `index` as 16-bit stereo; mono plays on both sides.
+    pub fn frame(&self, index: usize) -> (i16, i16) {
+        let at = index * self.frame_size();
+        let sample = |channel: usize| match self.bits {
+            8 => ((self.data[at + channel] as i16) - 128) << 8,
+            _ => le16(self.data, at + channel * 2) as i16,
+        };
+        let left = sample(0);
+        let right = if self.channels == 2 { sample(1) } else { left };
+        (left, right)
+    }
+}
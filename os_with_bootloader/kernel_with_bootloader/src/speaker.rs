@@ -0,0 +1,258 @@
+// The PC speaker. PIT channel 2 makes a square wave at the tone's
+// frequency; port 0x61 gates the channel's clock (bit 0) and connects its
+// output to the speaker (bit 1).
+//
+// `beep` and `play` block the calling thread for as long as they sound. The
+// terminal bell can't: the writer rings it with its lock held and
+// interrupts off. It starts the tone and leaves stopping it to the timer
+// interrupt. With sound disabled nothing is played and the writer flashes
+// the screen for the bell instead.
+
+use crate::shell;
+use crate::sync::{Mutex, SpinLock};
+use crate::task;
+use crate::time;
+use alloc::format;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+const CHANNEL_2: u16 = 0x42;
+const COMMAND: u16 = 0x43;
+const SPEAKER_CONTROL: u16 = 0x61;
+
+// Channel 2, lobyte/hibyte, mode 3 (square wave).
+const CHANNEL_2_SQUARE_WAVE: u8 = 0xB6;
+const GATE: u8 = 1 << 0;
+const SPEAKER_ON: u8 = 1 << 1;
+
+// Lowest frequency whose divisor fits the counter's 16 bits, and about
+// the highest anyone can hear.
+const MIN_FREQUENCY: u32 = 19;
+const MAX_FREQUENCY: u32 = 20_000;
+
+const BELL_FREQUENCY: u32 = 880;
+const BELL_MS: u64 = 100;
+const BEEP_FREQUENCY: u32 = 880;
+const BEEP_MS: u64 = 200;
+
+// Notes without a length last this long, and are followed by this much
+// silence so repeated ones don't run together.
+const DEFAULT_NOTE_MS: u64 = 250;
+const NOTE_GAP_MS: u64 = 10;
+
+// Octave 4 from C, in hundredths of a hertz (A4 = 440 Hz).
+const OCTAVE_4: [u32; 12] = [
+    26163, 27718, 29366, 31113, 32963, 34923, 36999, 39200, 41530, 44000, 46616, 49388,
+];
+
+/// What `play` plays without arguments: the start of the Ode to Joy.
+pub const ODE_TO_JOY: &str = "E4 E4 F4 G4 G4 F4 E4 D4 C4 C4 D4 E4 E4:375 D4:125 D4:500";
+
+static ENABLED: AtomicBool = AtomicBool::new(true);
+// Set while `beep` or `play` has the speaker; the bell stays quiet then.
+static PLAYING: AtomicBool = AtomicBool::new(false);
+// Tick at which the bell's tone stops; 0 when it isn't ringing.
+static BELL_UNTIL: AtomicU64 = AtomicU64::new(0);
+// Taken by `beep` and `play`, so sounds don't cut into each other.
+static PLAYER: Mutex<()> = Mutex::new(());
+// The ports are programmed from threads and from the timer interrupt.
+static PORTS: SpinLock<()> = SpinLock::new(());
+
+/// A tone, or a rest if `frequency` is 0.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Note {
+    pub frequency: u32,
+    pub ms: u64,
+}
+
+pub fn init() {
+    shell::register("beep", "sound the PC speaker, or turn sound on or off", beep_command);
+    shell::register("play", "play notes like C4 or F#5:500 (ms) on the PC speaker", play_command);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Turns sound on or off. Off silences the speaker right away.
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    if !enabled {
+        stop();
+    }
+}
+
+fn start(frequency: u32) {
+    let divisor = (time::PIT_FREQUENCY / frequency.clamp(MIN_FREQUENCY, MAX_FREQUENCY) as u64) as u16;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _guard = PORTS.lock();
+        let mut control: Port<u8> = Port::new(SPEAKER_CONTROL);
+        unsafe {
+            Port::<u8>::new(COMMAND).write(CHANNEL_2_SQUARE_WAVE);
+            let mut channel = Port::<u8>::new(CHANNEL_2);
+            channel.write(divisor as u8);
+            channel.write((divisor >> 8) as u8);
+            let value = control.read();
+            control.write(value | GATE | SPEAKER_ON);
+        }
+    });
+}
+
+fn stop() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _guard = PORTS.lock();
+        let mut control: Port<u8> = Port::new(SPEAKER_CONTROL);
+        unsafe {
+            let value = control.read();
+            control.write(value & !(GATE | SPEAKER_ON));
+        }
+    });
+}
+
+/// Sounds `frequency` Hz for `ms` milliseconds, blocking until it's over.
+/// Does nothing while sound is disabled.
+pub fn beep(frequency: u32, ms: u64) {
+    play(&[Note { frequency, ms }]);
+}
+
+/// Plays `notes` one after the other, blocking until they are over. Does
+/// nothing while sound is disabled.
+pub fn play(notes: &[Note]) {
+    if !is_enabled() {
+        return;
+    }
+    let _guard = PLAYER.lock();
+    PLAYING.store(true, Ordering::Relaxed);
+    for (i, note) in notes.iter().enumerate() {
+        if !is_enabled() {
+            break;
+        }
+        if note.frequency == 0 {
+            stop();
+            task::sleep(note.ms);
+            continue;
+        }
+        start(note.frequency);
+        if i + 1 < notes.len() && note.ms > NOTE_GAP_MS {
+            task::sleep(note.ms - NOTE_GAP_MS);
+            stop();
+            task::sleep(NOTE_GAP_MS);
+        } else {
+            task::sleep(note.ms);
+        }
+    }
+    stop();
+    PLAYING.store(false, Ordering::Relaxed);
+}
+
+/// Rings the terminal bell without blocking. Returns false if sound is
+/// disabled, so the caller can flash instead. May be called from interrupt
+/// handlers.
+pub fn bell() -> bool {
+    if !is_enabled() {
+        return false;
+    }
+    if !PLAYING.load(Ordering::Relaxed) {
+        start(BELL_FREQUENCY);
+        // A tick more, so the tone doesn't stop early if one is about to
+        // arrive.
+        BELL_UNTIL.store(time::ticks() + time::ms_to_ticks(BELL_MS) + 1, Ordering::Relaxed);
+    }
+    true
+}
+
+/// Called from the timer interrupt handler; ends the bell's tone.
+pub fn on_timer_tick() {
+    let until = BELL_UNTIL.load(Ordering::Relaxed);
+    if until == 0 || time::ticks() < until {
+        return;
+    }
+    let ended = BELL_UNTIL.compare_exchange(until, 0, Ordering::Relaxed, Ordering::Relaxed).is_ok();
+    if ended && !PLAYING.load(Ordering::Relaxed) {
+        stop();
+    }
+}
+
+/// Parses notes separated by whitespace: a name from C to B, optionally
+/// sharp (`#`) or flat (`b`), an octave (4 if left out) and a length in
+/// milliseconds after a colon. `R` is a rest. For example "C4 E4 G4:500 R
+/// C#5:125".
+pub fn parse_melody(text: &str) -> Result<Vec<Note>, String> {
+    text.split_whitespace()
+        .map(|token| parse_note(token).ok_or_else(|| format!("{}: not a note", token)))
+        .collect()
+}
+
+fn parse_note(token: &str) -> Option<Note> {
+    let (pitch, ms) = match token.split_once(':') {
+        Some((pitch, ms)) => (pitch, ms.parse().ok()?),
+        None => (token, DEFAULT_NOTE_MS),
+    };
+    let mut chars = pitch.chars();
+    let semitone: i32 = match chars.next()?.to_ascii_uppercase() {
+        'R' if chars.as_str().is_empty() => return Some(Note { frequency: 0, ms }),
+        'C' => 0,
+        'D' => 2,
+        'E' => 4,
+        'F' => 5,
+        'G' => 7,
+        'A' => 9,
+        'B' => 11,
+        _ => return None,
+    };
+    let rest = chars.as_str();
+    let (semitone, octave) = match rest.as_bytes().first() {
+        Some(b'#') => (semitone + 1, &rest[1..]),
+        Some(b'b') => (semitone - 1, &rest[1..]),
+        _ => (semitone, rest),
+    };
+    let octave: i32 = if octave.is_empty() { 4 } else { octave.parse().ok()? };
+    // Cb and B# spill into the neighbouring octave.
+    let (semitone, octave) = (semitone.rem_euclid(12), octave + semitone.div_euclid(12));
+    if !(0..=8).contains(&octave) {
+        return None;
+    }
+    let centihertz = OCTAVE_4[semitone as usize] as u64;
+    let frequency = if octave >= 4 {
+        centihertz << (octave - 4)
+    } else {
+        centihertz >> (4 - octave)
+    };
+    Some(Note {
+        frequency: (frequency / 100) as u32,
+        ms,
+    })
+}
+
+fn beep_command(args: &[&str], out: &mut dyn Write) -> Result<(), String> {
+    let usage = || String::from("usage: beep [frequency [ms]] | beep on | beep off");
+    let (frequency, ms) = match args {
+        [] => (BEEP_FREQUENCY, BEEP_MS),
+        ["on"] | ["off"] => {
+            set_enabled(args[0] == "on");
+            let _ = writeln!(out, "sound {}", args[0]);
+            return Ok(());
+        }
+        [frequency] => (frequency.parse().map_err(|_| usage())?, BEEP_MS),
+        [frequency, ms] => (frequency.parse().map_err(|_| usage())?, ms.parse().map_err(|_| usage())?),
+        _ => return Err(usage()),
+    };
+    if !is_enabled() {
+        return Err(String::from("sound is off"));
+    }
+    beep(frequency, ms);
+    Ok(())
+}
+
+fn play_command(args: &[&str], _out: &mut dyn Write) -> Result<(), String> {
+    let melody = if args.is_empty() { String::from(ODE_TO_JOY) } else { args.join(" ") };
+    let notes = parse_melody(&melody)?;
+    if !is_enabled() {
+        return Err(String::from("sound is off"));
+    }
+    play(&notes);
+    Ok(())
+}
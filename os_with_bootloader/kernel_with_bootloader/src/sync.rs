@@ -0,0 +1,25 @@
+// Blocking synchronization for kernel threads.
+//
+// Everything here parks the waiting thread on a `WaitQueue` instead of
+// spinning, so a contended lock costs no CPU time. None of it may be used from
+// interrupt handlers, which can't block; the only exceptions are the
+// non-blocking `try_*` methods and the wake-up side (`WaitQueue::wake_*`,
+// `Semaphore::release`, `Channel::try_send`). Code that shares state with an
+// interrupt handler uses `SpinLock` inside `without_interrupts`.
+
+mod channel;
+mod condvar;
+mod lockdep;
+mod mutex;
+mod rwlock;
+mod semaphore;
+mod spinlock;
+mod wait_queue;
+
+pub use channel::Channel;
+pub use condvar::Condvar;
+pub use mutex::{Mutex, MutexGuard};
+pub use rwlock::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+pub use semaphore::Semaphore;
+pub use spinlock::{SpinLock, SpinLockGuard};
+pub use wait_queue::WaitQueue;
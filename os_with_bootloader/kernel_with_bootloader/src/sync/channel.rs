@@ -0,0 +1,75 @@
+use super::WaitQueue;
+use alloc::collections::VecDeque;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Bounded multi-producer, multi-consumer queue. Share it through a `static`
+/// or an `Arc`.
+pub struct Channel<T> {
+    // Allocated up front and never grown, so `try_send` doesn't allocate and
+    // can be used from interrupt handlers.
+    buffer: spin::Mutex<VecDeque<T>>,
+    capacity: usize,
+    not_empty: WaitQueue,
+    not_full: WaitQueue,
+}
+
+impl<T> Channel<T> {
+    pub fn new(capacity: usize) -> Self {
+        assert!(capacity > 0, "channel capacity must not be zero");
+        Channel {
+            buffer: spin::Mutex::new(VecDeque::with_capacity(capacity)),
+            capacity,
+            not_empty: WaitQueue::new(),
+            not_full: WaitQueue::new(),
+        }
+    }
+
+    /// Queues `value`, blocking while the channel is full.
+    pub fn send(&self, value: T) {
+        let mut value = Some(value);
+        self.not_full.wait_until(|| {
+            let mut buffer = self.buffer.lock();
+            (buffer.len() < self.capacity).then(|| buffer.push_back(value.take().unwrap()))
+        });
+        self.not_empty.wake_one();
+    }
+
+    /// Queues `value` if there is room, otherwise hands it back.
+    pub fn try_send(&self, value: T) -> Result<(), T> {
+        without_interrupts(|| {
+            let mut buffer = self.buffer.lock();
+            if buffer.len() == self.capacity {
+                return Err(value);
+            }
+            buffer.push_back(value);
+            Ok(())
+        })?;
+        self.not_empty.wake_one();
+        Ok(())
+    }
+
+    /// Takes the oldest value, blocking while the channel is empty.
+    pub fn recv(&self) -> T {
+        let value = self.not_empty.wait_until(|| self.buffer.lock().pop_front());
+        self.not_full.wake_one();
+        value
+    }
+
+    pub fn try_recv(&self) -> Option<T> {
+        let value = without_interrupts(|| self.buffer.lock().pop_front())?;
+        self.not_full.wake_one();
+        Some(value)
+    }
+
+    pub fn len(&self) -> usize {
+        without_interrupts(|| self.buffer.lock().len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
@@ -0,0 +1,379 @@
+// Debug-build bookkeeping behind `SpinLock`.
+//
+// Every lock remembers which thread holds it and on which CPU, where it was
+// taken and whether interrupts were enabled at the time. That is enough to
+// catch a thread spinning on a lock it already holds itself, which is what
+// happens when an interrupt handler wants a lock the interrupted code holds.
+// Owners are threads rather than CPUs because a holder with interrupts
+// enabled can be preempted and carry on elsewhere; before the scheduler
+// runs, the CPU stands in for the thread. Waiters count their spins and
+// report a possible deadlock once they have waited too long.
+//
+// Lock order is checked like Linux's lockdep, with each lock being its own
+// class. Locks taken with interrupts disabled are pushed on a per-CPU stack;
+// taking B while A is on the stack records the edge A -> B. If B can already
+// reach A through recorded edges, the two orders can deadlock against each
+// other on two CPUs and a warning is printed, even if it never happened yet.
+// A lock taken with `try_lock` goes on the stack without recording edges to
+// it, since not waiting for it can't deadlock.
+// Locks taken with interrupts enabled are left out of the ordering: their
+// holder can be preempted and move to another CPU, so the stack would lie.
+//
+// Release builds keep none of this; `Tracking` is then an empty struct.
+
+#[cfg(debug_assertions)]
+pub(super) use debug::Tracking;
+
+#[cfg(not(debug_assertions))]
+pub(super) struct Tracking;
+
+#[cfg(not(debug_assertions))]
+impl Tracking {
+    pub const fn new() -> Self {
+        Tracking
+    }
+}
+
+#[cfg(debug_assertions)]
+mod debug {
+    use crate::cpu::{self, MAX_CPUS};
+    use crate::writer;
+    use core::panic::Location;
+    use core::ptr;
+    use core::sync::atomic::{AtomicBool, AtomicPtr, AtomicUsize, Ordering};
+    use x86_64::instructions::interrupts;
+    use x86_64::registers::model_specific::GsBase;
+
+    // After spinning this often a waiter reports a possible deadlock.
+    const SPIN_LIMIT: usize = 1 << 26;
+    // Marks an owner that is a CPU, for locks taken before threads exist.
+    const NO_THREAD: usize = 1 << 63;
+    // Locks one CPU can hold at once with interrupts disabled.
+    const MAX_HELD: usize = 16;
+    // Distinct "A held while taking B" pairs remembered.
+    const MAX_EDGES: usize = 128;
+
+    type CallSite = &'static Location<'static>;
+
+    pub struct Tracking {
+        // `owner_id()` of the holder plus one, 0 while free.
+        owner: AtomicUsize,
+        // CPU the holder took the lock on.
+        owner_cpu: AtomicUsize,
+        site: AtomicPtr<Location<'static>>,
+        interrupts_enabled: AtomicBool,
+        // Each kind of warning is printed once per lock.
+        warned_interrupts: AtomicBool,
+        warned_order: AtomicBool,
+    }
+
+    impl Tracking {
+        pub const fn new() -> Self {
+            Tracking {
+                owner: AtomicUsize::new(0),
+                owner_cpu: AtomicUsize::new(0),
+                site: AtomicPtr::new(ptr::null_mut()),
+                interrupts_enabled: AtomicBool::new(false),
+                warned_interrupts: AtomicBool::new(false),
+                warned_order: AtomicBool::new(false),
+            }
+        }
+
+        fn holder(&self) -> Option<(Owner, usize, Option<CallSite>)> {
+            let owner = self.owner.load(Ordering::Relaxed).checked_sub(1)?;
+            let cpu = self.owner_cpu.load(Ordering::Relaxed);
+            let site = unsafe { self.site.load(Ordering::Relaxed).as_ref() };
+            Some((Owner(owner), cpu, site))
+        }
+
+        /// Called before the first attempt to take the lock. Panics on a
+        /// certain self-deadlock, warns about likely ones.
+        pub fn before_lock(&self, id: usize, name: &str, caller: CallSite) {
+            let me = owner_id();
+            let Some((owner, _, site)) = self.holder() else {
+                return;
+            };
+            if owner.0 != me {
+                return;
+            }
+            let site = Site(site);
+            if !self.interrupts_enabled.load(Ordering::Relaxed) {
+                // Taken with interrupts disabled, so the holder can't have
+                // been preempted: it is further up this very call chain.
+                panic!(
+                    "deadlock: spin lock {:#x} ({}) taken again by {} on CPU {} at {}, already held since {}",
+                    id,
+                    name,
+                    owner,
+                    cpu_index(),
+                    caller,
+                    site
+                );
+            }
+            if !interrupts::are_enabled() && !self.warned_interrupts.swap(true, Ordering::Relaxed) {
+                report(format_args!(
+                    "lockdep: spin lock {:#x} ({}) wanted with interrupts disabled by {} on CPU {} at {}, \
+                     but it took the lock with interrupts enabled at {}. If this is an interrupt \
+                     handler, it never gets the lock; take it inside `without_interrupts` instead.",
+                    id,
+                    name,
+                    owner,
+                    cpu_index(),
+                    caller,
+                    site
+                ));
+            }
+        }
+
+        /// Called on every spin while the lock is held by someone else.
+        pub fn spinning(&self, id: usize, name: &str, caller: CallSite, spins: usize) {
+            if spins != SPIN_LIMIT {
+                return;
+            }
+            let me = Owner(owner_id());
+            match self.holder() {
+                Some((owner, cpu, site)) => report(format_args!(
+                    "lockdep: {} spinning on CPU {} at {} on lock {:#x} ({}) held by {} (taken on CPU {}) since {}",
+                    me,
+                    cpu_index(),
+                    caller,
+                    id,
+                    name,
+                    owner,
+                    cpu,
+                    Site(site)
+                )),
+                None => report(format_args!(
+                    "lockdep: {} spinning on CPU {} at {} on lock {:#x} ({}) held by <unknown>",
+                    me,
+                    cpu_index(),
+                    caller,
+                    id,
+                    name
+                )),
+            }
+        }
+
+        pub fn acquired(&self, id: usize, name: &str, caller: CallSite) {
+            let me = cpu_index();
+            if !self.record_holder(me, caller) {
+                check_order(self, id, name, caller, me);
+                push_held(me, id, caller);
+            }
+        }
+
+        /// Like `acquired`, for a lock taken without waiting. That can't
+        /// deadlock, so it records no order of its own; the locks taken while
+        /// it is held still order after it.
+        pub fn try_acquired(&self, id: usize, caller: CallSite) {
+            let me = cpu_index();
+            if !self.record_holder(me, caller) {
+                push_held(me, id, caller);
+            }
+        }
+
+        // Returns whether interrupts are enabled.
+        fn record_holder(&self, me: usize, caller: CallSite) -> bool {
+            let enabled = interrupts::are_enabled();
+            self.owner.store(owner_id() + 1, Ordering::Relaxed);
+            self.owner_cpu.store(me, Ordering::Relaxed);
+            self.site.store(ptr::from_ref(caller).cast_mut(), Ordering::Relaxed);
+            self.interrupts_enabled.store(enabled, Ordering::Relaxed);
+            enabled
+        }
+
+        pub fn released(&self, id: usize) {
+            self.owner.store(0, Ordering::Relaxed);
+            self.site.store(ptr::null_mut(), Ordering::Relaxed);
+            let held = &HELD[cpu_index()];
+            let len = held.len.load(Ordering::Relaxed);
+            if let Some(i) = (0..len).rev().find(|&i| held.ids[i].load(Ordering::Relaxed) == id) {
+                for j in i..len - 1 {
+                    held.ids[j].store(held.ids[j + 1].load(Ordering::Relaxed), Ordering::Relaxed);
+                    held.sites[j].store(held.sites[j + 1].load(Ordering::Relaxed), Ordering::Relaxed);
+                }
+                held.len.store(len - 1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // Formats a possibly unknown call site.
+    struct Site(Option<CallSite>);
+
+    impl core::fmt::Display for Site {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            match self.0 {
+                Some(site) => write!(f, "{}", site),
+                None => f.write_str("<unknown>"),
+            }
+        }
+    }
+
+    // Locks are taken before the per-CPU data exists; that is only ever the
+    // bootstrap CPU.
+    fn cpu_index() -> usize {
+        if GsBase::read().is_null() { 0 } else { cpu::id() }
+    }
+
+    // The running thread's id, or the CPU's marked with `NO_THREAD` while
+    // the CPU hasn't joined the scheduler.
+    fn owner_id() -> usize {
+        let thread = if GsBase::read().is_null() { None } else { cpu::current().current_thread() };
+        match thread {
+            Some(thread) => thread as usize,
+            None => NO_THREAD | cpu_index(),
+        }
+    }
+
+    // Formats an `owner_id()`.
+    struct Owner(usize);
+
+    impl core::fmt::Display for Owner {
+        fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+            if self.0 & NO_THREAD != 0 {
+                write!(f, "CPU {} (no thread yet)", self.0 & !NO_THREAD)
+            } else {
+                write!(f, "thread {}", self.0)
+            }
+        }
+    }
+
+    fn report(args: core::fmt::Arguments) {
+        writer::print_diagnostic(format_args!("\n{}\n", args));
+    }
+
+    struct HeldLocks {
+        len: AtomicUsize,
+        ids: [AtomicUsize; MAX_HELD],
+        sites: [AtomicPtr<Location<'static>>; MAX_HELD],
+    }
+
+    // Only ever touched by its own CPU, with interrupts disabled.
+    static HELD: [HeldLocks; MAX_CPUS] = [const {
+        HeldLocks {
+            len: AtomicUsize::new(0),
+            ids: [const { AtomicUsize::new(0) }; MAX_HELD],
+            sites: [const { AtomicPtr::new(ptr::null_mut()) }; MAX_HELD],
+        }
+    }; MAX_CPUS];
+
+    #[derive(Clone, Copy)]
+    struct Edge {
+        from: usize,
+        to: usize,
+        site: CallSite,
+    }
+
+    struct Graph {
+        edges: [Option<Edge>; MAX_EDGES],
+        len: usize,
+        warned_full: bool,
+    }
+
+    impl Graph {
+        fn contains(&self, from: usize, to: usize) -> bool {
+            self.edges[..self.len].iter().flatten().any(|e| e.from == from && e.to == to)
+        }
+
+        // The first edge of a path from `from` to `to`, if there is one.
+        // Breadth-first; each queue entry remembers the edge it started with.
+        fn path(&self, from: usize, to: usize) -> Option<Edge> {
+            let edges = &self.edges[..self.len];
+            let mut queue = [(0usize, usize::MAX); MAX_EDGES + 1];
+            let (mut head, mut tail) = (0, 1);
+            queue[0] = (from, usize::MAX);
+            while head < tail {
+                let (node, first) = queue[head];
+                head += 1;
+                for (i, edge) in edges.iter().enumerate() {
+                    let Some(edge) = edge.filter(|e| e.from == node) else {
+                        continue;
+                    };
+                    let first = if first == usize::MAX { i } else { first };
+                    if edge.to == to {
+                        return edges[first];
+                    }
+                    let seen = queue[..tail].iter().any(|&(n, _)| n == edge.to);
+                    if !seen && tail < queue.len() {
+                        queue[tail] = (edge.to, first);
+                        tail += 1;
+                    }
+                }
+            }
+            None
+        }
+    }
+
+    static GRAPH: spin::Mutex<Graph> = spin::Mutex::new(Graph {
+        edges: [None; MAX_EDGES],
+        len: 0,
+        warned_full: false,
+    });
+
+    fn check_order(lock: &Tracking, id: usize, name: &str, caller: CallSite, me: usize) {
+        let held = &HELD[me];
+        let len = held.len.load(Ordering::Relaxed);
+        // Reported once the graph is unlocked: printing takes the writer lock,
+        // which comes back here.
+        let mut inversion = None;
+        let mut full = false;
+        {
+            let mut graph = GRAPH.lock();
+            for i in 0..len {
+                let held_id = held.ids[i].load(Ordering::Relaxed);
+                if held_id == id || graph.contains(held_id, id) {
+                    continue;
+                }
+                if inversion.is_none() {
+                    if let Some(edge) = graph.path(id, held_id) {
+                        let held_site = unsafe { held.sites[i].load(Ordering::Relaxed).as_ref() };
+                        inversion = Some((held_id, held_site, edge));
+                    }
+                }
+                if graph.len < MAX_EDGES {
+                    let index = graph.len;
+                    graph.edges[index] = Some(Edge {
+                        from: held_id,
+                        to: id,
+                        site: caller,
+                    });
+                    graph.len += 1;
+                } else if !graph.warned_full {
+                    graph.warned_full = true;
+                    full = true;
+                }
+            }
+        }
+
+        if let Some((held_id, held_site, edge)) = inversion {
+            if !lock.warned_order.swap(true, Ordering::Relaxed) {
+                report(format_args!(
+                    "lockdep: possible deadlock: lock {:#x} ({}) taken on CPU {} at {} while \
+                     holding {:#x} (taken at {}), but {:#x} was held before when taking {:#x} at {}",
+                    id,
+                    name,
+                    me,
+                    caller,
+                    held_id,
+                    Site(held_site),
+                    id,
+                    edge.to,
+                    edge.site
+                ));
+            }
+        }
+        if full {
+            report(format_args!("lockdep: lock graph full, order checking is incomplete"));
+        }
+    }
+
+    fn push_held(me: usize, id: usize, caller: CallSite) {
+        let held = &HELD[me];
+        let len = held.len.load(Ordering::Relaxed);
+        if len < MAX_HELD {
+            held.ids[len].store(id, Ordering::Relaxed);
+            held.sites[len].store(ptr::from_ref(caller).cast_mut(), Ordering::Relaxed);
+            held.len.store(len + 1, Ordering::Relaxed);
+        }
+    }
+}
@@ -0,0 +1,137 @@
+use super::WaitQueue;
+use core::cell::UnsafeCell;
+use core::ops::{Deref, DerefMut};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Reader-writer lock that puts contending threads to sleep. Waiting writers
+/// hold off new readers, so a steady stream of readers can't starve them.
+pub struct RwLock<T: ?Sized> {
+    state: spin::Mutex<State>,
+    waiters: WaitQueue,
+    data: UnsafeCell<T>,
+}
+
+struct State {
+    readers: usize,
+    writer: bool,
+    waiting_writers: usize,
+}
+
+unsafe impl<T: ?Sized + Send> Send for RwLock<T> {}
+unsafe impl<T: ?Sized + Send + Sync> Sync for RwLock<T> {}
+
+pub struct RwLockReadGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+pub struct RwLockWriteGuard<'a, T: ?Sized> {
+    lock: &'a RwLock<T>,
+}
+
+impl<T> RwLock<T> {
+    pub const fn new(value: T) -> Self {
+        RwLock {
+            state: spin::Mutex::new(State {
+                readers: 0,
+                writer: false,
+                waiting_writers: 0,
+            }),
+            waiters: WaitQueue::new(),
+            data: UnsafeCell::new(value),
+        }
+    }
+}
+
+impl<T: ?Sized> RwLock<T> {
+    pub fn read(&self) -> RwLockReadGuard<'_, T> {
+        match self.try_read() {
+            Some(guard) => guard,
+            None => self.waiters.wait_until(|| self.try_read()),
+        }
+    }
+
+    pub fn try_read(&self) -> Option<RwLockReadGuard<'_, T>> {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if state.writer || state.waiting_writers > 0 {
+                return None;
+            }
+            state.readers += 1;
+            Some(RwLockReadGuard { lock: self })
+        })
+    }
+
+    pub fn write(&self) -> RwLockWriteGuard<'_, T> {
+        if let Some(guard) = self.try_write() {
+            return guard;
+        }
+        without_interrupts(|| self.state.lock().waiting_writers += 1);
+        self.waiters.wait_until(|| {
+            let mut state = self.state.lock();
+            if state.writer || state.readers > 0 {
+                return None;
+            }
+            state.writer = true;
+            state.waiting_writers -= 1;
+            Some(RwLockWriteGuard { lock: self })
+        })
+    }
+
+    pub fn try_write(&self) -> Option<RwLockWriteGuard<'_, T>> {
+        without_interrupts(|| {
+            let mut state = self.state.lock();
+            if state.writer || state.readers > 0 {
+                return None;
+            }
+            state.writer = true;
+            Some(RwLockWriteGuard { lock: self })
+        })
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockReadGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockReadGuard<'_, T> {
+    fn drop(&mut self) {
+        let last = without_interrupts(|| {
+            let mut state = self.lock.state.lock();
+            state.readers -= 1;
+            state.readers == 0
+        });
+        if last {
+            self.lock.waiters.wake_all();
+        }
+    }
+}
+
+impl<T: ?Sized> Deref for RwLockWriteGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> DerefMut for RwLockWriteGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.lock.data.get() }
+    }
+}
+
+impl<T: ?Sized> Drop for RwLockWriteGuard<'_, T> {
+    fn drop(&mut self) {
+        without_interrupts(|| self.lock.state.lock().writer = false);
+        // Readers and writers wait on the same queue; let them sort it out.
+        self.lock.waiters.wake_all();
+    }
+}
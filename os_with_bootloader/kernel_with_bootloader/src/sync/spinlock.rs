@@ -0,0 +1,110 @@
+use super::lockdep::Tracking;
+use core::ops::{Deref, DerefMut};
+#[cfg(debug_assertions)]
+use core::panic::Location;
+
+/// Spin lock for state shared with interrupt handlers or other CPUs.
+///
+/// A drop-in for `spin::Mutex`. Debug builds also check how it is used, see
+/// `lockdep.rs`: taking it again in the thread that holds it panics, lock
+/// order inversions and long waits are reported.
+pub struct SpinLock<T> {
+    tracking: Tracking,
+    inner: spin::Mutex<T>,
+}
+
+impl<T> SpinLock<T> {
+    pub const fn new(value: T) -> Self {
+        SpinLock {
+            tracking: Tracking::new(),
+            inner: spin::Mutex::new(value),
+        }
+    }
+
+    #[cfg(debug_assertions)]
+    fn id(&self) -> usize {
+        self as *const Self as usize
+    }
+
+    #[cfg(debug_assertions)]
+    fn name() -> &'static str {
+        core::any::type_name::<T>()
+    }
+
+    #[track_caller]
+    pub fn lock(&self) -> SpinLockGuard<'_, T> {
+        #[cfg(debug_assertions)]
+        {
+            let caller = Location::caller();
+            self.tracking.before_lock(self.id(), Self::name(), caller);
+            let mut spins = 0;
+            loop {
+                if let Some(inner) = self.inner.try_lock() {
+                    self.tracking.acquired(self.id(), Self::name(), caller);
+                    return SpinLockGuard { lock: self, inner };
+                }
+                while self.inner.is_locked() {
+                    spins += 1;
+                    self.tracking.spinning(self.id(), Self::name(), caller, spins);
+                    core::hint::spin_loop();
+                }
+            }
+        }
+        #[cfg(not(debug_assertions))]
+        SpinLockGuard {
+            lock: self,
+            inner: self.inner.lock(),
+        }
+    }
+
+    #[track_caller]
+    pub fn try_lock(&self) -> Option<SpinLockGuard<'_, T>> {
+        let inner = self.inner.try_lock()?;
+        #[cfg(debug_assertions)]
+        self.tracking.try_acquired(self.id(), Location::caller());
+        Some(SpinLockGuard { lock: self, inner })
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.inner.is_locked()
+    }
+
+    /// Releases the lock without a guard, for a guard that was forgotten.
+    ///
+    /// # Safety
+    /// The lock must be held and its guard must not be used anymore.
+    pub unsafe fn force_unlock(&self) {
+        #[cfg(debug_assertions)]
+        self.tracking.released(self.id());
+        unsafe { self.inner.force_unlock() }
+    }
+}
+
+pub struct SpinLockGuard<'a, T> {
+    lock: &'a SpinLock<T>,
+    inner: spin::MutexGuard<'a, T>,
+}
+
+impl<T> Deref for SpinLockGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.inner
+    }
+}
+
+impl<T> DerefMut for SpinLockGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+
+impl<T> Drop for SpinLockGuard<'_, T> {
+    // Runs before `inner` unlocks.
+    fn drop(&mut self) {
+        #[cfg(debug_assertions)]
+        self.lock.tracking.released(self.lock.id());
+        #[cfg(not(debug_assertions))]
+        let _ = self.lock;
+    }
+}
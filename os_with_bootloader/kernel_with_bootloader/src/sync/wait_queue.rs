@@ -0,0 +1,84 @@
+use crate::task::{self, ThreadId};
+use alloc::collections::VecDeque;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// Threads waiting for something to happen.
+pub struct WaitQueue {
+    waiters: spin::Mutex<VecDeque<ThreadId>>,
+}
+
+impl WaitQueue {
+    pub const fn new() -> Self {
+        WaitQueue {
+            waiters: spin::Mutex::new(VecDeque::new()),
+        }
+    }
+
+    /// Blocks until `condition` returns `Some`. The condition runs with
+    /// interrupts disabled and the queue locked, so a waker that changes the
+    /// state and then calls `wake_*` can't be missed.
+    pub fn wait_until<R>(&self, mut condition: impl FnMut() -> Option<R>) -> R {
+        loop {
+            let ready = without_interrupts(|| {
+                let mut waiters = self.waiters.lock();
+                if let Some(result) = condition() {
+                    return Some(result);
+                }
+                // Before the scheduler runs there is nobody to hand the CPU
+                // to; keep polling.
+                if let Some(id) = task::current_id() {
+                    waiters.push_back(id);
+                    task::block_current();
+                }
+                None
+            });
+            match ready {
+                Some(result) => return result,
+                None => task::yield_now(),
+            }
+        }
+    }
+
+    /// Blocks once, without a condition. `before_sleep` runs after the thread
+    /// is queued and before it gives up the CPU, which is what a condition
+    /// variable needs to release its mutex without losing a wakeup. Wakeups
+    /// may be spurious.
+    pub fn park(&self, before_sleep: impl FnOnce()) {
+        without_interrupts(|| {
+            if let Some(id) = task::current_id() {
+                self.waiters.lock().push_back(id);
+                task::block_current();
+            }
+            before_sleep();
+            task::yield_now();
+        });
+    }
+
+    /// Wakes the longest-waiting thread. Returns `false` if nobody was waiting.
+    pub fn wake_one(&self) -> bool {
+        let waiter = without_interrupts(|| self.waiters.lock().pop_front());
+        match waiter {
+            Some(id) => {
+                task::wake(id);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Wakes every waiting thread and returns how many there were.
+    pub fn wake_all(&self) -> usize {
+        let waiters = without_interrupts(|| core::mem::take(&mut *self.waiters.lock()));
+        let count = waiters.len();
+        for id in waiters {
+            task::wake(id);
+        }
+        count
+    }
+}
+
+impl Default for WaitQueue {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -0,0 +1,305 @@
+// System calls.
+//
+// User code enters the kernel with `syscall` (configured through the STAR,
+// LSTAR and SFMASK MSRs) or, as a fallback, with `int 0x80`. Both entry
+// paths save the user registers into a `SyscallFrame` on the thread's kernel
+// stack and hand it to the same dispatch table. Registers follow the Linux
+// convention: the number in rax, arguments in rdi, rsi, rdx, r10, r8, r9, the
+// result in rax, and errors as a negative errno. The numbers are our own.
+
+mod handlers;
+pub mod user_memory;
+
+use crate::cpu::{self, Cpu};
+use crate::fs::FsError;
+use crate::gdt;
+use crate::memory::protection;
+use crate::process::{self, loader::USER_SPACE_END};
+use core::mem::offset_of;
+use core::sync::atomic::Ordering;
+use x86_64::registers::model_specific::{Efer, EferFlags, LStar, SFMask, Star};
+use x86_64::registers::rflags::RFlags;
+use x86_64::VirtAddr;
+
+/// System call numbers. The user-side crate has its own copy; keep them in sync.
+pub mod nr {
+    pub const READ: usize = 0;
+    pub const WRITE: usize = 1;
+    pub const OPEN: usize = 2;
+    pub const CLOSE: usize = 3;
+    pub const MMAP: usize = 4;
+    pub const SLEEP: usize = 5;
+    pub const GETPID: usize = 6;
+    pub const EXIT: usize = 7;
+    pub const LSEEK: usize = 8;
+    pub const STAT: usize = 9;
+    pub const FSTAT: usize = 10;
+    pub const GETDENTS: usize = 11;
+}
+
+const SYSCALL_COUNT: usize = 12;
+
+const GS_BASE_MSR: u32 = 0xC000_0101;
+const KERNEL_GS_BASE_MSR: u32 = 0xC000_0102;
+
+/// Interrupt vector of the `int 0x80` entry point.
+pub const INT80_VECTOR: usize = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(i64)]
+pub enum Errno {
+    ENOENT = 2,
+    EIO = 5,
+    EBADF = 9,
+    ENOMEM = 12,
+    EFAULT = 14,
+    EBUSY = 16,
+    EEXIST = 17,
+    ENOTDIR = 20,
+    EISDIR = 21,
+    EINVAL = 22,
+    EMFILE = 24,
+    ENOSPC = 28,
+    EROFS = 30,
+    ENAMETOOLONG = 36,
+    ENOSYS = 38,
+    ENOTEMPTY = 39,
+}
+
+impl From<FsError> for Errno {
+    fn from(err: FsError) -> Self {
+        match err {
+            FsError::NotFound => Errno::ENOENT,
+            FsError::NotADirectory => Errno::ENOTDIR,
+            FsError::IsADirectory => Errno::EISDIR,
+            FsError::AlreadyExists => Errno::EEXIST,
+            FsError::DirectoryNotEmpty => Errno::ENOTEMPTY,
+            FsError::ReadOnly => Errno::EROFS,
+            FsError::InvalidPath | FsError::InvalidArgument => Errno::EINVAL,
+            FsError::BadFileDescriptor => Errno::EBADF,
+            FsError::TooManyOpenFiles => Errno::EMFILE,
+            FsError::NotSupported => Errno::EINVAL,
+            FsError::Busy => Errno::EBUSY,
+            FsError::NoSpace => Errno::ENOSPC,
+            FsError::Io => Errno::EIO,
+        }
+    }
+}
+
+/// User registers as pushed by the entry stubs below; the field order is the
+/// reverse of the push order.
+#[derive(Debug)]
+#[repr(C)]
+pub struct SyscallFrame {
+    pub r15: u64,
+    pub r14: u64,
+    pub r13: u64,
+    pub r12: u64,
+    pub rbp: u64,
+    pub rbx: u64,
+    pub r9: u64,
+    pub r8: u64,
+    pub r10: u64,
+    pub rdx: u64,
+    pub rsi: u64,
+    pub rdi: u64,
+    pub rax: u64,
+    pub r11: u64,
+    pub rcx: u64,
+}
+
+impl SyscallFrame {
+    fn args(&self) -> [u64; 6] {
+        [self.rdi, self.rsi, self.rdx, self.r10, self.r8, self.r9]
+    }
+}
+
+type Handler = fn(&[u64; 6]) -> Result<u64, Errno>;
+
+static SYSCALLS: [Option<Handler>; SYSCALL_COUNT] = {
+    let mut table: [Option<Handler>; SYSCALL_COUNT] = [None; SYSCALL_COUNT];
+    table[nr::READ] = Some(handlers::read);
+    table[nr::WRITE] = Some(handlers::write);
+    table[nr::OPEN] = Some(handlers::open);
+    table[nr::CLOSE] = Some(handlers::close);
+    table[nr::MMAP] = Some(handlers::mmap);
+    table[nr::SLEEP] = Some(handlers::sleep);
+    table[nr::GETPID] = Some(handlers::getpid);
+    table[nr::EXIT] = Some(handlers::exit);
+    table[nr::LSEEK] = Some(handlers::lseek);
+    table[nr::STAT] = Some(handlers::stat);
+    table[nr::FSTAT] = Some(handlers::fstat);
+    table[nr::GETDENTS] = Some(handlers::getdents);
+    table
+};
+
+/// Enables `syscall`/`sysret` on the calling CPU. Needs the GDT from `gdt::init`.
+pub fn init() {
+    let selectors = gdt::selectors();
+    unsafe {
+        Efer::update(|flags| flags.insert(EferFlags::SYSTEM_CALL_EXTENSIONS));
+    }
+    Star::write(
+        selectors.user_code,
+        selectors.user_data,
+        selectors.kernel_code,
+        selectors.kernel_data,
+    )
+    .expect("GDT layout does not fit syscall/sysret");
+    LStar::write(VirtAddr::from_ptr(syscall_entry as *const ()));
+    // Clearing AC keeps a user `popf` from switching SMAP off in the kernel.
+    SFMask::write(RFlags::INTERRUPT_FLAG | RFlags::DIRECTION_FLAG | RFlags::ALIGNMENT_CHECK);
+}
+
+/// Sets the stack `syscall` switches to. Called on every context switch.
+pub fn set_kernel_stack(top: VirtAddr) {
+    cpu::current().kernel_rsp.store(top.as_u64(), Ordering::Relaxed);
+}
+
+fn dispatch(frame: &mut SyscallFrame) {
+    let number = frame.rax as usize;
+    let result = match SYSCALLS.get(number).copied().flatten() {
+        Some(handler) => handler(&frame.args()),
+        None => Err(Errno::ENOSYS),
+    };
+    frame.rax = match result {
+        Ok(value) => value,
+        Err(errno) => (-(errno as i64)) as u64,
+    };
+}
+
+extern "C" fn syscall_dispatch(frame: &mut SyscallFrame) {
+    dispatch(frame);
+    // `sysret` to a non-canonical address faults in ring 0, with the user's
+    // stack pointer already loaded. It can only happen if the `syscall`
+    // instruction was the last one below the hole.
+    if frame.rcx >= USER_SPACE_END {
+        process::kill_current(format_args!("syscall returns to {:#x}", frame.rcx));
+    }
+}
+
+extern "C" fn int80_dispatch(frame: &mut SyscallFrame) {
+    dispatch(frame);
+}
+
+// `syscall` doesn't switch stacks. The stub gets the kernel stack of the
+// current thread from the per-CPU data, which it reaches through a `swapgs`
+// because GS_BASE may have been changed by user code. Interrupts stay masked
+// until the user registers are saved.
+#[unsafe(naked)]
+extern "C" fn syscall_entry() {
+    core::arch::naked_asm!(
+        "swapgs",
+        "mov gs:[{user_rsp}], rsp",
+        "mov rsp, gs:[{kernel_rsp}]",
+        "push qword ptr gs:[{user_rsp}]",
+        // rcx and r11 hold the user rip and rflags for `sysret`.
+        "push rcx",
+        "push r11",
+        "push rax",
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push r10",
+        "push r8",
+        "push r9",
+        "push rbx",
+        "push rbp",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        // The swap left the user's GS base in KERNEL_GS_BASE; put the per-CPU
+        // pointer back so both hold it again.
+        "mov ecx, {kernel_gs_base}",
+        "mov rax, gs:[0]",
+        "mov rdx, rax",
+        "shr rdx, 32",
+        "wrmsr",
+        "mov rdi, rsp",
+        "sti",
+        "call {dispatch}",
+        "cli",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "pop r9",
+        "pop r8",
+        "pop r10",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop rax",
+        "pop r11",
+        "pop rcx",
+        "pop rsp",
+        "sysretq",
+        user_rsp = const offset_of!(Cpu, user_rsp_scratch),
+        kernel_rsp = const offset_of!(Cpu, kernel_rsp),
+        kernel_gs_base = const KERNEL_GS_BASE_MSR,
+        dispatch = sym syscall_dispatch,
+    );
+}
+
+/// `int 0x80` entry point, registered in the IDT by `interrupts.rs`. The CPU
+/// has already switched to the kernel stack from the TSS and pushed the
+/// interrupt frame.
+#[unsafe(naked)]
+pub extern "C" fn int80_entry() {
+    core::arch::naked_asm!(
+        // The gate leaves EFLAGS.AC as user code set it; `clac` only exists
+        // on CPUs with SMAP.
+        "cmp byte ptr [rip + {smap_enabled}], 0",
+        "je 2f",
+        "clac",
+        "2:",
+        "push rcx",
+        "push r11",
+        "push rax",
+        "push rdi",
+        "push rsi",
+        "push rdx",
+        "push r10",
+        "push r8",
+        "push r9",
+        "push rbx",
+        "push rbp",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        // Coming from ring 3, so GS_BASE can't be trusted.
+        "mov ecx, {kernel_gs_base}",
+        "rdmsr",
+        "mov ecx, {gs_base}",
+        "wrmsr",
+        // 5 words of interrupt frame + 15 registers keep rsp 16-byte aligned.
+        "mov rdi, rsp",
+        "sti",
+        "call {dispatch}",
+        "cli",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbp",
+        "pop rbx",
+        "pop r9",
+        "pop r8",
+        "pop r10",
+        "pop rdx",
+        "pop rsi",
+        "pop rdi",
+        "pop rax",
+        "pop r11",
+        "pop rcx",
+        "iretq",
+        kernel_gs_base = const KERNEL_GS_BASE_MSR,
+        gs_base = const GS_BASE_MSR,
+        smap_enabled = sym protection::SMAP_ENABLED,
+        dispatch = sym int80_dispatch,
+    );
+}
@@ -0,0 +1,212 @@
+use super::user_memory::{self, MAX_IO_SIZE};
+use super::Errno;
+use crate::fs::{self, File, FileType, Metadata, OpenFlags, SeekFrom};
+use crate::memory::PagingError;
+use crate::process;
+use crate::task;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+
+const PROT_WRITE: u64 = 2;
+const PROT_EXEC: u64 = 4;
+const MAP_ANONYMOUS: u64 = 0x20;
+
+const SEEK_SET: u64 = 0;
+const SEEK_CUR: u64 = 1;
+const SEEK_END: u64 = 2;
+
+const PATH_MAX: usize = 4096;
+
+/// What `stat` and `fstat` fill in.
+#[repr(C)]
+struct Stat {
+    inode: u64,
+    size: u64,
+    mode: u32,
+    kind: u32,
+}
+
+// Kinds in `Stat` and in directory entries, with the Linux `DT_*` values.
+fn kind_code(kind: FileType) -> u8 {
+    match kind {
+        FileType::File => 8,
+        FileType::Directory => 4,
+        FileType::CharDevice => 2,
+        FileType::BlockDevice => 6,
+        FileType::Symlink => 10,
+    }
+}
+
+fn file(fd: u64) -> Result<Arc<File>, Errno> {
+    let process = process::current().ok_or(Errno::EBADF)?;
+    Ok(process.with_files(|files| files.get(fd as usize))?)
+}
+
+fn read_path(addr: u64, len: u64) -> Result<String, Errno> {
+    if len as usize > PATH_MAX {
+        return Err(Errno::ENAMETOOLONG);
+    }
+    let bytes = user_memory::read_bytes(addr, len as usize)?;
+    String::from_utf8(bytes).map_err(|_| Errno::EINVAL)
+}
+
+fn copy_stat(dst: u64, metadata: Metadata) -> Result<u64, Errno> {
+    let stat = Stat {
+        inode: metadata.inode,
+        size: metadata.size,
+        mode: metadata.mode,
+        kind: u32::from(kind_code(metadata.kind)),
+    };
+    let bytes = unsafe {
+        core::slice::from_raw_parts((&raw const stat).cast::<u8>(), size_of::<Stat>())
+    };
+    user_memory::copy_to_user(dst, bytes)?;
+    Ok(0)
+}
+
+/// `read(fd, buf, len)`. Reading the console blocks until at least one byte
+/// has been typed.
+pub fn read(args: &[u64; 6]) -> Result<u64, Errno> {
+    let [fd, buf, len, ..] = *args;
+    let file = file(fd)?;
+    let len = (len as usize).min(MAX_IO_SIZE);
+    user_memory::validate(buf, len, true)?;
+    if len == 0 {
+        return Ok(0);
+    }
+
+    let mut data = vec![0; len];
+    let count = file.read(&mut data)?;
+    user_memory::copy_to_user(buf, &data[..count])?;
+    Ok(count as u64)
+}
+
+/// `write(fd, buf, len)`.
+pub fn write(args: &[u64; 6]) -> Result<u64, Errno> {
+    let [fd, buf, len, ..] = *args;
+    let file = file(fd)?;
+    let data = user_memory::read_bytes(buf, len as usize)?;
+    Ok(file.write(&data)? as u64)
+}
+
+/// `open(path, path_len, flags)`, with the Linux `O_*` flags.
+pub fn open(args: &[u64; 6]) -> Result<u64, Errno> {
+    let [path, path_len, flags, ..] = *args;
+    let path = read_path(path, path_len)?;
+    let flags = u32::try_from(flags).ok().and_then(OpenFlags::from_bits).ok_or(Errno::EINVAL)?;
+    let process = process::current().ok_or(Errno::EINVAL)?;
+    let file = fs::open(&path, flags)?;
+    Ok(process.with_files(|files| files.insert(file))? as u64)
+}
+
+/// `close(fd)`.
+pub fn close(args: &[u64; 6]) -> Result<u64, Errno> {
+    let process = process::current().ok_or(Errno::EBADF)?;
+    process.with_files(|files| files.remove(args[0] as usize))?;
+    Ok(0)
+}
+
+/// `lseek(fd, offset, whence)`. Returns the new offset.
+pub fn lseek(args: &[u64; 6]) -> Result<u64, Errno> {
+    let [fd, offset, whence, ..] = *args;
+    let pos = match whence {
+        SEEK_SET => SeekFrom::Start(offset),
+        SEEK_CUR => SeekFrom::Current(offset as i64),
+        SEEK_END => SeekFrom::End(offset as i64),
+        _ => return Err(Errno::EINVAL),
+    };
+    Ok(file(fd)?.seek(pos)?)
+}
+
+/// `stat(path, path_len, statbuf)`.
+pub fn stat(args: &[u64; 6]) -> Result<u64, Errno> {
+    let [path, path_len, statbuf, ..] = *args;
+    let path = read_path(path, path_len)?;
+    copy_stat(statbuf, fs::stat(&path)?)
+}
+
+/// `fstat(fd, statbuf)`.
+pub fn fstat(args: &[u64; 6]) -> Result<u64, Errno> {
+    let [fd, statbuf, ..] = *args;
+    copy_stat(statbuf, file(fd)?.stat())
+}
+
+/// `getdents(fd, buf, len)`: as many entries as fit, in Linux's
+/// `linux_dirent64` layout. Returns the bytes written, 0 at the end.
+pub fn getdents(args: &[u64; 6]) -> Result<u64, Errno> {
+    let [fd, buf, len, ..] = *args;
+    let len = (len as usize).min(MAX_IO_SIZE);
+    user_memory::validate(buf, len, true)?;
+    let file = file(fd)?;
+
+    let mut data = Vec::new();
+    let mut too_small = false;
+    file.read_dir(|entry| {
+        // inode, offset, record length, type, then the name with a NUL
+        let record_len = (8 + 8 + 2 + 1 + entry.name.len() + 1).next_multiple_of(8);
+        if data.len() + record_len > len {
+            too_small = data.is_empty();
+            return false;
+        }
+        let next = data.len() + record_len;
+        data.extend_from_slice(&entry.inode.to_le_bytes());
+        data.extend_from_slice(&(next as u64).to_le_bytes());
+        data.extend_from_slice(&(record_len as u16).to_le_bytes());
+        data.push(kind_code(entry.kind));
+        data.extend_from_slice(entry.name.as_bytes());
+        data.resize(next, 0);
+        true
+    })?;
+    if too_small {
+        return Err(Errno::EINVAL);
+    }
+    user_memory::copy_to_user(buf, &data)?;
+    Ok(data.len() as u64)
+}
+
+/// `mmap(addr, len, prot, flags, fd, offset)`. Only anonymous mappings are
+/// supported; the address hint is ignored.
+pub fn mmap(args: &[u64; 6]) -> Result<u64, Errno> {
+    let [_addr, len, prot, flags, ..] = *args;
+    if len == 0 || flags & MAP_ANONYMOUS == 0 {
+        return Err(Errno::EINVAL);
+    }
+    let process = process::current().ok_or(Errno::EINVAL)?;
+    let start = process.reserve_mmap_region(len).map_err(|_| Errno::ENOMEM)?;
+
+    let mut page_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if prot & PROT_WRITE != 0 {
+        page_flags |= PageTableFlags::WRITABLE;
+    }
+    if prot & PROT_EXEC == 0 {
+        page_flags |= PageTableFlags::NO_EXECUTE;
+    }
+    let first = Page::<Size4KiB>::containing_address(start);
+    let count = len.div_ceil(4096);
+    process
+        .with_space(|space| space.map_anonymous(first, count, page_flags))
+        .map_err(|err| match err {
+            PagingError::OutOfFrames => Errno::ENOMEM,
+            _ => Errno::EINVAL,
+        })?;
+    Ok(start.as_u64())
+}
+
+/// `sleep(ms)`.
+pub fn sleep(args: &[u64; 6]) -> Result<u64, Errno> {
+    task::sleep(args[0]);
+    Ok(0)
+}
+
+/// `getpid()`. Kernel threads get 0.
+pub fn getpid(_args: &[u64; 6]) -> Result<u64, Errno> {
+    Ok(process::current().map_or(0, |process| process.pid().as_u64()))
+}
+
+/// `exit(code)`.
+pub fn exit(args: &[u64; 6]) -> Result<u64, Errno> {
+    process::exit_current(args[0] as i64);
+}
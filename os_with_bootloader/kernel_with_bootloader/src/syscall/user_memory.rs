@@ -0,0 +1,72 @@
+// Access to user memory from system calls.
+//
+// Every user pointer is checked against the current process's page tables
+// before the kernel touches it: the whole range must be below the user/kernel
+// split and mapped user-accessible (and writable, for copies into user
+// space). The copy itself runs with SMAP lifted.
+
+use super::Errno;
+use crate::memory::protection;
+use crate::process::{self, loader::USER_SPACE_END};
+use alloc::vec;
+use alloc::vec::Vec;
+use x86_64::structures::paging::{Page, PageTableFlags, Size4KiB};
+use x86_64::VirtAddr;
+
+/// Upper bound for a single read or write, so a bad length can't make the
+/// kernel allocate without limit.
+pub const MAX_IO_SIZE: usize = 64 * 1024;
+
+/// Checks that `len` bytes at `addr` belong to the current process.
+pub fn validate(addr: u64, len: usize, write: bool) -> Result<(), Errno> {
+    if len == 0 {
+        return Ok(());
+    }
+    let end = addr
+        .checked_add(len as u64)
+        .filter(|&end| end <= USER_SPACE_END)
+        .ok_or(Errno::EFAULT)?;
+    let process = process::current().ok_or(Errno::EFAULT)?;
+
+    let mut required = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE;
+    if write {
+        required |= PageTableFlags::WRITABLE;
+    }
+    let first = Page::<Size4KiB>::containing_address(VirtAddr::new(addr));
+    let last = Page::<Size4KiB>::containing_address(VirtAddr::new(end - 1));
+    process.with_space(|space| {
+        for page in Page::range_inclusive(first, last) {
+            match space.translate_with_flags(page.start_address()) {
+                Some((_, flags)) if flags.contains(required) => {}
+                _ => return Err(Errno::EFAULT),
+            }
+        }
+        Ok(())
+    })
+}
+
+pub fn copy_from_user(dst: &mut [u8], src: u64) -> Result<(), Errno> {
+    validate(src, dst.len(), false)?;
+    protection::with_user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(src as *const u8, dst.as_mut_ptr(), dst.len());
+    });
+    Ok(())
+}
+
+pub fn copy_to_user(dst: u64, src: &[u8]) -> Result<(), Errno> {
+    validate(dst, src.len(), true)?;
+    protection::with_user_access(|| unsafe {
+        core::ptr::copy_nonoverlapping(src.as_ptr(), dst as *mut u8, src.len());
+    });
+    Ok(())
+}
+
+/// Copies a user buffer of at most [`MAX_IO_SIZE`] bytes into the kernel.
+pub fn read_bytes(src: u64, len: usize) -> Result<Vec<u8>, Errno> {
+    if len > MAX_IO_SIZE {
+        return Err(Errno::EINVAL);
+    }
+    let mut buf = vec![0; len];
+    copy_from_user(&mut buf, src)?;
+    Ok(buf)
+}
@@ -0,0 +1,299 @@
+// Preemptive kernel threads.
+//
+// Every thread has its own guarded stack. The timer interrupt counts down the
+// running thread's time slice and switches to the next thread in a
+// round-robin run queue shared by all CPUs. Each CPU's boot context becomes
+// its idle thread, which only runs (and `hlt`s) when nothing else is ready.
+
+pub mod context;
+pub mod scheduler;
+
+use crate::cpu;
+use crate::memory::stack::GuardedStack;
+use crate::memory::PagingError;
+use crate::process::{Pid, Process};
+use crate::time;
+use alloc::boxed::Box;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicU64, Ordering};
+use scheduler::{schedule, Scheduler, SCHEDULER};
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub const THREAD_STACK_SIZE: u64 = 64 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct ThreadId(u64);
+
+impl ThreadId {
+    fn new() -> Self {
+        static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+        ThreadId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+    }
+
+    pub fn as_u64(&self) -> u64 {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThreadState {
+    Ready,
+    Running,
+    /// Sleeping until the given timer tick.
+    Sleeping(u64),
+    /// Waiting for another thread to wake it up.
+    Blocked,
+    Exited,
+}
+
+impl ThreadState {
+    /// Lower-case name, as in the task list.
+    pub fn name(&self) -> &'static str {
+        match self {
+            ThreadState::Ready => "ready",
+            ThreadState::Running => "running",
+            ThreadState::Sleeping(_) => "sleeping",
+            ThreadState::Blocked => "blocked",
+            ThreadState::Exited => "exited",
+        }
+    }
+}
+
+pub struct Thread {
+    id: ThreadId,
+    name: String,
+    state: ThreadState,
+    // Whether the thread has an entry in the run queue, so it never gets two.
+    queued: bool,
+    rsp: u64,
+    // `None` for the idle thread, which runs on the bootloader's stack.
+    stack: Option<GuardedStack>,
+    entry: Option<Box<dyn FnOnce() + Send>>,
+    // Set for threads that run user code; decides which page tables are loaded.
+    process: Option<Arc<Process>>,
+}
+
+/// Turns the code that is currently running into the bootstrap CPU's idle
+/// thread and sets up the scheduler. Call [`idle_loop`] once the boot work is
+/// done.
+pub fn init() {
+    without_interrupts(|| {
+        let mut scheduler = Scheduler::new();
+        scheduler.add_idle(idle_thread());
+        *SCHEDULER.lock() = Some(scheduler);
+    });
+}
+
+/// Same as [`init`] for an application processor joining the scheduler.
+pub fn init_ap() {
+    without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_mut()
+            .expect("task::init has not been called")
+            .add_idle(idle_thread())
+    });
+}
+
+fn idle_thread() -> Box<Thread> {
+    Box::new(Thread {
+        id: ThreadId::new(),
+        name: format!("idle/{}", cpu::id()),
+        state: ThreadState::Running,
+        queued: false,
+        rsp: 0,
+        stack: None,
+        entry: None,
+        process: None,
+    })
+}
+
+/// Starts a new kernel thread running `f`.
+pub fn spawn<F>(name: &str, f: F) -> Result<ThreadId, PagingError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_thread(name, None, f)
+}
+
+/// Starts a thread that runs in `process`'s address space. `f` is expected
+/// to drop into user mode.
+pub fn spawn_in_process<F>(name: &str, process: Arc<Process>, f: F) -> Result<ThreadId, PagingError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    spawn_thread(name, Some(process), f)
+}
+
+fn spawn_thread<F>(name: &str, process: Option<Arc<Process>>, f: F) -> Result<ThreadId, PagingError>
+where
+    F: FnOnce() + Send + 'static,
+{
+    let stack = GuardedStack::new(name, THREAD_STACK_SIZE)?;
+    let rsp = unsafe { context::initial_stack(stack.top(), thread_start) };
+    let thread = Box::new(Thread {
+        id: ThreadId::new(),
+        name: String::from(name),
+        state: ThreadState::Ready,
+        queued: false,
+        rsp,
+        stack: Some(stack),
+        entry: Some(Box::new(f)),
+        process,
+    });
+    let id = thread.id;
+
+    without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_mut()
+            .expect("task::init has not been called")
+            .add(thread)
+    });
+    Ok(id)
+}
+
+// First code every new thread runs, reached through `context::switch`.
+extern "C" fn thread_start() -> ! {
+    // We arrive here with interrupts still disabled and the scheduler lock
+    // still held from the switch.
+    scheduler::finish_switch();
+    let entry = SCHEDULER.lock().as_mut().unwrap().current_mut().entry.take();
+    x86_64::instructions::interrupts::enable();
+
+    if let Some(entry) = entry {
+        entry();
+    }
+    exit();
+}
+
+/// Gives up the rest of the time slice.
+pub fn yield_now() {
+    schedule();
+}
+
+/// Blocks the current thread for at least `ms` milliseconds.
+pub fn sleep(ms: u64) {
+    let until = time::ticks().saturating_add(time::ms_to_ticks(ms));
+    without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            scheduler.current_mut().state = ThreadState::Sleeping(until);
+        }
+    });
+    schedule();
+}
+
+/// Marks the current thread as blocked. It keeps running until it next calls
+/// into the scheduler, and after that only runs again once [`wake`] is called.
+/// Used by `sync`, which does this with interrupts disabled so a wakeup can't
+/// slip in between.
+pub fn block_current() {
+    without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            scheduler.current_mut().state = ThreadState::Blocked;
+        }
+    });
+}
+
+/// Makes a blocked thread runnable again. Safe to call from interrupt handlers.
+pub fn wake(id: ThreadId) {
+    without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            scheduler.wake(id);
+        }
+    });
+}
+
+/// Ends the current thread. Its stack is freed later by the idle thread.
+pub fn exit() -> ! {
+    without_interrupts(|| {
+        if let Some(scheduler) = SCHEDULER.lock().as_mut() {
+            scheduler.current_mut().state = ThreadState::Exited;
+        }
+    });
+    schedule();
+    unreachable!("exited thread was scheduled again");
+}
+
+pub fn current_id() -> Option<ThreadId> {
+    without_interrupts(|| SCHEDULER.lock().as_ref().and_then(|s| s.current()))
+}
+
+pub fn current_process() -> Option<Arc<Process>> {
+    without_interrupts(|| {
+        SCHEDULER
+            .lock()
+            .as_mut()
+            .and_then(|s| s.current_mut().process.clone())
+    })
+}
+
+/// Called by the timer interrupt handlers (the PIT on the bootstrap CPU, the
+/// APIC timer elsewhere) after the EOI has been sent.
+pub fn on_timer_tick() {
+    // Nobody holds the lock with interrupts enabled, so this can only wait
+    // for another CPU.
+    let preempt = SCHEDULER
+        .lock()
+        .as_mut()
+        .filter(|s| s.current().is_some())
+        .is_some_and(|s| s.tick(time::ticks()));
+    if preempt {
+        schedule();
+    }
+}
+
+/// What each CPU's boot context does once everything is set up: free the
+/// stacks of exited threads and halt until the next interrupt.
+pub fn idle_loop() -> ! {
+    loop {
+        let zombies = without_interrupts(|| {
+            SCHEDULER
+                .lock()
+                .as_mut()
+                .map(|s| s.take_zombies())
+                .unwrap_or_default()
+        });
+        // Dropped with interrupts enabled, outside the scheduler lock.
+        drop(zombies);
+        x86_64::instructions::hlt();
+    }
+}
+
+/// Snapshot of one thread, for diagnostics.
+#[derive(Debug, Clone)]
+pub struct ThreadInfo {
+    pub id: ThreadId,
+    pub name: String,
+    pub pid: Option<Pid>,
+    pub state: ThreadState,
+    pub stack_size: u64,
+    pub stack_used: u64,
+}
+
+pub fn threads() -> Vec<ThreadInfo> {
+    without_interrupts(|| {
+        let scheduler = SCHEDULER.lock();
+        let Some(scheduler) = scheduler.as_ref() else {
+            return Vec::new();
+        };
+        scheduler
+            .threads
+            .values()
+            .map(|thread| ThreadInfo {
+                id: thread.id,
+                name: thread.name.clone(),
+                pid: thread.process.as_ref().map(|p| p.pid()),
+                state: thread.state,
+                stack_size: thread.stack.as_ref().map_or(0, |s| s.info().size()),
+                stack_used: thread
+                    .stack
+                    .as_ref()
+                    .map_or(0, |s| s.info().high_water_mark()),
+            })
+            .collect()
+    })
+}
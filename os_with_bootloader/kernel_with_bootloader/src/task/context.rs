@@ -0,0 +1,58 @@
+// Kernel threads switch on their own stacks: the outgoing thread pushes its
+// callee-saved registers and parks its stack pointer in its `Thread`, the
+// incoming thread pops its registers off its stack and returns to wherever it
+// was switched out. Everything else is already saved by the normal calling
+// convention (or by the interrupt handler when we are preempted).
+//
+// The kernel target has no SSE, so there is no FPU state to carry along.
+
+use x86_64::VirtAddr;
+
+/// Saves the current context into `*old_rsp` and resumes the one at `new_rsp`.
+///
+/// # Safety
+/// `new_rsp` must come from a previous switch or from [`initial_stack`].
+/// Interrupts must be disabled.
+#[unsafe(naked)]
+pub unsafe extern "C" fn switch(old_rsp: *mut u64, new_rsp: u64) {
+    core::arch::naked_asm!(
+        "push rbp",
+        "push rbx",
+        "push r12",
+        "push r13",
+        "push r14",
+        "push r15",
+        "mov [rdi], rsp",
+        "mov rsp, rsi",
+        "pop r15",
+        "pop r14",
+        "pop r13",
+        "pop r12",
+        "pop rbx",
+        "pop rbp",
+        "ret",
+    );
+}
+
+/// Lays out a fresh stack so that the first [`switch`] to it "returns" into
+/// `entry`. Returns the stack pointer to switch to.
+///
+/// # Safety
+/// `stack_top` must be the 16-byte aligned top of a mapped, unused stack.
+pub unsafe fn initial_stack(stack_top: VirtAddr, entry: extern "C" fn() -> !) -> u64 {
+    let mut rsp = stack_top.as_mut_ptr::<u64>();
+    unsafe {
+        // Fake return address, so `entry` starts with the stack aligned the
+        // way a normal call would leave it.
+        rsp = rsp.sub(1);
+        rsp.write(0);
+        rsp = rsp.sub(1);
+        rsp.write(entry as usize as u64);
+        // rbp, rbx, r12, r13, r14, r15
+        for _ in 0..6 {
+            rsp = rsp.sub(1);
+            rsp.write(0);
+        }
+    }
+    rsp as u64
+}
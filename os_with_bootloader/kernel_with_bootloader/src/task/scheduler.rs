@@ -0,0 +1,236 @@
+use super::{context, Thread, ThreadId, ThreadState};
+use crate::cpu::{self, MAX_CPUS};
+use crate::gdt;
+use crate::memory::AddressSpace;
+use crate::sync::SpinLock;
+use crate::syscall;
+use alloc::boxed::Box;
+use alloc::collections::{BTreeMap, VecDeque};
+use alloc::vec::Vec;
+use x86_64::registers::control::{Cr3, Cr3Flags};
+
+/// Timer ticks a thread may run before it is preempted.
+const TIME_SLICE: u64 = 5;
+
+pub struct Scheduler {
+    // Boxed so a thread's saved stack pointer keeps its address while the
+    // map is modified.
+    pub(super) threads: BTreeMap<ThreadId, Box<Thread>>,
+    // Shared by all CPUs. Threads are queued when they become ready and not
+    // running, at most once each; entries for threads that have blocked or
+    // exited since are skipped when they come up.
+    run_queue: VecDeque<ThreadId>,
+    cpus: [Option<CpuSlot>; MAX_CPUS],
+    // Exited threads whose stacks can't be freed while we may still be on them.
+    zombies: Vec<Box<Thread>>,
+}
+
+/// What the scheduler tracks for each CPU.
+#[derive(Debug, Clone, Copy)]
+struct CpuSlot {
+    current: ThreadId,
+    idle: ThreadId,
+    slice_left: u64,
+}
+
+// Held across `context::switch` and released by the thread that is switched
+// to, so no other CPU can pick up a thread whose stack is still in use.
+pub static SCHEDULER: SpinLock<Option<Scheduler>> = SpinLock::new(None);
+
+impl Scheduler {
+    pub fn new() -> Self {
+        Scheduler {
+            threads: BTreeMap::new(),
+            run_queue: VecDeque::new(),
+            cpus: [None; MAX_CPUS],
+            zombies: Vec::new(),
+        }
+    }
+
+    /// Makes `idle`, the context that is running right now, the idle thread
+    /// of the calling CPU.
+    pub fn add_idle(&mut self, idle: Box<Thread>) {
+        let id = idle.id;
+        self.threads.insert(id, idle);
+        self.cpus[cpu::id()] = Some(CpuSlot {
+            current: id,
+            idle: id,
+            slice_left: TIME_SLICE,
+        });
+        cpu::current().set_current_thread(id.as_u64());
+    }
+
+    fn slot(&mut self) -> &mut CpuSlot {
+        self.cpus[cpu::id()]
+            .as_mut()
+            .expect("CPU has not joined the scheduler")
+    }
+
+    /// Thread running on the calling CPU, if it has joined the scheduler.
+    pub fn current(&self) -> Option<ThreadId> {
+        self.cpus[cpu::id()].map(|slot| slot.current)
+    }
+
+    pub fn add(&mut self, mut thread: Box<Thread>) {
+        // The queue never holds more entries than there are threads, so
+        // keeping room for all of them means the timer interrupt never has
+        // to allocate.
+        let threads = self.threads.len() + 1;
+        self.run_queue.reserve(threads.saturating_sub(self.run_queue.len()));
+        enqueue(&mut self.run_queue, &mut thread);
+        self.threads.insert(thread.id, thread);
+    }
+
+    pub fn current_mut(&mut self) -> &mut Thread {
+        let current = self.slot().current;
+        self.threads.get_mut(&current).unwrap()
+    }
+
+    pub fn take_zombies(&mut self) -> Vec<Box<Thread>> {
+        core::mem::take(&mut self.zombies)
+    }
+
+    /// Makes a blocked or sleeping thread runnable again.
+    pub fn wake(&mut self, id: ThreadId) {
+        let Some(thread) = self.threads.get_mut(&id) else {
+            return;
+        };
+        if !matches!(thread.state, ThreadState::Blocked | ThreadState::Sleeping(_)) {
+            return;
+        }
+        thread.state = ThreadState::Ready;
+        // A thread that has marked itself blocked but not yet switched away
+        // queues itself when it does.
+        if !running_anywhere(&self.cpus, id) {
+            enqueue(&mut self.run_queue, thread);
+        }
+    }
+
+    /// Wakes sleepers whose time has come and counts down the calling CPU's
+    /// time slice. Returns `true` if the current thread should be preempted.
+    pub fn tick(&mut self, now: u64) -> bool {
+        // Same as `wake`, without collecting the sleepers first: this runs in
+        // the timer interrupt, which must not allocate.
+        for thread in self.threads.values_mut() {
+            if let ThreadState::Sleeping(until) = thread.state {
+                if until <= now {
+                    thread.state = ThreadState::Ready;
+                    if !running_anywhere(&self.cpus, thread.id) {
+                        enqueue(&mut self.run_queue, thread);
+                    }
+                }
+            }
+        }
+
+        let queue_empty = self.run_queue.is_empty();
+        let slot = self.slot();
+        if slot.current == slot.idle {
+            return !queue_empty;
+        }
+        slot.slice_left = slot.slice_left.saturating_sub(1);
+        slot.slice_left == 0
+    }
+
+    /// Picks the next thread to run on the calling CPU. Returns the stack
+    /// pointer slots for the switch, or `None` if the current thread keeps
+    /// running.
+    fn pick_next(&mut self) -> Option<(*mut u64, u64)> {
+        let CpuSlot { current: previous, idle, .. } = *self.slot();
+
+        let thread = self.threads.get_mut(&previous).unwrap();
+        // `Ready` if it was woken up before it got to switch away.
+        if matches!(thread.state, ThreadState::Running | ThreadState::Ready) {
+            thread.state = ThreadState::Ready;
+            if previous != idle {
+                enqueue(&mut self.run_queue, thread);
+            }
+        }
+
+        let next = loop {
+            let Some(id) = self.run_queue.pop_front() else {
+                break idle;
+            };
+            // Gone if it exited and was reaped while queued.
+            let Some(thread) = self.threads.get_mut(&id) else {
+                continue;
+            };
+            thread.queued = false;
+            if thread.state == ThreadState::Ready {
+                break id;
+            }
+        };
+
+        self.threads.get_mut(&next).unwrap().state = ThreadState::Running;
+        self.slot().slice_left = TIME_SLICE;
+        if next == previous {
+            return None;
+        }
+
+        self.enter(next);
+
+        if self.threads[&previous].state == ThreadState::Exited {
+            let thread = self.threads.remove(&previous).unwrap();
+            self.zombies.push(thread);
+        }
+        self.slot().current = next;
+        cpu::current().set_current_thread(next.as_u64());
+
+        let old_rsp = match self.threads.get_mut(&previous) {
+            Some(thread) => &mut thread.rsp as *mut u64,
+            // The exited thread moved to the zombie list; its Box didn't move.
+            None => &mut self.zombies.last_mut().unwrap().rsp as *mut u64,
+        };
+        let new_rsp = self.threads[&next].rsp;
+        Some((old_rsp, new_rsp))
+    }
+
+    // Loads what the CPU needs to run `next`: its page tables and the kernel
+    // stack to use when it traps out of user mode.
+    fn enter(&self, next: ThreadId) {
+        let thread = &self.threads[&next];
+        if let Some(stack) = &thread.stack {
+            gdt::set_kernel_stack(stack.top());
+            syscall::set_kernel_stack(stack.top());
+        }
+
+        let level_4_frame = match &thread.process {
+            Some(process) => process.level_4_frame(),
+            None => AddressSpace::kernel().level_4_frame(),
+        };
+        if Cr3::read().0 != level_4_frame {
+            unsafe { Cr3::write(level_4_frame, Cr3Flags::empty()) };
+        }
+    }
+}
+
+fn enqueue(run_queue: &mut VecDeque<ThreadId>, thread: &mut Thread) {
+    if !thread.queued {
+        thread.queued = true;
+        run_queue.push_back(thread.id);
+    }
+}
+
+fn running_anywhere(cpus: &[Option<CpuSlot>], id: ThreadId) -> bool {
+    cpus.iter().flatten().any(|slot| slot.current == id)
+}
+
+/// Switches to the next runnable thread, if there is one.
+pub fn schedule() {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut scheduler = SCHEDULER.lock();
+        let Some((old_rsp, new_rsp)) = scheduler.as_mut().and_then(|s| s.pick_next()) else {
+            return;
+        };
+        // The lock stays held until the switch is done; whoever runs next
+        // releases it, see `finish_switch`.
+        core::mem::forget(scheduler);
+        unsafe { context::switch(old_rsp, new_rsp) };
+        finish_switch();
+    });
+}
+
+/// Releases the scheduler lock taken by the thread that switched to us.
+/// Called right after every switch, including the first one into a new thread.
+pub fn finish_switch() {
+    unsafe { SCHEDULER.force_unlock() };
+}
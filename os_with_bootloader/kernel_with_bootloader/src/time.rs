@@ -0,0 +1,42 @@
+use core::sync::atomic::{AtomicU64, Ordering};
+use x86_64::instructions::port::Port;
+
+/// Timer interrupts per second once the PIT is programmed.
+pub const TIMER_HZ: u64 = 100;
+
+/// The PIT's input clock, in Hz.
+pub const PIT_FREQUENCY: u64 = 1_193_182;
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+/// Programs PIT channel 0 to fire IRQ 0 `TIMER_HZ` times per second.
+pub fn init() {
+    let divisor = (PIT_FREQUENCY / TIMER_HZ) as u16;
+    let mut command: Port<u8> = Port::new(0x43);
+    let mut channel_0: Port<u8> = Port::new(0x40);
+    unsafe {
+        // Channel 0, lobyte/hibyte, mode 3 (square wave).
+        command.write(0x36);
+        channel_0.write((divisor & 0xff) as u8);
+        channel_0.write((divisor >> 8) as u8);
+    }
+}
+
+/// Called from the timer interrupt handler.
+pub fn tick() {
+    TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+pub fn uptime_ms() -> u64 {
+    ticks() * 1000 / TIMER_HZ
+}
+
+/// Number of ticks that covers at least `ms` milliseconds. Durations too long
+/// to count come out as `u64::MAX`, which never arrives.
+pub fn ms_to_ticks(ms: u64) -> u64 {
+    ms.saturating_mul(TIMER_HZ).div_ceil(1000)
+}
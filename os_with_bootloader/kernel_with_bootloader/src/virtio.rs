@@ -0,0 +1,294 @@
+// Legacy virtio over PCI, the interface QEMU's transitional devices offer
+// next to the modern one. The device registers sit in I/O BAR 0 and each
+// virtqueue is one physically contiguous block handed over by page number.
+// The drivers poll the used rings, so queues are set up with interrupts
+// suppressed.
+
+use crate::memory::dma::DmaBuffer;
+use crate::pci::{Bar, PciAddress};
+use core::sync::atomic::{fence, Ordering};
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+pub const VENDOR_ID: u16 = 0x1AF4;
+
+// Legacy register offsets in the I/O BAR.
+const DEVICE_FEATURES: u16 = 0x00;
+const GUEST_FEATURES: u16 = 0x04;
+const QUEUE_ADDRESS: u16 = 0x08;
+const QUEUE_SIZE: u16 = 0x0C;
+const QUEUE_SELECT: u16 = 0x0E;
+const QUEUE_NOTIFY: u16 = 0x10;
+const DEVICE_STATUS: u16 = 0x12;
+// Device-specific configuration, as long as MSI-X is off.
+const DEVICE_CONFIG: u16 = 0x14;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FAILED: u8 = 128;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+const AVAIL_F_NO_INTERRUPT: u16 = 1;
+
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    NoIoBar,
+    QueueUnavailable,
+    NoMemory,
+}
+
+/// A virtio device driven through the legacy register layout.
+pub struct LegacyDevice {
+    io: u16,
+}
+
+impl LegacyDevice {
+    pub fn new(pci: PciAddress) -> Result<Self, VirtioError> {
+        let Some(Bar::Io(io)) = pci.bar(0) else {
+            return Err(VirtioError::NoIoBar);
+        };
+        pci.enable_bus_master();
+        Ok(LegacyDevice { io })
+    }
+
+    fn read_u8(&self, offset: u16) -> u8 {
+        unsafe { Port::<u8>::new(self.io + offset).read() }
+    }
+
+    fn write_u8(&self, offset: u16, value: u8) {
+        unsafe { Port::<u8>::new(self.io + offset).write(value) }
+    }
+
+    fn read_u16(&self, offset: u16) -> u16 {
+        unsafe { Port::<u16>::new(self.io + offset).read() }
+    }
+
+    fn write_u16(&self, offset: u16, value: u16) {
+        unsafe { Port::<u16>::new(self.io + offset).write(value) }
+    }
+
+    fn read_u32(&self, offset: u16) -> u32 {
+        unsafe { Port::<u32>::new(self.io + offset).read() }
+    }
+
+    fn write_u32(&self, offset: u16, value: u32) {
+        unsafe { Port::<u32>::new(self.io + offset).write(value) }
+    }
+
+    /// Resets the device and agrees on the features in `wanted` that it
+    /// offers, which are returned. Queues are set up next, then `driver_ok`.
+    pub fn negotiate(&self, wanted: u32) -> u32 {
+        self.write_u8(DEVICE_STATUS, 0);
+        self.write_u8(DEVICE_STATUS, STATUS_ACKNOWLEDGE);
+        self.write_u8(DEVICE_STATUS, STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+        let features = self.read_u32(DEVICE_FEATURES) & wanted;
+        self.write_u32(GUEST_FEATURES, features);
+        features
+    }
+
+    pub fn driver_ok(&self) {
+        let status = self.read_u8(DEVICE_STATUS);
+        self.write_u8(DEVICE_STATUS, status | STATUS_DRIVER_OK);
+    }
+
+    /// Tells the device the driver gave up on it.
+    pub fn fail(&self) {
+        let status = self.read_u8(DEVICE_STATUS);
+        self.write_u8(DEVICE_STATUS, status | STATUS_FAILED);
+    }
+
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, VirtioError> {
+        self.write_u16(QUEUE_SELECT, index);
+        let size = self.read_u16(QUEUE_SIZE);
+        if size == 0 {
+            return Err(VirtioError::QueueUnavailable);
+        }
+        let queue = Virtqueue::new(index, size)?;
+        self.write_u32(QUEUE_ADDRESS, (queue.memory.phys().as_u64() / PAGE_SIZE as u64) as u32);
+        Ok(queue)
+    }
+
+    /// Tells the device there are new buffers in `queue`.
+    pub fn notify(&self, queue: &Virtqueue) {
+        self.write_u16(QUEUE_NOTIFY, queue.index);
+    }
+
+    pub fn config_u8(&self, offset: u16) -> u8 {
+        self.read_u8(DEVICE_CONFIG + offset)
+    }
+
+    pub fn config_u16(&self, offset: u16) -> u16 {
+        self.read_u16(DEVICE_CONFIG + offset)
+    }
+
+    pub fn config_u32(&self, offset: u16) -> u32 {
+        self.read_u32(DEVICE_CONFIG + offset)
+    }
+
+    pub fn config_u64(&self, offset: u16) -> u64 {
+        self.config_u32(offset) as u64 | (self.config_u32(offset + 4) as u64) << 32
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Descriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct UsedElement {
+    id: u32,
+    len: u32,
+}
+
+/// One buffer of a request, in the order the device should see them.
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub addr: PhysAddr,
+    pub len: u32,
+    /// The device fills this buffer rather than reading it.
+    pub device_writes: bool,
+}
+
+/// A split virtqueue: descriptor table, available ring and used ring, laid
+/// out the way legacy devices expect. Unused descriptors form a free list
+/// through their `next` fields.
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    memory: DmaBuffer,
+    used_offset: usize,
+    free_head: u16,
+    free_count: u16,
+    last_used: u16,
+}
+
+impl Virtqueue {
+    fn new(index: u16, size: u16) -> Result<Self, VirtioError> {
+        let entries = size as usize;
+        let used_offset = (16 * entries + 6 + 2 * entries).next_multiple_of(PAGE_SIZE);
+        let pages = (used_offset + (6 + 8 * entries).next_multiple_of(PAGE_SIZE)) / PAGE_SIZE;
+        let memory = DmaBuffer::new(pages).ok_or(VirtioError::NoMemory)?;
+        let queue = Virtqueue {
+            index,
+            size,
+            memory,
+            used_offset,
+            free_head: 0,
+            free_count: size,
+            last_used: 0,
+        };
+        for i in 0..size {
+            unsafe { (*queue.descriptor(i)).next = i.wrapping_add(1) };
+        }
+        unsafe { queue.avail_flags().write_volatile(AVAIL_F_NO_INTERRUPT) };
+        Ok(queue)
+    }
+
+    fn descriptor(&self, i: u16) -> *mut Descriptor {
+        unsafe { (self.memory.as_mut_ptr() as *mut Descriptor).add(i as usize) }
+    }
+
+    fn avail_flags(&self) -> *mut u16 {
+        unsafe { self.memory.as_mut_ptr().add(16 * self.size as usize) as *mut u16 }
+    }
+
+    fn avail_idx(&self) -> *mut u16 {
+        unsafe { self.avail_flags().add(1) }
+    }
+
+    fn avail_ring(&self, slot: u16) -> *mut u16 {
+        unsafe { self.avail_flags().add(2 + (slot % self.size) as usize) }
+    }
+
+    fn used_idx(&self) -> *const u16 {
+        unsafe { self.memory.as_mut_ptr().add(self.used_offset + 2) as *const u16 }
+    }
+
+    fn used_ring(&self, slot: u16) -> *const UsedElement {
+        unsafe {
+            (self.memory.as_mut_ptr().add(self.used_offset + 4) as *const UsedElement)
+                .add((slot % self.size) as usize)
+        }
+    }
+
+    /// Number of descriptors, which bounds the chain IDs.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Hands a chain of buffers to the device and returns the ID that
+    /// `pop_used` reports for it. `None` if there aren't enough free
+    /// descriptors. The device only looks at it after `notify`.
+    pub fn add(&mut self, buffers: &[Buffer]) -> Option<u16> {
+        if buffers.is_empty() || buffers.len() > self.free_count as usize {
+            return None;
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let last = i + 1 == buffers.len();
+            let descriptor = self.descriptor(index);
+            unsafe {
+                let next = (*descriptor).next;
+                descriptor.write_volatile(Descriptor {
+                    addr: buffer.addr.as_u64(),
+                    len: buffer.len,
+                    flags: if buffer.device_writes { DESC_F_WRITE } else { 0 }
+                        | if last { 0 } else { DESC_F_NEXT },
+                    next,
+                });
+                if last {
+                    self.free_head = next;
+                } else {
+                    index = next;
+                }
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+
+        unsafe {
+            let slot = self.avail_idx().read_volatile();
+            self.avail_ring(slot).write_volatile(head);
+            fence(Ordering::Release);
+            self.avail_idx().write_volatile(slot.wrapping_add(1));
+        }
+        fence(Ordering::SeqCst);
+        Some(head)
+    }
+
+    /// The next chain the device is done with, as its ID and the number of
+    /// bytes the device wrote. Its descriptors become free again.
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        let used = unsafe { self.used_idx().read_volatile() };
+        if used == self.last_used {
+            return None;
+        }
+        fence(Ordering::Acquire);
+        let element = unsafe { self.used_ring(self.last_used).read_volatile() };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        let head = element.id as u16;
+        let mut tail = head;
+        let mut count = 1;
+        unsafe {
+            while (*self.descriptor(tail)).flags & DESC_F_NEXT != 0 {
+                tail = (*self.descriptor(tail)).next;
+                count += 1;
+            }
+            (*self.descriptor(tail)).next = self.free_head;
+        }
+        self.free_head = head;
+        self.free_count += count;
+        Some((head, element.len))
+    }
+}
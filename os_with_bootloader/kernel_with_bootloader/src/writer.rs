@@ -1,4 +1,5 @@
 mod constants;
+mod editing;
 use core::{
     fmt::{self, Write},
     ptr,
@@ -7,6 +8,9 @@ use bootloader_api::info::FrameBufferInfo;
 use constants::font_constants;
 use constants::font_constants::{BACKUP_CHAR, CHAR_RASTER_HEIGHT, FONT_WEIGHT};
 use noto_sans_mono_bitmap::{get_raster, RasterizedChar};
+use crate::sync::SpinLock;
+use crate::time;
+use core::sync::atomic::{AtomicU64, Ordering};
 
 const LINE_SPACING: usize = 2;
 const LETTER_SPACING: usize = 1;
@@ -16,6 +20,12 @@ const BORDER_PADDING: usize = 1;
 const COLOR_BLUE: [u8; 3] = [255, 0, 0];                     
 const COLOR_RED: [u8; 3] = [0, 0, 255];           
 
+// How long the screen stays inverted for a bell when sound is off.
+const FLASH_MS: u64 = 100;
+
+// Tick at which the flash ends; 0 when the screen isn't flashed.
+static FLASH_UNTIL: AtomicU64 = AtomicU64::new(0);
+
 fn get_char_raster(c: char) -> RasterizedChar {
     fn get(c: char) -> Option<RasterizedChar> {
         get_raster(c, FONT_WEIGHT, CHAR_RASTER_HEIGHT)
@@ -29,6 +39,9 @@ pub struct FrameBufferWriter {
     x_pos: usize,
     y_pos: usize,
     current_color: [u8; 3],
+    // The screen is inverted for a visual bell. Whatever is drawn meanwhile
+    // is drawn inverted too, so it comes out right when the flash ends.
+    flashed: bool,
 }
 
 impl FrameBufferWriter {
@@ -39,6 +52,7 @@ impl FrameBufferWriter {
             x_pos: BORDER_PADDING,  // Start at the leftmost side
             y_pos: BORDER_PADDING,
             current_color: COLOR_BLUE,
+            flashed: false,
         };
         logger.clear();
         logger
@@ -59,7 +73,19 @@ impl FrameBufferWriter {
     pub fn clear(&mut self) {
         self.x_pos = BORDER_PADDING;
         self.y_pos = BORDER_PADDING;
-        self.framebuffer.fill(0);
+        let background = self.background();
+        self.framebuffer.fill(background);
+    }
+
+    /// Layout of the framebuffer, as the bootloader described it.
+    pub fn info(&self) -> FrameBufferInfo {
+        self.info
+    }
+
+    /// The raw pixels, for /dev/fb0. Text printed later draws over them.
+    pub fn pixels_mut(&mut self) -> &mut [u8] {
+        self.end_flash();
+        self.framebuffer
     }
 
     fn width(&self) -> usize {
@@ -74,6 +100,7 @@ impl FrameBufferWriter {
         match c {
             '\n' => self.newline(),
             '\r' => self.carriage_return(),
+            '\x07' => self.bell(),
             '\t' => {
                 for _ in 0..4 {
                     self.write_char(' ');
@@ -95,6 +122,36 @@ impl FrameBufferWriter {
         }
     }
 
+    // The speaker rings the bell if it can; otherwise the screen flashes.
+    fn bell(&mut self) {
+        if crate::speaker::bell() {
+            return;
+        }
+        if !self.flashed {
+            self.invert();
+            self.flashed = true;
+        }
+        FLASH_UNTIL.store(time::ticks() + time::ms_to_ticks(FLASH_MS) + 1, Ordering::Relaxed);
+    }
+
+    // Value of every byte of a blank pixel.
+    fn background(&self) -> u8 {
+        if self.flashed { 0xFF } else { 0 }
+    }
+
+    fn invert(&mut self) {
+        for byte in self.framebuffer.iter_mut() {
+            *byte = !*byte;
+        }
+    }
+
+    fn end_flash(&mut self) {
+        if self.flashed {
+            self.invert();
+            self.flashed = false;
+        }
+    }
+
     fn scroll(&mut self) {
         let char_height = CHAR_RASTER_HEIGHT.val() + LINE_SPACING;
         let stride_bytes = self.info.stride * self.info.bytes_per_pixel;
@@ -106,7 +163,8 @@ impl FrameBufferWriter {
 
         // Clear the new empty row
         let clear_start = (height - char_height) * stride_bytes;
-        self.framebuffer[clear_start..].fill(0);
+        let background = self.background();
+        self.framebuffer[clear_start..].fill(background);
 
         self.y_pos -= char_height;
     }
@@ -130,6 +188,7 @@ impl FrameBufferWriter {
             (self.current_color[1] as u16 * intensity as u16 / 255) as u8,
             (self.current_color[2] as u16 * intensity as u16 / 255) as u8,
         ];
+        let color = color.map(|value| value ^ self.background());
         let bytes_per_pixel = self.info.bytes_per_pixel;
         let byte_offset = pixel_offset * bytes_per_pixel;
         self.framebuffer[byte_offset..(byte_offset + bytes_per_pixel)]
@@ -185,10 +244,88 @@ impl Write for FrameBufferWriter {
     }
 }
 
+// The writer is shared by the entry point, interrupt handlers and the allocator
+// diagnostics, so it lives behind a lock instead of being passed around.
+pub static FRAME_BUFFER_WRITER: SpinLock<Option<FrameBufferWriter>> = SpinLock::new(None);
+
+/// Runs `f` on the writer with interrupts disabled, so neither an interrupt
+/// handler nor a preempting thread can find the lock already taken.
+pub fn with_writer<R>(f: impl FnOnce(&mut FrameBufferWriter) -> R) -> Option<R> {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        FRAME_BUFFER_WRITER.lock().as_mut().map(f)
+    })
+}
+
+/// Called from the timer interrupt handler; ends a visual bell. If another
+/// CPU is printing, the flash ends with that instead.
+pub fn on_timer_tick() {
+    if !flash_due() {
+        return;
+    }
+    if let Some(mut writer) = FRAME_BUFFER_WRITER.try_lock() {
+        end_flash_if_due(&mut writer);
+    }
+}
+
+fn flash_due() -> bool {
+    let until = FLASH_UNTIL.load(Ordering::Relaxed);
+    until != 0 && time::ticks() >= until
+}
+
+fn end_flash_if_due(writer: &mut Option<FrameBufferWriter>) {
+    if flash_due() {
+        FLASH_UNTIL.store(0, Ordering::Relaxed);
+        if let Some(writer) = writer.as_mut() {
+            writer.end_flash();
+        }
+    }
+}
+
+#[doc(hidden)]
+pub fn _print(args: fmt::Arguments) {
+    // Interrupt handlers print too; keep them out while the lock is held.
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut writer = FRAME_BUFFER_WRITER.lock();
+        end_flash_if_due(&mut writer);
+        if let Some(writer) = writer.as_mut() {
+            let _ = writer.write_fmt(args);
+        }
+    });
+}
+
+/// Prints even if the writer lock is stuck, for panics and lock diagnostics
+/// (the stuck lock may well be this one). After waiting a while the lock is
+/// broken, which may garble output but beats printing nothing.
+pub fn print_diagnostic(args: fmt::Arguments) {
+    const ATTEMPTS: usize = 1 << 20;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        for _ in 0..ATTEMPTS {
+            if let Some(mut writer) = FRAME_BUFFER_WRITER.try_lock() {
+                if let Some(writer) = writer.as_mut() {
+                    let _ = writer.write_fmt(args);
+                }
+                return;
+            }
+            core::hint::spin_loop();
+        }
+        unsafe { FRAME_BUFFER_WRITER.force_unlock() };
+        if let Some(mut writer) = FRAME_BUFFER_WRITER.try_lock() {
+            if let Some(writer) = writer.as_mut() {
+                let _ = writer.write_fmt(args);
+            }
+        }
+    });
+}
+
 #[macro_export]
 macro_rules! print {
-    ($writer:expr, $($arg:tt)*) => {{
-        use core::fmt::Write;
-        let _ = write!($writer, $($arg)*);
-    }};
+    ($($arg:tt)*) => {
+        $crate::writer::_print(format_args!($($arg)*))
+    };
+}
+
+#[macro_export]
+macro_rules! println {
+    () => ($crate::print!("\n"));
+    ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
@@ -0,0 +1,52 @@
+// Cursor movement and backspace for the keyboard handler's line editing.
+
+use super::constants::font_constants::{self, CHAR_RASTER_HEIGHT};
+use super::{FrameBufferWriter, BORDER_PADDING, LETTER_SPACING, LINE_SPACING};
+
+impl FrameBufferWriter {
+    fn char_advance() -> usize {
+        font_constants::CHAR_RASTER_WIDTH + LETTER_SPACING
+    }
+
+    fn line_height() -> usize {
+        CHAR_RASTER_HEIGHT.val() + LINE_SPACING
+    }
+
+    // Blanks the character cell at the current position.
+    fn clear_cell(&mut self) {
+        for y in 0..Self::line_height() {
+            for x in 0..Self::char_advance() {
+                self.write_pixel(self.x_pos + x, self.y_pos + y, 0);
+            }
+        }
+    }
+
+    pub fn backspace(&mut self) {
+        self.cursor_left();
+        self.clear_cell();
+    }
+
+    pub fn cursor_left(&mut self) {
+        if self.x_pos >= BORDER_PADDING + Self::char_advance() {
+            self.x_pos -= Self::char_advance();
+        }
+    }
+
+    pub fn cursor_right(&mut self) {
+        if self.x_pos + 2 * Self::char_advance() < self.width() {
+            self.x_pos += Self::char_advance();
+        }
+    }
+
+    pub fn cursor_up(&mut self) {
+        if self.y_pos >= BORDER_PADDING + Self::line_height() {
+            self.y_pos -= Self::line_height();
+        }
+    }
+
+    pub fn cursor_down(&mut self) {
+        if self.y_pos + 2 * Self::line_height() < self.height() {
+            self.y_pos += Self::line_height();
+        }
+    }
+}
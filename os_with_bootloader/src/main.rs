@@ -1,5 +1,249 @@
 use std::env;
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::thread;
+use std::time::{Duration, Instant};
+
+// The scratch disk the kernel's block drivers are tested against: 64 MiB
+// with an MBR holding an 8 MiB Linux partition and a FAT32 partition
+// filling the rest. The kernel mounts the FAT partition on /mnt/vda2, and
+// the host can get at it with mtools (`mdir -i scratch.img@@10M`).
+const SCRATCH_SECTORS: u32 = 64 * 2048;
+const SCRATCH_PARTITIONS: [(u8, u32, u32); 2] = [
+    (0x83, 2048, 8 * 2048),
+    (0x0C, 10 * 2048, SCRATCH_SECTORS - 10 * 2048),
+];
+
+// QEMU user-mode networking for the kernel's network card: no setup on the
+// host, and the kernel's echo service (port 7) is reachable on localhost,
+// e.g. `nc 127.0.0.1 5555` or `nc -u 127.0.0.1 5555`, and so are its remote
+// shell (port 23), e.g. `telnet 127.0.0.1 5523`, and its web server (port
+// 80), e.g. http://127.0.0.1:5580/ or `curl 127.0.0.1:5580/api/tasks`.
+const ECHO_FORWARD: u16 = 5555;
+const SHELL_FORWARD: u16 = 5523;
+const HTTP_FORWARD: u16 = 5580;
+
+fn netdev() -> String {
+    format!(
+        "user,id=net0,hostfwd=tcp:127.0.0.1:{0}-:7,hostfwd=udp:127.0.0.1:{0}-:7,hostfwd=tcp:127.0.0.1:{1}-:23,hostfwd=tcp:127.0.0.1:{2}-:80",
+        ECHO_FORWARD, SHELL_FORWARD, HTTP_FORWARD
+    )
+}
+
+// How `--script` talks to the remote shell: the prompt that ends every
+// command's output, how long the kernel gets to boot and each command to
+// finish.
+const SHELL_PROMPT: &[u8] = b"> ";
+const SCRIPT_BOOT_TIMEOUT: Duration = Duration::from_secs(60);
+const SCRIPT_GREETING_TIMEOUT: Duration = Duration::from_secs(5);
+const SCRIPT_COMMAND_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs the commands in `script`, one per line, in a remote shell session,
+/// printing what each one writes. Blank lines and lines starting with `#`
+/// are skipped.
+fn run_script(script: &Path) -> io::Result<()> {
+    let commands = fs::read_to_string(script)?;
+    let mut session = connect_shell()?;
+    session.set_read_timeout(Some(SCRIPT_COMMAND_TIMEOUT))?;
+    for command in commands.lines().map(str::trim) {
+        if command.is_empty() || command.starts_with('#') {
+            continue;
+        }
+        println!("> {}", command);
+        session.write_all(format!("{}\r\n", command).as_bytes())?;
+        print!("{}", read_to_prompt(&mut session)?);
+    }
+    session.write_all(b"exit\r\n")
+}
+
+// QEMU accepts connections on the host before anything listens in the
+// guest, so the shell is only there once its greeting and prompt arrive.
+fn connect_shell() -> io::Result<TcpStream> {
+    let deadline = Instant::now() + SCRIPT_BOOT_TIMEOUT;
+    loop {
+        let attempt = TcpStream::connect(("127.0.0.1", SHELL_FORWARD)).and_then(|mut stream| {
+            stream.set_read_timeout(Some(SCRIPT_GREETING_TIMEOUT))?;
+            read_to_prompt(&mut stream)?;
+            Ok(stream)
+        });
+        match attempt {
+            Ok(stream) => return Ok(stream),
+            Err(e) if Instant::now() >= deadline => return Err(e),
+            Err(_) => thread::sleep(Duration::from_secs(1)),
+        }
+    }
+}
+
+/// The shell's output up to its next prompt, which is left out.
+fn read_to_prompt(stream: &mut TcpStream) -> io::Result<String> {
+    let mut output = Vec::new();
+    let mut buf = [0; 1024];
+    while !output.ends_with(SHELL_PROMPT) {
+        let count = stream.read(&mut buf)?;
+        if count == 0 {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the shell closed the connection"));
+        }
+        output.extend_from_slice(&buf[..count]);
+    }
+    output.truncate(output.len() - SHELL_PROMPT.len());
+    Ok(String::from_utf8_lossy(&output).replace("\r\n", "\n"))
+}
+
+/// Creates the scratch disk image next to the boot image, unless it is
+/// already there; its contents are kept between runs.
+fn scratch_disk(boot_image: &str) -> io::Result<PathBuf> {
+    let path = Path::new(boot_image).with_file_name("scratch.img");
+    if path.exists() {
+        return Ok(path);
+    }
+    let mut mbr = [0u8; 512];
+    for (slot, (kind, start, sectors)) in SCRATCH_PARTITIONS.iter().enumerate() {
+        let entry = &mut mbr[446 + slot * 16..446 + (slot + 1) * 16];
+        // CHS fields unused; LBA only.
+        entry[1..4].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+        entry[4] = *kind;
+        entry[5..8].copy_from_slice(&[0xFE, 0xFF, 0xFF]);
+        entry[8..12].copy_from_slice(&start.to_le_bytes());
+        entry[12..16].copy_from_slice(&sectors.to_le_bytes());
+    }
+    mbr[510] = 0x55;
+    mbr[511] = 0xAA;
+    let mut image = vec![0u8; SCRATCH_SECTORS as usize * 512];
+    image[..512].copy_from_slice(&mbr);
+    fs::write(&path, image)?;
+
+    let (_, start, sectors) = SCRATCH_PARTITIONS[1];
+    let mut file = fs::OpenOptions::new().read(true).write(true).open(&path)?;
+    let partition = Slice::new(&mut file, start as u64 * 512, sectors as u64 * 512);
+    let options = fatfs::FormatVolumeOptions::new()
+        .fat_type(fatfs::FatType::Fat32)
+        .bytes_per_cluster(512)
+        .total_sectors(sectors)
+        .volume_label(*b"SCRATCH    ");
+    fatfs::format_volume(partition, options)?;
+    let volume = fatfs::FileSystem::new(Slice::new(&mut file, start as u64 * 512, sectors as u64 * 512), fatfs::FsOptions::new())?;
+    volume
+        .root_dir()
+        .create_file("Read me first.txt")?
+        .write_all(b"Written by the runner when it created this disk.\n")?;
+    Ok(path)
+}
+
+// Read-only fixtures for the kernel's ext2 driver, made with mke2fs from
+// the tree `stage_fixture` writes: an ext2 volume with 1 KiB blocks, so
+// large.bin needs double indirect blocks, and an ext4 one whose files are
+// mapped with extents. The kernel mounts them on /mnt/vdb and /mnt/vdc.
+const FIXTURES: [(&str, &[&str]); 2] = [
+    ("ext2.img", &["-t", "ext2", "-b", "1024"]),
+    ("ext4.img", &["-t", "ext4", "-O", "^has_journal"]),
+];
+const FIXTURE_SIZE: &str = "8M";
+// Size of large.bin; byte i holds `fixture_byte(i)`.
+const FIXTURE_LARGE_SIZE: usize = 3 * 1024 * 1024 + 123;
+
+fn fixture_byte(i: usize) -> u8 {
+    (i * 7 + i / 251) as u8
+}
+
+/// Builds the ext2 fixture images next to the boot image, unless they are
+/// already there. Needs mke2fs from e2fsprogs.
+fn ext2_fixtures(boot_image: &str) -> io::Result<Vec<PathBuf>> {
+    let paths: Vec<PathBuf> = FIXTURES.iter().map(|(name, _)| Path::new(boot_image).with_file_name(name)).collect();
+    if paths.iter().all(|path| path.exists()) {
+        return Ok(paths);
+    }
+    let stage = Path::new(boot_image).with_file_name("ext2-fixture");
+    if stage.exists() {
+        fs::remove_dir_all(&stage)?;
+    }
+    stage_fixture(&stage)?;
+    for ((_, options), path) in FIXTURES.iter().zip(&paths) {
+        let status = Command::new("mke2fs")
+            .args(["-q", "-F", "-L", "fixture"])
+            .args(*options)
+            .arg("-d")
+            .arg(&stage)
+            .arg(path)
+            .arg(FIXTURE_SIZE)
+            .status()?;
+        if !status.success() {
+            let _ = fs::remove_file(path);
+            return Err(io::Error::other(format!("mke2fs failed for {}", path.display())));
+        }
+    }
+    fs::remove_dir_all(&stage)?;
+    Ok(paths)
+}
+
+fn stage_fixture(stage: &Path) -> io::Result<()> {
+    fs::create_dir_all(stage.join("docs/deep"))?;
+    fs::write(stage.join("README"), "Hello from ext2.\n")?;
+    fs::write(stage.join("docs/deep/note.txt"), "Found through a symlink.\n")?;
+    fs::write(stage.join("large.bin"), (0..FIXTURE_LARGE_SIZE).map(fixture_byte).collect::<Vec<u8>>())?;
+    #[cfg(unix)]
+    {
+        std::os::unix::fs::symlink("docs/deep", stage.join("deep"))?;
+        std::os::unix::fs::symlink("deep/note.txt", stage.join("note"))?;
+    }
+    Ok(())
+}
+
+/// A byte range of a file, seen as a file of its own.
+struct Slice<'a> {
+    file: &'a mut File,
+    start: u64,
+    len: u64,
+    pos: u64,
+}
+
+impl<'a> Slice<'a> {
+    fn new(file: &'a mut File, start: u64, len: u64) -> Self {
+        Slice { file, start, len, pos: 0 }
+    }
+
+    fn available(&self, want: usize) -> usize {
+        want.min(self.len.saturating_sub(self.pos) as usize)
+    }
+}
+
+impl Read for Slice<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = self.available(buf.len());
+        self.file.seek(SeekFrom::Start(self.start + self.pos))?;
+        let read = self.file.read(&mut buf[..len])?;
+        self.pos += read as u64;
+        Ok(read)
+    }
+}
+
+impl Write for Slice<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = self.available(buf.len());
+        self.file.seek(SeekFrom::Start(self.start + self.pos))?;
+        let written = self.file.write(&buf[..len])?;
+        self.pos += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+impl Seek for Slice<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+            SeekFrom::End(delta) => self.len.checked_add_signed(delta),
+        };
+        self.pos = new.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "seek before start"))?;
+        Ok(self.pos)
+    }
+}
 
 fn main() {
     // Read environment variables
@@ -29,7 +273,64 @@ fn main() {
 
     let uefi = false; // Change to `true` to boot using UEFI
 
+    // `--smp N` boots with N CPUs, `--q35` the PCI Express machine (whose
+    // ACPI tables have an MCFG) instead of the default i440FX, and
+    // `--virtio-net` gives it a virtio-net card instead of QEMU's usual e1000.
+    // `--script FILE` runs without a display, feeds the commands in FILE to
+    // the kernel's remote shell and stops QEMU when they are done.
+    // `--audio FILE` adds an AC'97 sound card and records what it and the PC
+    // speaker play into the WAV file FILE.
+    let mut smp = None;
+    let mut q35 = false;
+    let mut nic = "e1000";
+    let mut script = None;
+    let mut audio = None;
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--q35" {
+            q35 = true;
+        } else if arg == "--virtio-net" {
+            nic = "virtio-net-pci";
+        } else if arg == "--audio" {
+            match args.next() {
+                Some(path) => audio = Some(path),
+                None => {
+                    eprintln!("Error: --audio expects a file");
+                    std::process::exit(1);
+                }
+            }
+        } else if arg == "--script" {
+            match args.next() {
+                Some(path) => script = Some(PathBuf::from(path)),
+                None => {
+                    eprintln!("Error: --script expects a file");
+                    std::process::exit(1);
+                }
+            }
+        } else if arg == "--smp" {
+            match args.next().and_then(|n| n.parse::<u32>().ok()) {
+                Some(n) if n > 0 => smp = Some(n),
+                _ => {
+                    eprintln!("Error: --smp expects a CPU count");
+                    std::process::exit(1);
+                }
+            }
+        }
+    }
+
     let mut cmd = Command::new(qemu_path);
+    let mut machine = String::from(if q35 { "q35" } else { "pc" });
+    if let Some(path) = &audio {
+        cmd.arg("-audiodev").arg(format!("wav,id=snd0,path={}", path));
+        machine.push_str(",pcspk-audiodev=snd0");
+        cmd.arg("-device").arg("AC97,audiodev=snd0");
+    }
+    if q35 || audio.is_some() {
+        cmd.arg("-machine").arg(machine);
+    }
+    if script.is_some() {
+        cmd.arg("-display").arg("none");
+    }
 
     if uefi {
         cmd.arg("-bios")
@@ -41,10 +342,47 @@ fn main() {
             .arg(format!("format=raw,file={}", bios_path));
     }
 
+    match scratch_disk(&bios_path) {
+        Ok(scratch) => {
+            cmd.arg("-drive")
+                .arg(format!("format=raw,file={},if=virtio", scratch.display()));
+        }
+        Err(e) => eprintln!("Warning: no scratch disk: {}", e),
+    }
+
+    match ext2_fixtures(&bios_path) {
+        Ok(fixtures) => {
+            for fixture in fixtures {
+                cmd.arg("-drive")
+                    .arg(format!("format=raw,file={},if=virtio,readonly=on", fixture.display()));
+            }
+        }
+        Err(e) => eprintln!("Warning: no ext2 fixtures: {}", e),
+    }
+
+    cmd.arg("-netdev")
+        .arg(netdev())
+        .arg("-device")
+        .arg(format!("{},netdev=net0", nic));
+
+    if let Some(cpus) = smp {
+        cmd.arg("-smp").arg(cpus.to_string());
+    }
+
     match cmd.spawn() {
         Ok(mut child) => {
             println!("QEMU started successfully!");
-            child.wait().expect("Failed to wait on QEMU");
+            if let Some(script) = script {
+                let result = run_script(&script);
+                let _ = child.kill();
+                let _ = child.wait();
+                if let Err(e) = result {
+                    eprintln!("Error: script {} failed: {}", script.display(), e);
+                    std::process::exit(1);
+                }
+            } else {
+                child.wait().expect("Failed to wait on QEMU");
+            }
         }
         Err(e) => {
             eprintln!("Failed to start QEMU: {:?}", e);
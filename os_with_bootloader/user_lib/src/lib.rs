@@ -0,0 +1,247 @@
+// System call wrappers for programs that run as user processes on the kernel.
+//
+// Every wrapper returns the kernel's result as-is turned into a `Result`:
+// negative values are errno codes.
+
+#![no_std]
+
+use core::arch::asm;
+
+/// System call numbers, for [`syscall6`] and [`int80`].
+// Must match `syscall::nr` in the kernel.
+pub mod nr {
+    pub const READ: u64 = 0;
+    pub const WRITE: u64 = 1;
+    pub const OPEN: u64 = 2;
+    pub const CLOSE: u64 = 3;
+    pub const MMAP: u64 = 4;
+    pub const SLEEP: u64 = 5;
+    pub const GETPID: u64 = 6;
+    pub const EXIT: u64 = 7;
+    pub const LSEEK: u64 = 8;
+    pub const STAT: u64 = 9;
+    pub const FSTAT: u64 = 10;
+    pub const GETDENTS: u64 = 11;
+}
+
+pub const STDIN: u64 = 0;
+pub const STDOUT: u64 = 1;
+pub const STDERR: u64 = 2;
+
+pub const O_RDONLY: u64 = 0;
+pub const O_WRONLY: u64 = 1;
+pub const O_RDWR: u64 = 2;
+pub const O_CREAT: u64 = 0o100;
+pub const O_TRUNC: u64 = 0o1000;
+pub const O_APPEND: u64 = 0o2000;
+pub const O_DIRECTORY: u64 = 0o200000;
+
+pub const SEEK_SET: u64 = 0;
+pub const SEEK_CUR: u64 = 1;
+pub const SEEK_END: u64 = 2;
+
+/// File kinds in [`Stat::kind`] and directory entries (`DT_*`).
+pub const DT_CHR: u8 = 2;
+pub const DT_DIR: u8 = 4;
+pub const DT_BLK: u8 = 6;
+pub const DT_REG: u8 = 8;
+pub const DT_LNK: u8 = 10;
+
+pub const PROT_READ: u64 = 1;
+pub const PROT_WRITE: u64 = 2;
+pub const PROT_EXEC: u64 = 4;
+pub const MAP_PRIVATE: u64 = 0x02;
+pub const MAP_ANONYMOUS: u64 = 0x20;
+
+/// What [`stat`] and [`fstat`] return.
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+pub struct Stat {
+    pub inode: u64,
+    pub size: u64,
+    pub mode: u32,
+    /// One of the `DT_*` constants.
+    pub kind: u32,
+}
+
+/// Error number returned by the kernel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Errno(pub u64);
+
+impl Errno {
+    pub const ENOENT: Errno = Errno(2);
+    pub const EIO: Errno = Errno(5);
+    pub const EBADF: Errno = Errno(9);
+    pub const ENOMEM: Errno = Errno(12);
+    pub const EFAULT: Errno = Errno(14);
+    pub const EBUSY: Errno = Errno(16);
+    pub const EEXIST: Errno = Errno(17);
+    pub const ENOTDIR: Errno = Errno(20);
+    pub const EISDIR: Errno = Errno(21);
+    pub const EINVAL: Errno = Errno(22);
+    pub const EMFILE: Errno = Errno(24);
+    pub const ENOSPC: Errno = Errno(28);
+    pub const EROFS: Errno = Errno(30);
+    pub const ENAMETOOLONG: Errno = Errno(36);
+    pub const ENOSYS: Errno = Errno(38);
+    pub const ENOTEMPTY: Errno = Errno(39);
+}
+
+fn check(ret: u64) -> Result<u64, Errno> {
+    let signed = ret as i64;
+    if (-4095..0).contains(&signed) {
+        Err(Errno(signed.unsigned_abs()))
+    } else {
+        Ok(ret)
+    }
+}
+
+/// Raw `syscall` with up to six arguments. `rcx` and `r11` are clobbered by
+/// the instruction itself.
+///
+/// # Safety
+///
+/// The arguments must be valid for the system call `number`.
+pub unsafe fn syscall6(number: u64, args: [u64; 6]) -> u64 {
+    let ret;
+    unsafe {
+        asm!(
+            "syscall",
+            inlateout("rax") number => ret,
+            in("rdi") args[0],
+            in("rsi") args[1],
+            in("rdx") args[2],
+            in("r10") args[3],
+            in("r8") args[4],
+            in("r9") args[5],
+            lateout("rcx") _,
+            lateout("r11") _,
+            options(nostack),
+        );
+    }
+    ret
+}
+
+/// Same as [`syscall6`], but through the `int 0x80` gate.
+///
+/// # Safety
+///
+/// The arguments must be valid for the system call `number`.
+pub unsafe fn int80(number: u64, args: [u64; 6]) -> u64 {
+    let ret;
+    unsafe {
+        asm!(
+            "int 0x80",
+            inlateout("rax") number => ret,
+            in("rdi") args[0],
+            in("rsi") args[1],
+            in("rdx") args[2],
+            in("r10") args[3],
+            in("r8") args[4],
+            in("r9") args[5],
+            options(nostack),
+        );
+    }
+    ret
+}
+
+pub fn read(fd: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+    let ret = unsafe { syscall6(nr::READ, [fd, buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0, 0]) };
+    check(ret).map(|count| count as usize)
+}
+
+pub fn write(fd: u64, buf: &[u8]) -> Result<usize, Errno> {
+    let ret = unsafe { syscall6(nr::WRITE, [fd, buf.as_ptr() as u64, buf.len() as u64, 0, 0, 0]) };
+    check(ret).map(|count| count as usize)
+}
+
+pub fn open(path: &str, flags: u64) -> Result<u64, Errno> {
+    let ret = unsafe { syscall6(nr::OPEN, [path.as_ptr() as u64, path.len() as u64, flags, 0, 0, 0]) };
+    check(ret)
+}
+
+pub fn close(fd: u64) -> Result<(), Errno> {
+    let ret = unsafe { syscall6(nr::CLOSE, [fd, 0, 0, 0, 0, 0]) };
+    check(ret).map(|_| ())
+}
+
+/// Moves the file offset; returns the new one.
+pub fn lseek(fd: u64, offset: i64, whence: u64) -> Result<u64, Errno> {
+    let ret = unsafe { syscall6(nr::LSEEK, [fd, offset as u64, whence, 0, 0, 0]) };
+    check(ret)
+}
+
+pub fn stat(path: &str) -> Result<Stat, Errno> {
+    let mut stat = Stat::default();
+    let ret = unsafe {
+        syscall6(nr::STAT, [path.as_ptr() as u64, path.len() as u64, &raw mut stat as u64, 0, 0, 0])
+    };
+    check(ret).map(|_| stat)
+}
+
+pub fn fstat(fd: u64) -> Result<Stat, Errno> {
+    let mut stat = Stat::default();
+    let ret = unsafe { syscall6(nr::FSTAT, [fd, &raw mut stat as u64, 0, 0, 0, 0]) };
+    check(ret).map(|_| stat)
+}
+
+/// Reads directory entries into `buf` in the `linux_dirent64` layout; see
+/// [`DirEntries`] for walking them. Returns 0 at the end of the directory.
+pub fn getdents(fd: u64, buf: &mut [u8]) -> Result<usize, Errno> {
+    let ret = unsafe { syscall6(nr::GETDENTS, [fd, buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0, 0]) };
+    check(ret).map(|count| count as usize)
+}
+
+/// Iterates over the `(inode, kind, name)` records [`getdents`] returned.
+pub struct DirEntries<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> DirEntries<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        DirEntries { data }
+    }
+}
+
+impl<'a> Iterator for DirEntries<'a> {
+    type Item = (u64, u8, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.data.len() < 19 {
+            return None;
+        }
+        let inode = u64::from_le_bytes(self.data[0..8].try_into().unwrap());
+        let record_len = usize::from(u16::from_le_bytes([self.data[16], self.data[17]]));
+        let kind = self.data[18];
+        // A record too short for its own header would never move us along.
+        if record_len < 19 || record_len > self.data.len() {
+            return None;
+        }
+        let record = &self.data[..record_len];
+        let name = &record[19..];
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+        self.data = &self.data[record_len..];
+        Some((inode, kind, core::str::from_utf8(name).unwrap_or("?")))
+    }
+}
+
+/// Maps `len` bytes of zeroed anonymous memory and returns its address.
+pub fn mmap(len: usize, prot: u64) -> Result<*mut u8, Errno> {
+    let flags = MAP_PRIVATE | MAP_ANONYMOUS;
+    let ret = unsafe { syscall6(nr::MMAP, [0, len as u64, prot, flags, u64::MAX, 0]) };
+    check(ret).map(|addr| addr as *mut u8)
+}
+
+/// Blocks for at least `ms` milliseconds.
+pub fn sleep(ms: u64) {
+    unsafe { syscall6(nr::SLEEP, [ms, 0, 0, 0, 0, 0]) };
+}
+
+pub fn getpid() -> u64 {
+    unsafe { syscall6(nr::GETPID, [0; 6]) }
+}
+
+pub fn exit(code: i64) -> ! {
+    unsafe { syscall6(nr::EXIT, [code as u64, 0, 0, 0, 0, 0]) };
+    unreachable!("exit returned")
+}
@@ -1,4 +1,6 @@
-use std::path::PathBuf;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 
 fn main() {
     // Print the OUT_DIR environment variable, set by Cargo for build outputs
@@ -15,15 +17,27 @@ fn main() {
     // Get the path to the kernel file (created with bootloader)
     let kernel = PathBuf::from(std::env::var_os("CARGO_BIN_FILE_KERNEL_WITH_BOOTLOADER").unwrap());
 
+    // Pack the initramfs directory (INITRAMFS_DIR overrides it) into a tar archive,
+    // which the bootloader hands to the kernel as its ramdisk
+    println!("cargo:rerun-if-env-changed=INITRAMFS_DIR");
+    let initramfs_dir = std::env::var_os("INITRAMFS_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(std::env::var_os("CARGO_MANIFEST_DIR").unwrap()).join("initramfs"));
+    println!("cargo:rerun-if-changed={}", initramfs_dir.display());
+    let ramdisk = out_dir.join("initramfs.tar");
+    pack_initramfs(&initramfs_dir, &ramdisk).unwrap();
+
     // Create a UEFI disk image (optional)
     let uefi_path = out_dir.join("uefi.img");
     bootloader::UefiBoot::new(&kernel)
+        .set_ramdisk(&ramdisk)
         .create_disk_image(&uefi_path)
         .unwrap();
 
     // Create a BIOS disk image
     let bios_path = out_dir.join("bios.img");
     bootloader::BiosBoot::new(&kernel)
+        .set_ramdisk(&ramdisk)
         .create_disk_image(&bios_path)
         .unwrap();
 
@@ -31,3 +45,55 @@ fn main() {
     println!("cargo:rustc-env=UEFI_PATH={}", uefi_path.display());
     println!("cargo:rustc-env=BIOS_PATH={}", bios_path.display());
 }
+
+// Writes `dir` as a ustar archive; a missing directory gives an empty archive.
+fn pack_initramfs(dir: &Path, out: &Path) -> io::Result<()> {
+    let mut archive = Vec::new();
+    if dir.is_dir() {
+        add_dir(&mut archive, dir, "")?;
+    }
+    // Two zero blocks end the archive
+    archive.extend_from_slice(&[0; 1024]);
+    fs::write(out, archive)
+}
+
+fn add_dir(archive: &mut Vec<u8>, dir: &Path, prefix: &str) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    // Sorted, so the archive doesn't change when the files don't
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        println!("cargo:rerun-if-changed={}", entry.path().display());
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            let name = name + "/";
+            archive.extend_from_slice(&tar_header(&name, 0, b'5', 0o755));
+            add_dir(archive, &entry.path(), &name)?;
+        } else if file_type.is_file() {
+            let data = fs::read(entry.path())?;
+            archive.extend_from_slice(&tar_header(&name, data.len() as u64, b'0', 0o644));
+            archive.extend_from_slice(&data);
+            archive.resize(archive.len().next_multiple_of(512), 0);
+        }
+    }
+    Ok(())
+}
+
+fn tar_header(name: &str, size: u64, kind: u8, mode: u32) -> [u8; 512] {
+    assert!(name.len() <= 100, "initramfs path too long for tar: {}", name);
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..108].copy_from_slice(format!("{:07o}\0", mode).as_bytes());
+    header[108..116].copy_from_slice(b"0000000\0"); // uid
+    header[116..124].copy_from_slice(b"0000000\0"); // gid
+    header[124..136].copy_from_slice(format!("{:011o}\0", size).as_bytes());
+    header[136..148].copy_from_slice(b"00000000000\0"); // mtime
+    header[156] = kind;
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    // The checksum is computed with its own field as spaces
+    header[148..156].copy_from_slice(b"        ");
+    let checksum: u32 = header.iter().map(|&b| u32::from(b)).sum();
+    header[148..156].copy_from_slice(format!("{:06o}\0 ", checksum).as_bytes());
+    header
+}